@@ -0,0 +1,231 @@
+use std::{
+    path::Path,
+    sync::RwLock,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use reqwest::{
+    header::{HeaderMap, HeaderValue, AUTHORIZATION, CONTENT_LENGTH},
+    Body, StatusCode,
+};
+use serde::Deserialize;
+use tokio::fs::File;
+use tokio_util::io::ReaderStream;
+use url::Url;
+
+use crate::{http, i18n::t};
+
+/// Storage API version sent with token authenticated requests, which Azure requires.
+const API_VERSION: &str = "2020-10-02";
+
+/// Tokens are fetched again this long before they expire, so none runs out mid-request.
+const TOKEN_MARGIN: Duration = Duration::from_secs(300);
+
+static TOKEN: RwLock<Option<(String, Instant)>> = RwLock::new(None);
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: u64,
+}
+
+fn env(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|v| !v.is_empty())
+}
+
+/// The `https` URL of the blob an `az://container/path` URL points at, in the storage
+/// account from `AZURE_STORAGE_ACCOUNT`. A SAS token in the URL's query, or else in
+/// `AZURE_STORAGE_SAS_TOKEN`, is carried over.
+pub fn blob_url(src: &Url) -> Result<Url> {
+    let account = env("AZURE_STORAGE_ACCOUNT").ok_or_else(|| anyhow!(t!("azure.no_account")))?;
+    let container = src
+        .host_str()
+        .ok_or_else(|| anyhow!("Azure URL has no container: {}", src))?;
+    let mut url = Url::parse(&format!(
+        "https://{}.blob.core.windows.net/{}{}",
+        account,
+        container,
+        src.path()
+    ))?;
+    let sas = src
+        .query()
+        .map(str::to_string)
+        .or_else(|| env("AZURE_STORAGE_SAS_TOKEN"));
+    url.set_query(sas.as_deref().map(|s| s.trim_start_matches('?')));
+    Ok(url)
+}
+
+/// Gets a token for the service principal in `AZURE_TENANT_ID`, `AZURE_CLIENT_ID` and
+/// `AZURE_CLIENT_SECRET`, the same variables the Azure SDKs read.
+async fn fetch_token() -> Result<(String, Instant)> {
+    let (tenant, client_id, secret) = match (
+        env("AZURE_TENANT_ID"),
+        env("AZURE_CLIENT_ID"),
+        env("AZURE_CLIENT_SECRET"),
+    ) {
+        (Some(t), Some(c), Some(s)) => (t, c, s),
+        _ => return Err(anyhow!(t!("azure.no_credentials"))),
+    };
+    let resp = http::client()
+        .post(format!(
+            "https://login.microsoftonline.com/{}/oauth2/v2.0/token",
+            tenant
+        ))
+        .form(&[
+            ("grant_type", "client_credentials"),
+            ("client_id", client_id.as_str()),
+            ("client_secret", secret.as_str()),
+            ("scope", "https://storage.azure.com/.default"),
+        ])
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<TokenResponse>()
+        .await?;
+    let expires = Instant::now() + Duration::from_secs(resp.expires_in);
+    Ok((resp.access_token, expires))
+}
+
+async fn token() -> Result<String> {
+    if let Some((token, expires)) = TOKEN.read().unwrap().as_ref() {
+        if Instant::now() + TOKEN_MARGIN < *expires {
+            return Ok(token.clone());
+        }
+    }
+    let (token, expires) = fetch_token().await?;
+    *TOKEN.write().unwrap() = Some((token.clone(), expires));
+    Ok(token)
+}
+
+/// The blob URL of `src` and the headers to request it with. With a SAS token the URL
+/// carries the authorization, without one a token from the environment's service
+/// principal goes into the headers.
+pub async fn request_parts(src: &Url) -> Result<(Url, HeaderMap)> {
+    let url = blob_url(src)?;
+    let mut headers = HeaderMap::new();
+    if url.query().is_none() {
+        headers.insert(
+            AUTHORIZATION,
+            HeaderValue::from_str(&format!("Bearer {}", token().await?))?,
+        );
+        headers.insert("x-ms-version", HeaderValue::from_static(API_VERSION));
+    }
+    Ok((url, headers))
+}
+
+/// The response carrying the blob at `src`, `None` if there is no such blob.
+pub async fn fetch(src: &Url) -> Result<Option<reqwest::Response>> {
+    let (url, headers) = request_parts(src).await?;
+    let resp = http::client().get(url).headers(headers).send().await?;
+    if resp.status() == StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    Ok(Some(resp.error_for_status()?))
+}
+
+/// Size of the blob at `src`.
+pub async fn size(src: &Url) -> Result<Option<u64>> {
+    let (url, headers) = request_parts(src).await?;
+    let resp = http::client()
+        .head(url)
+        .headers(headers)
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(resp.content_length())
+}
+
+/// Uploads `local_file` as the block blob at `dest` in a single request, streaming it
+/// from disk.
+pub async fn put(dest: &Url, local_file: &Path) -> Result<()> {
+    let (url, headers) = request_parts(dest).await?;
+    let f = File::open(local_file).await?;
+    let len = f.metadata().await?.len();
+    let resp = http::client()
+        .put(url)
+        .headers(headers)
+        .header("x-ms-blob-type", "BlockBlob")
+        .header(CONTENT_LENGTH, len)
+        .body(Body::wrap_stream(ReaderStream::new(f)))
+        .send()
+        .await?;
+    if !resp.status().is_success() {
+        return Err(anyhow!(t!("azure.failed", "PUT", dest, resp.status())));
+    }
+    Ok(())
+}
+
+/// Deletes the blob at `url`. Deleting one that isn't there is fine.
+pub async fn delete(url: &Url) -> Result<()> {
+    let (blob, headers) = request_parts(url).await?;
+    let resp = http::client().delete(blob).headers(headers).send().await?;
+    match resp.status() {
+        s if s.is_success() || s == StatusCode::NOT_FOUND => Ok(()),
+        s => Err(anyhow!(t!("azure.failed", "DELETE", url, s))),
+    }
+}
+
+/// Text of every `<tag>` element in `xml`, with the entities Azure escapes decoded. Blob
+/// listings are simple enough not to need an XML parser.
+fn elements<'a>(xml: &'a str, tag: &'a str) -> impl Iterator<Item = String> + 'a {
+    let (open, close) = (format!("<{}>", tag), format!("</{}>", tag));
+    xml.split(open.as_str()).skip(1).filter_map(move |rest| {
+        let text = rest.split_once(close.as_str())?.0;
+        Some(
+            text.replace("&lt;", "<")
+                .replace("&gt;", ">")
+                .replace("&quot;", "\"")
+                .replace("&apos;", "'")
+                .replace("&amp;", "&"),
+        )
+    })
+}
+
+/// `az://` URLs of every blob below `prefix`, following pagination.
+pub async fn list(prefix: &Url) -> Result<Vec<Url>> {
+    let container = prefix
+        .host_str()
+        .ok_or_else(|| anyhow!("Azure URL has no container: {}", prefix))?;
+    let mut container_url = prefix.clone();
+    container_url.set_path("");
+    let (base, headers) = request_parts(&container_url).await?;
+    let blob_prefix = percent_encoding::percent_decode_str(prefix.path().trim_start_matches('/'))
+        .decode_utf8()?
+        .into_owned();
+    let mut urls = Vec::new();
+    let mut marker: Option<String> = None;
+    loop {
+        let mut url = base.clone();
+        url.query_pairs_mut()
+            .append_pair("restype", "container")
+            .append_pair("comp", "list")
+            .append_pair("prefix", &blob_prefix);
+        if let Some(m) = &marker {
+            url.query_pairs_mut().append_pair("marker", m);
+        }
+        let body = http::client()
+            .get(url)
+            .headers(headers.clone())
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        for name in elements(&body, "Name") {
+            let mut blob = Url::parse(&format!("az://{}/", container))?;
+            blob.path_segments_mut()
+                .map_err(|_| anyhow!("Cannot build an Azure URL for {}", name))?
+                .clear()
+                .extend(name.split('/'));
+            urls.push(blob);
+        }
+        marker = elements(&body, "NextMarker")
+            .next()
+            .filter(|m| !m.is_empty());
+        if marker.is_none() {
+            break;
+        }
+    }
+    Ok(urls)
+}
@@ -0,0 +1,713 @@
+use std::{path::Path, pin::Pin, time::Duration};
+
+use anyhow::{anyhow, Result};
+use futures::future::BoxFuture;
+use google_cloud_storage::http::objects::get::GetObjectRequest;
+use reqwest::{header::HeaderMap, StatusCode};
+use tokio::io::AsyncRead;
+use url::Url;
+
+use crate::{
+    azure,
+    events::EventSender,
+    ftp, github, http,
+    i18n::t,
+    ipfs,
+    manifest::{fetch_manifest_http, Manifest},
+    oci, plugin,
+    push::{self, gcs, local, s3, sftp},
+    signed,
+    sync::{
+        get_file_file, get_file_gcs, get_file_http, get_file_plugin, get_file_s3, write_chunks,
+    },
+    util, webdav,
+};
+
+/// A storage provider, what manifests and sources with one of its URL schemes are read from
+/// and pushed to. Everything but downloading is optional, the defaults say it isn't
+/// supported. Content addressed stores, OCI registries and IPFS, can't store a file at a
+/// URL of the pusher's choosing and have pushes of their own.
+pub trait Backend: Send + Sync {
+    /// The manifest at `url` as it arrives, maybe compressed, `None` if there is none.
+    fn get_manifest<'a>(&'a self, url: &'a Url) -> BoxFuture<'a, Result<Option<ManifestBody>>> {
+        unsupported("get-manifest", url)
+    }
+
+    /// Downloads `src` to `dest`, returning the SHA-512 of the bytes written. With
+    /// `sparse`, zero blocks are left as holes.
+    fn get_file<'a>(
+        &'a self,
+        src: &'a Url,
+        dest: &'a Path,
+        stall_timeout: Duration,
+        sparse: bool,
+        tx: EventSender,
+    ) -> BoxFuture<'a, Result<String>>;
+
+    /// Size of `src` without downloading it, if the backend can tell.
+    fn size<'a>(&'a self, _src: &'a Url) -> BoxFuture<'a, Result<Option<u64>>> {
+        Box::pin(async { Ok(None) })
+    }
+
+    /// Stores `local_file` at `dest`, replacing what is there. `sha512` is passed along for
+    /// storage that can check it.
+    fn put_file<'a>(
+        &'a self,
+        dest: &'a Url,
+        _local_file: &'a Path,
+        _sha512: Option<&'a str>,
+    ) -> BoxFuture<'a, Result<()>> {
+        unsupported("put", dest)
+    }
+
+    /// Removes `url`. Removing something that isn't there is not an error.
+    fn delete_file<'a>(&'a self, url: &'a Url) -> BoxFuture<'a, Result<()>> {
+        unsupported("delete", url)
+    }
+
+    /// URLs of everything stored below `prefix`.
+    fn list<'a>(&'a self, prefix: &'a Url) -> BoxFuture<'a, Result<Vec<Url>>> {
+        unsupported("list", prefix)
+    }
+
+    /// Pushes `base`, whose manifest is `local`, to the manifest URL `target`, returning
+    /// the manifest as published if it differs from `local`. The default stores changed
+    /// files with `put_file` and drops the rest with `delete_file`, see `push::push_dir`.
+    fn push<'a>(
+        &'a self,
+        base: &'a Path,
+        target: &'a Url,
+        local: &'a Manifest,
+        remote: Option<&'a Manifest>,
+        checksums: bool,
+    ) -> BoxFuture<'a, Result<Option<Manifest>>> {
+        Box::pin(async move {
+            // the uploads run on tasks of their own, which need the registry's backend
+            push::push_dir(for_url(target)?, base, target, local, remote, checksums).await?;
+            Ok(None)
+        })
+    }
+}
+
+/// A manifest as its backend delivers it.
+pub type ManifestBody = Pin<Box<dyn AsyncRead + Send>>;
+
+/// The body of a manifest response, read as it arrives.
+fn streamed(resp: reqwest::Response) -> ManifestBody {
+    Box::pin(http::body_reader(resp))
+}
+
+/// A manifest its backend can only hand over whole.
+fn in_memory(body: Option<Vec<u8>>) -> Option<ManifestBody> {
+    body.map(|b| Box::pin(std::io::Cursor::new(b)) as ManifestBody)
+}
+
+fn unsupported<'a, T: Send + 'a>(op: &'static str, url: &Url) -> BoxFuture<'a, Result<T>> {
+    let err = anyhow!(t!("backend.unsupported", url.scheme(), op));
+    Box::pin(async move { Err(err) })
+}
+
+/// Every backend, by the URL schemes it handles.
+static BACKENDS: &[(&[&str], &dyn Backend)] = &[
+    (&["http", "https"], &Http),
+    (&["dav", "davs"], &Webdav),
+    (&["file"], &File),
+    (&["ftp", "ftps"], &Ftp),
+    (&["ssh"], &Sftp),
+    (&["gs"], &Gcs),
+    (&["s3"], &S3),
+    (&["az"], &Azure),
+    (&["github"], &Github),
+    (&["oci"], &Oci),
+    (&["ipfs"], &Ipfs),
+    (&["plugin", "exec"], &Plugin),
+];
+
+/// The backend for `url`'s scheme.
+pub fn for_url(url: &Url) -> Result<&'static dyn Backend> {
+    BACKENDS
+        .iter()
+        .find(|(schemes, _)| schemes.contains(&url.scheme()))
+        .map(|(_, b)| *b)
+        .ok_or_else(|| anyhow!(t!("backend.unknown_scheme", url.scheme(), url)))
+}
+
+/// Whether an HTTP download was refused for lack of credentials.
+fn is_denied(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<reqwest::Error>()
+        .and_then(|e| e.status())
+        .is_some_and(|s| s == StatusCode::UNAUTHORIZED || s == StatusCode::FORBIDDEN)
+}
+
+/// Downloads a GCS or S3 object through a signed URL, which unlike the storage APIs
+/// resumes where a broken download left off.
+async fn get_file_signed(
+    src: &Url,
+    dest: &Path,
+    stall_timeout: Duration,
+    sparse: bool,
+    tx: EventSender,
+) -> Result<String> {
+    let url = signed::url_for(src)
+        .await?
+        .ok_or_else(|| anyhow!("Cannot sign a URL for {}", src))?;
+    let headers = match gcs::object_of(src) {
+        Some(_) => gcs::encryption_headers()?,
+        None => HeaderMap::new(),
+    };
+    get_file_http(&url, &headers, dest, stall_timeout, sparse, tx).await
+}
+
+async fn head_size(url: &Url) -> Result<Option<u64>> {
+    let resp = http::client()
+        .head(url.as_ref())
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(resp.content_length())
+}
+
+struct Http;
+
+impl Backend for Http {
+    fn get_manifest<'a>(&'a self, url: &'a Url) -> BoxFuture<'a, Result<Option<ManifestBody>>> {
+        Box::pin(async move { Ok(fetch_manifest_http(url).await?.map(streamed)) })
+    }
+
+    fn get_file<'a>(
+        &'a self,
+        src: &'a Url,
+        dest: &'a Path,
+        stall_timeout: Duration,
+        sparse: bool,
+        tx: EventSender,
+    ) -> BoxFuture<'a, Result<String>> {
+        Box::pin(async move {
+            // requester pays buckets refuse anonymous requests
+            if gcs::billing_project().is_some() && gcs::object_of(src).is_some() {
+                return Gcs.get_file(src, dest, stall_timeout, sparse, tx).await;
+            }
+            let headers = HeaderMap::new();
+            match get_file_http(src, &headers, dest, stall_timeout, sparse, tx.clone()).await {
+                // a private bucket, try again with credentials
+                Err(e) if is_denied(&e) && gcs::object_of(src).is_some() => {
+                    if signed::enabled() {
+                        get_file_signed(src, dest, stall_timeout, sparse, tx).await
+                    } else {
+                        get_file_gcs(src, dest, stall_timeout, sparse, tx).await
+                    }
+                }
+                res => res,
+            }
+        })
+    }
+
+    fn size<'a>(&'a self, src: &'a Url) -> BoxFuture<'a, Result<Option<u64>>> {
+        Box::pin(head_size(src))
+    }
+
+    fn put_file<'a>(
+        &'a self,
+        dest: &'a Url,
+        local_file: &'a Path,
+        _sha512: Option<&'a str>,
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(push::http::put(dest, local_file))
+    }
+
+    fn delete_file<'a>(&'a self, url: &'a Url) -> BoxFuture<'a, Result<()>> {
+        Box::pin(push::http::delete(url))
+    }
+}
+
+struct Webdav;
+
+impl Backend for Webdav {
+    fn get_manifest<'a>(&'a self, url: &'a Url) -> BoxFuture<'a, Result<Option<ManifestBody>>> {
+        Box::pin(async move {
+            let url = webdav::to_http(url)?;
+            Ok(fetch_manifest_http(&url).await?.map(streamed))
+        })
+    }
+
+    fn get_file<'a>(
+        &'a self,
+        src: &'a Url,
+        dest: &'a Path,
+        stall_timeout: Duration,
+        sparse: bool,
+        tx: EventSender,
+    ) -> BoxFuture<'a, Result<String>> {
+        Box::pin(async move {
+            let src = webdav::to_http(src)?;
+            get_file_http(&src, &HeaderMap::new(), dest, stall_timeout, sparse, tx).await
+        })
+    }
+
+    fn size<'a>(&'a self, src: &'a Url) -> BoxFuture<'a, Result<Option<u64>>> {
+        Box::pin(async move { head_size(&webdav::to_http(src)?).await })
+    }
+
+    fn put_file<'a>(
+        &'a self,
+        dest: &'a Url,
+        local_file: &'a Path,
+        _sha512: Option<&'a str>,
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move { webdav::Client::configured().put(dest, local_file).await })
+    }
+
+    fn delete_file<'a>(&'a self, url: &'a Url) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move { webdav::Client::configured().delete(url).await })
+    }
+}
+
+struct File;
+
+impl Backend for File {
+    fn get_manifest<'a>(&'a self, url: &'a Url) -> BoxFuture<'a, Result<Option<ManifestBody>>> {
+        Box::pin(async move {
+            let path = util::url_path(url)?;
+            if !path.is_file() {
+                return Ok(None);
+            }
+            Ok(Some(
+                Box::pin(tokio::fs::File::open(path).await?) as ManifestBody
+            ))
+        })
+    }
+
+    fn get_file<'a>(
+        &'a self,
+        src: &'a Url,
+        dest: &'a Path,
+        _stall_timeout: Duration,
+        sparse: bool,
+        tx: EventSender,
+    ) -> BoxFuture<'a, Result<String>> {
+        Box::pin(get_file_file(src, dest, sparse, tx))
+    }
+
+    fn size<'a>(&'a self, src: &'a Url) -> BoxFuture<'a, Result<Option<u64>>> {
+        Box::pin(async move { Ok(Some(tokio::fs::metadata(util::url_path(src)?).await?.len())) })
+    }
+
+    fn put_file<'a>(
+        &'a self,
+        dest: &'a Url,
+        local_file: &'a Path,
+        _sha512: Option<&'a str>,
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move { local::copy_into_place(local_file, &util::url_path(dest)?).await })
+    }
+
+    fn delete_file<'a>(&'a self, url: &'a Url) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move { local::remove(&util::url_path(url)?).await })
+    }
+
+    fn list<'a>(&'a self, prefix: &'a Url) -> BoxFuture<'a, Result<Vec<Url>>> {
+        Box::pin(async move {
+            let dir = util::url_path(prefix)?;
+            ignore::WalkBuilder::new(&dir)
+                .standard_filters(false)
+                .build()
+                .filter_map(|d| d.ok())
+                .filter(|d| d.path().is_file())
+                .map(|d| util::file_url(d.path()))
+                .collect()
+        })
+    }
+}
+
+struct Ftp;
+
+impl Backend for Ftp {
+    fn get_manifest<'a>(&'a self, url: &'a Url) -> BoxFuture<'a, Result<Option<ManifestBody>>> {
+        Box::pin(async move { Ok(in_memory(ftp::fetch(url).await?)) })
+    }
+
+    fn get_file<'a>(
+        &'a self,
+        src: &'a Url,
+        dest: &'a Path,
+        stall_timeout: Duration,
+        sparse: bool,
+        tx: EventSender,
+    ) -> BoxFuture<'a, Result<String>> {
+        Box::pin(write_chunks(
+            src,
+            ftp::download(src),
+            dest,
+            stall_timeout,
+            sparse,
+            tx,
+        ))
+    }
+
+    fn size<'a>(&'a self, src: &'a Url) -> BoxFuture<'a, Result<Option<u64>>> {
+        Box::pin(ftp::size(src))
+    }
+
+    fn put_file<'a>(
+        &'a self,
+        dest: &'a Url,
+        local_file: &'a Path,
+        _sha512: Option<&'a str>,
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(ftp::put(dest, local_file))
+    }
+
+    fn delete_file<'a>(&'a self, url: &'a Url) -> BoxFuture<'a, Result<()>> {
+        Box::pin(ftp::delete(url))
+    }
+}
+
+struct Sftp;
+
+impl Backend for Sftp {
+    fn get_file<'a>(
+        &'a self,
+        src: &'a Url,
+        dest: &'a Path,
+        stall_timeout: Duration,
+        sparse: bool,
+        tx: EventSender,
+    ) -> BoxFuture<'a, Result<String>> {
+        Box::pin(write_chunks(
+            src,
+            sftp::download(src),
+            dest,
+            stall_timeout,
+            sparse,
+            tx,
+        ))
+    }
+
+    fn size<'a>(&'a self, src: &'a Url) -> BoxFuture<'a, Result<Option<u64>>> {
+        Box::pin(sftp::size(src))
+    }
+
+    fn put_file<'a>(
+        &'a self,
+        dest: &'a Url,
+        local_file: &'a Path,
+        _sha512: Option<&'a str>,
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(sftp::put_file(dest, local_file))
+    }
+
+    fn delete_file<'a>(&'a self, url: &'a Url) -> BoxFuture<'a, Result<()>> {
+        Box::pin(sftp::delete_file(url))
+    }
+
+    fn list<'a>(&'a self, prefix: &'a Url) -> BoxFuture<'a, Result<Vec<Url>>> {
+        Box::pin(sftp::list(prefix))
+    }
+}
+
+struct Gcs;
+
+impl Backend for Gcs {
+    fn get_manifest<'a>(&'a self, url: &'a Url) -> BoxFuture<'a, Result<Option<ManifestBody>>> {
+        Box::pin(async move { Ok(in_memory(gcs::fetch(url).await?)) })
+    }
+
+    fn get_file<'a>(
+        &'a self,
+        src: &'a Url,
+        dest: &'a Path,
+        stall_timeout: Duration,
+        sparse: bool,
+        tx: EventSender,
+    ) -> BoxFuture<'a, Result<String>> {
+        Box::pin(async move {
+            if signed::enabled() {
+                get_file_signed(src, dest, stall_timeout, sparse, tx).await
+            } else {
+                get_file_gcs(src, dest, stall_timeout, sparse, tx).await
+            }
+        })
+    }
+
+    fn size<'a>(&'a self, src: &'a Url) -> BoxFuture<'a, Result<Option<u64>>> {
+        Box::pin(async move {
+            let (bucket, object) =
+                gcs::object_of(src).ok_or_else(|| anyhow!("Not a GCS object URL: {}", src))?;
+            let o = gcs::client()
+                .await?
+                .get_object(
+                    &GetObjectRequest {
+                        bucket,
+                        object,
+                        ..Default::default()
+                    },
+                    None,
+                )
+                .await?;
+            // the size of a gzipped object is the compressed one
+            Ok(o.content_encoding.is_none().then_some(o.size as u64))
+        })
+    }
+
+    fn put_file<'a>(
+        &'a self,
+        dest: &'a Url,
+        local_file: &'a Path,
+        sha512: Option<&'a str>,
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(gcs::put(dest, local_file, sha512))
+    }
+
+    fn delete_file<'a>(&'a self, url: &'a Url) -> BoxFuture<'a, Result<()>> {
+        Box::pin(gcs::delete(url))
+    }
+
+    fn list<'a>(&'a self, prefix: &'a Url) -> BoxFuture<'a, Result<Vec<Url>>> {
+        Box::pin(gcs::list(prefix))
+    }
+
+    /// Stages the upload and only publishes once every object is in place, see
+    /// `gcs::push_to`.
+    fn push<'a>(
+        &'a self,
+        base: &'a Path,
+        target: &'a Url,
+        local: &'a Manifest,
+        remote: Option<&'a Manifest>,
+        checksums: bool,
+    ) -> BoxFuture<'a, Result<Option<Manifest>>> {
+        Box::pin(async move {
+            let published = gcs::push_to(base, target, local, remote, checksums).await?;
+            Ok(Some(published))
+        })
+    }
+}
+
+struct S3;
+
+impl Backend for S3 {
+    fn get_manifest<'a>(&'a self, url: &'a Url) -> BoxFuture<'a, Result<Option<ManifestBody>>> {
+        Box::pin(async move {
+            Ok(s3::get(url)
+                .await?
+                .map(|o| Box::pin(o.body.into_async_read()) as ManifestBody))
+        })
+    }
+
+    fn get_file<'a>(
+        &'a self,
+        src: &'a Url,
+        dest: &'a Path,
+        stall_timeout: Duration,
+        sparse: bool,
+        tx: EventSender,
+    ) -> BoxFuture<'a, Result<String>> {
+        Box::pin(async move {
+            if signed::enabled() {
+                get_file_signed(src, dest, stall_timeout, sparse, tx).await
+            } else {
+                get_file_s3(src, dest, stall_timeout, sparse, tx).await
+            }
+        })
+    }
+
+    fn size<'a>(&'a self, src: &'a Url) -> BoxFuture<'a, Result<Option<u64>>> {
+        Box::pin(s3::size(src))
+    }
+
+    fn put_file<'a>(
+        &'a self,
+        dest: &'a Url,
+        local_file: &'a Path,
+        sha512: Option<&'a str>,
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(s3::put(dest, local_file, sha512))
+    }
+
+    fn delete_file<'a>(&'a self, url: &'a Url) -> BoxFuture<'a, Result<()>> {
+        Box::pin(s3::delete(url))
+    }
+
+    fn list<'a>(&'a self, prefix: &'a Url) -> BoxFuture<'a, Result<Vec<Url>>> {
+        Box::pin(s3::list(prefix))
+    }
+}
+
+struct Azure;
+
+impl Backend for Azure {
+    fn get_manifest<'a>(&'a self, url: &'a Url) -> BoxFuture<'a, Result<Option<ManifestBody>>> {
+        Box::pin(async move { Ok(azure::fetch(url).await?.map(streamed)) })
+    }
+
+    fn get_file<'a>(
+        &'a self,
+        src: &'a Url,
+        dest: &'a Path,
+        stall_timeout: Duration,
+        sparse: bool,
+        tx: EventSender,
+    ) -> BoxFuture<'a, Result<String>> {
+        Box::pin(async move {
+            let (url, headers) = azure::request_parts(src).await?;
+            get_file_http(&url, &headers, dest, stall_timeout, sparse, tx).await
+        })
+    }
+
+    fn size<'a>(&'a self, src: &'a Url) -> BoxFuture<'a, Result<Option<u64>>> {
+        Box::pin(azure::size(src))
+    }
+
+    fn put_file<'a>(
+        &'a self,
+        dest: &'a Url,
+        local_file: &'a Path,
+        _sha512: Option<&'a str>,
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(azure::put(dest, local_file))
+    }
+
+    fn delete_file<'a>(&'a self, url: &'a Url) -> BoxFuture<'a, Result<()>> {
+        Box::pin(azure::delete(url))
+    }
+
+    fn list<'a>(&'a self, prefix: &'a Url) -> BoxFuture<'a, Result<Vec<Url>>> {
+        Box::pin(azure::list(prefix))
+    }
+}
+
+struct Github;
+
+impl Backend for Github {
+    fn get_manifest<'a>(&'a self, url: &'a Url) -> BoxFuture<'a, Result<Option<ManifestBody>>> {
+        Box::pin(async move { Ok(github::fetch(url).await?.map(streamed)) })
+    }
+
+    fn get_file<'a>(
+        &'a self,
+        src: &'a Url,
+        dest: &'a Path,
+        stall_timeout: Duration,
+        sparse: bool,
+        tx: EventSender,
+    ) -> BoxFuture<'a, Result<String>> {
+        Box::pin(async move {
+            let (url, headers) = github::request_parts(src).await?;
+            get_file_http(&url, &headers, dest, stall_timeout, sparse, tx).await
+        })
+    }
+
+    fn size<'a>(&'a self, src: &'a Url) -> BoxFuture<'a, Result<Option<u64>>> {
+        Box::pin(github::size(src))
+    }
+
+    fn put_file<'a>(
+        &'a self,
+        dest: &'a Url,
+        local_file: &'a Path,
+        _sha512: Option<&'a str>,
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(push::github::put(dest, local_file))
+    }
+
+    fn delete_file<'a>(&'a self, url: &'a Url) -> BoxFuture<'a, Result<()>> {
+        Box::pin(push::github::delete(url))
+    }
+
+    fn list<'a>(&'a self, prefix: &'a Url) -> BoxFuture<'a, Result<Vec<Url>>> {
+        Box::pin(push::github::list(prefix))
+    }
+}
+
+struct Oci;
+
+impl Backend for Oci {
+    fn get_manifest<'a>(&'a self, url: &'a Url) -> BoxFuture<'a, Result<Option<ManifestBody>>> {
+        Box::pin(async move { Ok(in_memory(oci::fetch(url).await?)) })
+    }
+
+    fn get_file<'a>(
+        &'a self,
+        src: &'a Url,
+        dest: &'a Path,
+        stall_timeout: Duration,
+        sparse: bool,
+        tx: EventSender,
+    ) -> BoxFuture<'a, Result<String>> {
+        Box::pin(async move {
+            let (url, headers) = oci::request_parts(src).await?;
+            get_file_http(&url, &headers, dest, stall_timeout, sparse, tx).await
+        })
+    }
+
+    fn size<'a>(&'a self, src: &'a Url) -> BoxFuture<'a, Result<Option<u64>>> {
+        Box::pin(oci::size(src))
+    }
+}
+
+struct Ipfs;
+
+impl Backend for Ipfs {
+    fn get_manifest<'a>(&'a self, url: &'a Url) -> BoxFuture<'a, Result<Option<ManifestBody>>> {
+        Box::pin(async move { Ok(in_memory(ipfs::fetch(url).await?)) })
+    }
+
+    fn get_file<'a>(
+        &'a self,
+        src: &'a Url,
+        dest: &'a Path,
+        stall_timeout: Duration,
+        sparse: bool,
+        tx: EventSender,
+    ) -> BoxFuture<'a, Result<String>> {
+        Box::pin(write_chunks(
+            src,
+            ipfs::download(src),
+            dest,
+            stall_timeout,
+            sparse,
+            tx,
+        ))
+    }
+
+    fn size<'a>(&'a self, src: &'a Url) -> BoxFuture<'a, Result<Option<u64>>> {
+        Box::pin(ipfs::size(src))
+    }
+}
+
+/// `plugin://` and `exec://` helpers. The protocol has no way to ask for a size.
+struct Plugin;
+
+impl Backend for Plugin {
+    fn get_manifest<'a>(&'a self, url: &'a Url) -> BoxFuture<'a, Result<Option<ManifestBody>>> {
+        Box::pin(async move { Ok(in_memory(plugin::get_manifest(url).await?)) })
+    }
+
+    fn get_file<'a>(
+        &'a self,
+        src: &'a Url,
+        dest: &'a Path,
+        _stall_timeout: Duration,
+        sparse: bool,
+        tx: EventSender,
+    ) -> BoxFuture<'a, Result<String>> {
+        Box::pin(get_file_plugin(src, dest, sparse, tx))
+    }
+
+    fn put_file<'a>(
+        &'a self,
+        dest: &'a Url,
+        local_file: &'a Path,
+        sha512: Option<&'a str>,
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(plugin::put_object(dest, local_file, sha512))
+    }
+
+    fn delete_file<'a>(&'a self, url: &'a Url) -> BoxFuture<'a, Result<()>> {
+        Box::pin(plugin::delete(url))
+    }
+
+    fn list<'a>(&'a self, prefix: &'a Url) -> BoxFuture<'a, Result<Vec<Url>>> {
+        Box::pin(plugin::list(prefix))
+    }
+}
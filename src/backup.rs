@@ -0,0 +1,153 @@
+use std::{
+    collections::BTreeSet,
+    fs::{self, File},
+    io::{BufReader, BufWriter},
+    path::{Path, PathBuf},
+    sync::RwLock,
+};
+
+use anyhow::{anyhow, Result};
+use ignore::WalkBuilder;
+use relative_path::RelativePathBuf;
+use serde::{Deserialize, Serialize};
+
+use crate::{i18n::t, perms, util};
+
+const BACKUP_DIR: &str = "backup";
+const BACKUP_FILE: &str = "backup.json";
+const FILES_DIR: &str = "files";
+
+/// Synced directory whose replaced and deleted files are kept, if backups are on.
+static DIR: RwLock<Option<PathBuf>> = RwLock::new(None);
+
+/// What the last sync with `--backup` changed, enough to put the directory back the way
+/// it was. The replaced and deleted files themselves are under `files/`.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Backup {
+    /// Files that sync created where there was nothing before.
+    pub created: BTreeSet<RelativePathBuf>,
+    /// Whether there was a local manifest before the sync.
+    pub had_manifest: bool,
+}
+
+fn backup_dir(dir: &Path) -> PathBuf {
+    util::state_dir(dir).join(BACKUP_DIR)
+}
+
+fn files_dir(dir: &Path) -> PathBuf {
+    backup_dir(dir).join(FILES_DIR)
+}
+
+/// Makes sync move every file it replaces or deletes in `dir` into the backup first.
+pub fn configure(dir: Option<&Path>) {
+    *DIR.write().unwrap() = dir.map(Path::to_path_buf);
+}
+
+/// Replaces the previous backup of `dir` with a fresh one for a sync about to create
+/// `created`, keeping the current local manifest.
+pub fn start(dir: &Path, created: BTreeSet<RelativePathBuf>) -> Result<()> {
+    let backup = backup_dir(dir);
+    if backup.exists() {
+        fs::remove_dir_all(&backup)?;
+    }
+    fs::create_dir_all(&backup)?;
+    let manifest = dir.join("comstar.json");
+    let had_manifest = manifest.is_file();
+    if had_manifest {
+        fs::copy(&manifest, backup.join("comstar.json"))?;
+    }
+    let writer = BufWriter::new(File::create(backup.join(BACKUP_FILE))?);
+    serde_json::to_writer_pretty(
+        writer,
+        &Backup {
+            created,
+            had_manifest,
+        },
+    )?;
+    Ok(())
+}
+
+/// Moves `path` into the backup before sync replaces or deletes it. Does nothing when
+/// backups are off, the file doesn't exist or it is comstar's own, e.g. a staged download.
+pub fn preserve(path: &Path) -> Result<()> {
+    let dir = match DIR.read().unwrap().clone() {
+        Some(d) => d,
+        None => return Ok(()),
+    };
+    if !path.is_file() || path.starts_with(util::state_dir(&dir)) {
+        return Ok(());
+    }
+    let relative = match path.strip_prefix(&dir) {
+        Ok(r) => RelativePathBuf::from_path(r)?,
+        Err(_) => return Ok(()),
+    };
+    let kept = relative.to_logical_path(files_dir(&dir));
+    // the first version is the one from before the sync
+    if kept.exists() {
+        return Ok(());
+    }
+    if let Some(parent) = kept.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::rename(path, kept)?;
+    Ok(())
+}
+
+/// Puts `dir` back the way it was before the last sync with `--backup`: removes files that
+/// sync created, moves replaced and deleted files back and restores the local manifest.
+pub fn rollback(dir: &Path) -> Result<()> {
+    let backup = backup_dir(dir);
+    let record = backup.join(BACKUP_FILE);
+    if !record.is_file() {
+        return Err(anyhow!(t!("rollback.none", dir.display())));
+    }
+    let br = BufReader::new(File::open(&record)?);
+    let Backup {
+        created,
+        had_manifest,
+    } = serde_json::from_reader(br)?;
+
+    let mut removed = 0;
+    for path in created {
+        let live = path.to_logical_path(dir);
+        if live.is_file() {
+            let unlocked = perms::unlock(&live, true)?;
+            let res = fs::remove_file(&live);
+            unlocked.relock()?;
+            res?;
+            removed += 1;
+        }
+    }
+
+    let files = files_dir(dir);
+    let mut restored = 0;
+    if files.is_dir() {
+        let kept: Vec<PathBuf> = WalkBuilder::new(&files)
+            .standard_filters(false)
+            .build()
+            .filter_map(|d| d.ok())
+            .filter(|d| d.path().is_file())
+            .map(|d| d.into_path())
+            .collect();
+        for entry in kept {
+            let relative = RelativePathBuf::from_path(entry.strip_prefix(&files)?)?;
+            let live = relative.to_logical_path(dir);
+            perms::create_parents(&live)?;
+            let unlocked = perms::unlock(&live, true)?;
+            let res = fs::rename(&entry, &live);
+            unlocked.relock()?;
+            res?;
+            restored += 1;
+        }
+    }
+
+    let manifest = dir.join("comstar.json");
+    if had_manifest {
+        fs::copy(backup.join("comstar.json"), &manifest)?;
+    } else if manifest.exists() {
+        fs::remove_file(&manifest)?;
+    }
+    fs::remove_dir_all(&backup)?;
+    println!("{}", t!("rollback.done", restored, removed));
+    Ok(())
+}
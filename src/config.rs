@@ -0,0 +1,86 @@
+use std::{collections::BTreeMap, fs, path::PathBuf};
+
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use toml::{value::Table, Value};
+use url::Url;
+
+use crate::sync::SyncOptions;
+
+#[derive(Debug, Default, Deserialize)]
+pub struct Config {
+    #[serde(default)]
+    pub profiles: BTreeMap<String, Profile>,
+}
+
+/// A `[profiles.<name>]` table: `dir` and `manifest`, plus any sync option by its field name.
+/// Unknown keys are an error rather than silently ignored.
+#[derive(Debug, Deserialize)]
+#[serde(try_from = "Table")]
+pub struct Profile {
+    pub dir: PathBuf,
+    pub manifest: Url,
+    pub options: SyncOptions,
+}
+
+impl TryFrom<Table> for Profile {
+    type Error = anyhow::Error;
+
+    // not #[serde(flatten)], serde can't combine that with deny_unknown_fields
+    fn try_from(mut table: Table) -> Result<Self> {
+        let mut take = |key: &str| {
+            table
+                .remove(key)
+                .ok_or_else(|| anyhow!("missing field `{}`", key))
+        };
+        let dir = take("dir")?.try_into()?;
+        let manifest = take("manifest")?.try_into()?;
+        let options = Value::Table(table).try_into()?;
+        Ok(Profile {
+            dir,
+            manifest,
+            options,
+        })
+    }
+}
+
+/// `$COMSTAR_CONFIG`, or `comstar/config.toml` in the platform config directory.
+pub fn config_path() -> Option<PathBuf> {
+    if let Some(p) = std::env::var_os("COMSTAR_CONFIG") {
+        return Some(PathBuf::from(p));
+    }
+    dirs::config_dir().map(|d| d.join("comstar").join("config.toml"))
+}
+
+impl Config {
+    pub fn load() -> Result<Config> {
+        let path = match config_path() {
+            Some(p) if p.is_file() => p,
+            _ => return Ok(Config::default()),
+        };
+        let contents = fs::read_to_string(&path)?;
+        let mut config: Config = toml::from_str(&contents)
+            .map_err(|e| anyhow!("Could not parse config {}: {}", path.display(), e))?;
+        // relative to the config file, not to wherever comstar happens to be run from
+        if let Some(base) = path.parent() {
+            for p in config.profiles.values_mut() {
+                if p.dir.is_relative() {
+                    p.dir = base.join(&p.dir);
+                }
+            }
+        }
+        Ok(config)
+    }
+
+    pub fn profile(&self, name: &str) -> Result<&Profile> {
+        self.profiles.get(name).ok_or_else(|| {
+            anyhow!(
+                "Unknown profile {}, check {}",
+                name,
+                config_path()
+                    .map(|p| p.display().to_string())
+                    .unwrap_or_else(|| "your config".into())
+            )
+        })
+    }
+}
@@ -1,22 +1,64 @@
-use std::{collections::HashMap, time::Duration};
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    fs::{self, File},
+    io::BufWriter,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
 
 use anyhow::Result;
-use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
-use tokio::sync::mpsc::Receiver;
+use chrono::{DateTime, Utc};
+use indicatif::{
+    BinaryBytes, HumanDuration, MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle,
+};
+use serde::Serialize;
+use tokio::sync::Notify;
 
-#[derive(Debug, Clone)]
+use crate::{i18n::t, ipc, util};
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
 pub enum Event {
     CloseStream,
     FileStarted { name: String, size: Option<u64> },
+    FileLength { name: String, size: u64 },
+    FileHost { name: String, host: String },
     FileProgress { name: String, bytes: u64 },
     FileDone { name: String },
+    FileFailed { name: String, error: String },
+    FileRetried { name: String, attempt: u32 },
+    FileStalled { name: String },
+    FileThrottled { name: String, seconds: u64 },
+    FileSkipped { name: String, reason: String },
 }
 
 impl Event {
-    pub fn unknown_file_started<S: Into<String>>(name: S) -> Self {
+    pub fn file_started<S: Into<String>>(name: S, size: Option<u64>) -> Self {
         Event::FileStarted {
             name: name.into(),
-            size: None,
+            size,
+        }
+    }
+
+    pub fn unknown_file_started<S: Into<String>>(name: S) -> Self {
+        Event::file_started(name, None)
+    }
+
+    pub fn file_length<S: Into<String>>(name: S, size: u64) -> Self {
+        Event::FileLength {
+            name: name.into(),
+            size,
+        }
+    }
+
+    pub fn file_host<S: Into<String>, H: Into<String>>(name: S, host: H) -> Self {
+        Event::FileHost {
+            name: name.into(),
+            host: host.into(),
         }
     }
 
@@ -31,25 +73,147 @@ impl Event {
         Event::FileDone { name: name.into() }
     }
 
+    pub fn file_failed<S: Into<String>, E: ToString>(name: S, error: E) -> Self {
+        Event::FileFailed {
+            name: name.into(),
+            error: error.to_string(),
+        }
+    }
+
+    pub fn file_retried<S: Into<String>>(name: S, attempt: u32) -> Self {
+        Event::FileRetried {
+            name: name.into(),
+            attempt,
+        }
+    }
+
+    pub fn file_stalled<S: Into<String>>(name: S) -> Self {
+        Event::FileStalled { name: name.into() }
+    }
+
+    pub fn file_throttled<S: Into<String>>(name: S, wait: Duration) -> Self {
+        Event::FileThrottled {
+            name: name.into(),
+            seconds: wait.as_secs(),
+        }
+    }
+
+    pub fn file_skipped<S: Into<String>, R: Into<String>>(name: S, reason: R) -> Self {
+        Event::FileSkipped {
+            name: name.into(),
+            reason: reason.into(),
+        }
+    }
+
     pub fn close() -> Self {
         Event::CloseStream
     }
 }
 
-fn create_spinner(size: u64) -> ProgressBar {
-    let style = ProgressStyle::with_template(
-        "  {spinner} {msg} ({bytes}, {binary_bytes_per_sec} {elapsed})",
+/// Once this many events are queued, the oldest byte-count update is dropped to make room.
+const CHANNEL_CAPACITY: usize = 256;
+
+#[derive(Debug)]
+struct Shared {
+    queue: Mutex<VecDeque<Event>>,
+    notify: Notify,
+    senders: AtomicUsize,
+    closed: AtomicBool,
+}
+
+/// Sending half of the progress channel. Progress reporting is best-effort: sending never
+/// blocks and never fails, so a slow or vanished display can't hold up or abort real work.
+#[derive(Debug)]
+pub struct EventSender(Arc<Shared>);
+
+#[derive(Debug)]
+pub struct EventReceiver(Arc<Shared>);
+
+pub fn channel() -> (EventSender, EventReceiver) {
+    let shared = Arc::new(Shared {
+        queue: Mutex::new(VecDeque::new()),
+        notify: Notify::new(),
+        senders: AtomicUsize::new(1),
+        closed: AtomicBool::new(false),
+    });
+    (EventSender(shared.clone()), EventReceiver(shared))
+}
+
+impl EventSender {
+    pub fn send(&self, e: Event) {
+        if self.0.closed.load(Ordering::Acquire) {
+            return;
+        }
+        {
+            let mut queue = self.0.queue.lock().unwrap();
+            if queue.len() >= CHANNEL_CAPACITY {
+                // lifecycle events are never dropped, the bars and counters depend on them
+                if let Some(i) = queue
+                    .iter()
+                    .position(|e| matches!(e, Event::FileProgress { .. }))
+                {
+                    queue.remove(i);
+                }
+            }
+            queue.push_back(e);
+        }
+        self.0.notify.notify_one();
+    }
+}
+
+impl Clone for EventSender {
+    fn clone(&self) -> Self {
+        self.0.senders.fetch_add(1, Ordering::AcqRel);
+        EventSender(self.0.clone())
+    }
+}
+
+impl Drop for EventSender {
+    fn drop(&mut self) {
+        self.0.senders.fetch_sub(1, Ordering::AcqRel);
+        self.0.notify.notify_one();
+    }
+}
+
+impl EventReceiver {
+    /// The next event, or `None` once the queue is drained and every sender is gone.
+    pub async fn recv(&mut self) -> Option<Event> {
+        loop {
+            if let Some(e) = self.0.queue.lock().unwrap().pop_front() {
+                return Some(e);
+            }
+            if self.0.senders.load(Ordering::Acquire) == 0 {
+                return None;
+            }
+            self.0.notify.notified().await;
+        }
+    }
+}
+
+impl Drop for EventReceiver {
+    fn drop(&mut self) {
+        self.0.closed.store(true, Ordering::Release);
+        self.0.queue.lock().unwrap().clear();
+    }
+}
+
+fn file_bar_style() -> ProgressStyle {
+    ProgressStyle::with_template(
+        "  {spinner} {msg} [{bar:30}] {bytes}/{total_bytes} ({binary_bytes_per_sec}, {eta})",
     )
-    .unwrap();
-    let pb = ProgressBar::new_spinner();
-    pb.set_length(size);
-    pb.set_style(style);
+    .unwrap()
+}
+
+// file bars start hidden, `FileBars` decides which ones are drawn
+fn create_spinner(size: u64) -> ProgressBar {
+    let pb = ProgressBar::with_draw_target(Some(size), ProgressDrawTarget::hidden());
+    pb.set_style(file_bar_style());
     pb
 }
 
 fn create_unknown_spinner() -> ProgressBar {
     let style = ProgressStyle::with_template("  {spinner} {msg} ({elapsed})").unwrap();
-    let pb = ProgressBar::new_spinner();
+    let pb = ProgressBar::with_draw_target(None, ProgressDrawTarget::hidden());
     pb.set_style(style);
     pb
 }
@@ -61,52 +225,443 @@ fn header_progress(max: u64) -> ProgressBar {
     pb
 }
 
+/// Combined transfer rate of all files over a sliding window.
+struct Throughput {
+    window: Duration,
+    samples: VecDeque<(Instant, u64)>,
+}
+
+impl Throughput {
+    fn new(window: Duration) -> Self {
+        Self {
+            window,
+            samples: VecDeque::new(),
+        }
+    }
+
+    fn record(&mut self, bytes: u64) {
+        let now = Instant::now();
+        self.samples.push_back((now, bytes));
+        self.expire(now);
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while let Some((t, _)) = self.samples.front() {
+            if now.duration_since(*t) <= self.window {
+                break;
+            }
+            self.samples.pop_front();
+        }
+    }
+
+    /// Bytes per second, 0 once nothing has moved for a whole window.
+    fn rate(&mut self) -> u64 {
+        self.expire(Instant::now());
+        let bytes: u64 = self.samples.iter().map(|(_, b)| b).sum();
+        bytes / self.window.as_secs().max(1)
+    }
+}
+
+fn header_message(action: &str, failed: usize, rate: u64) -> String {
+    let msg = if failed > 0 {
+        t!("progress.errors", action, failed)
+    } else {
+        action.to_string()
+    };
+    if rate > 0 {
+        format!("{} [{}/s]", msg, BinaryBytes(rate))
+    } else {
+        msg
+    }
+}
+
+/// How many entries the slowest files and hosts sections list.
+const SLOWEST_LISTED: usize = 5;
+
+struct InFlight {
+    started: Instant,
+    host: Option<String>,
+    bytes: u64,
+}
+
+struct FileTiming {
+    name: String,
+    host: Option<String>,
+    bytes: u64,
+    elapsed: Duration,
+}
+
+/// Start and finish times of every completed file, for the end-of-run report.
+#[derive(Default)]
+struct Timings {
+    in_flight: HashMap<String, InFlight>,
+    done: Vec<FileTiming>,
+}
+
+impl Timings {
+    fn start(&mut self, name: &str) {
+        self.in_flight.insert(
+            name.to_string(),
+            InFlight {
+                started: Instant::now(),
+                host: None,
+                bytes: 0,
+            },
+        );
+    }
+
+    fn host(&mut self, name: &str, host: String) {
+        if let Some(f) = self.in_flight.get_mut(name) {
+            f.host = Some(host);
+        }
+    }
+
+    fn progress(&mut self, name: &str, bytes: u64) {
+        if let Some(f) = self.in_flight.get_mut(name) {
+            f.bytes += bytes;
+        }
+    }
+
+    fn finish(&mut self, name: &str) {
+        if let Some(f) = self.in_flight.remove(name) {
+            self.done.push(FileTiming {
+                name: name.to_string(),
+                host: f.host,
+                bytes: f.bytes,
+                elapsed: f.started.elapsed(),
+            });
+        }
+    }
+
+    fn abandon(&mut self, name: &str) {
+        self.in_flight.remove(name);
+    }
+
+    /// Lists the slowest files and the hosts with the lowest average throughput, but only
+    /// when something took long enough to be worth looking at.
+    fn report(&mut self) {
+        self.done.sort_by_key(|f| std::cmp::Reverse(f.elapsed));
+        if !self
+            .done
+            .first()
+            .is_some_and(|f| f.elapsed >= Duration::from_secs(1))
+        {
+            return;
+        }
+        println!("{}", t!("progress.slowest_files"));
+        for f in self.done.iter().take(SLOWEST_LISTED) {
+            println!(
+                "{}",
+                t!(
+                    "progress.slow_file",
+                    f.name,
+                    BinaryBytes(f.bytes),
+                    HumanDuration(f.elapsed)
+                )
+            );
+        }
+
+        let mut hosts: Vec<_> = self.host_stats().into_iter().collect();
+        if hosts.is_empty() {
+            return;
+        }
+        hosts.sort_by_key(|(_, h)| h.bytes_per_sec);
+        println!("{}", t!("progress.slowest_hosts"));
+        for (host, h) in hosts.iter().take(SLOWEST_LISTED) {
+            println!(
+                "{}",
+                t!(
+                    "progress.slow_host",
+                    host,
+                    BinaryBytes(h.bytes_per_sec),
+                    h.files
+                )
+            );
+        }
+    }
+
+    fn host_stats(&self) -> BTreeMap<String, HostStats> {
+        let mut hosts: BTreeMap<String, HostStats> = BTreeMap::new();
+        for f in self.done.iter() {
+            if let Some(h) = &f.host {
+                let e = hosts.entry(h.clone()).or_default();
+                e.files += 1;
+                e.bytes += f.bytes;
+                e.seconds += f.elapsed.as_secs_f64();
+            }
+        }
+        for h in hosts.values_mut() {
+            h.bytes_per_sec = (h.bytes as f64 / h.seconds.max(0.001)) as u64;
+        }
+        hosts
+    }
+}
+
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct HostStats {
+    pub files: usize,
+    pub bytes: u64,
+    pub seconds: f64,
+    pub bytes_per_sec: u64,
+}
+
+/// Machine-readable statistics for one run, kept under `.comstar/stats/`.
+#[derive(Debug, Clone, Serialize)]
+pub struct RunStats {
+    pub started_at: DateTime<Utc>,
+    pub seconds: f64,
+    pub files: usize,
+    pub failed: usize,
+    pub skipped: usize,
+    pub retries: u64,
+    pub stalls: u64,
+    pub throttles: u64,
+    pub bytes: u64,
+    pub hosts: BTreeMap<String, HostStats>,
+}
+
+impl RunStats {
+    /// Writes the stats for a `kind` run (sync, push) into the state dir under `dir`.
+    pub fn save(&self, dir: &Path, kind: &str) -> Result<()> {
+        let stats_dir = util::state_dir(dir).join("stats");
+        fs::create_dir_all(&stats_dir)?;
+        let name = format!("{}-{}.json", self.started_at.format("%Y%m%dT%H%M%SZ"), kind);
+        let writer = BufWriter::new(File::create(stats_dir.join(name))?);
+        serde_json::to_writer_pretty(writer, self)?;
+        Ok(())
+    }
+}
+
+/// At most this many per-file bars are drawn, so the stack never outgrows the terminal.
+const MAX_VISIBLE_BARS: usize = 8;
+/// How often the drawn bars are rotated when more files are in flight than fit.
+const ROTATE_INTERVAL: Duration = Duration::from_secs(3);
+
+/// The per-file bars, of which only the first `MAX_VISIBLE_BARS` are drawn. The rest are
+/// summarized by an overflow line and take turns being shown.
+struct FileBars {
+    mp: MultiProgress,
+    bars: HashMap<String, ProgressBar>,
+    shown: VecDeque<String>,
+    hidden: VecDeque<String>,
+    overflow: ProgressBar,
+    overflow_shown: bool,
+}
+
+impl FileBars {
+    fn new(mp: MultiProgress) -> Self {
+        let overflow = ProgressBar::hidden();
+        overflow.set_style(ProgressStyle::with_template("  {msg}").unwrap());
+        Self {
+            mp,
+            bars: HashMap::new(),
+            shown: VecDeque::new(),
+            hidden: VecDeque::new(),
+            overflow,
+            overflow_shown: false,
+        }
+    }
+
+    fn get(&self, name: &str) -> Option<&ProgressBar> {
+        self.bars.get(name)
+    }
+
+    fn insert(&mut self, name: String, pb: ProgressBar) {
+        self.bars.insert(name.clone(), pb);
+        self.hidden.push_back(name);
+        self.fill();
+    }
+
+    fn remove(&mut self, name: &str) {
+        if let Some(pb) = self.bars.remove(name) {
+            pb.finish_and_clear();
+            self.mp.remove(&pb);
+        }
+        self.shown.retain(|n| n != name);
+        self.hidden.retain(|n| n != name);
+        self.fill();
+    }
+
+    /// Moves the longest-shown bar to the back of the queue and shows the next hidden one.
+    fn rotate(&mut self) {
+        if self.hidden.is_empty() {
+            return;
+        }
+        if let Some(name) = self.shown.pop_front() {
+            if let Some(pb) = self.bars.get(&name) {
+                self.mp.remove(pb);
+            }
+            self.hidden.push_back(name);
+        }
+        self.fill();
+    }
+
+    fn show(&self, pb: &ProgressBar) {
+        if self.overflow_shown {
+            self.mp.insert_before(&self.overflow, pb.clone());
+        } else {
+            self.mp.add(pb.clone());
+        }
+    }
+
+    fn fill(&mut self) {
+        while self.shown.len() < MAX_VISIBLE_BARS {
+            let name = match self.hidden.pop_front() {
+                Some(n) => n,
+                None => break,
+            };
+            if let Some(pb) = self.bars.get(&name) {
+                self.show(pb);
+            }
+            self.shown.push_back(name);
+        }
+        if self.hidden.is_empty() {
+            if self.overflow_shown {
+                self.mp.remove(&self.overflow);
+                self.overflow_shown = false;
+            }
+        } else {
+            self.overflow
+                .set_message(t!("progress.overflow", self.hidden.len()));
+            if !self.overflow_shown {
+                self.mp.add(self.overflow.clone());
+                self.overflow_shown = true;
+            }
+        }
+    }
+}
+
 #[tracing::instrument]
-pub async fn event_output(mut ch: Receiver<Event>, action: String, max_items: u64) -> Result<()> {
+pub async fn event_output(
+    mut ch: EventReceiver,
+    action: String,
+    max_items: u64,
+) -> Result<RunStats> {
+    let started_at = Utc::now();
+    let started = Instant::now();
     let mp = MultiProgress::new();
-    let mut current_pbs: HashMap<String, ProgressBar> = HashMap::new();
     let header = mp.add(header_progress(max_items));
+    let mut bars = FileBars::new(mp.clone());
+    let mut failed: Vec<(String, String)> = Vec::new();
+    let mut skipped: Vec<(String, String)> = Vec::new();
+    let mut throughput = Throughput::new(Duration::from_secs(5));
+    let mut timings = Timings::default();
+    let mut total_bytes = 0;
+    let mut retries = 0;
+    let mut stalls = 0;
+    let mut throttles = 0;
+    let mut rotate = tokio::time::interval(ROTATE_INTERVAL);
 
     header.enable_steady_tick(Duration::from_millis(100));
     header.set_message(action.clone());
+    ipc::start_action(&action, max_items);
     loop {
-        if let Some(e) = ch.recv().await {
-            match e {
-                Event::CloseStream => break,
-                Event::FileStarted { name, size } => {
-                    let pb = if let Some(s) = size {
-                        mp.add(create_spinner(s))
-                    } else {
-                        mp.add(create_unknown_spinner())
-                    };
-                    pb.enable_steady_tick(Duration::from_millis(100));
-                    pb.set_message(name.clone());
-                    current_pbs.insert(name.clone(), pb);
+        let e = tokio::select! {
+            e = ch.recv() => match e {
+                Some(e) => e,
+                None => break,
+            },
+            _ = rotate.tick() => {
+                bars.rotate();
+                continue;
+            }
+        };
+        ipc::publish(&action, &e);
+        match e {
+            Event::CloseStream => break,
+            Event::FileStarted { name, size } => {
+                let pb = if let Some(s) = size {
+                    create_spinner(s)
+                } else {
+                    create_unknown_spinner()
+                };
+                pb.enable_steady_tick(Duration::from_millis(100));
+                pb.set_message(name.clone());
+                timings.start(&name);
+                bars.insert(name, pb);
+            }
+            Event::FileLength { name, size } => {
+                if let Some(pb) = bars.get(&name) {
+                    pb.set_length(size);
+                    pb.set_style(file_bar_style());
                 }
-                Event::FileProgress { name, bytes } => {
-                    if let Some(pb) = current_pbs.get(&name) {
+            }
+            Event::FileHost { name, host } => timings.host(&name, host),
+            Event::FileProgress { name, bytes } => {
+                timings.progress(&name, bytes);
+                if let Some(pb) = bars.get(&name) {
+                    if pb.length().is_none() && pb.position() == 0 {
                         let style = ProgressStyle::with_template(
                             "  {spinner} {msg} ({bytes}, {binary_bytes_per_sec} {elapsed})",
                         )
                         .unwrap();
                         pb.set_style(style);
-                        pb.inc(bytes);
                     }
+                    pb.inc(bytes);
                 }
-                Event::FileDone { name } => {
-                    if let Some(pb) = current_pbs.get(&name) {
-                        pb.finish_and_clear();
-                    }
-                    header.inc(1);
-                    current_pbs.remove(&name);
+                throughput.record(bytes);
+                total_bytes += bytes;
+            }
+            Event::FileDone { name } => {
+                timings.finish(&name);
+                bars.remove(&name);
+                header.inc(1);
+            }
+            Event::FileFailed { name, error } => {
+                timings.abandon(&name);
+                bars.remove(&name);
+                header.inc(1);
+                failed.push((name, error));
+            }
+            Event::FileRetried { name, attempt } => {
+                retries += 1;
+                if let Some(pb) = bars.get(&name) {
+                    pb.set_message(t!("progress.retry", name, attempt));
                 }
             }
-        } else {
-            break;
+            Event::FileStalled { name } => {
+                stalls += 1;
+                if let Some(pb) = bars.get(&name) {
+                    pb.set_message(t!("progress.stalled", name));
+                }
+            }
+            Event::FileThrottled { name, seconds } => {
+                throttles += 1;
+                if let Some(pb) = bars.get(&name) {
+                    pb.set_message(t!("progress.throttled", name, seconds));
+                }
+            }
+            Event::FileSkipped { name, reason } => {
+                timings.abandon(&name);
+                bars.remove(&name);
+                header.inc(1);
+                skipped.push((name, reason));
+            }
         }
+        header.set_message(header_message(&action, failed.len(), throughput.rate()));
     }
-    mp.clear()?;
+    // a terminal that can't be cleared is not worth failing the run over
+    let _ = mp.clear();
     header.set_style(ProgressStyle::with_template("{msg} ({pos}/{len} {elapsed})").unwrap());
-    header.finish_with_message(format!("{}: Done.", action));
-    Ok(())
+    header.finish_with_message(t!("progress.done", action));
+    timings.report();
+    let stats = RunStats {
+        started_at,
+        seconds: started.elapsed().as_secs_f64(),
+        files: timings.done.len(),
+        failed: failed.len(),
+        skipped: skipped.len(),
+        retries,
+        stalls,
+        throttles,
+        bytes: total_bytes,
+        hosts: timings.host_stats(),
+    };
+    for (name, reason) in skipped {
+        println!("{}", t!("progress.skipped", name, reason));
+    }
+    for (name, error) in failed {
+        println!("{}", t!("progress.failed", name, error));
+    }
+    Ok(stats)
 }
@@ -0,0 +1,170 @@
+use std::{fs::File, io::Read, path::Path};
+
+use anyhow::{anyhow, Result};
+use percent_encoding::percent_decode_str;
+use suppaftp::{
+    native_tls::TlsConnector, types::FileType, FtpError, NativeTlsConnector, NativeTlsFtpStream,
+    Status,
+};
+use tokio::sync::mpsc;
+use url::Url;
+
+use crate::util::Chunk;
+
+/// Explicit FTPS starts out on the plain FTP port and upgrades with AUTH TLS.
+const DEFAULT_PORT: u16 = 21;
+
+/// Bytes read from the data connection at a time.
+const CHUNK: usize = 64 * 1024;
+
+/// Chunks the blocking reader may get ahead of the writer.
+const CHUNKS_IN_FLIGHT: usize = 16;
+
+/// Suffix of a file while it is being uploaded, renamed away once it is complete so the
+/// server never serves half a file under its real name.
+const PARTIAL_SUFFIX: &str = ".comstar-part";
+
+/// Logs in to the server of `url`, anonymously unless the URL has a user, upgrading to TLS
+/// for `ftps://`.
+fn connect(url: &Url) -> Result<NativeTlsFtpStream> {
+    let host = url
+        .host_str()
+        .ok_or_else(|| anyhow!("FTP URL has no host: {}", url))?;
+    let mut ftp = NativeTlsFtpStream::connect((host, url.port().unwrap_or(DEFAULT_PORT)))?;
+    if url.scheme() == "ftps" {
+        ftp = ftp.into_secure(NativeTlsConnector::from(TlsConnector::new()?), host)?;
+    }
+    let user = percent_decode_str(url.username()).decode_utf8()?;
+    let password = percent_decode_str(url.password().unwrap_or("")).decode_utf8()?;
+    match (user.as_ref(), password.as_ref()) {
+        ("", _) => ftp.login("anonymous", "anonymous@")?,
+        (u, p) => ftp.login(u, p)?,
+    }
+    ftp.transfer_type(FileType::Binary)?;
+    Ok(ftp)
+}
+
+fn path_of(url: &Url) -> Result<String> {
+    Ok(percent_decode_str(url.path()).decode_utf8()?.into_owned())
+}
+
+fn is_missing(e: &FtpError) -> bool {
+    matches!(e, FtpError::UnexpectedResponse(r) if r.status == Status::FileUnavailable)
+}
+
+/// Streams the file at `url` to `tx`. Returns `false` if the server says there is no such
+/// file.
+fn download_blocking(url: &Url, tx: &mpsc::Sender<Result<Chunk>>) -> Result<bool> {
+    let mut ftp = connect(url)?;
+    let path = path_of(url)?;
+    // not every server supports SIZE, the download works without it
+    if let Ok(size) = ftp.size(&path) {
+        if tx.blocking_send(Ok(Chunk::Length(size as u64))).is_err() {
+            return Ok(true);
+        }
+    }
+    let mut stream = match ftp.retr_as_stream(&path) {
+        Ok(s) => s,
+        Err(e) if is_missing(&e) => return Ok(false),
+        Err(e) => return Err(e.into()),
+    };
+    let mut buf = vec![0u8; CHUNK];
+    loop {
+        let n = stream.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        // the receiving end gave up, no need to read the rest
+        if tx
+            .blocking_send(Ok(Chunk::Data(buf[..n].to_vec())))
+            .is_err()
+        {
+            return Ok(true);
+        }
+    }
+    ftp.finalize_retr_stream(stream)?;
+    let _ = ftp.quit();
+    Ok(true)
+}
+
+/// Downloads the file at `url` on a blocking thread, the FTP client has no async API.
+/// The receiver gets the file's length if known, then its data, and closes once the file
+/// is complete. A missing file arrives as an error.
+pub fn download(url: &Url) -> mpsc::Receiver<Result<Chunk>> {
+    let (tx, rx) = mpsc::channel(CHUNKS_IN_FLIGHT);
+    let url = url.clone();
+    tokio::task::spawn_blocking(move || {
+        let res = match download_blocking(&url, &tx) {
+            Ok(true) => return,
+            Ok(false) => Err(anyhow!("No such file on the FTP server: {}", url)),
+            Err(e) => Err(e),
+        };
+        let _ = tx.blocking_send(res);
+    });
+    rx
+}
+
+/// The whole file at `url`, `None` if the server doesn't have it.
+pub async fn fetch(url: &Url) -> Result<Option<Vec<u8>>> {
+    let url = url.clone();
+    tokio::task::spawn_blocking(move || {
+        let mut ftp = connect(&url)?;
+        let body = match ftp.retr_as_buffer(&path_of(&url)?) {
+            Ok(b) => Some(b.into_inner()),
+            Err(e) if is_missing(&e) => None,
+            Err(e) => return Err(e.into()),
+        };
+        let _ = ftp.quit();
+        Ok(body)
+    })
+    .await?
+}
+
+/// Size of the file at `url` as the server reports it, if it supports SIZE.
+pub async fn size(url: &Url) -> Result<Option<u64>> {
+    let url = url.clone();
+    tokio::task::spawn_blocking(move || {
+        let mut ftp = connect(&url)?;
+        let size = ftp.size(&path_of(&url)?).ok().map(|s| s as u64);
+        let _ = ftp.quit();
+        Ok(size)
+    })
+    .await?
+}
+
+/// Uploads `local_file` to `url`, creating directories as needed.
+pub async fn put(url: &Url, local_file: &Path) -> Result<()> {
+    let (url, local_file) = (url.clone(), local_file.to_path_buf());
+    tokio::task::spawn_blocking(move || {
+        let mut ftp = connect(&url)?;
+        let path = path_of(&url)?;
+        let mut dir = String::new();
+        let parents: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+        for part in &parents[..parents.len().saturating_sub(1)] {
+            dir = format!("{}/{}", dir, part);
+            // most likely there already, and if it really can't be made the upload says so
+            let _ = ftp.mkdir(&dir);
+        }
+        let partial = format!("{}{}", path, PARTIAL_SUFFIX);
+        ftp.put_file(&partial, &mut File::open(&local_file)?)?;
+        ftp.rename(&partial, &path)?;
+        let _ = ftp.quit();
+        Ok(())
+    })
+    .await?
+}
+
+/// Deletes the file at `url`. Deleting something that isn't there is fine.
+pub async fn delete(url: &Url) -> Result<()> {
+    let url = url.clone();
+    tokio::task::spawn_blocking(move || {
+        let mut ftp = connect(&url)?;
+        match ftp.rm(path_of(&url)?) {
+            Err(e) if !is_missing(&e) => return Err(e.into()),
+            _ => {}
+        }
+        let _ = ftp.quit();
+        Ok(())
+    })
+    .await?
+}
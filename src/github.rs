@@ -0,0 +1,241 @@
+use std::{collections::HashMap, fmt, str::FromStr, sync::RwLock};
+
+use anyhow::{anyhow, Result};
+use percent_encoding::percent_decode_str;
+use reqwest::{
+    header::{HeaderMap, HeaderValue, ACCEPT, AUTHORIZATION},
+    Method, RequestBuilder, StatusCode,
+};
+use serde::Deserialize;
+use url::Url;
+
+use crate::{http, i18n::t};
+
+const API: &str = "https://api.github.com";
+
+/// Releases already looked up during this run, by repository and tag.
+static RELEASES: RwLock<Option<HashMap<(String, String), Option<Release>>>> = RwLock::new(None);
+
+/// A repository, written `owner/name`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Repo {
+    pub owner: String,
+    pub name: String,
+}
+
+impl FromStr for Repo {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.split_once('/') {
+            Some((owner, name)) if !owner.is_empty() && !name.is_empty() && !name.contains('/') => {
+                Ok(Repo {
+                    owner: owner.to_string(),
+                    name: name.to_string(),
+                })
+            }
+            _ => Err(anyhow!("Invalid repository {}, expected owner/name", s)),
+        }
+    }
+}
+
+impl fmt::Display for Repo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.owner, self.name)
+    }
+}
+
+/// An asset of a release, from a `github://owner/repo/tag/asset` URL.
+#[derive(Debug, Clone)]
+pub struct AssetRef {
+    pub repo: Repo,
+    pub tag: String,
+    pub name: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Release {
+    pub id: u64,
+    pub upload_url: String,
+    pub assets: Vec<Asset>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Asset {
+    pub id: u64,
+    pub name: String,
+    pub size: u64,
+    /// The API URL, which serves the content to `Accept: application/octet-stream`.
+    pub url: Url,
+}
+
+impl Release {
+    pub fn asset(&self, name: &str) -> Option<&Asset> {
+        self.assets.iter().find(|a| a.name == name)
+    }
+}
+
+/// The repository, tag and asset name of a `github://owner/repo/tag/asset` URL.
+pub fn asset_of(src: &Url) -> Result<AssetRef> {
+    let bad = || {
+        anyhow!(
+            "Invalid GitHub URL {}, expected github://owner/repo/tag/asset",
+            src
+        )
+    };
+    let owner = src.host_str().filter(|h| !h.is_empty()).ok_or_else(bad)?;
+    let segments: Vec<String> = src
+        .path_segments()
+        .ok_or_else(bad)?
+        .map(|s| Ok(percent_decode_str(s).decode_utf8()?.into_owned()))
+        .collect::<Result<_>>()?;
+    match &segments[..] {
+        [name, tag, asset] if !name.is_empty() && !tag.is_empty() && !asset.is_empty() => {
+            Ok(AssetRef {
+                repo: Repo {
+                    owner: owner.to_string(),
+                    name: name.clone(),
+                },
+                tag: tag.clone(),
+                name: asset.clone(),
+            })
+        }
+        _ => Err(bad()),
+    }
+}
+
+/// The `github://` URL of asset `name` of the release `tag` of `repo`.
+pub fn asset_url(repo: &Repo, tag: &str, name: &str) -> Result<Url> {
+    let mut url = Url::parse(&format!("github://{}/", repo.owner))?;
+    url.path_segments_mut()
+        .map_err(|_| anyhow!("Cannot build a GitHub URL for {}", repo))?
+        .clear()
+        .extend([repo.name.as_str(), tag, name]);
+    Ok(url)
+}
+
+/// Token from `GITHUB_TOKEN`, or `GH_TOKEN` like the GitHub CLI reads.
+pub fn token() -> Option<String> {
+    ["GITHUB_TOKEN", "GH_TOKEN"]
+        .iter()
+        .find_map(|v| std::env::var(v).ok().filter(|t| !t.is_empty()))
+}
+
+fn auth_headers() -> Result<HeaderMap> {
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        "X-GitHub-Api-Version",
+        HeaderValue::from_static("2022-11-28"),
+    );
+    if let Some(token) = token() {
+        headers.insert(
+            AUTHORIZATION,
+            HeaderValue::from_str(&format!("Bearer {}", token))?,
+        );
+    }
+    Ok(headers)
+}
+
+/// A request to the GitHub REST API, authenticated if there is a token.
+pub fn api(method: Method, url: &str) -> Result<RequestBuilder> {
+    Ok(http::client()
+        .request(method, url)
+        .headers(auth_headers()?)
+        .header(ACCEPT, "application/vnd.github+json"))
+}
+
+/// The release `tag` of `repo` as it is now, `None` if there is none.
+pub async fn fetch_release(repo: &Repo, tag: &str) -> Result<Option<Release>> {
+    let mut url = Url::parse(&format!("{}/repos/{}/releases/tags/", API, repo))?;
+    url.path_segments_mut()
+        .map_err(|_| anyhow!("Cannot build a GitHub URL for {}", repo))?
+        .pop_if_empty()
+        .push(tag);
+    let resp = api(Method::GET, url.as_str())?.send().await?;
+    if resp.status() == StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    Ok(Some(resp.error_for_status()?.json().await?))
+}
+
+/// The release `tag` of `repo`, looked up once per run.
+async fn release(repo: &Repo, tag: &str) -> Result<Option<Release>> {
+    let key = (repo.to_string(), tag.to_string());
+    if let Some(r) = RELEASES.read().unwrap().as_ref().and_then(|m| m.get(&key)) {
+        return Ok(r.clone());
+    }
+    let r = fetch_release(repo, tag).await?;
+    RELEASES
+        .write()
+        .unwrap()
+        .get_or_insert_with(HashMap::new)
+        .insert(key, r.clone());
+    Ok(r)
+}
+
+/// The URL and headers to download the asset at `src` with. Without a token that's the
+/// public download link, which costs no API requests. With one it's the API URL, which
+/// also works for private repositories and redirects to storage that the token isn't
+/// sent to.
+pub async fn request_parts(src: &Url) -> Result<(Url, HeaderMap)> {
+    let asset = asset_of(src)?;
+    if token().is_none() {
+        let mut url = Url::parse(&format!(
+            "https://github.com/{}/releases/download/",
+            asset.repo
+        ))?;
+        url.path_segments_mut()
+            .map_err(|_| anyhow!("Cannot build a GitHub URL for {}", src))?
+            .pop_if_empty()
+            .extend([asset.tag.as_str(), asset.name.as_str()]);
+        return Ok((url, HeaderMap::new()));
+    }
+    let found = release(&asset.repo, &asset.tag)
+        .await?
+        .and_then(|r| r.asset(&asset.name).map(|a| a.url.clone()));
+    let url = found.ok_or_else(|| anyhow!(t!("github.no_asset", src)))?;
+    let mut headers = auth_headers()?;
+    headers.insert(ACCEPT, HeaderValue::from_static("application/octet-stream"));
+    Ok((url, headers))
+}
+
+/// The response carrying the asset at `src`, `None` if there is no such asset.
+pub async fn fetch(src: &Url) -> Result<Option<reqwest::Response>> {
+    if token().is_some() {
+        let asset = asset_of(src)?;
+        let release = release(&asset.repo, &asset.tag).await?;
+        if release
+            .as_ref()
+            .and_then(|r| r.asset(&asset.name))
+            .is_none()
+        {
+            return Ok(None);
+        }
+    }
+    let (url, headers) = request_parts(src).await?;
+    let resp = http::client().get(url).headers(headers).send().await?;
+    if resp.status() == StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    Ok(Some(resp.error_for_status()?))
+}
+
+/// Size of the asset at `src`.
+pub async fn size(src: &Url) -> Result<Option<u64>> {
+    if token().is_some() {
+        let asset = asset_of(src)?;
+        let release = release(&asset.repo, &asset.tag).await?;
+        return match release.as_ref().and_then(|r| r.asset(&asset.name)) {
+            Some(a) => Ok(Some(a.size)),
+            None => Err(anyhow!(t!("github.no_asset", src))),
+        };
+    }
+    let (url, headers) = request_parts(src).await?;
+    let resp = http::client()
+        .head(url)
+        .headers(headers)
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(resp.content_length())
+}
@@ -0,0 +1,150 @@
+use std::{
+    collections::HashSet,
+    io,
+    sync::RwLock,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use futures::TryStreamExt;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use tokio::io::AsyncRead;
+use tokio_util::io::StreamReader;
+use url::Url;
+
+use crate::i18n::t;
+
+/// Header carrying the run's trace ID when no other name is given.
+const DEFAULT_TRACE_HEADER: &str = "X-Comstar-Trace-Id";
+
+static CLIENT: RwLock<Option<reqwest::Client>> = RwLock::new(None);
+
+/// User-Agent and default headers from `configure`, for clients built later.
+static SETTINGS: RwLock<Option<(String, HeaderMap)>> = RwLock::new(None);
+
+/// Client speaking HTTP/3 only, set when `--http3` is given.
+static HTTP3_CLIENT: RwLock<Option<reqwest::Client>> = RwLock::new(None);
+
+/// Hosts an HTTP/3 request failed to reach, which get the regular client from then on.
+static NO_HTTP3: RwLock<Option<HashSet<String>>> = RwLock::new(None);
+
+fn default_user_agent() -> String {
+    format!("comstar/{}", env!("CARGO_PKG_VERSION"))
+}
+
+/// An ID that is unique enough to pick one run out of CDN and origin logs.
+fn new_trace_id() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or_default();
+    format!("{:x}-{:x}", nanos, std::process::id())
+}
+
+/// Sets up the client every HTTP and GCS request of this run goes through. With
+/// `trace_header` or `trace_id`, each request carries the run's trace ID, which is printed
+/// so it can be looked up later. With `http3`, downloads try HTTP/3 first. Replaces the
+/// clients of any earlier call.
+pub fn configure(
+    user_agent: Option<&str>,
+    trace_header: Option<&str>,
+    trace_id: Option<&str>,
+    http3: bool,
+) -> Result<()> {
+    let mut headers = HeaderMap::new();
+    if trace_header.is_some() || trace_id.is_some() {
+        let name = HeaderName::from_bytes(trace_header.unwrap_or(DEFAULT_TRACE_HEADER).as_bytes())?;
+        let id = trace_id.map(str::to_string).unwrap_or_else(new_trace_id);
+        headers.insert(name.clone(), HeaderValue::from_str(&id)?);
+        eprintln!("{}", t!("http.trace_id", name, id));
+    }
+    let user_agent = user_agent
+        .map(str::to_string)
+        .unwrap_or_else(default_user_agent);
+    *SETTINGS.write().unwrap() = Some((user_agent, headers));
+    *CLIENT.write().unwrap() = Some(builder(HeaderMap::new()).build()?);
+    *HTTP3_CLIENT.write().unwrap() = if http3 {
+        Some(http3_client(builder(HeaderMap::new()))?)
+    } else {
+        None
+    };
+    Ok(())
+}
+
+/// A builder with the configured User-Agent and default headers, plus `extra`.
+fn builder(extra: HeaderMap) -> reqwest::ClientBuilder {
+    let (user_agent, mut headers) = SETTINGS
+        .read()
+        .unwrap()
+        .clone()
+        .unwrap_or_else(|| (default_user_agent(), HeaderMap::new()));
+    headers.extend(extra);
+    reqwest::Client::builder()
+        .user_agent(user_agent)
+        .default_headers(headers)
+}
+
+#[cfg(feature = "http3")]
+fn http3_client(builder: reqwest::ClientBuilder) -> Result<reqwest::Client> {
+    Ok(builder.http3_prior_knowledge().build()?)
+}
+
+#[cfg(not(feature = "http3"))]
+fn http3_client(_: reqwest::ClientBuilder) -> Result<reqwest::Client> {
+    Err(anyhow::anyhow!(t!("http.no_http3")))
+}
+
+/// The configured client, or one with just the default User-Agent if `configure` wasn't
+/// called.
+pub fn client() -> reqwest::Client {
+    if let Some(c) = CLIENT.read().unwrap().as_ref() {
+        return c.clone();
+    }
+    CLIENT
+        .write()
+        .unwrap()
+        .get_or_insert_with(|| builder(HeaderMap::new()).build().unwrap_or_default())
+        .clone()
+}
+
+/// The body of `resp`, read as it arrives.
+pub fn body_reader(resp: reqwest::Response) -> impl AsyncRead + Send {
+    StreamReader::new(
+        resp.bytes_stream()
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e)),
+    )
+}
+
+/// A client like the configured one that also sends `headers` with every request.
+pub fn client_with_headers(headers: HeaderMap) -> Result<reqwest::Client> {
+    Ok(builder(headers).build()?)
+}
+
+/// The client to download `src` with, and whether it is the HTTP/3 one. HTTP/3 is used
+/// when `--http3` is given, unless it already failed for the host.
+pub fn download_client(src: &Url) -> (reqwest::Client, bool) {
+    let quic = HTTP3_CLIENT.read().unwrap().clone().filter(|_| {
+        let host = src.host_str().unwrap_or_default();
+        !NO_HTTP3
+            .read()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|hosts| hosts.contains(host))
+    });
+    match quic {
+        Some(c) => (c, true),
+        None => (client(), false),
+    }
+}
+
+/// Makes later downloads from `src`'s host go over HTTP/2 or 1.1, e.g. because UDP is
+/// blocked on the way there.
+pub fn http3_failed(src: &Url) {
+    let host = src.host_str().unwrap_or_default().to_string();
+    tracing::warn!("HTTP/3 to {} failed, falling back to HTTP/2 and 1.1", host);
+    NO_HTTP3
+        .write()
+        .unwrap()
+        .get_or_insert_with(HashSet::new)
+        .insert(host);
+}
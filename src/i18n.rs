@@ -0,0 +1,827 @@
+//! Catalog for user-facing messages.
+//!
+//! The locale comes from `COMSTAR_LANG`, falling back to `LC_ALL`, `LC_MESSAGES` and `LANG`.
+//! Translations can be added without rebuilding by dropping a `<lang>.toml` of
+//! `"key" = "text"` pairs into `locale/` next to the config file. Placeholders are
+//! positional: `{0}`, `{1}`, ...
+
+use std::{collections::HashMap, fmt::Display, fs, sync::OnceLock};
+
+use crate::config;
+
+const EN: &[(&str, &str)] = &[
+    ("azure.failed", "{0} {1} failed: {2}"),
+    (
+        "azure.no_account",
+        "az:// URLs need the storage account in AZURE_STORAGE_ACCOUNT",
+    ),
+    (
+        "azure.no_credentials",
+        "No SAS token in the URL or AZURE_STORAGE_SAS_TOKEN, and no AZURE_TENANT_ID, AZURE_CLIENT_ID and AZURE_CLIENT_SECRET to get a token with",
+    ),
+    (
+        "backend.unknown_scheme",
+        "Unsupported URL scheme {0}: {1}",
+    ),
+    ("backend.unsupported", "{0}:// URLs do not support {1}"),
+    (
+        "check.consistent",
+        "Local files, local manifest and remote agree.",
+    ),
+    ("check.found", "{0} paths are inconsistent"),
+    ("check.header", "LOCAL      PUBLISHED  REMOTE     PATH"),
+    ("compare.same", "Directories have identical contents."),
+    ("du.unknown_size", "Source of {0} did not report a size"),
+    (
+        "file.unc_unsupported",
+        "{0} is a network share, which can only be read directly on Windows. Mount it and use its local path instead",
+    ),
+    (
+        "gcs.bad_key",
+        "The GCS encryption key must be 32 bytes, base64 encoded",
+    ),
+    (
+        "generate.checksums_algo",
+        "--checksums needs sha512, this manifest uses {0}",
+    ),
+    ("generate.signed", "Signed, verify with --verify-key {0}"),
+    (
+        "get.bad_range",
+        "Server kept answering {0} with the wrong byte range",
+    ),
+    (
+        "get.hash_mismatch",
+        "Downloaded {0} does not match the manifest hash",
+    ),
+    (
+        "get.short_chunk",
+        "Download of {0} ended early at byte {1}",
+    ),
+    ("get.stalled", "No data from {0} for {1}s"),
+    ("get.truncated", "{0} is truncated, got {1} of {2} bytes"),
+    ("get.unknown_path", "{0} is not in the manifest"),
+    (
+        "github.asset_clash",
+        "{0} and {1} would both be uploaded as asset {2}, rename one of them",
+    ),
+    ("github.failed", "{0} {1} failed: {2}"),
+    ("github.no_asset", "{0} is not an asset of the release"),
+    (
+        "github.no_token",
+        "Pushing to GitHub needs a token in GITHUB_TOKEN or GH_TOKEN",
+    ),
+    (
+        "hash.volatile",
+        "{0} kept changing while it was hashed, gave up after {1} attempts",
+    ),
+    (
+        "http.no_http3",
+        "--http3 needs comstar built with the http3 feature",
+    ),
+    ("http.trace_id", "Sending {0}: {1}"),
+    ("ipfs.checksums_added", "Checksums added as {0}"),
+    ("ipfs.failed", "ipfs {0} failed: {1}"),
+    ("ipfs.manifest_added", "Manifest added as {0}"),
+    ("ipfs.no_cid", "ipfs add returned no CID for {0}"),
+    ("ipfs.spawn", "Could not run ipfs: {0}"),
+    ("lint.bad_digest", "must be 128 hex digits"),
+    ("lint.bad_mode", "must be an octal mode like 0755"),
+    ("lint.bad_time", "must be an RFC 3339 timestamp"),
+    ("lint.bad_url", "{0} is not a valid URL: {1}"),
+    ("lint.bad_xattrs", "must be an object of hex strings"),
+    (
+        "lint.dangling",
+        "refers to {0}, which is not in the manifest",
+    ),
+    ("lint.duplicate_path", "path already listed by entry {0}"),
+    ("lint.entry", "entry {0}"),
+    (
+        "lint.escapes",
+        "{0} is not a path inside the synced directory",
+    ),
+    ("lint.failed", "Found {0} problem(s)."),
+    ("lint.header", "manifest header: {0}"),
+    ("lint.line", "line {0}"),
+    ("lint.missing", "missing"),
+    ("lint.not_bool", "must be true or false"),
+    ("lint.not_integer", "must be a whole number"),
+    ("lint.not_object", "entry is not a JSON object"),
+    ("lint.not_string", "must be a string"),
+    ("lint.not_utf8", "Manifest is not valid UTF-8: {0}"),
+    ("lint.ok", "Manifest is well formed, {0} entries."),
+    (
+        "locate.bad_query",
+        "{0} is neither a file nor a hex digest of at least {1} characters",
+    ),
+    ("locate.none", "No entries with hash {0}"),
+    ("manifest.not_found", "Remote manifest not found: {0}"),
+    (
+        "manifest.requires",
+        "This manifest requires comstar {0} or newer, you are running {1}. Please upgrade comstar.",
+    ),
+    (
+        "manifest.skipped_entry",
+        "Skipping manifest entry {0} ({1}): {2}",
+    ),
+    ("oci.failed", "{0} {1} failed: {2}"),
+    (
+        "oci.no_credentials",
+        "Registry {0} wants a login, set COMSTAR_OCI_USER and COMSTAR_OCI_PASSWORD or run docker login",
+    ),
+    (
+        "oci.no_digest",
+        "{0} does not name a blob, expected registry/repository@sha256:...",
+    ),
+    (
+        "oci.push_digest",
+        "Cannot push to {0}, push to a tag instead of a digest",
+    ),
+    ("oci.pushed", "Pushed {0} as {1}"),
+    (
+        "pin.exists",
+        "Directory is already pinned to another version, use --update to replace the pin.",
+    ),
+    (
+        "pin.mismatch",
+        "Manifest changed since it was pinned (pinned {0}, now {1}). Run comstar pin --update to accept it.",
+    ),
+    ("pin.pinned", "Pinned manifest generated at {0}."),
+    (
+        "pin.unchanged",
+        "Already pinned to the manifest generated at {0}.",
+    ),
+    (
+        "plugin.bad_answer",
+        "Plugin {0} gave an invalid answer: {1}",
+    ),
+    ("plugin.failed", "Plugin {0} failed: {1}"),
+    ("plugin.missing", "{0} does not exist"),
+    (
+        "plugin.no_answer",
+        "Plugin {0} exited without an answer ({1})",
+    ),
+    ("plugin.spawn", "Could not run plugin {0}: {1}"),
+    ("progress.checking_sources", "Checking sources"),
+    ("progress.done", "{0}: Done."),
+    ("progress.errors", "{0} ({1} failed)"),
+    ("progress.failed", "  FAILED: {0}: {1}"),
+    ("progress.fetching", "Fetching files"),
+    ("progress.generating", "Generating manifest"),
+    ("progress.overflow", "… and {0} more in progress"),
+    ("progress.pruning", "Pruning stray files"),
+    ("progress.pushing", "Pushing differences"),
+    ("progress.retry", "{0} (retry {1})"),
+    ("progress.skipped", "  SKIPPED ({1}): {0}"),
+    ("progress.slow_file", "  {0} ({1} in {2})"),
+    ("progress.slow_host", "  {0} ({1}/s over {2} files)"),
+    ("progress.slowest_files", "Slowest files:"),
+    ("progress.slowest_hosts", "Slowest hosts:"),
+    ("progress.stalled", "{0} (stalled, restarting)"),
+    ("progress.syncing", "Synchronizing files"),
+    ("progress.throttled", "{0} (throttled, waiting {1}s)"),
+    ("progress.untracked", "Searching for untracked files"),
+    ("progress.validating", "Validating files"),
+    (
+        "push.bad_replica",
+        "{0} is not a GCS object URL, replicas must be gs:// or storage.googleapis.com manifests",
+    ),
+    (
+        "push.chunk_mismatch",
+        "GCS did not store the chunk of {0} starting at byte {1} as sent",
+    ),
+    ("push.delete_failed", "Failed to delete {0} object(s):"),
+    (
+        "push.dirty",
+        "{0} files changed since the manifest was generated, push again once they are settled or pass --allow-dirty",
+    ),
+    ("push.http_failed", "{0} {1} failed: {2}"),
+    (
+        "push.overlapping",
+        "Cannot push into {0}, it overlaps the directory being pushed",
+    ),
+    (
+        "push.staging_mismatch",
+        "Staged upload of {0} does not match the local file, nothing was published",
+    ),
+    ("quota.file", "{0} is {1}, over the {2} limit per file"),
+    (
+        "quota.total",
+        "Not downloading {0}, the {1} limit for this sync is used up",
+    ),
+    (
+        "rollback.done",
+        "Restored {0} files and removed {1} files created by the last sync",
+    ),
+    (
+        "rollback.none",
+        "No backup in {0}, only syncs run with --backup can be rolled back",
+    ),
+    ("sftp.auth_failed", "Could not log in to {1} as {0}"),
+    (
+        "sftp.host_key_mismatch",
+        "Host key of {0} does not match ~/.ssh/known_hosts, refusing to connect",
+    ),
+    (
+        "sftp.host_unknown",
+        "{0} is not in ~/.ssh/known_hosts, connect with ssh once to add it",
+    ),
+    (
+        "shutdown.interrupted",
+        "Interrupted, run again to resume.",
+    ),
+    (
+        "shutdown.requested",
+        "Stopping, waiting up to {0}s for running transfers. Signal again to stop immediately.",
+    ),
+    ("signed.failed", "Could not sign a URL for {0}: {1}"),
+    (
+        "signing.bad_key",
+        "{0} does not hold a base64 encoded 32 byte Ed25519 key",
+    ),
+    (
+        "signing.bad_public_key",
+        "The verify key must be a base64 encoded 32 byte Ed25519 public key",
+    ),
+    (
+        "signing.bad_signature",
+        "The signature of the manifest at {0} does not match the verify key",
+    ),
+    ("signing.unsigned", "The manifest at {0} is not signed"),
+    (
+        "stats.duplicates",
+        "Duplicate content: {0} entries, {1} bytes wasted",
+    ),
+    ("stats.entries", "Manifest entries: {0}"),
+    ("stats.missing", "Missing: {0} files, {1} bytes"),
+    ("stats.present", "Present: {0} files, {1} bytes"),
+    ("stats.unknown_size", "unknown"),
+    ("stats.untracked", "Untracked: {0} files, {1} bytes"),
+    (
+        "sync.attributes",
+        "Could not change attributes of {0}: {1}",
+    ),
+    (
+        "sync.bad_link",
+        "Refusing to link {0} to {1}, which is outside the synced directory",
+    ),
+    ("sync.bad_name", "Cannot write {0} on this system: {1}"),
+    (
+        "sync.busy",
+        "{0} is in use by another process, close it or use --busy-policy",
+    ),
+    ("sync.cancelled", "Sync cancelled."),
+    (
+        "sync.changes",
+        "Syncing against manifest, {0} changes found.",
+    ),
+    (
+        "sync.failed",
+        "{0} file(s) could not be synced, run sync again to retry.",
+    ),
+    (
+        "sync.full_validation",
+        "Could not sync against manifest, running full validation.",
+    ),
+    ("sync.in_use", "in use"),
+    (
+        "sync.interrupted",
+        "Sync stopped early: {0} downloaded, {1} deleted, {2} failed.",
+    ),
+    (
+        "sync.link_blocked",
+        "Cannot create link {0}, there is a directory in its place",
+    ),
+    ("sync.mirror", "Downloading from {0}"),
+    (
+        "sync.name_char",
+        "{0} contains the character '{1}', which Windows does not allow",
+    ),
+    (
+        "sync.name_reserved",
+        "{0} is a reserved device name on Windows",
+    ),
+    (
+        "sync.name_stream",
+        "{0} contains ':', which Windows treats as an alternate data stream",
+    ),
+    (
+        "sync.name_trailing",
+        "{0} ends with a dot or space, which Windows strips",
+    ),
+    ("sync.no_profiles", "No profiles configured."),
+    (
+        "sync.no_space",
+        "Not enough disk space, the downloads need {0} but only {1} is free",
+    ),
+    (
+        "sync.original_failed",
+        "Not copied, {0} with the same content did not sync",
+    ),
+    ("sync.profile", "Syncing profile {0}"),
+    (
+        "sync.read_only",
+        "{0} is read-only. Make it writable or sync with --read-only override.",
+    ),
+    ("sync.served_by", "{0} served {1} files"),
+    (
+        "sync.skipped_summary",
+        "Some files were in use and skipped, run sync again to finish.",
+    ),
+    (
+        "sync.then_unclean",
+        "Sync did not validate cleanly ({0} differences), not running command.",
+    ),
+    ("validate.counts", "Missing items: {0}, Desynced items: {1}"),
+    (
+        "validate.counts_force",
+        "Missing items: {0}, Desynced items: {1}, Untracked items: {2}",
+    ),
+    ("validate.failed", "Validation failed."),
+    ("validate.header", "DIFFERENCES"),
+    ("validate.mismatch", "  HASH MISMATCH: {0}"),
+    ("validate.missing", "  MISSING FILE: {0}"),
+    ("validate.mode_mismatch", "  MODE MISMATCH: {0}"),
+    ("validate.ok", "All files validated."),
+    (
+        "validate.restored",
+        "File matches the manifest again: {0}",
+    ),
+    ("validate.source_hash", "  HASH MISMATCH AT SOURCE: {0}"),
+    (
+        "validate.source_size",
+        "  SIZE MISMATCH: {0} (manifest {1}, source {2})",
+    ),
+    ("validate.source_unreachable", "  UNREACHABLE: {0} ({1})"),
+    (
+        "validate.sources_counts",
+        "Unreachable: {0}, Size mismatches: {1}, Hash mismatches: {2}",
+    ),
+    ("validate.sources_ok", "All sources reachable."),
+    ("validate.unknown", "  UNKNOWN FILE: {0}"),
+    (
+        "validate.watching",
+        "Watching {0} for changes, press Ctrl-C to stop.",
+    ),
+    ("webdav.failed", "{0} {1} failed: {2}"),
+];
+
+const DE: &[(&str, &str)] = &[
+    ("azure.failed", "{0} {1} fehlgeschlagen: {2}"),
+    (
+        "azure.no_account",
+        "az://-URLs benötigen das Speicherkonto in AZURE_STORAGE_ACCOUNT",
+    ),
+    (
+        "azure.no_credentials",
+        "Kein SAS-Token in der URL oder AZURE_STORAGE_SAS_TOKEN und kein AZURE_TENANT_ID, AZURE_CLIENT_ID und AZURE_CLIENT_SECRET, um ein Token zu beziehen",
+    ),
+    (
+        "backend.unknown_scheme",
+        "Nicht unterstütztes URL-Schema {0}: {1}",
+    ),
+    (
+        "backend.unsupported",
+        "{0}://-URLs unterstützen {1} nicht",
+    ),
+    (
+        "check.consistent",
+        "Lokale Dateien, lokales Manifest und Gegenstelle stimmen überein.",
+    ),
+    ("check.found", "{0} Pfade sind inkonsistent"),
+    ("check.header", "LOKAL      VERÖFF.    ENTFERNT   PFAD"),
+    ("compare.same", "Verzeichnisse haben identischen Inhalt."),
+    (
+        "du.unknown_size",
+        "Quelle von {0} hat keine Größe gemeldet",
+    ),
+    (
+        "file.unc_unsupported",
+        "{0} ist eine Netzwerkfreigabe, die nur unter Windows direkt gelesen werden kann. Binde sie ein und verwende stattdessen ihren lokalen Pfad",
+    ),
+    (
+        "gcs.bad_key",
+        "Der GCS-Schlüssel muss 32 Bytes lang und Base64-kodiert sein",
+    ),
+    (
+        "generate.checksums_algo",
+        "--checksums setzt sha512 voraus, dieses Manifest verwendet {0}",
+    ),
+    ("generate.signed", "Signiert, prüfen mit --verify-key {0}"),
+    (
+        "get.bad_range",
+        "Server hat für {0} wiederholt den falschen Byte-Bereich geliefert",
+    ),
+    (
+        "get.hash_mismatch",
+        "Heruntergeladene Datei {0} passt nicht zum Hash im Manifest",
+    ),
+    (
+        "get.short_chunk",
+        "Download von {0} endete vorzeitig bei Byte {1}",
+    ),
+    ("get.stalled", "Seit {1}s keine Daten von {0}"),
+    (
+        "get.truncated",
+        "{0} ist unvollständig, {1} von {2} Bytes erhalten",
+    ),
+    ("get.unknown_path", "{0} ist nicht im Manifest enthalten"),
+    (
+        "github.asset_clash",
+        "{0} und {1} würden beide als Asset {2} hochgeladen, benenne eine davon um",
+    ),
+    ("github.failed", "{0} {1} fehlgeschlagen: {2}"),
+    ("github.no_asset", "{0} ist kein Asset des Releases"),
+    (
+        "github.no_token",
+        "Push nach GitHub benötigt ein Token in GITHUB_TOKEN oder GH_TOKEN",
+    ),
+    (
+        "hash.volatile",
+        "{0} hat sich während des Hashens ständig geändert, Abbruch nach {1} Versuchen",
+    ),
+    (
+        "http.no_http3",
+        "--http3 benötigt ein mit dem Feature http3 gebautes comstar",
+    ),
+    ("http.trace_id", "Sende {0}: {1}"),
+    ("ipfs.checksums_added", "Prüfsummen hinzugefügt als {0}"),
+    ("ipfs.failed", "ipfs {0} ist fehlgeschlagen: {1}"),
+    ("ipfs.manifest_added", "Manifest hinzugefügt als {0}"),
+    ("ipfs.no_cid", "ipfs add hat keine CID für {0} geliefert"),
+    ("ipfs.spawn", "ipfs konnte nicht ausgeführt werden: {0}"),
+    ("lint.bad_digest", "muss aus 128 Hex-Ziffern bestehen"),
+    ("lint.bad_mode", "muss ein oktaler Modus wie 0755 sein"),
+    ("lint.bad_time", "muss ein Zeitstempel nach RFC 3339 sein"),
+    ("lint.bad_url", "{0} ist keine gültige URL: {1}"),
+    (
+        "lint.bad_xattrs",
+        "muss ein Objekt aus Hex-Zeichenketten sein",
+    ),
+    (
+        "lint.dangling",
+        "verweist auf {0}, das nicht im Manifest steht",
+    ),
+    (
+        "lint.duplicate_path",
+        "Pfad bereits in Eintrag {0} aufgeführt",
+    ),
+    ("lint.entry", "Eintrag {0}"),
+    (
+        "lint.escapes",
+        "{0} ist kein Pfad innerhalb des synchronisierten Verzeichnisses",
+    ),
+    ("lint.failed", "{0} Problem(e) gefunden."),
+    ("lint.header", "Manifest-Kopf: {0}"),
+    ("lint.line", "Zeile {0}"),
+    ("lint.missing", "fehlt"),
+    ("lint.not_bool", "muss true oder false sein"),
+    ("lint.not_integer", "muss eine ganze Zahl sein"),
+    ("lint.not_object", "Eintrag ist kein JSON-Objekt"),
+    ("lint.not_string", "muss eine Zeichenkette sein"),
+    ("lint.not_utf8", "Manifest ist kein gültiges UTF-8: {0}"),
+    ("lint.ok", "Manifest ist wohlgeformt, {0} Einträge."),
+    (
+        "locate.bad_query",
+        "{0} ist weder eine Datei noch ein Hex-Hash mit mindestens {1} Zeichen",
+    ),
+    ("locate.none", "Keine Einträge mit Hash {0}"),
+    ("manifest.not_found", "Entferntes Manifest nicht gefunden: {0}"),
+    (
+        "manifest.requires",
+        "Dieses Manifest benötigt comstar {0} oder neuer, installiert ist {1}. Bitte comstar aktualisieren.",
+    ),
+    (
+        "manifest.skipped_entry",
+        "Überspringe Manifest-Eintrag {0} ({1}): {2}",
+    ),
+    ("oci.failed", "{0} {1} fehlgeschlagen: {2}"),
+    (
+        "oci.no_credentials",
+        "Registry {0} verlangt eine Anmeldung, setze COMSTAR_OCI_USER und COMSTAR_OCI_PASSWORD oder führe docker login aus",
+    ),
+    (
+        "oci.no_digest",
+        "{0} benennt keinen Blob, erwartet registry/repository@sha256:...",
+    ),
+    (
+        "oci.push_digest",
+        "Push nach {0} nicht möglich, pushe zu einem Tag statt einem Digest",
+    ),
+    ("oci.pushed", "{0} gepusht als {1}"),
+    (
+        "pin.exists",
+        "Verzeichnis ist bereits auf eine andere Version festgelegt, --update ersetzt sie.",
+    ),
+    (
+        "pin.mismatch",
+        "Manifest hat sich seit dem Festlegen geändert (festgelegt {0}, jetzt {1}). comstar pin --update übernimmt es.",
+    ),
+    ("pin.pinned", "Manifest vom {0} festgelegt."),
+    (
+        "pin.unchanged",
+        "Bereits auf das Manifest vom {0} festgelegt.",
+    ),
+    (
+        "plugin.bad_answer",
+        "Plugin {0} hat ungültig geantwortet: {1}",
+    ),
+    ("plugin.failed", "Plugin {0} ist fehlgeschlagen: {1}"),
+    ("plugin.missing", "{0} existiert nicht"),
+    (
+        "plugin.no_answer",
+        "Plugin {0} wurde ohne Antwort beendet ({1})",
+    ),
+    (
+        "plugin.spawn",
+        "Plugin {0} konnte nicht gestartet werden: {1}",
+    ),
+    ("progress.checking_sources", "Prüfe Quellen"),
+    ("progress.done", "{0}: Fertig."),
+    ("progress.errors", "{0} ({1} fehlgeschlagen)"),
+    ("progress.failed", "  FEHLGESCHLAGEN: {0}: {1}"),
+    ("progress.fetching", "Dateien werden abgerufen"),
+    ("progress.generating", "Manifest wird erstellt"),
+    ("progress.overflow", "… und {0} weitere in Arbeit"),
+    ("progress.pruning", "Überzählige Dateien werden gelöscht"),
+    ("progress.pushing", "Änderungen werden hochgeladen"),
+    ("progress.retry", "{0} (Versuch {1})"),
+    ("progress.skipped", "  ÜBERSPRUNGEN ({1}): {0}"),
+    ("progress.slow_file", "  {0} ({1} in {2})"),
+    ("progress.slow_host", "  {0} ({1}/s über {2} Dateien)"),
+    ("progress.slowest_files", "Langsamste Dateien:"),
+    ("progress.slowest_hosts", "Langsamste Server:"),
+    ("progress.stalled", "{0} (hängt, wird neu gestartet)"),
+    ("progress.syncing", "Dateien werden synchronisiert"),
+    ("progress.throttled", "{0} (gedrosselt, warte {1}s)"),
+    ("progress.untracked", "Suche nach unbekannten Dateien"),
+    ("progress.validating", "Dateien werden geprüft"),
+    (
+        "push.bad_replica",
+        "{0} ist keine GCS-Objekt-URL, Replikate müssen gs://- oder storage.googleapis.com-Manifeste sein",
+    ),
+    (
+        "push.chunk_mismatch",
+        "GCS hat den Abschnitt von {0} ab Byte {1} nicht wie gesendet gespeichert",
+    ),
+    ("push.delete_failed", "{0} Objekt(e) konnten nicht gelöscht werden:"),
+    (
+        "push.dirty",
+        "{0} Dateien haben sich seit dem Erzeugen des Manifests geändert, erneut pushen sobald sie fertig sind oder --allow-dirty angeben",
+    ),
+    ("push.http_failed", "{0} {1} fehlgeschlagen: {2}"),
+    (
+        "push.overlapping",
+        "Push nach {0} nicht möglich, es überschneidet sich mit dem Quellverzeichnis",
+    ),
+    (
+        "push.staging_mismatch",
+        "Bereitgestellter Upload von {0} stimmt nicht mit der lokalen Datei überein, nichts wurde veröffentlicht",
+    ),
+    (
+        "quota.file",
+        "{0} ist {1} groß, über der Grenze von {2} pro Datei",
+    ),
+    (
+        "quota.total",
+        "{0} wird nicht geladen, die Grenze von {1} für diese Synchronisation ist ausgeschöpft",
+    ),
+    (
+        "rollback.done",
+        "{0} Dateien wiederhergestellt und {1} von der letzten Synchronisierung angelegte Dateien entfernt",
+    ),
+    (
+        "rollback.none",
+        "Keine Sicherung in {0}, nur mit --backup ausgeführte Synchronisierungen lassen sich zurücknehmen",
+    ),
+    (
+        "sftp.auth_failed",
+        "Anmeldung an {1} als {0} fehlgeschlagen",
+    ),
+    (
+        "sftp.host_key_mismatch",
+        "Host-Schlüssel von {0} passt nicht zu ~/.ssh/known_hosts, Verbindung abgelehnt",
+    ),
+    (
+        "sftp.host_unknown",
+        "{0} fehlt in ~/.ssh/known_hosts, einmal mit ssh verbinden, um ihn hinzuzufügen",
+    ),
+    (
+        "shutdown.interrupted",
+        "Unterbrochen, erneut starten zum Fortsetzen.",
+    ),
+    (
+        "shutdown.requested",
+        "Beende, warte bis zu {0}s auf laufende Übertragungen. Erneutes Signal beendet sofort.",
+    ),
+    (
+        "signed.failed",
+        "Konnte keine signierte URL für {0} erstellen: {1}",
+    ),
+    (
+        "signing.bad_key",
+        "{0} enthält keinen base64-kodierten Ed25519-Schlüssel mit 32 Bytes",
+    ),
+    (
+        "signing.bad_public_key",
+        "Der Prüfschlüssel muss ein base64-kodierter öffentlicher Ed25519-Schlüssel mit 32 Bytes sein",
+    ),
+    (
+        "signing.bad_signature",
+        "Die Signatur des Manifests unter {0} passt nicht zum Prüfschlüssel",
+    ),
+    (
+        "signing.unsigned",
+        "Das Manifest unter {0} ist nicht signiert",
+    ),
+    (
+        "stats.duplicates",
+        "Doppelte Inhalte: {0} Einträge, {1} Bytes verschwendet",
+    ),
+    ("stats.entries", "Manifest-Einträge: {0}"),
+    ("stats.missing", "Fehlend: {0} Dateien, {1} Bytes"),
+    ("stats.present", "Vorhanden: {0} Dateien, {1} Bytes"),
+    ("stats.unknown_size", "unbekannt"),
+    ("stats.untracked", "Nicht erfasst: {0} Dateien, {1} Bytes"),
+    (
+        "sync.attributes",
+        "Attribute von {0} konnten nicht geändert werden: {1}",
+    ),
+    (
+        "sync.bad_link",
+        "Verknüpfung von {0} nach {1} abgelehnt, das Ziel liegt außerhalb des synchronisierten Verzeichnisses",
+    ),
+    (
+        "sync.bad_name",
+        "{0} kann auf diesem System nicht geschrieben werden: {1}",
+    ),
+    (
+        "sync.busy",
+        "{0} wird von einem anderen Prozess verwendet, bitte schließen oder --busy-policy nutzen",
+    ),
+    ("sync.cancelled", "Synchronisierung abgebrochen."),
+    (
+        "sync.changes",
+        "Abgleich mit dem Manifest, {0} Änderungen gefunden.",
+    ),
+    (
+        "sync.failed",
+        "{0} Datei(en) konnten nicht synchronisiert werden, bitte erneut synchronisieren.",
+    ),
+    (
+        "sync.full_validation",
+        "Abgleich mit dem Manifest nicht möglich, alle Dateien werden geprüft.",
+    ),
+    ("sync.in_use", "in Verwendung"),
+    (
+        "sync.interrupted",
+        "Synchronisierung vorzeitig beendet: {0} heruntergeladen, {1} gelöscht, {2} fehlgeschlagen.",
+    ),
+    (
+        "sync.link_blocked",
+        "Verknüpfung {0} kann nicht angelegt werden, an ihrer Stelle liegt ein Verzeichnis",
+    ),
+    ("sync.mirror", "Lade von {0} herunter"),
+    (
+        "sync.name_char",
+        "{0} enthält das Zeichen '{1}', das Windows nicht erlaubt",
+    ),
+    (
+        "sync.name_reserved",
+        "{0} ist unter Windows ein reservierter Gerätename",
+    ),
+    (
+        "sync.name_stream",
+        "{0} enthält ':', was Windows als alternativen Datenstrom behandelt",
+    ),
+    (
+        "sync.name_trailing",
+        "{0} endet mit Punkt oder Leerzeichen, die Windows entfernt",
+    ),
+    ("sync.no_profiles", "Keine Profile konfiguriert."),
+    (
+        "sync.no_space",
+        "Nicht genug Speicherplatz, die Downloads brauchen {0}, frei sind nur {1}",
+    ),
+    (
+        "sync.original_failed",
+        "Nicht kopiert, {0} mit demselben Inhalt wurde nicht synchronisiert",
+    ),
+    ("sync.profile", "Synchronisiere Profil {0}"),
+    (
+        "sync.read_only",
+        "{0} ist schreibgeschützt. Schreibrechte setzen oder mit --read-only override synchronisieren.",
+    ),
+    ("sync.served_by", "{0} hat {1} Dateien geliefert"),
+    (
+        "sync.skipped_summary",
+        "Einige Dateien waren in Verwendung und wurden übersprungen, bitte erneut synchronisieren.",
+    ),
+    (
+        "sync.then_unclean",
+        "Synchronisierung nicht fehlerfrei ({0} Abweichungen), Befehl wird nicht ausgeführt.",
+    ),
+    ("validate.counts", "Fehlend: {0}, Abweichend: {1}"),
+    (
+        "validate.counts_force",
+        "Fehlend: {0}, Abweichend: {1}, Unbekannt: {2}",
+    ),
+    ("validate.failed", "Prüfung fehlgeschlagen."),
+    ("validate.header", "ABWEICHUNGEN"),
+    ("validate.mismatch", "  HASH ABWEICHEND: {0}"),
+    ("validate.missing", "  DATEI FEHLT: {0}"),
+    ("validate.mode_mismatch", "  RECHTE ABWEICHEND: {0}"),
+    ("validate.ok", "Alle Dateien geprüft."),
+    (
+        "validate.restored",
+        "Datei stimmt wieder mit dem Manifest überein: {0}",
+    ),
+    (
+        "validate.source_hash",
+        "  HASH AN DER QUELLE ABWEICHEND: {0}",
+    ),
+    (
+        "validate.source_size",
+        "  GRÖSSE ABWEICHEND: {0} (Manifest {1}, Quelle {2})",
+    ),
+    (
+        "validate.source_unreachable",
+        "  NICHT ERREICHBAR: {0} ({1})",
+    ),
+    (
+        "validate.sources_counts",
+        "Nicht erreichbar: {0}, Größe abweichend: {1}, Hash abweichend: {2}",
+    ),
+    ("validate.sources_ok", "Alle Quellen erreichbar."),
+    ("validate.unknown", "  UNBEKANNTE DATEI: {0}"),
+    (
+        "validate.watching",
+        "Überwache {0} auf Änderungen, Strg-C beendet.",
+    ),
+    ("webdav.failed", "{0} {1} fehlgeschlagen: {2}"),
+];
+
+static CATALOG: OnceLock<HashMap<String, String>> = OnceLock::new();
+
+fn detect_language() -> String {
+    ["COMSTAR_LANG", "LC_ALL", "LC_MESSAGES", "LANG"]
+        .iter()
+        .filter_map(|v| std::env::var(v).ok())
+        .find(|v| !v.is_empty())
+        .map(|v| {
+            v.split(['_', '.', '-'])
+                .next()
+                .unwrap_or("")
+                .to_ascii_lowercase()
+        })
+        .unwrap_or_else(|| "en".into())
+}
+
+fn load_catalog() -> HashMap<String, String> {
+    let lang = detect_language();
+    let builtin = match lang.as_str() {
+        "de" => DE,
+        _ => EN,
+    };
+    let mut catalog: HashMap<String, String> = builtin
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+    let external = config::config_path()
+        .and_then(|p| {
+            p.parent()
+                .map(|d| d.join("locale").join(format!("{}.toml", lang)))
+        })
+        .and_then(|p| fs::read_to_string(p).ok())
+        .and_then(|s| toml::from_str::<HashMap<String, String>>(&s).ok());
+    if let Some(external) = external {
+        catalog.extend(external);
+    }
+    catalog
+}
+
+/// Looks up `key` in the active catalog and fills in positional placeholders.
+pub fn message(key: &str, args: &[&dyn Display]) -> String {
+    let catalog = CATALOG.get_or_init(load_catalog);
+    let template = catalog
+        .get(key)
+        .map(|s| s.as_str())
+        .or_else(|| EN.iter().find(|(k, _)| *k == key).map(|(_, v)| *v))
+        .unwrap_or(key);
+    let mut out = template.to_string();
+    for (i, arg) in args.iter().enumerate() {
+        out = out.replace(&format!("{{{}}}", i), &arg.to_string());
+    }
+    out
+}
+
+/// `t!("key", args...)` formats a catalog message.
+macro_rules! t {
+    ($key:expr) => {
+        $crate::i18n::message($key, &[])
+    };
+    ($key:expr, $($arg:expr),+ $(,)?) => {
+        $crate::i18n::message($key, &[$(&$arg as &dyn std::fmt::Display),+])
+    };
+}
+
+pub(crate) use t;
@@ -0,0 +1,629 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    io::{self, BufWriter, Write},
+    path::Path,
+};
+
+use anyhow::{anyhow, Result};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use indicatif::BinaryBytes;
+use relative_path::{RelativePath, RelativePathBuf};
+use serde::{Deserialize, Serialize};
+use serde_json::{value::RawValue, Value};
+use structopt::StructOpt;
+use tokio::io::AsyncWriteExt;
+use url::Url;
+
+use crate::{
+    events,
+    i18n::t,
+    manifest::{self, EntryProblem, ManifestEntry},
+    sync, util, validate,
+};
+
+/// Abbreviated digests shorter than this match too much to be useful.
+const MIN_DIGEST_PREFIX: usize = 8;
+
+#[derive(Debug, StructOpt)]
+pub struct LsOptions {
+    #[structopt(long, help = "Only list paths matching this glob. May be repeated.")]
+    pub glob: Vec<String>,
+    #[structopt(
+        long,
+        parse(try_from_str = Url::parse),
+        help = "Only list entries that are new or changed since this manifest."
+    )]
+    pub since: Option<Url>,
+    #[structopt(long, help = "Print entries as JSON lines instead of columns.")]
+    pub json: bool,
+}
+
+fn glob_set(patterns: &[String]) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for p in patterns {
+        builder.add(Glob::new(p)?);
+    }
+    Ok(builder.build()?)
+}
+
+fn print_entry<W: Write>(out: &mut W, e: &ManifestEntry, json: bool) -> Result<()> {
+    if json {
+        serde_json::to_writer(&mut *out, e)?;
+        writeln!(out)?;
+    } else {
+        let short = &e.sha512[..e.sha512.len().min(16)];
+        match &e.duplicate_of {
+            Some(original) => writeln!(out, "{}  {} -> {}", short, e.path, original)?,
+            None => writeln!(out, "{}  {}", short, e.path)?,
+        }
+    }
+    Ok(())
+}
+
+/// Lists the entries of a manifest, streaming it so huge manifests print right away.
+pub async fn ls(target: &Url, opts: &LsOptions) -> Result<()> {
+    let globs = glob_set(&opts.glob)?;
+    let previous: Option<HashMap<RelativePathBuf, String>> = match &opts.since {
+        Some(since) => {
+            let mut hashes = HashMap::new();
+            manifest::stream_manifest(since, |e| {
+                hashes.insert(e.path, e.sha512);
+                Ok(())
+            })
+            .await?
+            .ok_or_else(|| anyhow!(t!("manifest.not_found", since)))?;
+            Some(hashes)
+        }
+        None => None,
+    };
+
+    let mut out = BufWriter::new(io::stdout());
+    let found = manifest::stream_manifest(target, |e| {
+        if !opts.glob.is_empty() && !globs.is_match(e.path.as_str()) {
+            return Ok(());
+        }
+        if previous
+            .as_ref()
+            .is_some_and(|prev| prev.get(&e.path) == Some(&e.sha512))
+        {
+            return Ok(());
+        }
+        print_entry(&mut out, &e, opts.json)
+    })
+    .await?;
+    out.flush()?;
+    found
+        .map(|_| ())
+        .ok_or_else(|| anyhow!(t!("manifest.not_found", target)))
+}
+
+/// Prints every entry whose hash matches `query`, which is either a local file to hash or
+/// a full or abbreviated hex digest.
+pub async fn locate(target: &Url, query: &str, json: bool) -> Result<()> {
+    let path = Path::new(query);
+    let digest = if path.is_file() {
+        // hashed the way the manifest hashes its entries
+        let algo = manifest::get_manifest(target)
+            .await?
+            .map(|m| m.algo)
+            .unwrap_or_default();
+        util::hash_file_with(path.to_path_buf(), algo).await?
+    } else if query.len() >= MIN_DIGEST_PREFIX && query.chars().all(|c| c.is_ascii_hexdigit()) {
+        query.to_ascii_lowercase()
+    } else {
+        return Err(anyhow!(t!("locate.bad_query", query, MIN_DIGEST_PREFIX)));
+    };
+
+    let mut out = BufWriter::new(io::stdout());
+    let mut matches = 0;
+    let found = manifest::stream_manifest(target, |e| {
+        if e.sha512.starts_with(&digest) {
+            matches += 1;
+            print_entry(&mut out, &e, json)?;
+        }
+        Ok(())
+    })
+    .await?;
+    out.flush()?;
+    if found.is_none() {
+        return Err(anyhow!(t!("manifest.not_found", target)));
+    }
+    if matches == 0 {
+        return Err(anyhow!(t!("locate.none", digest)));
+    }
+    Ok(())
+}
+
+/// How a directory compares to a manifest. Files are counted as present when they exist,
+/// without hashing them; `validate` is the command that checks content.
+#[derive(Debug, Default, Serialize)]
+pub struct DirStats {
+    pub entries: u64,
+    pub present: u64,
+    pub present_bytes: u64,
+    pub missing: u64,
+    /// Unknown when the manifest doesn't record the size of every missing entry.
+    pub missing_bytes: Option<u64>,
+    pub untracked: u64,
+    pub untracked_bytes: u64,
+    /// Entries whose content is also listed under another path.
+    pub duplicates: u64,
+    /// Bytes wasted on duplicates, the size of every entry above beyond the first copy of its
+    /// content. Sync only downloads that content once, the rest are local copies or links.
+    pub duplicate_bytes: u64,
+}
+
+/// Reports how `dir` compares to the manifest at `target` without touching anything.
+pub async fn stats(target: &Url, dir: &Path, json: bool) -> Result<()> {
+    let mut local: HashMap<RelativePathBuf, u64> = HashMap::new();
+    for dirent in util::get_walker(dir)?.filter_map(|d| d.ok()) {
+        let path = dirent.path();
+        if !path.is_file() {
+            continue;
+        }
+        let relative = RelativePathBuf::from_path(path.strip_prefix(dir)?)?;
+        local.insert(relative, path.metadata()?.len());
+    }
+
+    let mut stats = DirStats::default();
+    let mut digests = HashSet::new();
+    let mut missing_bytes = Some(0);
+    manifest::stream_manifest(target, |e| {
+        stats.entries += 1;
+        let size = local.remove(&e.path);
+        match size {
+            Some(len) => {
+                stats.present += 1;
+                stats.present_bytes += len;
+            }
+            None => {
+                stats.missing += 1;
+                missing_bytes = missing_bytes.zip(e.size).map(|(a, b)| a + b);
+            }
+        }
+        if e.duplicate_of.is_some() || !digests.insert(e.sha512) {
+            stats.duplicates += 1;
+            stats.duplicate_bytes += e.size.or(size).unwrap_or(0);
+        }
+        Ok(())
+    })
+    .await?
+    .ok_or_else(|| anyhow!(t!("manifest.not_found", target)))?;
+    stats.missing_bytes = missing_bytes;
+    stats.untracked = local.len() as u64;
+    stats.untracked_bytes = local.values().sum();
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&stats)?);
+        return Ok(());
+    }
+    let missing_bytes = match stats.missing_bytes {
+        Some(b) => b.to_string(),
+        None => t!("stats.unknown_size"),
+    };
+    println!("{}", t!("stats.entries", stats.entries));
+    println!(
+        "{}",
+        t!("stats.present", stats.present, stats.present_bytes)
+    );
+    println!("{}", t!("stats.missing", stats.missing, missing_bytes));
+    println!(
+        "{}",
+        t!("stats.untracked", stats.untracked, stats.untracked_bytes)
+    );
+    println!(
+        "{}",
+        t!("stats.duplicates", stats.duplicates, stats.duplicate_bytes)
+    );
+    Ok(())
+}
+
+/// Prints the total size of every directory in a manifest, deepest first like `du`.
+/// Sources are only asked for their length when the manifest doesn't record it.
+pub async fn du(target: &Url, depth: Option<usize>, bytes: bool) -> Result<()> {
+    let entries = manifest::get_manifest(target)
+        .await?
+        .ok_or_else(|| anyhow!(t!("manifest.not_found", target)))?
+        .entries;
+    let sizes = util::bounded_tasks(entries, util::jobs().net, |e| async move {
+        let size = match e.size {
+            Some(s) => s,
+            None => sync::remote_size(&e.source)
+                .await?
+                .ok_or_else(|| anyhow!(t!("du.unknown_size", e.path)))?,
+        };
+        Ok((e.path, size))
+    })
+    .await?;
+
+    let mut dirs: BTreeMap<RelativePathBuf, u64> = BTreeMap::new();
+    for (path, size) in sizes {
+        let mut parent = path.parent();
+        while let Some(p) = parent {
+            *dirs.entry(p.to_relative_path_buf()).or_default() += size;
+            parent = p.parent();
+        }
+    }
+
+    let mut out = BufWriter::new(io::stdout());
+    // reverse order puts every directory after its children, the root last
+    for (dir, size) in dirs.iter().rev() {
+        let level = dir.components().count();
+        if depth.is_some_and(|d| level > d) {
+            continue;
+        }
+        let name = if dir.as_str().is_empty() {
+            "."
+        } else {
+            dir.as_str()
+        };
+        if bytes {
+            writeln!(out, "{}\t{}", size, name)?;
+        } else {
+            writeln!(out, "{}\t{}", BinaryBytes(*size), name)?;
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// Hashes two local directories and prints the paths that were added, removed or changed
+/// going from `a` to `b`.
+pub async fn compare(a: &Path, b: &Path, json: bool) -> Result<()> {
+    let opts = manifest::GenerateOptions::default();
+    let mut generated = Vec::with_capacity(2);
+    for dir in [a, b] {
+        let base = Url::from_directory_path(dir)
+            .map_err(|_| anyhow!("Cannot make URL from directory {}", dir.display()))?;
+        generated.push(manifest::generate_manifest(base, dir, &opts).await?);
+    }
+    let after = generated.pop().unwrap().entries;
+    let before: HashMap<RelativePathBuf, String> = generated
+        .pop()
+        .unwrap()
+        .entries
+        .into_iter()
+        .map(|e| (e.path, e.sha512))
+        .collect();
+
+    let mut changes: BTreeMap<RelativePathBuf, &str> = BTreeMap::new();
+    let mut seen = HashSet::new();
+    for e in &after {
+        match before.get(&e.path) {
+            None => {
+                changes.insert(e.path.clone(), "added");
+            }
+            Some(sha512) if *sha512 != e.sha512 => {
+                changes.insert(e.path.clone(), "changed");
+            }
+            Some(_) => {}
+        }
+        seen.insert(&e.path);
+    }
+    for path in before.keys().filter(|p| !seen.contains(p)) {
+        changes.insert(path.clone(), "removed");
+    }
+
+    let mut out = BufWriter::new(io::stdout());
+    for (path, change) in &changes {
+        if json {
+            serde_json::to_writer(
+                &mut out,
+                &serde_json::json!({ "path": path, "change": change }),
+            )?;
+            writeln!(out)?;
+        } else {
+            let marker = match *change {
+                "added" => '+',
+                "removed" => '-',
+                _ => '~',
+            };
+            writeln!(out, "{} {}", marker, path)?;
+        }
+    }
+    out.flush()?;
+    if changes.is_empty() && !json {
+        println!("{}", t!("compare.same"));
+    }
+    Ok(())
+}
+
+/// How one path disagrees between the local tree, the local manifest and the remote side.
+/// Each column compares a pair, so the one that's set says where the problem is.
+#[derive(Debug, Default, Serialize)]
+struct CheckRow {
+    /// Local file against the local manifest: missing, modified, mode or untracked.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    local: Option<&'static str>,
+    /// Local manifest against the remote one: added, changed or removed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    published: Option<&'static str>,
+    /// Remote object against the remote manifest: missing.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    remote: Option<&'static str>,
+}
+
+/// Cross-checks local files, the local manifest in `dir` and the manifest at `remote`
+/// together with the objects it points at, printing one row per path that is out of line
+/// anywhere.
+pub async fn check(remote: &Url, dir: &Path, json: bool) -> Result<()> {
+    let local_url = Url::from_file_path(dir.join("comstar.json"))
+        .map_err(|_| anyhow!("Cannot make URL from directory {}", dir.display()))?;
+    let local_manifest = manifest::get_manifest(&local_url)
+        .await?
+        .ok_or_else(|| anyhow!(t!("manifest.not_found", local_url)))?;
+    let remote_manifest = manifest::get_manifest(remote)
+        .await?
+        .ok_or_else(|| anyhow!(t!("manifest.not_found", remote)))?;
+    let mut rows: BTreeMap<RelativePathBuf, CheckRow> = BTreeMap::new();
+
+    for d in validate::verify_manifest(&local_url, dir, true).await? {
+        rows.entry(d.path).or_default().local = Some(match d.ty {
+            validate::DifferenceType::FileMissing(_) => "missing",
+            validate::DifferenceType::HashMismatch { .. } => "modified",
+            validate::DifferenceType::ModeMismatch(_) => "mode",
+            validate::DifferenceType::UnknownFile => "untracked",
+        });
+    }
+
+    let published: HashMap<&RelativePath, &str> = remote_manifest
+        .entries
+        .iter()
+        .map(|e| (e.path.as_relative_path(), e.sha512.as_str()))
+        .collect();
+    let mut seen = HashSet::new();
+    for e in &local_manifest.entries {
+        let change = match published.get(e.path.as_relative_path()) {
+            None => Some("added"),
+            Some(sha512) if *sha512 != e.sha512 => Some("changed"),
+            Some(_) => None,
+        };
+        if let Some(change) = change {
+            rows.entry(e.path.clone()).or_default().published = Some(change);
+        }
+        seen.insert(e.path.as_relative_path());
+    }
+    for path in published.keys().filter(|p| !seen.contains(*p)) {
+        rows.entry(path.to_relative_path_buf())
+            .or_default()
+            .published = Some("removed");
+    }
+
+    // duplicates share their original's source, no need to ask twice
+    let sources = remote_manifest
+        .entries
+        .iter()
+        .filter(|e| e.duplicate_of.is_none())
+        .cloned();
+    let unreachable = util::bounded_tasks(sources, util::jobs().net, |e| async move {
+        Ok(sync::remote_size(&e.source)
+            .await
+            .is_err()
+            .then_some(e.path))
+    })
+    .await?;
+    for path in unreachable.into_iter().flatten() {
+        rows.entry(path).or_default().remote = Some("missing");
+    }
+
+    let mut out = BufWriter::new(io::stdout());
+    if json {
+        for (path, row) in &rows {
+            let mut v = serde_json::to_value(row)?;
+            v["path"] = serde_json::json!(path);
+            serde_json::to_writer(&mut out, &v)?;
+            writeln!(out)?;
+        }
+    } else if !rows.is_empty() {
+        writeln!(out, "{}", t!("check.header"))?;
+        for (path, row) in &rows {
+            writeln!(
+                out,
+                "{:<10} {:<10} {:<10} {}",
+                row.local.unwrap_or("-"),
+                row.published.unwrap_or("-"),
+                row.remote.unwrap_or("-"),
+                path
+            )?;
+        }
+    }
+    out.flush()?;
+    if !rows.is_empty() {
+        return Err(anyhow!(t!("check.found", rows.len())));
+    }
+    if !json {
+        println!("{}", t!("check.consistent"));
+    }
+    Ok(())
+}
+
+/// One problem `lint` found, located by line where possible.
+#[derive(Debug, Serialize)]
+struct LintDiagnostic {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    line: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    entry: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    path: Option<String>,
+    #[serde(flatten)]
+    problem: EntryProblem,
+}
+
+impl std::fmt::Display for LintDiagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if let Some(line) = self.line {
+            write!(f, "{}: ", t!("lint.line", line))?;
+        }
+        match (self.entry, &self.path) {
+            (Some(i), Some(p)) => write!(f, "{} ({}): ", t!("lint.entry", i), p)?,
+            (Some(i), None) => write!(f, "{}: ", t!("lint.entry", i))?,
+            _ => {}
+        }
+        write!(f, "{}", self.problem)
+    }
+}
+
+#[derive(Deserialize)]
+struct RawEntries<'a> {
+    #[serde(borrow, default)]
+    entries: Vec<&'a RawValue>,
+}
+
+/// Checks a manifest for everything that would make comstar reject or skip part of it,
+/// reporting every problem instead of stopping at the first.
+pub async fn lint(target: &Url, json: bool) -> Result<()> {
+    let bytes = manifest::fetch_manifest_bytes(target)
+        .await?
+        .ok_or_else(|| anyhow!(t!("manifest.not_found", target)))?;
+    let text = std::str::from_utf8(&bytes).map_err(|e| anyhow!(t!("lint.not_utf8", e)))?;
+    let mut diagnostics = Vec::new();
+
+    let mut top: Value = match serde_json::from_str(text) {
+        Ok(v) => v,
+        Err(e) => {
+            // nothing past a syntax error can be trusted
+            diagnostics.push(LintDiagnostic {
+                line: Some(e.line()),
+                entry: None,
+                path: None,
+                problem: EntryProblem {
+                    field: None,
+                    reason: e.to_string(),
+                },
+            });
+            return report_lint(&diagnostics, 0, json);
+        }
+    };
+    if let Some(obj) = top.as_object_mut() {
+        obj.insert("entries".into(), Value::Array(Vec::new()));
+    }
+    if let Err(e) = serde_json::from_value::<manifest::Manifest>(top) {
+        diagnostics.push(LintDiagnostic {
+            line: None,
+            entry: None,
+            path: None,
+            problem: EntryProblem {
+                field: None,
+                reason: t!("lint.header", e),
+            },
+        });
+    }
+
+    let raw: RawEntries = match serde_json::from_str(text) {
+        Ok(r) => r,
+        Err(e) => {
+            diagnostics.push(LintDiagnostic {
+                line: Some(e.line()),
+                entry: None,
+                path: None,
+                problem: EntryProblem {
+                    field: Some("entries"),
+                    reason: e.to_string(),
+                },
+            });
+            return report_lint(&diagnostics, 0, json);
+        }
+    };
+    let mut paths: HashMap<String, usize> = HashMap::new();
+    let mut references = Vec::new();
+    for (i, entry) in raw.entries.iter().enumerate() {
+        let offset = entry.get().as_ptr() as usize - text.as_ptr() as usize;
+        let line = text[..offset].matches('\n').count() + 1;
+        let value: Value = serde_json::from_str(entry.get())?;
+        let path = value.get("path").and_then(|p| p.as_str()).map(String::from);
+        let mut problems = manifest::check_entry(&value);
+        if let Some(p) = &path {
+            if let Some(first) = paths.insert(p.clone(), i) {
+                problems.push(EntryProblem {
+                    field: Some("path"),
+                    reason: t!("lint.duplicate_path", first),
+                });
+            }
+        }
+        for field in ["duplicate_of", "hard_link_of"] {
+            if let Some(target) = value.get(field).and_then(|v| v.as_str()) {
+                references.push((line, i, path.clone(), field, target.to_string()));
+            }
+        }
+        diagnostics.extend(problems.into_iter().map(|problem| LintDiagnostic {
+            line: Some(line),
+            entry: Some(i),
+            path: path.clone(),
+            problem,
+        }));
+    }
+    for (line, i, path, field, target) in references {
+        if !paths.contains_key(&target) {
+            diagnostics.push(LintDiagnostic {
+                line: Some(line),
+                entry: Some(i),
+                path,
+                problem: EntryProblem {
+                    field: Some(field),
+                    reason: t!("lint.dangling", target),
+                },
+            });
+        }
+    }
+    diagnostics.sort_by_key(|d| d.line);
+    report_lint(&diagnostics, raw.entries.len(), json)
+}
+
+fn report_lint(diagnostics: &[LintDiagnostic], entries: usize, json: bool) -> Result<()> {
+    let mut out = BufWriter::new(io::stdout());
+    for d in diagnostics {
+        if json {
+            serde_json::to_writer(&mut out, d)?;
+            writeln!(out)?;
+        } else {
+            writeln!(out, "{}", d)?;
+        }
+    }
+    out.flush()?;
+    if !diagnostics.is_empty() {
+        return Err(anyhow!(t!("lint.failed", diagnostics.len())));
+    }
+    if !json {
+        println!("{}", t!("lint.ok", entries));
+    }
+    Ok(())
+}
+
+/// Fetches a single entry, verifies it and writes it to stdout. The content is spooled to a
+/// temporary file first so nothing unverified ever reaches the pipe.
+pub async fn cat(target: &Url, path: &RelativePath) -> Result<()> {
+    let mut entry = None;
+    // spooled so the signature can be checked before the entry's source is trusted
+    let (header, _) = manifest::spool_manifest(target, manifest::SpooledEntries::temp()?, |e| {
+        if e.path == path {
+            entry = Some(e.clone());
+        }
+        Ok(())
+    })
+    .await?
+    .ok_or_else(|| anyhow!(t!("manifest.not_found", target)))?;
+    let entry = entry.ok_or_else(|| anyhow!(t!("get.unknown_path", path)))?;
+    util::set_algo(header.algo);
+
+    let spool = std::env::temp_dir().join(format!("comstar-cat-{}", std::process::id()));
+    let res = spool_to_stdout(&entry, &spool).await;
+    let _ = std::fs::remove_file(&spool);
+    res
+}
+
+async fn spool_to_stdout(entry: &ManifestEntry, spool: &Path) -> Result<()> {
+    // nobody is watching progress here, a closed channel just discards it
+    let (tx, rx) = events::channel();
+    drop(rx);
+    let sha512 =
+        sync::get_file(&entry.source, spool, sync::DEFAULT_STALL_TIMEOUT, false, tx).await?;
+    if sha512 != entry.sha512 {
+        return Err(anyhow!(t!("get.hash_mismatch", entry.path)));
+    }
+    let mut input = tokio::fs::File::open(spool).await?;
+    let mut stdout = tokio::io::stdout();
+    tokio::io::copy(&mut input, &mut stdout).await?;
+    stdout.flush().await?;
+    Ok(())
+}
@@ -0,0 +1,187 @@
+//! Opt-in local control socket for frontends.
+//!
+//! Clients speak newline-delimited JSON. Each request is an object with a `cmd` of
+//! `status`, `cancel` or `subscribe`. `subscribe` streams every progress event on that
+//! connection until the run ends, so a frontend wanting to cancel as well should open
+//! a second connection.
+
+use std::{
+    collections::BTreeSet,
+    path::Path,
+    sync::{Mutex, OnceLock},
+};
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
+    sync::broadcast::{self, error::RecvError},
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::events::Event;
+
+static SERVER: OnceLock<IpcState> = OnceLock::new();
+
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct Status {
+    pub action: Option<String>,
+    pub total: u64,
+    pub done: u64,
+    pub failed: u64,
+    pub in_progress: BTreeSet<String>,
+}
+
+struct IpcState {
+    events: broadcast::Sender<String>,
+    status: Mutex<Status>,
+    cancel: CancellationToken,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "cmd", rename_all = "snake_case")]
+enum Request {
+    Status,
+    Cancel,
+    Subscribe,
+}
+
+/// Starts listening on a Unix socket (or a named pipe on Windows) at `path`.
+pub fn start(path: &Path) -> Result<()> {
+    let (events, _) = broadcast::channel(1024);
+    SERVER
+        .set(IpcState {
+            events,
+            status: Mutex::new(Status::default()),
+            cancel: CancellationToken::new(),
+        })
+        .map_err(|_| anyhow!("IPC server already started"))?;
+    listen(path)
+}
+
+#[cfg(unix)]
+fn listen(path: &Path) -> Result<()> {
+    if path.exists() {
+        std::fs::remove_file(path)?;
+    }
+    let listener = tokio::net::UnixListener::bind(path)?;
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(handle_client(stream));
+        }
+    });
+    Ok(())
+}
+
+#[cfg(windows)]
+fn listen(path: &Path) -> Result<()> {
+    use tokio::net::windows::named_pipe::ServerOptions;
+
+    let name = path.as_os_str().to_owned();
+    let mut server = ServerOptions::new()
+        .first_pipe_instance(true)
+        .create(&name)?;
+    tokio::spawn(async move {
+        while server.connect().await.is_ok() {
+            let client = server;
+            server = match ServerOptions::new().create(&name) {
+                Ok(s) => s,
+                Err(_) => break,
+            };
+            tokio::spawn(handle_client(client));
+        }
+    });
+    Ok(())
+}
+
+async fn write_line<W: AsyncWrite + Unpin>(w: &mut W, line: &str) -> Result<()> {
+    w.write_all(line.as_bytes()).await?;
+    w.write_all(b"\n").await?;
+    w.flush().await?;
+    Ok(())
+}
+
+async fn handle_client<S: AsyncRead + AsyncWrite + Send + 'static>(stream: S) -> Result<()> {
+    let state = SERVER
+        .get()
+        .ok_or_else(|| anyhow!("IPC server not started"))?;
+    let (r, mut w) = tokio::io::split(stream);
+    let mut lines = BufReader::new(r).lines();
+    while let Some(line) = lines.next_line().await? {
+        let req: Request = match serde_json::from_str(&line) {
+            Ok(r) => r,
+            Err(e) => {
+                let msg = json!({ "type": "error", "message": e.to_string() });
+                write_line(&mut w, &msg.to_string()).await?;
+                continue;
+            }
+        };
+        match req {
+            Request::Status => {
+                let status = state.status.lock().unwrap().clone();
+                let msg = json!({ "type": "status", "status": status });
+                write_line(&mut w, &msg.to_string()).await?;
+            }
+            Request::Cancel => {
+                state.cancel.cancel();
+                write_line(&mut w, &json!({ "type": "cancelling" }).to_string()).await?;
+            }
+            Request::Subscribe => {
+                let mut rx = state.events.subscribe();
+                loop {
+                    match rx.recv().await {
+                        Ok(line) => write_line(&mut w, &line).await?,
+                        Err(RecvError::Lagged(_)) => continue,
+                        Err(RecvError::Closed) => return Ok(()),
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Records the start of a new progress phase, no-op unless the server is running.
+pub fn start_action(action: &str, total: u64) {
+    if let Some(state) = SERVER.get() {
+        let mut status = state.status.lock().unwrap();
+        *status = Status {
+            action: Some(action.to_string()),
+            total,
+            ..Default::default()
+        };
+    }
+}
+
+/// Forwards a progress event to subscribers, no-op unless the server is running.
+pub fn publish(action: &str, e: &Event) {
+    if let Some(state) = SERVER.get() {
+        {
+            let mut status = state.status.lock().unwrap();
+            match e {
+                Event::FileStarted { name, .. } => {
+                    status.in_progress.insert(name.clone());
+                }
+                Event::FileDone { name } | Event::FileSkipped { name, .. } => {
+                    status.in_progress.remove(name);
+                    status.done += 1;
+                }
+                Event::FileFailed { name, .. } => {
+                    status.in_progress.remove(name);
+                    status.done += 1;
+                    status.failed += 1;
+                }
+                _ => {}
+            }
+        }
+        let msg = json!({ "type": "event", "action": action, "event": e });
+        // nobody subscribed is fine
+        let _ = state.events.send(msg.to_string());
+    }
+}
+
+/// Whether a client asked for the current run to be cancelled.
+pub fn cancelled() -> bool {
+    SERVER.get().is_some_and(|s| s.cancel.is_cancelled())
+}
@@ -0,0 +1,123 @@
+use std::{path::Path, process::Stdio};
+
+use anyhow::{anyhow, Result};
+use tokio::{
+    io::{AsyncReadExt, BufReader},
+    process::Command,
+    sync::mpsc,
+};
+use url::Url;
+
+use crate::{i18n::t, util::Chunk};
+
+/// The IPFS command line client, talking to the local node.
+const PROGRAM: &str = "ipfs";
+
+/// Bytes read from `ipfs cat` at a time.
+const CHUNK: usize = 64 * 1024;
+
+/// Chunks the reader may get ahead of the writer.
+const CHUNKS_IN_FLIGHT: usize = 16;
+
+/// The IPFS path of an `ipfs://<cid>[/path]` URL.
+fn ipfs_path(url: &Url) -> Result<String> {
+    match url.host_str() {
+        Some(cid) if !cid.is_empty() => Ok(format!("/ipfs/{}{}", cid, url.path())),
+        _ => Err(anyhow!("IPFS URL has no CID: {}", url)),
+    }
+}
+
+fn command() -> Command {
+    let mut cmd = Command::new(PROGRAM);
+    cmd.stdin(Stdio::null()).kill_on_drop(true);
+    cmd
+}
+
+/// Runs `ipfs` with `args`, returning its stdout.
+async fn run(args: &[&str]) -> Result<Vec<u8>> {
+    let out = command()
+        .args(args)
+        .output()
+        .await
+        .map_err(|e| anyhow!(t!("ipfs.spawn", e)))?;
+    if !out.status.success() {
+        return Err(anyhow!(t!(
+            "ipfs.failed",
+            args.join(" "),
+            String::from_utf8_lossy(&out.stderr).trim()
+        )));
+    }
+    Ok(out.stdout)
+}
+
+/// Adds `file` to the local node, which pins it, and returns its CID.
+pub async fn add(file: &Path) -> Result<String> {
+    let file = file.to_string_lossy();
+    let out = run(&["add", "--quiet", "--cid-version=1", &file]).await?;
+    String::from_utf8(out)?
+        .lines()
+        .last()
+        .map(|l| l.trim().to_string())
+        .filter(|l| !l.is_empty())
+        .ok_or_else(|| anyhow!(t!("ipfs.no_cid", file)))
+}
+
+async fn cat(url: &Url, tx: &mpsc::Sender<Result<Chunk>>) -> Result<()> {
+    let mut child = command()
+        .args(["cat", &ipfs_path(url)?])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| anyhow!(t!("ipfs.spawn", e)))?;
+    let mut stdout = BufReader::new(child.stdout.take().unwrap());
+    let mut buf = vec![0u8; CHUNK];
+    loop {
+        let n = stdout.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        // the receiving end gave up, dropping the child kills it
+        if tx.send(Ok(Chunk::Data(buf[..n].to_vec()))).await.is_err() {
+            return Ok(());
+        }
+    }
+    let out = child.wait_with_output().await?;
+    if !out.status.success() {
+        return Err(anyhow!(t!(
+            "ipfs.failed",
+            format!("cat {}", url),
+            String::from_utf8_lossy(&out.stderr).trim()
+        )));
+    }
+    Ok(())
+}
+
+/// Streams the file at an `ipfs://` URL from the local node. The receiver gets its length
+/// if known, then its data, and closes once the file is complete.
+pub fn download(url: &Url) -> mpsc::Receiver<Result<Chunk>> {
+    let (tx, rx) = mpsc::channel(CHUNKS_IN_FLIGHT);
+    let url = url.clone();
+    tokio::spawn(async move {
+        if let Ok(Some(len)) = size(&url).await {
+            if tx.send(Ok(Chunk::Length(len))).await.is_err() {
+                return;
+            }
+        }
+        if let Err(e) = cat(&url, &tx).await {
+            let _ = tx.send(Err(e)).await;
+        }
+    });
+    rx
+}
+
+/// The whole file at an `ipfs://` URL. Content on IPFS is only ever missing for as long as
+/// no peer has it, so there is no `None` here.
+pub async fn fetch(url: &Url) -> Result<Option<Vec<u8>>> {
+    Ok(Some(run(&["cat", &ipfs_path(url)?]).await?))
+}
+
+/// Size of the file at an `ipfs://` URL.
+pub async fn size(url: &Url) -> Result<Option<u64>> {
+    let out = run(&["files", "stat", "--size", &ipfs_path(url)?]).await?;
+    Ok(String::from_utf8(out)?.trim().parse().ok())
+}
@@ -0,0 +1,70 @@
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{BufReader, BufWriter},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use relative_path::RelativePathBuf;
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::util;
+
+const JOURNAL_FILE: &str = "journal.json";
+
+/// Files an unfinished sync already downloaded and verified, so the next sync of the same
+/// manifest doesn't fetch them again. Gone once a sync completes.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Journal {
+    pub manifest: Url,
+    /// Whether `completed` files are waiting in the staging area rather than in place.
+    #[serde(default)]
+    pub staged: bool,
+    pub completed: BTreeMap<RelativePathBuf, String>,
+}
+
+fn journal_path(dir: &Path) -> PathBuf {
+    util::state_dir(dir).join(JOURNAL_FILE)
+}
+
+impl Journal {
+    /// The journal left for `manifest`, or an empty one if there is none or it was left
+    /// by a sync of another manifest or in the other `staged` mode.
+    pub fn load(dir: &Path, manifest: &Url, staged: bool) -> Result<Journal> {
+        let path = journal_path(dir);
+        let empty = Journal {
+            manifest: manifest.clone(),
+            staged,
+            completed: BTreeMap::new(),
+        };
+        if !path.is_file() {
+            return Ok(empty);
+        }
+        let br = BufReader::new(File::open(path)?);
+        let journal: Journal = serde_json::from_reader(br)?;
+        if journal.manifest != *manifest || journal.staged != staged {
+            return Ok(empty);
+        }
+        Ok(journal)
+    }
+
+    pub fn save(&self, dir: &Path) -> Result<()> {
+        if self.completed.is_empty() {
+            return Journal::clear(dir);
+        }
+        fs::create_dir_all(util::state_dir(dir))?;
+        let writer = BufWriter::new(File::create(journal_path(dir))?);
+        serde_json::to_writer_pretty(writer, self)?;
+        Ok(())
+    }
+
+    pub fn clear(dir: &Path) -> Result<()> {
+        let path = journal_path(dir);
+        if path.exists() {
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+}
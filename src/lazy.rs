@@ -0,0 +1,54 @@
+use std::{
+    collections::BTreeSet,
+    fs::{self, File},
+    io::{BufReader, BufWriter},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use relative_path::RelativePathBuf;
+use serde::{Deserialize, Serialize};
+
+use crate::{perms, util};
+
+const PLACEHOLDERS_FILE: &str = "placeholders.json";
+
+/// Files created empty by a lazy sync, still waiting for their real content.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Placeholders {
+    pub paths: BTreeSet<RelativePathBuf>,
+}
+
+fn placeholders_path(dir: &Path) -> PathBuf {
+    util::state_dir(dir).join(PLACEHOLDERS_FILE)
+}
+
+impl Placeholders {
+    pub fn load(dir: &Path) -> Result<Placeholders> {
+        let path = placeholders_path(dir);
+        if !path.is_file() {
+            return Ok(Placeholders::default());
+        }
+        let br = BufReader::new(File::open(path)?);
+        Ok(serde_json::from_reader(br)?)
+    }
+
+    pub fn save(&self, dir: &Path) -> Result<()> {
+        let path = placeholders_path(dir);
+        if self.paths.is_empty() {
+            if path.exists() {
+                fs::remove_file(path)?;
+            }
+            return Ok(());
+        }
+        fs::create_dir_all(util::state_dir(dir))?;
+        let writer = BufWriter::new(File::create(path)?);
+        serde_json::to_writer_pretty(writer, self)?;
+        Ok(())
+    }
+}
+
+pub fn create_placeholder(path: &Path) -> Result<()> {
+    perms::create_file_sync(path)?;
+    Ok(())
+}
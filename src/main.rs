@@ -1,22 +1,76 @@
-use std::{fs, io::BufWriter, path::PathBuf};
+use std::path::{Path, PathBuf};
 
 use anyhow::{bail, Result};
+use i18n::t;
+use path_slash::PathExt;
 use relative_path::RelativePathBuf;
 use structopt::StructOpt;
 use url::Url;
-use validate::DifferenceType;
+use validate::{DifferenceType, SourceProblem};
 
+mod azure;
+mod backend;
+mod backup;
+mod config;
 mod events;
+mod ftp;
+mod github;
+mod http;
+mod i18n;
+mod inspect;
+mod ipc;
+mod ipfs;
+mod journal;
+mod lazy;
 mod manifest;
+mod mirrors;
+mod mtime;
+mod oci;
+mod perms;
+mod pin;
+mod plugin;
 mod push;
+mod quota;
+mod ratelimit;
+mod shutdown;
+mod signed;
+mod signing;
+mod sparse;
+mod symlinks;
 mod sync;
 mod util;
 mod validate;
+mod watch;
+mod webdav;
+mod xattrs;
 
 fn parse_url(s: &str) -> Result<Url> {
+    // a network share, \\server\share\comstar.json
+    if cfg!(windows) && s.starts_with(r"\\") {
+        return util::file_url(Path::new(s));
+    }
     Ok(Url::parse(s)?)
 }
 
+/// What every push generates its manifest from.
+#[derive(Debug, StructOpt)]
+struct PushSource {
+    #[structopt(
+        short,
+        long,
+        parse(from_os_str),
+        help = "Directory to push. Default is current directory."
+    )]
+    dir: Option<PathBuf>,
+    #[structopt(flatten)]
+    generate: manifest::GenerateOptions,
+    #[structopt(
+        long = "allow-dirty",
+        help = "Push even if files changed while the manifest was being generated."
+    )]
+    allow_dirty: bool,
+}
+
 #[derive(Debug, StructOpt)]
 #[structopt(about = "Push directory changes to online storage.")]
 enum PushArgs {
@@ -29,22 +83,278 @@ enum PushArgs {
             help = "URI to manifest to diff against. If it does not exist, comstar will assume a first push and push all."
         )]
         manifest: Url,
+        #[structopt(short, long, help = "Bucket name to push to")]
+        bucket: String,
+        #[structopt(short = "p", long = "bucket-path", help = "Path prefix inside bucket.")]
+        bucket_path: Option<PathBuf>,
+        #[structopt(
+            long = "max-qps",
+            help = "Most requests per second to send to the bucket. Default is no limit."
+        )]
+        max_qps: Option<u32>,
+        #[structopt(
+            long = "kms-key",
+            help = "Cloud KMS key to encrypt uploaded objects with instead of the bucket default, projects/<p>/locations/<l>/keyRings/<r>/cryptoKeys/<k>."
+        )]
+        kms_key: Option<String>,
+        #[structopt(
+            long,
+            parse(try_from_str = parse_url),
+            help = "Manifest URL of a replica to update as well, e.g. gs://other-bucket/path/comstar.json. May be repeated. Changed objects are copied from the first bucket inside GCS, so every file is only uploaded once."
+        )]
+        replica: Vec<Url>,
+        #[structopt(flatten)]
+        source: PushSource,
+    },
+    #[structopt(about = "Push to S3.")]
+    S3 {
         #[structopt(
             short,
             long,
-            parse(from_os_str),
-            help = "Directory to push. Default is current directory."
+            parse(try_from_str = parse_url),
+            help = "URI to manifest to diff against. If it does not exist, comstar will assume a first push and push all."
         )]
-        dir: Option<PathBuf>,
+        manifest: Url,
         #[structopt(short, long, help = "Bucket name to push to")]
         bucket: String,
         #[structopt(short = "p", long = "bucket-path", help = "Path prefix inside bucket.")]
         bucket_path: Option<PathBuf>,
+        #[structopt(
+            long,
+            help = "AWS region of the bucket. Default is taken from the environment or AWS profile."
+        )]
+        region: Option<String>,
+        #[structopt(flatten)]
+        source: PushSource,
+    },
+    #[structopt(
+        about = "Push as assets of a GitHub release. The token is read from GITHUB_TOKEN or GH_TOKEN."
+    )]
+    Github {
+        #[structopt(short, long, help = "Repository to push to, as owner/name.")]
+        repo: github::Repo,
+        #[structopt(
+            short,
+            long,
+            help = "Tag of the release to push to. The release is created if it does not exist."
+        )]
+        tag: String,
+        #[structopt(flatten)]
+        source: PushSource,
+    },
+    #[structopt(about = "Push to another directory, e.g. an NFS mount or an external drive.")]
+    Local {
+        #[structopt(
+            short,
+            long,
+            parse(from_os_str),
+            help = "Directory to mirror into. Created if it does not exist."
+        )]
+        target: PathBuf,
+        #[structopt(
+            short,
+            long,
+            parse(try_from_str = parse_url),
+            help = "URI consumers will read the manifest from. Default is comstar.json in the target directory."
+        )]
+        manifest: Option<Url>,
+        #[structopt(flatten)]
+        source: PushSource,
+    },
+    #[structopt(about = "Push to a server over SFTP.")]
+    Sftp {
+        #[structopt(
+            short,
+            long,
+            parse(try_from_str = parse_url),
+            help = "URI to manifest to diff against. If it does not exist, comstar will assume a first push and push all."
+        )]
+        manifest: Url,
+        #[structopt(
+            short,
+            long,
+            help = "Server to push to, as [user@]host[:port]. Its key must be in ~/.ssh/known_hosts."
+        )]
+        server: push::sftp::Server,
+        #[structopt(
+            short = "p",
+            long = "remote-path",
+            parse(from_os_str),
+            help = "Directory on the server to push into."
+        )]
+        remote_path: PathBuf,
+        #[structopt(
+            short,
+            long,
+            parse(from_os_str),
+            help = "Private key to log in with. Default is to ask the SSH agent."
+        )]
+        identity: Option<PathBuf>,
+        #[structopt(flatten)]
+        source: PushSource,
+    },
+    #[structopt(about = "Push to a WebDAV server.")]
+    Webdav {
+        #[structopt(
+            short,
+            long,
+            parse(try_from_str = parse_url),
+            help = "dav://, davs:// or http(s):// URI of the manifest to push. Files are stored next to it."
+        )]
+        manifest: Url,
+        #[structopt(
+            short,
+            long,
+            help = "User to log in as, with basic or digest auth. The password is read from COMSTAR_WEBDAV_PASSWORD. Default is the user in the manifest URI, if any."
+        )]
+        user: Option<String>,
+        #[structopt(flatten)]
+        source: PushSource,
+    },
+    #[structopt(about = "Push with plain HTTP PUT and DELETE requests.")]
+    Http {
+        #[structopt(
+            short,
+            long,
+            parse(try_from_str = parse_url),
+            help = "http(s):// URI consumers will read the manifest from. Files are stored next to it."
+        )]
+        manifest: Url,
+        #[structopt(
+            long = "base-url",
+            parse(try_from_str = parse_url),
+            help = "URL to upload to, if it differs from where files are served. Default is the directory of the manifest URI."
+        )]
+        base_url: Option<Url>,
+        #[structopt(
+            long = "auth-header",
+            default_value = "Authorization",
+            help = "Header to send the value of COMSTAR_HTTP_AUTH in, if that is set."
+        )]
+        auth_header: String,
+        #[structopt(flatten)]
+        source: PushSource,
+    },
+    #[structopt(
+        about = "Add the directory to the local IPFS node. Sources in the manifest become ipfs:// CIDs."
+    )]
+    Ipfs {
+        #[structopt(flatten)]
+        source: PushSource,
+    },
+    #[structopt(
+        about = "Push as an OCI artifact to a container registry. Logs in with COMSTAR_OCI_USER and COMSTAR_OCI_PASSWORD, or what docker login stored."
+    )]
+    Oci {
+        #[structopt(
+            short,
+            long,
+            help = "Where to push, as registry/repository:tag, e.g. ghcr.io/owner/assets:v1. Sync from oci://<the same>."
+        )]
+        reference: oci::Reference,
+        #[structopt(flatten)]
+        source: PushSource,
+    },
+    #[structopt(
+        about = "Push through a comstar-plugin-<name> program on PATH, or any helper program with exec://."
+    )]
+    Plugin {
+        #[structopt(
+            short,
+            long,
+            parse(try_from_str = parse_url),
+            help = "plugin://<name>/... or exec://<program>/... URI of the manifest to push. Objects are stored next to it."
+        )]
+        manifest: Url,
+        #[structopt(
+            long,
+            help = "Also delete objects next to the manifest that neither the old nor the new manifest lists."
+        )]
+        prune: bool,
+        #[structopt(flatten)]
+        source: PushSource,
+    },
+    #[structopt(
+        about = "Push to the manifest URI of any storage comstar can write to, picked by its scheme: file, gs, s3, az, ssh, ftp(s), dav(s), http(s), github, plugin or exec. Credentials are read from the same environment variables as for the other push commands."
+    )]
+    Url {
+        #[structopt(
+            short,
+            long,
+            parse(try_from_str = parse_url),
+            help = "URI of the manifest to push. Files are stored next to it."
+        )]
+        manifest: Url,
+        #[structopt(
+            long,
+            help = "Also delete files next to the manifest that the new manifest doesn't list. Not every storage can list its files."
+        )]
+        prune: bool,
+        #[structopt(flatten)]
+        source: PushSource,
     },
 }
 
 #[derive(Debug, StructOpt)]
 #[structopt(about = "Sync files from a static source.")]
+struct Cli {
+    #[structopt(
+        long = "net-jobs",
+        help = "Number of concurrent transfers. Default is 10."
+    )]
+    net_jobs: Option<usize>,
+    #[structopt(
+        long = "hash-jobs",
+        help = "Number of files hashed at once. Default is the number of CPUs."
+    )]
+    hash_jobs: Option<usize>,
+    #[structopt(
+        long,
+        help = "Skip malformed manifest entries, reporting each, instead of rejecting the manifest."
+    )]
+    lenient: bool,
+    #[structopt(
+        long = "user-agent",
+        help = "User-Agent sent with every HTTP and GCS request. Default is comstar/<version>."
+    )]
+    user_agent: Option<String>,
+    #[structopt(
+        long = "trace-header",
+        help = "Send a per-run trace ID in this header with every HTTP and GCS request, to find the run in CDN and origin logs. Default header is X-Comstar-Trace-Id when --trace-id is given."
+    )]
+    trace_header: Option<String>,
+    #[structopt(
+        long = "trace-id",
+        help = "Trace ID to send instead of a generated one. Enables the trace header."
+    )]
+    trace_id: Option<String>,
+    #[structopt(
+        long = "http3",
+        help = "Download over HTTP/3 (QUIC), falling back to HTTP/2 and 1.1 for hosts it fails on. Needs a build with the http3 feature."
+    )]
+    http3: bool,
+    #[structopt(flatten)]
+    s3_endpoint: push::s3::EndpointOptions,
+    #[structopt(
+        long = "billing-project",
+        help = "Google Cloud project to bill for requests to requester pays buckets, when pushing to or syncing from them."
+    )]
+    billing_project: Option<String>,
+    #[structopt(
+        long = "gcs-encryption-key",
+        help = "Base64 encoded AES-256 key GCS objects are encrypted with on push and decrypted with on sync. Default is COMSTAR_GCS_ENCRYPTION_KEY."
+    )]
+    gcs_encryption_key: Option<String>,
+    #[structopt(
+        long = "verify-key",
+        help = "Base64 encoded Ed25519 public key manifests must be signed with to be synced or validated. Default is COMSTAR_VERIFY_KEY."
+    )]
+    verify_key: Option<String>,
+    #[structopt(subcommand)]
+    cmd: Args,
+}
+
+#[derive(Debug, StructOpt)]
 enum Args {
     Push(PushArgs),
     #[structopt(about = "Generate manifests for directories.")]
@@ -63,6 +373,14 @@ enum Args {
             parse(try_from_str = parse_url)
         )]
         target: Option<Url>,
+        #[structopt(
+            long,
+            possible_values = &["gzip", "zstd"],
+            help = "Also write a comstar.json.gz or comstar.json.zst to publish instead of the plain manifest. Sync and validate read either."
+        )]
+        compress: Option<manifest::Compression>,
+        #[structopt(flatten)]
+        generate: manifest::GenerateOptions,
     },
     #[structopt(about = "Sync a directory from a manifest.")]
     Sync {
@@ -80,17 +398,206 @@ enum Args {
             help = "Directory to sync to. Default is current directory."
         )]
         dir: Option<PathBuf>,
+        #[structopt(flatten)]
+        options: sync::SyncOptions,
+        #[structopt(help = "Sync a named profile from the config file instead.")]
+        profile: Option<String>,
+        #[structopt(
+            long,
+            conflicts_with = "profile",
+            help = "Sync every profile in the config file."
+        )]
+        all: bool,
+        #[structopt(
+            long,
+            parse(from_os_str),
+            help = "Serve progress, status and cancellation on a local socket (named pipe on Windows)."
+        )]
+        ipc: Option<PathBuf>,
+    },
+    #[structopt(
+        about = "Download and verify specific entries, e.g. to repair files or fill in placeholders left by a lazy sync."
+    )]
+    Get {
         #[structopt(
             short,
             long,
-            help = "Ensure that ONLY files in the manifest are at the destination. Deletes any file not in the manifest."
+            parse(try_from_str = parse_url),
+            help = "URI of manifest to fetch from.  Defaults to the manifest in the directory."
         )]
-        force: bool,
+        manifest: Option<Url>,
+        #[structopt(
+            short,
+            long,
+            parse(from_os_str),
+            help = "Target directory. Default is current directory."
+        )]
+        dir: Option<PathBuf>,
+        #[structopt(
+            parse(from_str),
+            required = true,
+            help = "Paths to fetch, relative to the directory. Directories fetch every entry below them."
+        )]
+        paths: Vec<RelativePathBuf>,
+    },
+    #[structopt(about = "Hash files the way generate does and print manifest-style entries.")]
+    Hash {
+        #[structopt(
+            parse(from_os_str),
+            required = true,
+            help = "Files or directories to hash. Directories are walked with the same ignore rules as generate."
+        )]
+        paths: Vec<PathBuf>,
+        #[structopt(
+            long,
+            default_value = "sha512",
+            possible_values = &["sha512", "sha256", "blake3"],
+            help = "Hash algorithm."
+        )]
+        algo: util::HashAlgo,
+    },
+    #[structopt(about = "List the entries of a manifest without syncing it.")]
+    Ls {
+        #[structopt(parse(try_from_str = parse_url), help = "URI of the manifest to list.")]
+        manifest: Url,
+        #[structopt(flatten)]
+        options: inspect::LsOptions,
+    },
+    #[structopt(
+        alias = "info",
+        about = "Show how a directory compares to a manifest without changing it, including the bytes wasted on duplicate content."
+    )]
+    Stats {
+        #[structopt(
+            short,
+            long,
+            parse(try_from_str = parse_url),
+            help = "URI of manifest to compare against.  Defaults to looking for manifest in current dir."
+        )]
+        manifest: Option<Url>,
+        #[structopt(
+            short,
+            long,
+            parse(from_os_str),
+            help = "Directory to inspect. Default is current directory."
+        )]
+        dir: Option<PathBuf>,
+        #[structopt(long, help = "Print statistics as JSON.")]
+        json: bool,
+    },
+    #[structopt(about = "Show how much each directory of a manifest adds to the download.")]
+    Du {
+        #[structopt(parse(try_from_str = parse_url), help = "URI of the manifest to measure.")]
+        manifest: Url,
+        #[structopt(
+            short = "D",
+            long,
+            help = "Only show directories this many levels deep."
+        )]
+        depth: Option<usize>,
+        #[structopt(
+            short,
+            long,
+            help = "Print sizes in bytes instead of human readable units."
+        )]
+        bytes: bool,
+    },
+    #[structopt(about = "Show which files differ between two local directories.")]
+    Compare {
+        #[structopt(parse(from_os_str), help = "Directory to compare from.")]
+        a: PathBuf,
+        #[structopt(parse(from_os_str), help = "Directory to compare to.")]
+        b: PathBuf,
+        #[structopt(long, help = "Print changes as JSON lines instead of a list.")]
+        json: bool,
+    },
+    #[structopt(
+        about = "Cross-check local files, the local manifest and the remote manifest and objects."
+    )]
+    Check {
+        #[structopt(
+            short,
+            long,
+            parse(try_from_str = parse_url),
+            help = "URI of the published manifest."
+        )]
+        manifest: Url,
+        #[structopt(
+            short,
+            long,
+            parse(from_os_str),
+            help = "Directory to check. Default is current directory."
+        )]
+        dir: Option<PathBuf>,
+        #[structopt(long, help = "Print discrepancies as JSON lines instead of a table.")]
+        json: bool,
+    },
+    #[structopt(
+        name = "lint-manifest",
+        about = "Report every problem in a manifest, with line numbers."
+    )]
+    LintManifest {
+        #[structopt(parse(try_from_str = parse_url), help = "URI of the manifest to check.")]
+        manifest: Url,
+        #[structopt(long, help = "Print problems as JSON lines.")]
+        json: bool,
+    },
+    #[structopt(about = "Print the JSON Schema of the manifest format.")]
+    Schema,
+    #[structopt(about = "Find manifest entries by content hash.")]
+    Locate {
         #[structopt(
-            long = "validate",
-            help = "Force validation of local files instead of trusting the local manifest"
+            short,
+            long,
+            parse(try_from_str = parse_url),
+            help = "URI of manifest to search.  Defaults to looking for manifest in current dir."
         )]
-        force_validate: bool,
+        manifest: Option<Url>,
+        #[structopt(help = "A local file to hash, or a full or abbreviated sha512 digest.")]
+        query: String,
+        #[structopt(long, help = "Print entries as JSON lines instead of columns.")]
+        json: bool,
+    },
+    #[structopt(about = "Write a single manifest entry to stdout after verifying it.")]
+    Cat {
+        #[structopt(
+            short,
+            long,
+            parse(try_from_str = parse_url),
+            help = "URI of the manifest containing the entry."
+        )]
+        manifest: Url,
+        #[structopt(parse(from_str), help = "Path of the entry in the manifest.")]
+        path: RelativePathBuf,
+    },
+    #[structopt(about = "Undo the last sync that ran with --backup.")]
+    Rollback {
+        #[structopt(
+            short,
+            long,
+            parse(from_os_str),
+            help = "Directory to roll back. Default is current directory."
+        )]
+        dir: Option<PathBuf>,
+    },
+    #[structopt(about = "Pin a directory to the current manifest version in comstar.lock.")]
+    Pin {
+        #[structopt(
+            short,
+            long,
+            parse(try_from_str = parse_url),
+            help = "URI of manifest to pin."
+        )]
+        manifest: Url,
+        #[structopt(
+            short,
+            long,
+            parse(from_os_str),
+            help = "Directory to pin. Default is current directory."
+        )]
+        dir: Option<PathBuf>,
+        #[structopt(long, help = "Replace an existing pin with the current version.")]
+        update: bool,
     },
     #[structopt(about = "Validate a directory against a manifest.")]
     Validate {
@@ -114,6 +621,28 @@ enum Args {
             help = "Ensure that ONLY files in the manifest are at the destination. Complains about any file not in the manifest."
         )]
         force: bool,
+        #[structopt(
+            short,
+            long,
+            help = "Validate the objects in this GCS bucket instead of a local directory. Nothing is downloaded."
+        )]
+        bucket: Option<String>,
+        #[structopt(short = "p", long = "bucket-path", help = "Path prefix inside bucket.")]
+        bucket_path: Option<PathBuf>,
+        #[structopt(
+            long,
+            conflicts_with_all = &["bucket", "force", "watch"],
+            help = "Check every entry's source with a HEAD request instead of a local directory, to find dead links and size mismatches. Nothing is downloaded."
+        )]
+        sources: bool,
+        #[structopt(
+            long = "quick-check",
+            conflicts_with_all = &["bucket", "sources"],
+            help = "Trust files whose size and modification time match the manifest instead of hashing them."
+        )]
+        quick_check: bool,
+        #[structopt(flatten)]
+        watch: watch::WatchOptions,
     },
 }
 
@@ -126,80 +655,533 @@ fn base_dir(d: Option<PathBuf>) -> Result<PathBuf> {
     Ok(dir.canonicalize()?)
 }
 
+/// URL of the `comstar.json` in `dir`.
+fn local_manifest_url(dir: &Path) -> Result<Url> {
+    let manifest = dir.join("comstar.json");
+    Url::from_file_path(&manifest)
+        .map_err(|_| anyhow::anyhow!("Cannot make URL from directory {}", &manifest.display()))
+}
+
+/// `<scheme>://<bucket>/<prefix>/comstar.json`, where a push to a bucket puts the manifest.
+fn bucket_manifest_url(
+    scheme: &str,
+    bucket: &str,
+    prefix: Option<&RelativePathBuf>,
+) -> Result<Url> {
+    let object = push::prefixed(prefix, RelativePathBuf::from("comstar.json"));
+    let mut url = Url::parse(&format!("{}://{}/", scheme, bucket))?;
+    url.path_segments_mut()
+        .map_err(|_| anyhow::anyhow!("Cannot make URL for bucket {}", bucket))?
+        .clear()
+        .extend(object.components().map(|c| c.as_str()));
+    Ok(url)
+}
+
+/// Generates the manifest of the directory `source` names, with sources below the manifest
+/// URL `manifest`, and writes it and its checksums into the directory. Fails if files
+/// changed meanwhile, unless `--allow-dirty`. Returns the directory and the manifest.
+async fn prepare_push(source: &PushSource, manifest: Url) -> Result<(PathBuf, manifest::Manifest)> {
+    let local_dir = base_dir(source.dir.clone())?;
+    let scan = push::quick_scan(&local_dir)?;
+    let local_manifest =
+        manifest::generate_manifest(manifest, &local_dir, &source.generate).await?;
+    manifest::write_manifest(&local_manifest, &local_dir)?;
+    if source.generate.checksums {
+        manifest::write_checksums(&local_manifest, &local_dir)?;
+    }
+    if !source.allow_dirty {
+        push::check_unchanged(&local_dir, &scan)?;
+    }
+    Ok((local_dir, local_manifest))
+}
+
+async fn run_sync(target_url: &Url, sync_dir: &Path, options: &sync::SyncOptions) -> Result<()> {
+    let summary = match sync::sync_manifest(target_url, sync_dir, options).await {
+        Err(e) if e.is::<shutdown::Interrupted>() => {
+            println!("{}", e);
+            std::process::exit(shutdown::EXIT_INTERRUPTED);
+        }
+        r => r?,
+    };
+    if let Some(command) = &options.then {
+        let differences = validate::verify_manifest(target_url, sync_dir, options.force).await?;
+        if !differences.is_empty() {
+            bail!(t!("sync.then_unclean", differences.len()));
+        }
+        let status = sync::run_then(command, target_url, sync_dir, &summary).await?;
+        if !status.success() {
+            std::process::exit(status.code().unwrap_or(1));
+        }
+    }
+    Ok(())
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
-    let args = Args::from_args();
+    let cli = Cli::from_args();
+    let defaults = util::Jobs::default();
+    util::set_jobs(util::Jobs {
+        net: cli.net_jobs.unwrap_or(defaults.net).max(1),
+        hash: cli.hash_jobs.unwrap_or(defaults.hash).max(1),
+    });
+    manifest::set_lenient(cli.lenient);
+    http::configure(
+        cli.user_agent.as_deref(),
+        cli.trace_header.as_deref(),
+        cli.trace_id.as_deref(),
+        cli.http3,
+    )?;
+    push::s3::configure(cli.s3_endpoint);
+    push::gcs::set_billing_project(cli.billing_project.clone());
+    push::gcs::set_encryption_key(cli.gcs_encryption_key.clone())?;
+    signing::set_verify_key(cli.verify_key.clone())?;
 
-    match args {
+    match cli.cmd {
         Args::Push(pa) => match pa {
             PushArgs::Google {
                 manifest,
-                dir,
                 bucket,
                 bucket_path,
+                max_qps,
+                kms_key,
+                replica,
+                source,
+            } => {
+                let prefix = bucket_path.map(RelativePathBuf::from_path).transpose()?;
+                let target = bucket_manifest_url("gs", &bucket, prefix.as_ref())?;
+                let (local_dir, local_manifest) = prepare_push(&source, manifest.clone()).await?;
+                let checksums = source.generate.checksums;
+
+                push::gcs::configure(max_qps, kms_key);
+                let published = push::push_to(
+                    &local_dir,
+                    &target,
+                    &manifest,
+                    local_manifest,
+                    checksums,
+                    false,
+                )
+                .await?;
+                let origin = push::gcs::Origin { bucket, prefix };
+                for r in &replica {
+                    push::gcs::push_replica(
+                        &local_dir, &published, &manifest, &origin, r, checksums,
+                    )
+                    .await?;
+                }
+                if !replica.is_empty() {
+                    // leave the primary's manifest behind, not the last replica's
+                    manifest::write_manifest(&published, &local_dir)?;
+                }
+            }
+            PushArgs::S3 {
+                manifest,
+                bucket,
+                bucket_path,
+                region,
+                source,
+            } => {
+                let prefix = bucket_path.map(RelativePathBuf::from_path).transpose()?;
+                let target = bucket_manifest_url("s3", &bucket, prefix.as_ref())?;
+                let (local_dir, local_manifest) = prepare_push(&source, manifest.clone()).await?;
+
+                push::s3::set_region(region);
+                push::push_to(
+                    &local_dir,
+                    &target,
+                    &manifest,
+                    local_manifest,
+                    source.generate.checksums,
+                    false,
+                )
+                .await?;
+            }
+            PushArgs::Github { repo, tag, source } => {
+                let manifest = github::asset_url(&repo, &tag, "comstar.json")?;
+                let (local_dir, mut local_manifest) =
+                    prepare_push(&source, manifest.clone()).await?;
+                push::github::set_sources(&mut local_manifest, &repo, &tag)?;
+                manifest::write_manifest(&local_manifest, &local_dir)?;
+
+                push::push_to(
+                    &local_dir,
+                    &manifest,
+                    &manifest,
+                    local_manifest,
+                    source.generate.checksums,
+                    false,
+                )
+                .await?;
+            }
+            PushArgs::Local {
+                target,
+                manifest,
+                source,
+            } => {
+                let local_dir = base_dir(source.dir.clone())?;
+                std::fs::create_dir_all(&target)?;
+                let target = target.canonicalize()?;
+                if target.starts_with(&local_dir) || local_dir.starts_with(&target) {
+                    bail!(t!("push.overlapping", target.display()));
+                }
+                let published = local_manifest_url(&target)?;
+                let manifest = manifest.unwrap_or_else(|| published.clone());
+                let (local_dir, local_manifest) = prepare_push(&source, manifest).await?;
+
+                push::push_to(
+                    &local_dir,
+                    &published,
+                    &published,
+                    local_manifest,
+                    source.generate.checksums,
+                    false,
+                )
+                .await?;
+            }
+            PushArgs::Sftp {
+                manifest,
+                server,
+                remote_path,
+                identity,
+                source,
+            } => {
+                let target = server.url(&remote_path.join("comstar.json"))?;
+                let (local_dir, local_manifest) = prepare_push(&source, manifest.clone()).await?;
+
+                push::sftp::set_identity(identity);
+                push::push_to(
+                    &local_dir,
+                    &target,
+                    &manifest,
+                    local_manifest,
+                    source.generate.checksums,
+                    false,
+                )
+                .await?;
+            }
+            PushArgs::Webdav {
+                manifest,
+                user,
+                source,
+            } => {
+                webdav::set_credentials(webdav::Credentials::for_push(&manifest, user));
+                // credentials stay out of the sources written into the manifest
+                let target = webdav::without_credentials(&manifest);
+                let (local_dir, local_manifest) = prepare_push(&source, target.clone()).await?;
+
+                push::push_to(
+                    &local_dir,
+                    &webdav::to_dav(&target)?,
+                    &manifest,
+                    local_manifest,
+                    source.generate.checksums,
+                    false,
+                )
+                .await?;
+            }
+            PushArgs::Http {
+                manifest,
+                base_url,
+                auth_header,
+                source,
             } => {
-                let local_dir = base_dir(dir)?;
-                let bucket_prefix = bucket_path.map(|pb| RelativePathBuf::from_path(pb).unwrap());
-                let local_manifest =
-                    manifest::generate_manifest(manifest.clone(), &local_dir).await?;
-                let manifest_file = fs::OpenOptions::new()
-                    .truncate(true)
-                    .write(true)
-                    .create(true)
-                    .open(&local_dir.join("comstar.json"))?;
-                let writer = BufWriter::new(manifest_file);
-                serde_json::to_writer_pretty(writer, &local_manifest)?;
-                let remote_manifest = manifest::get_manifest(&manifest).await?;
+                push::http::set_auth(&auth_header)?;
+                let target = match base_url {
+                    Some(u) => mirrors::as_directory(&u).join("comstar.json")?,
+                    None => manifest.clone(),
+                };
+                let (local_dir, local_manifest) = prepare_push(&source, manifest.clone()).await?;
 
-                push::gcs::push_dir(
+                push::push_to(
                     &local_dir,
-                    &local_manifest,
-                    remote_manifest.as_ref(),
-                    &bucket,
-                    bucket_prefix,
+                    &target,
+                    &manifest,
+                    local_manifest,
+                    source.generate.checksums,
+                    false,
+                )
+                .await?;
+            }
+            PushArgs::Ipfs { source } => {
+                let local_dir = base_dir(source.dir.clone())?;
+                // sources are replaced by CIDs once the files are added
+                let placeholder = Url::from_directory_path(&local_dir).map_err(|_| {
+                    anyhow::anyhow!("Cannot make URL from directory {}", local_dir.display())
+                })?;
+                let (local_dir, mut local_manifest) = prepare_push(&source, placeholder).await?;
+
+                let (manifest_url, checksums_url) = push::ipfs::push_dir(
+                    &local_dir,
+                    &mut local_manifest,
+                    source.generate.checksums,
+                )
+                .await?;
+                println!("{}", t!("ipfs.manifest_added", manifest_url));
+                if let Some(url) = checksums_url {
+                    println!("{}", t!("ipfs.checksums_added", url));
+                }
+            }
+            PushArgs::Oci { reference, source } => {
+                if reference.digest.is_some() {
+                    bail!(t!("oci.push_digest", reference));
+                }
+                let manifest = reference.to_url()?;
+                // sources are replaced by blob digests once the files are pushed
+                let (local_dir, mut local_manifest) =
+                    prepare_push(&source, manifest.clone()).await?;
+
+                let digest = push::oci::push_dir(
+                    &local_dir,
+                    &mut local_manifest,
+                    &reference,
+                    source.generate.checksums,
+                )
+                .await?;
+                println!("{}", t!("oci.pushed", manifest, digest));
+            }
+            PushArgs::Plugin {
+                manifest,
+                prune,
+                source,
+            } => {
+                if !matches!(manifest.scheme(), "plugin" | "exec") {
+                    return Err(anyhow::anyhow!(
+                        "Not a plugin:// or exec:// URL: {}",
+                        manifest
+                    ));
+                }
+                let (local_dir, local_manifest) = prepare_push(&source, manifest.clone()).await?;
+
+                push::push_to(
+                    &local_dir,
+                    &manifest,
+                    &manifest,
+                    local_manifest,
+                    source.generate.checksums,
+                    prune,
+                )
+                .await?;
+            }
+            PushArgs::Url {
+                manifest,
+                prune,
+                source,
+            } => {
+                push::http::set_auth("Authorization")?;
+                webdav::set_credentials(webdav::Credentials::for_push(&manifest, None));
+                let target = match manifest.scheme() {
+                    "dav" | "davs" => webdav::without_credentials(&manifest),
+                    _ => manifest.clone(),
+                };
+                if let Ok(path) = target.to_file_path() {
+                    let into = path.parent().unwrap_or(&path);
+                    std::fs::create_dir_all(into)?;
+                    let into = into.canonicalize()?;
+                    let local_dir = base_dir(source.dir.clone())?;
+                    if into.starts_with(&local_dir) || local_dir.starts_with(&into) {
+                        bail!(t!("push.overlapping", into.display()));
+                    }
+                }
+                let (local_dir, local_manifest) = prepare_push(&source, target.clone()).await?;
+
+                push::push_to(
+                    &local_dir,
+                    &target,
+                    &manifest,
+                    local_manifest,
+                    source.generate.checksums,
+                    prune,
                 )
                 .await?;
             }
         },
-        Args::Generate { dir, target } => {
+        Args::Generate {
+            dir,
+            target,
+            compress,
+            generate,
+        } => {
             let generate_dir = base_dir(dir)?;
             let default_url = Url::from_directory_path(&generate_dir).map_err(|_| {
                 anyhow::anyhow!("Cannot make URL from directory {}", &generate_dir.display())
             })?;
             let target_url = target.unwrap_or(default_url);
 
-            let manifest = manifest::generate_manifest(target_url, &generate_dir).await?;
+            let manifest =
+                manifest::generate_manifest(target_url, &generate_dir, &generate).await?;
 
-            let manifest_file = fs::OpenOptions::new()
-                .truncate(true)
-                .write(true)
-                .create(true)
-                .open(&generate_dir.join("comstar.json"))?;
-            let writer = BufWriter::new(manifest_file);
-            serde_json::to_writer_pretty(writer, &manifest)?;
+            manifest::write_manifest(&manifest, &generate_dir)?;
+            if let Some(compression) = compress {
+                manifest::write_manifest_compressed(&manifest, &generate_dir, compression)?;
+            }
+            if let Some(key) = signing::public_key()? {
+                println!("{}", t!("generate.signed", key));
+            }
+            if generate.checksums {
+                manifest::write_checksums(&manifest, &generate_dir)?;
+            }
         }
         Args::Sync {
             manifest,
             dir,
-            force,
-            force_validate,
+            mut options,
+            profile,
+            all,
+            ipc,
         } => {
-            let sync_dir = base_dir(dir)?;
-            let default_manifest = sync_dir.join("comstar.json");
-            let default_url = Url::from_directory_path(&default_manifest).map_err(|_| {
-                anyhow::anyhow!(
-                    "Cannot make URL from directory {}",
-                    &default_manifest.display()
-                )
-            })?;
-            let target_url = manifest.unwrap_or(default_url);
-            sync::sync_manifest(&target_url, &sync_dir, force, force_validate).await?;
+            // sync sets these again for every profile, the command line wins over the profile
+            options.verify_key = cli.verify_key;
+            options.billing_project = cli.billing_project;
+            options.gcs_encryption_key = cli.gcs_encryption_key;
+            if let Some(socket) = ipc {
+                ipc::start(&socket)?;
+            }
+            shutdown::install();
+            if all || profile.is_some() {
+                let config = config::Config::load()?;
+                let profiles: Vec<(&String, &config::Profile)> = if let Some(name) = &profile {
+                    vec![(name, config.profile(name)?)]
+                } else {
+                    config.profiles.iter().collect()
+                };
+                if profiles.is_empty() {
+                    bail!(t!("sync.no_profiles"));
+                }
+                for (name, p) in profiles {
+                    println!("{}", t!("sync.profile", name));
+                    let opts = options.clone().merge(&p.options);
+                    run_sync(&p.manifest, &base_dir(Some(p.dir.clone()))?, &opts).await?;
+                }
+            } else {
+                let sync_dir = base_dir(dir)?;
+                let default_manifest = sync_dir.join("comstar.json");
+                let default_url = Url::from_directory_path(&default_manifest).map_err(|_| {
+                    anyhow::anyhow!(
+                        "Cannot make URL from directory {}",
+                        &default_manifest.display()
+                    )
+                })?;
+                let target_url = manifest.unwrap_or(default_url);
+                run_sync(&target_url, &sync_dir, &options).await?;
+            }
+        }
+        Args::Get {
+            manifest,
+            dir,
+            paths,
+        } => {
+            let get_dir = base_dir(dir)?;
+            let target_url = match manifest {
+                Some(m) => m,
+                None => local_manifest_url(&get_dir)?,
+            };
+            sync::get_paths(&target_url, &get_dir, &paths).await?;
+        }
+        Args::Hash { paths, algo } => {
+            let mut files = Vec::new();
+            for p in paths {
+                if p.is_dir() {
+                    for d in util::get_walker(&p)?.filter_map(|d| d.ok()) {
+                        if d.path().is_file() {
+                            let rel = d.path().strip_prefix(&p)?.to_slash_lossy().to_string();
+                            files.push((rel, d.into_path()));
+                        }
+                    }
+                } else {
+                    files.push((p.to_slash_lossy().to_string(), p));
+                }
+            }
+            let mut hashed =
+                util::bounded_tasks(files, util::jobs().hash, |(rel, path)| async move {
+                    Ok((rel, util::hash_file_with(path, algo).await?))
+                })
+                .await?;
+            hashed.sort();
+            for (path, hash) in hashed {
+                let mut entry = serde_json::Map::new();
+                entry.insert("path".into(), path.into());
+                entry.insert(algo.name().into(), hash.into());
+                println!("{}", serde_json::Value::Object(entry));
+            }
+        }
+        Args::Ls { manifest, options } => {
+            inspect::ls(&manifest, &options).await?;
+        }
+        Args::Stats {
+            manifest,
+            dir,
+            json,
+        } => {
+            let stats_dir = base_dir(dir)?;
+            let target_url = match manifest {
+                Some(m) => m,
+                None => local_manifest_url(&stats_dir)?,
+            };
+            inspect::stats(&target_url, &stats_dir, json).await?;
+        }
+        Args::Du {
+            manifest,
+            depth,
+            bytes,
+        } => {
+            inspect::du(&manifest, depth, bytes).await?;
+        }
+        Args::Compare { a, b, json } => {
+            inspect::compare(&base_dir(Some(a))?, &base_dir(Some(b))?, json).await?;
+        }
+        Args::Check {
+            manifest,
+            dir,
+            json,
+        } => {
+            inspect::check(&manifest, &base_dir(dir)?, json).await?;
+        }
+        Args::Schema => {
+            let schema = schemars::schema_for!(manifest::Manifest);
+            println!("{}", serde_json::to_string_pretty(&schema)?);
+        }
+        Args::LintManifest { manifest, json } => {
+            inspect::lint(&manifest, json).await?;
+        }
+        Args::Locate {
+            manifest,
+            query,
+            json,
+        } => {
+            let target_url = match manifest {
+                Some(m) => m,
+                None => local_manifest_url(&base_dir(None)?)?,
+            };
+            inspect::locate(&target_url, &query, json).await?;
+        }
+        Args::Cat { manifest, path } => {
+            inspect::cat(&manifest, &path).await?;
+        }
+        Args::Rollback { dir } => {
+            backup::rollback(&base_dir(dir)?)?;
+        }
+        Args::Pin {
+            manifest,
+            dir,
+            update,
+        } => {
+            let pin_dir = base_dir(dir)?;
+            let remote = manifest::get_manifest(&manifest)
+                .await?
+                .ok_or_else(|| anyhow::anyhow!(t!("manifest.not_found", manifest)))?;
+            pin::pin(&pin_dir, &manifest, &remote, update)?;
         }
         Args::Validate {
             manifest,
             dir,
             force,
+            bucket,
+            bucket_path,
+            sources,
+            quick_check,
+            watch,
         } => {
+            mtime::configure(quick_check);
             let validate_dir = base_dir(dir)?;
             let default_manifest = validate_dir.join("comstar.json");
             let default_url = Url::from_file_path(&default_manifest).map_err(|_| {
@@ -210,45 +1192,107 @@ async fn main() -> Result<()> {
             })?;
             let target_url = manifest.unwrap_or(default_url);
 
-            let differences = validate::verify_manifest(&target_url, &validate_dir, force).await?;
+            if sources {
+                let problems = validate::check_sources(&target_url).await?;
+                if problems.is_empty() {
+                    println!("{}", t!("validate.sources_ok"));
+                    return Ok(());
+                }
+                let (mut unreachable, mut size_mismatch, mut hash_mismatch) = (0, 0, 0);
+                let header = t!("validate.header");
+                println!("{}", header);
+                println!("{}", "-".repeat(header.chars().count()));
+                for (p, problem) in problems {
+                    match problem {
+                        SourceProblem::Unreachable(reason) => {
+                            unreachable += 1;
+                            println!("{}", t!("validate.source_unreachable", p, reason));
+                        }
+                        SourceProblem::SizeMismatch { expected, actual } => {
+                            size_mismatch += 1;
+                            println!("{}", t!("validate.source_size", p, expected, actual));
+                        }
+                        SourceProblem::HashMismatch => {
+                            hash_mismatch += 1;
+                            println!("{}", t!("validate.source_hash", p));
+                        }
+                    }
+                }
+                println!();
+                println!(
+                    "{}",
+                    t!(
+                        "validate.sources_counts",
+                        unreachable,
+                        size_mismatch,
+                        hash_mismatch
+                    )
+                );
+                bail!(t!("validate.failed"));
+            }
+
+            let differences = if let Some(bucket) = bucket {
+                let bucket_prefix = bucket_path.map(RelativePathBuf::from_path).transpose()?;
+                let manifest = manifest::get_manifest(&target_url)
+                    .await?
+                    .ok_or_else(|| anyhow::anyhow!(t!("manifest.not_found", target_url)))?;
+                push::gcs::verify_bucket(&manifest, &bucket, bucket_prefix, force).await?
+            } else {
+                validate::verify_manifest(&target_url, &validate_dir, force).await?
+            };
             if differences.len() == 0 {
-                println!("All files validated.");
+                println!("{}", t!("validate.ok"));
             } else {
                 let mut missing_count = 0;
                 let mut hash_mismatch_count = 0;
                 let mut unknown_count = 0;
-                println!("DIFFERENCES");
-                println!("-----------");
+                let header = t!("validate.header");
+                println!("{}", header);
+                println!("{}", "-".repeat(header.chars().count()));
                 for diff in differences {
                     let p = diff.path;
                     match &diff.ty {
                         DifferenceType::FileMissing(_) => {
                             missing_count += 1;
-                            println!("  MISSING FILE: {}", p);
+                            println!("{}", t!("validate.missing", p));
                         }
                         DifferenceType::HashMismatch { .. } => {
                             hash_mismatch_count += 1;
-                            println!("  HASH MISMATCH: {}", p);
+                            println!("{}", t!("validate.mismatch", p));
+                        }
+                        DifferenceType::ModeMismatch(_) => {
+                            hash_mismatch_count += 1;
+                            println!("{}", t!("validate.mode_mismatch", p));
                         }
                         DifferenceType::UnknownFile => {
                             unknown_count += 1;
-                            println!("  UNKNOWN FILE: {}", p);
+                            println!("{}", t!("validate.unknown", p));
                         }
                     }
                 }
                 println!("");
                 if force {
                     println!(
-                        "Missing items: {}, Desynced items: {}, Untracked items: {}",
-                        missing_count, hash_mismatch_count, unknown_count
+                        "{}",
+                        t!(
+                            "validate.counts_force",
+                            missing_count,
+                            hash_mismatch_count,
+                            unknown_count
+                        )
                     );
                 } else {
                     println!(
-                        "Missing items: {}, Desynced items: {}",
-                        missing_count, hash_mismatch_count
+                        "{}",
+                        t!("validate.counts", missing_count, hash_mismatch_count)
                     );
                 }
-                bail!("Validation failed.");
+                if !watch.watch {
+                    bail!(t!("validate.failed"));
+                }
+            }
+            if watch.watch {
+                watch::watch(&target_url, &validate_dir, force, &watch).await?;
             }
         }
     }
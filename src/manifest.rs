@@ -1,41 +1,642 @@
 use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt,
     fs::{self, File},
-    io::{BufReader, BufWriter},
-    path::Path,
-    sync::Arc,
+    io::{BufRead, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
 };
 
 use anyhow::{anyhow, Result};
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, TimeZone, Utc};
+use globset::{Glob, GlobSet, GlobSetBuilder};
 use path_slash::PathExt;
 use relative_path::{RelativePath, RelativePathBuf};
 use reqwest::StatusCode;
-use serde::{Deserialize, Serialize};
-use tokio::sync::{mpsc::Sender, Semaphore};
+use schemars::JsonSchema;
+use serde::{
+    de::{self, DeserializeSeed, MapAccess, SeqAccess, Visitor},
+    Deserialize, Deserializer, Serialize,
+};
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha512};
+use structopt::StructOpt;
+use tokio::sync::mpsc;
+use tokio_util::io::SyncIoBridge;
 use url::Url;
 
 use crate::{
-    events::{self, Event},
-    util,
+    backend,
+    events::{self, Event, EventSender},
+    http,
+    i18n::t,
+    mtime,
+    perms::{self, Mode},
+    signing, sparse,
+    symlinks::{self, Symlink},
+    util::{self, HashAlgo},
+    xattrs,
 };
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
 pub struct Manifest {
     pub source: Url,
     pub generated_at: DateTime<Utc>,
+    #[serde(default, alias = "message", skip_serializing_if = "Option::is_none")]
+    pub notes: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub requires_comstar: Option<String>,
+    /// Algorithm of every digest in the manifest.
+    #[serde(default, skip_serializing_if = "HashAlgo::is_default")]
+    pub algo: HashAlgo,
+    /// Symbolic links in the tree that point inside it, sync recreates them.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub symlinks: Vec<Symlink>,
+    /// Directories with nothing in them, sync creates them. Any other directory is implied
+    /// by the paths below it.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    #[schemars(with = "Vec<String>")]
+    pub empty_dirs: Vec<RelativePathBuf>,
+    /// Base64 Ed25519 signature over `unsigned_digest`, made by `generate --sign-key`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+    // must stay the last field, `write_manifest_streaming` relies on it
     pub entries: Vec<ManifestEntry>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+impl Manifest {
+    /// Copy of the manifest without its entries, for `write_manifest_streaming`.
+    pub fn header(&self) -> Manifest {
+        Manifest {
+            source: self.source.clone(),
+            generated_at: self.generated_at,
+            notes: self.notes.clone(),
+            requires_comstar: self.requires_comstar.clone(),
+            algo: self.algo,
+            symlinks: self.symlinks.clone(),
+            empty_dirs: self.empty_dirs.clone(),
+            signature: self.signature.clone(),
+            entries: Vec::new(),
+        }
+    }
+
+    /// SHA-512 of the manifest as comstar writes it, independent of how the publisher
+    /// formatted it.
+    pub fn digest(&self) -> Result<String> {
+        let mut hasher = Sha512::new();
+        write_manifest_streaming(&mut hasher, &self.header(), &self.entries)?;
+        Ok(format!("{:x}", hasher.finalize()))
+    }
+
+    /// `digest` with the signature left out, what the signature covers.
+    pub fn unsigned_digest(&self) -> Result<String> {
+        self.content_digest(self.generated_at)
+    }
+
+    /// `unsigned_digest` as if the manifest had been generated at `generated_at`, to tell
+    /// whether two generations of a tree say anything different.
+    pub fn content_digest(&self, generated_at: DateTime<Utc>) -> Result<String> {
+        let mut header = self.header();
+        header.generated_at = generated_at;
+        header.signature = None;
+        let mut hasher = Sha512::new();
+        write_manifest_streaming(&mut hasher, &header, &self.entries)?;
+        Ok(format!("{:x}", hasher.finalize()))
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
 pub struct ManifestEntry {
+    /// Slash separated path relative to the synced directory.
+    #[schemars(with = "String")]
     pub path: RelativePathBuf,
+    /// Lowercase hex digest of the file content in the manifest's `algo`. Named after the
+    /// default so older manifests keep parsing.
+    #[schemars(regex(pattern = r"^([0-9a-f]{64}){1,2}$"))]
     pub sha512: String,
+    /// Where sync downloads the file from.
     pub source: Url,
+    /// Size of the file content in bytes. Missing from manifests generated before it was
+    /// recorded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    /// Modification time of the file when generated, sync restores it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mtime: Option<DateTime<Utc>>,
+    /// Unix permission bits of the file as an octal string, sync applies them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schemars(with = "Option<String>", regex(pattern = r"^0?[0-7]{3}$"))]
+    pub mode: Option<Mode>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub priority: Option<i32>,
+    /// Earlier entry with the same content, sync copies it instead of downloading again.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schemars(with = "Option<String>")]
+    pub duplicate_of: Option<RelativePathBuf>,
+    /// Entry this file was hard linked to when generated, sync links it again.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schemars(with = "Option<String>")]
+    pub hard_link_of: Option<RelativePathBuf>,
+    /// The file had holes when generated, sync recreates them.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub sparse: bool,
+    /// Extended attributes and ACLs recorded with `generate --xattrs`, values hex encoded.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub xattrs: BTreeMap<String, String>,
+    /// Digest of the bytes at rest when push stored the file gzipped.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schemars(regex(pattern = r"^([0-9a-f]{64}){1,2}$"))]
+    pub encoded_sha512: Option<String>,
+    /// Size of the bytes at rest when push stored the file gzipped.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encoded_size: Option<u64>,
+}
+
+#[derive(Debug, Default, StructOpt)]
+pub struct GenerateOptions {
+    #[structopt(
+        long,
+        help = "Glob of files to download first on sync. May be repeated, earlier globs take precedence."
+    )]
+    pub priority: Vec<String>,
+    #[structopt(
+        long,
+        help = "Release notes shown to users when they sync this manifest."
+    )]
+    pub notes: Option<String>,
+    #[structopt(
+        long = "notes-file",
+        parse(from_os_str),
+        conflicts_with = "notes",
+        help = "Read release notes from a file."
+    )]
+    pub notes_file: Option<PathBuf>,
+    #[structopt(
+        long = "requires-comstar",
+        help = "Minimum comstar version clients need to consume this manifest."
+    )]
+    pub requires_comstar: Option<semver::Version>,
+    #[structopt(
+        long,
+        help = "Record extended attributes and ACLs so sync --xattrs can restore them. Unix only."
+    )]
+    pub xattrs: bool,
+    #[structopt(
+        long = "xattr-pattern",
+        requires = "xattrs",
+        help = "Glob of attribute names to record. May be repeated. Default is user.*, security.selinux and POSIX ACLs."
+    )]
+    pub xattr_pattern: Vec<String>,
+    #[structopt(
+        long,
+        parse(try_from_str = parse_timestamp),
+        help = "Timestamp to record as generated_at, as RFC 3339 or Unix seconds. Defaults to SOURCE_DATE_EPOCH if set, otherwise now."
+    )]
+    pub timestamp: Option<DateTime<Utc>>,
+    #[structopt(
+        long,
+        help = "Also write a SHA512SUMS file next to the manifest, checkable with sha512sum -c. Push uploads it too."
+    )]
+    pub checksums: bool,
+    #[structopt(
+        long,
+        default_value = "sha512",
+        possible_values = &["sha512", "sha256", "blake3"],
+        help = "Hash algorithm of the manifest. blake3 is much faster on large trees, clients need a comstar that knows it."
+    )]
+    pub algo: HashAlgo,
+    #[structopt(
+        long = "sign-key",
+        parse(from_os_str),
+        help = "File with a base64 encoded Ed25519 key to sign the manifest with, e.g. from openssl rand -base64 32. Sync and validate check the signature with --verify-key."
+    )]
+    pub sign_key: Option<PathBuf>,
+}
+
+/// Accepts RFC 3339 or Unix seconds, the form `SOURCE_DATE_EPOCH` uses.
+fn parse_timestamp(s: &str) -> Result<DateTime<Utc>> {
+    let s = s.trim();
+    if let Ok(secs) = s.parse::<i64>() {
+        return Utc
+            .timestamp_opt(secs, 0)
+            .single()
+            .ok_or_else(|| anyhow!("Timestamp {} is out of range", s));
+    }
+    Ok(DateTime::parse_from_rfc3339(s)
+        .map_err(|e| anyhow!("Invalid timestamp {}: {}", s, e))?
+        .with_timezone(&Utc))
+}
+
+impl GenerateOptions {
+    fn notes(&self) -> Result<Option<String>> {
+        if let Some(f) = &self.notes_file {
+            return Ok(Some(fs::read_to_string(f)?));
+        }
+        Ok(self.notes.clone())
+    }
+
+    /// `generated_at` for the manifest. Pinning it through `--timestamp` or
+    /// `SOURCE_DATE_EPOCH` makes generating the same tree twice give identical manifests.
+    fn generated_at(&self) -> Result<DateTime<Utc>> {
+        if let Some(ts) = self.timestamp {
+            return Ok(ts);
+        }
+        match std::env::var("SOURCE_DATE_EPOCH") {
+            Ok(epoch) if !epoch.trim().is_empty() => parse_timestamp(&epoch),
+            _ => Ok(Utc::now()),
+        }
+    }
+}
+
+/// Checks the `requires_comstar` gate before deserializing the rest of the manifest,
+/// so older clients fail with an upgrade message rather than a parse error.
+fn check_requires(header: &Map<String, Value>) -> Result<()> {
+    if let Some(required) = header.get("requires_comstar").and_then(|v| v.as_str()) {
+        let required = semver::Version::parse(required)
+            .map_err(|e| anyhow!("Invalid requires_comstar version {}: {}", required, e))?;
+        let current = semver::Version::parse(env!("CARGO_PKG_VERSION"))?;
+        if current < required {
+            return Err(anyhow!(t!("manifest.requires", required, current)));
+        }
+    }
+    Ok(())
+}
+
+static LENIENT: AtomicBool = AtomicBool::new(false);
+
+/// Makes manifest reading skip malformed entries, reporting each one, instead of failing
+/// on the first.
+pub fn set_lenient(lenient: bool) {
+    LENIENT.store(lenient, Ordering::Relaxed);
+}
+
+/// Something wrong with one field of a manifest entry, or the entry as a whole.
+#[derive(Debug, Clone, Serialize)]
+pub struct EntryProblem {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub field: Option<&'static str>,
+    pub reason: String,
+}
+
+impl fmt::Display for EntryProblem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.field {
+            Some(field) => write!(f, "{}: {}", field, self.reason),
+            None => f.write_str(&self.reason),
+        }
+    }
+}
+
+fn problem(field: &'static str, reason: String) -> EntryProblem {
+    EntryProblem {
+        field: Some(field),
+        reason,
+    }
+}
+
+/// Checks an entry field by field, so a bad manifest can be explained better than by the
+/// first serde error. Empty if the entry is fine.
+pub fn check_entry(value: &Value) -> Vec<EntryProblem> {
+    let obj = match value.as_object() {
+        Some(o) => o,
+        None => {
+            return vec![EntryProblem {
+                field: None,
+                reason: t!("lint.not_object"),
+            }]
+        }
+    };
+    let mut problems = Vec::new();
+    for field in ["path", "sha512", "source"] {
+        match obj.get(field) {
+            None => problems.push(problem(field, t!("lint.missing"))),
+            Some(v) if !v.is_string() => problems.push(problem(field, t!("lint.not_string"))),
+            Some(_) => {}
+        }
+    }
+    if let Some(path) = obj.get("path").and_then(|v| v.as_str()) {
+        if path.is_empty()
+            || path.starts_with('/')
+            || RelativePath::new(path)
+                .components()
+                .any(|c| c.as_str() == "..")
+        {
+            problems.push(problem("path", t!("lint.escapes", path)));
+        }
+    }
+    for field in ["sha512", "encoded_sha512"] {
+        if let Some(sha) = obj.get(field).and_then(|v| v.as_str()) {
+            // 128 digits for SHA-512, 64 for the others
+            if !matches!(sha.len(), 64 | 128) || !sha.chars().all(|c| c.is_ascii_hexdigit()) {
+                problems.push(problem(field, t!("lint.bad_digest")));
+            }
+        }
+    }
+    for field in ["size", "encoded_size"] {
+        if obj.get(field).is_some_and(|v| !v.is_null() && !v.is_u64()) {
+            problems.push(problem(field, t!("lint.not_integer")));
+        }
+    }
+    if obj.get("mtime").is_some_and(|v| {
+        !v.is_null()
+            && v.as_str()
+                .and_then(|s| DateTime::parse_from_rfc3339(s).ok())
+                .is_none()
+    }) {
+        problems.push(problem("mtime", t!("lint.bad_time")));
+    }
+    if obj
+        .get("mode")
+        .is_some_and(|v| !v.is_null() && v.as_str().and_then(|s| s.parse::<Mode>().ok()).is_none())
+    {
+        problems.push(problem("mode", t!("lint.bad_mode")));
+    }
+    if let Some(source) = obj.get("source").and_then(|v| v.as_str()) {
+        if let Err(e) = Url::parse(source) {
+            problems.push(problem("source", t!("lint.bad_url", source, e)));
+        }
+    }
+    if obj
+        .get("priority")
+        .is_some_and(|v| !v.is_null() && v.as_i64().and_then(|p| i32::try_from(p).ok()).is_none())
+    {
+        problems.push(problem("priority", t!("lint.not_integer")));
+    }
+    for field in ["duplicate_of", "hard_link_of"] {
+        if obj
+            .get(field)
+            .is_some_and(|v| !v.is_null() && !v.is_string())
+        {
+            problems.push(problem(field, t!("lint.not_string")));
+        }
+    }
+    if obj.get("sparse").is_some_and(|v| !v.is_boolean()) {
+        problems.push(problem("sparse", t!("lint.not_bool")));
+    }
+    if let Some(xattrs) = obj.get("xattrs") {
+        let ok = xattrs
+            .as_object()
+            .is_some_and(|m| m.values().all(|v| v.is_string()));
+        if !ok {
+            problems.push(problem("xattrs", t!("lint.bad_xattrs")));
+        }
+    }
+    if problems.is_empty() {
+        if let Err(e) = ManifestEntry::deserialize(value) {
+            problems.push(EntryProblem {
+                field: None,
+                reason: e.to_string(),
+            });
+        }
+    }
+    problems
+}
+
+/// Turns one entry into a `ManifestEntry`, or reports why it was skipped.
+fn lenient_entry(index: usize, value: Value) -> Option<ManifestEntry> {
+    let problems = check_entry(&value);
+    if problems.is_empty() {
+        if let Ok(entry) = serde_json::from_value(value) {
+            return Some(entry);
+        }
+    }
+    let path = value
+        .get("path")
+        .and_then(|p| p.as_str())
+        .unwrap_or("?")
+        .to_string();
+    let reasons: Vec<String> = problems.iter().map(|p| p.to_string()).collect();
+    eprintln!(
+        "{}",
+        t!("manifest.skipped_entry", index, path, reasons.join("; "))
+    );
+    None
+}
+
+struct EntriesSeed<'a, F>(&'a mut F);
+
+impl<'de, 'a, F> DeserializeSeed<'de> for EntriesSeed<'a, F>
+where
+    F: FnMut(ManifestEntry) -> Result<()>,
+{
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de, 'a, F> Visitor<'de> for EntriesSeed<'a, F>
+where
+    F: FnMut(ManifestEntry) -> Result<()>,
+{
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a list of manifest entries")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        if LENIENT.load(Ordering::Relaxed) {
+            let mut index = 0;
+            while let Some(value) = seq.next_element::<Value>()? {
+                if let Some(entry) = lenient_entry(index, value) {
+                    (self.0)(entry).map_err(de::Error::custom)?;
+                }
+                index += 1;
+            }
+            return Ok(());
+        }
+        while let Some(entry) = seq.next_element::<ManifestEntry>()? {
+            (self.0)(entry).map_err(de::Error::custom)?;
+        }
+        Ok(())
+    }
+}
+
+/// Collects every top-level field except `entries`, which is handed to the callback.
+struct ManifestVisitor<'a, F>(&'a mut F);
+
+impl<'de, 'a, F> Visitor<'de> for ManifestVisitor<'a, F>
+where
+    F: FnMut(ManifestEntry) -> Result<()>,
+{
+    type Value = Map<String, Value>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a comstar manifest")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+        let mut header = Map::new();
+        while let Some(key) = map.next_key::<String>()? {
+            if key == "entries" {
+                check_requires(&header).map_err(de::Error::custom)?;
+                map.next_value_seed(EntriesSeed(&mut *self.0))?;
+            } else {
+                let value = map.next_value::<Value>()?;
+                header.insert(key, value);
+            }
+        }
+        Ok(header)
+    }
+}
+
+/// Reads a manifest one entry at a time without holding them all in memory. The returned
+/// manifest carries the header fields and no entries.
+pub fn read_manifest_streaming<R, F>(reader: R, mut f: F) -> Result<Manifest>
+where
+    R: Read,
+    F: FnMut(ManifestEntry) -> Result<()>,
+{
+    let mut de = serde_json::Deserializer::from_reader(reader);
+    let mut header = (&mut de).deserialize_map(ManifestVisitor(&mut f))?;
+    de.end()?;
+    check_requires(&header)?;
+    header.insert("entries".into(), Value::Array(Vec::new()));
+    Ok(serde_json::from_value(Value::Object(header))?)
+}
+
+/// Writes `header` up to and including the `[` its entries follow.
+fn write_head<W: Write>(w: &mut W, header: &Manifest) -> Result<()> {
+    debug_assert!(header.entries.is_empty());
+    let head = serde_json::to_string(header)?;
+    let head = head
+        .strip_suffix("[]}")
+        .ok_or_else(|| anyhow!("Manifest header did not end with its entries"))?;
+    w.write_all(head.as_bytes())?;
+    w.write_all(b"[")?;
+    Ok(())
+}
+
+/// Writes entry number `index` of a manifest, after its head.
+fn write_entry<W: Write>(w: &mut W, index: usize, entry: &ManifestEntry) -> Result<()> {
+    if index > 0 {
+        w.write_all(b",")?;
+    }
+    w.write_all(b"\n")?;
+    serde_json::to_writer(&mut *w, entry)?;
+    Ok(())
+}
+
+fn write_tail<W: Write>(w: &mut W) -> Result<()> {
+    w.write_all(b"\n]}\n")?;
+    w.flush()?;
+    Ok(())
+}
+
+/// Writes `header` followed by `entries`, one entry per line. `header.entries` must be empty.
+pub fn write_manifest_streaming<'a, W, I>(mut w: W, header: &Manifest, entries: I) -> Result<()>
+where
+    W: Write,
+    I: IntoIterator<Item = &'a ManifestEntry>,
+{
+    write_head(&mut w, header)?;
+    for (i, e) in entries.into_iter().enumerate() {
+        write_entry(&mut w, i, e)?;
+    }
+    write_tail(&mut w)
+}
+
+static SPOOL_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Entries of a manifest being streamed, kept in a file the way `write_manifest_streaming`
+/// writes them. The manifest can then be hashed, read back and written out once its header
+/// is known, without holding its entries. The file is removed on drop.
+pub struct SpooledEntries {
+    path: PathBuf,
+    w: BufWriter<File>,
+    count: usize,
 }
 
+impl SpooledEntries {
+    pub fn create(path: PathBuf) -> Result<Self> {
+        let w = BufWriter::new(File::create(&path)?);
+        Ok(SpooledEntries { path, w, count: 0 })
+    }
+
+    /// A spool in the temp directory, for manifests that aren't synced anywhere.
+    pub fn temp() -> Result<Self> {
+        let n = SPOOL_COUNTER.fetch_add(1, Ordering::Relaxed);
+        Self::create(std::env::temp_dir().join(format!(
+            "comstar-entries-{}-{}",
+            std::process::id(),
+            n
+        )))
+    }
+
+    pub fn push(&mut self, entry: &ManifestEntry) -> Result<()> {
+        write_entry(&mut self.w, self.count, entry)?;
+        self.count += 1;
+        Ok(())
+    }
+
+    /// How many entries are spooled.
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    /// The spooled entries, read back one at a time in the order they were pushed.
+    pub fn entries(&mut self) -> Result<impl Iterator<Item = Result<ManifestEntry>>> {
+        self.w.flush()?;
+        let lines = BufReader::new(File::open(&self.path)?).lines();
+        // one entry per line, every one but the last followed by a comma
+        Ok(lines.filter_map(|line| -> Option<Result<ManifestEntry>> {
+            let line = match line {
+                Ok(l) => l,
+                Err(e) => return Some(Err(e.into())),
+            };
+            let entry = line.strip_suffix(',').unwrap_or(&line);
+            (!entry.is_empty()).then(|| Ok(serde_json::from_str(entry)?))
+        }))
+    }
+
+    /// Writes the manifest `header` heads, with the spooled entries, like
+    /// `write_manifest_streaming` would.
+    fn write_to<W: Write>(&mut self, mut w: W, header: &Manifest) -> Result<()> {
+        self.w.flush()?;
+        write_head(&mut w, header)?;
+        std::io::copy(&mut File::open(&self.path)?, &mut w)?;
+        write_tail(&mut w)
+    }
+
+    /// `Manifest::digest` of `header` with the spooled entries.
+    pub fn digest(&mut self, header: &Manifest) -> Result<String> {
+        let mut hasher = Sha512::new();
+        self.write_to(&mut hasher, header)?;
+        Ok(format!("{:x}", hasher.finalize()))
+    }
+
+    /// `Manifest::unsigned_digest` of `header` with the spooled entries.
+    pub fn unsigned_digest(&mut self, header: &Manifest) -> Result<String> {
+        let mut header = header.header();
+        header.signature = None;
+        self.digest(&header)
+    }
+
+    /// Writes `header` with the spooled entries to `comstar.json` in `dir`, signature and
+    /// all as it was read.
+    pub fn write_manifest(&mut self, header: &Manifest, dir: &Path) -> Result<()> {
+        let manifest_file = File::create(dir.join("comstar.json"))?;
+        self.write_to(BufWriter::new(manifest_file), header)
+    }
+}
+
+impl Drop for SpooledEntries {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Requests the manifest, `None` if the server has no manifest. The body is left to be
+/// read as it arrives.
 #[tracing::instrument]
-async fn get_manifest_http(target: &Url) -> Result<Option<Manifest>> {
-    let resp = reqwest::get(target.as_ref()).await?;
+pub async fn fetch_manifest_http(target: &Url) -> Result<Option<reqwest::Response>> {
+    let resp = http::client().get(target.as_ref()).send().await?;
     if resp.status() == StatusCode::NOT_FOUND {
         return Ok(None);
     }
@@ -46,112 +647,434 @@ async fn get_manifest_http(target: &Url) -> Result<Option<Manifest>> {
             resp.text().await?
         ));
     }
-    Ok(Some(resp.json().await?))
+    Ok(Some(resp))
 }
 
-#[tracing::instrument]
-async fn get_manifest_file(target: &Url) -> Result<Option<Manifest>> {
-    let f = target
-        .to_file_path()
-        .map_err(|_| anyhow::anyhow!("Invalid file URL: {}", target))?;
-    if !f.exists() || !f.is_file() {
-        return Ok(None);
+/// How a manifest file is compressed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    pub fn extension(self) -> &'static str {
+        match self {
+            Compression::Gzip => "gz",
+            Compression::Zstd => "zst",
+        }
     }
-    let file = File::open(&f)?;
-    let br = BufReader::new(file);
-    let manifest = serde_json::from_reader(br)?;
-    Ok(Some(manifest))
+
+    /// Tells from its first bytes whether a manifest is compressed. Manifests are read by
+    /// their content, so a `.gz` or `.zst` name and a `Content-Encoding` the HTTP client
+    /// didn't undo itself both work.
+    fn sniff(head: &[u8]) -> Option<Compression> {
+        if head.starts_with(&[0x1f, 0x8b]) {
+            Some(Compression::Gzip)
+        } else if head.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Some(Compression::Zstd)
+        } else {
+            None
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "gzip" | "gz" => Ok(Compression::Gzip),
+            "zstd" | "zst" => Ok(Compression::Zstd),
+            _ => Err(anyhow!("Unknown compression {}, expected gzip or zstd", s)),
+        }
+    }
+}
+
+/// `reader` with the manifest it reads decompressed, if it is compressed.
+fn decompressed<'a, R: BufRead + 'a>(mut reader: R) -> Result<Box<dyn Read + 'a>> {
+    Ok(match Compression::sniff(reader.fill_buf()?) {
+        Some(Compression::Gzip) => Box::new(flate2::bufread::MultiGzDecoder::new(reader)),
+        Some(Compression::Zstd) => Box::new(zstd::Decoder::with_buffer(reader)?),
+        None => Box::new(reader),
+    })
+}
+
+/// The bytes of the manifest at `target`, decompressed, `None` if there is none.
+pub async fn fetch_manifest_bytes(target: &Url) -> Result<Option<Vec<u8>>> {
+    let mut reader = match backend::for_url(target)?.get_manifest(target).await? {
+        Some(r) => r,
+        None => return Ok(None),
+    };
+    let mut body = Vec::new();
+    tokio::io::AsyncReadExt::read_to_end(&mut reader, &mut body).await?;
+    if Compression::sniff(&body).is_none() {
+        return Ok(Some(body));
+    }
+    let mut plain = Vec::new();
+    decompressed(&body[..])?.read_to_end(&mut plain)?;
+    Ok(Some(plain))
+}
+
+/// Entries parsed ahead of what `stream_manifest`'s callback has taken.
+const ENTRIES_IN_FLIGHT: usize = 1024;
+
+/// Streams the manifest at `target` entry by entry into `f`, see `read_manifest_streaming`.
+/// The manifest is parsed as its backend delivers it, so neither the body nor the entries
+/// are held all at once.
+#[tracing::instrument(skip(f))]
+pub async fn stream_manifest<F>(target: &Url, mut f: F) -> Result<Option<Manifest>>
+where
+    F: FnMut(ManifestEntry) -> Result<()>,
+{
+    let body = match backend::for_url(target)?.get_manifest(target).await? {
+        Some(b) => SyncIoBridge::new(b),
+        None => return Ok(None),
+    };
+    // the parser blocks on the body, entries come back over a channel
+    let (tx, mut rx) = mpsc::channel(ENTRIES_IN_FLIGHT);
+    let parser = tokio::task::spawn_blocking(move || {
+        read_manifest_streaming(decompressed(BufReader::new(body))?, |e| {
+            tx.blocking_send(e)
+                .map_err(|_| anyhow!("Manifest entries are no longer wanted"))
+        })
+    });
+    while let Some(e) = rx.recv().await {
+        if let Err(err) = f(e) {
+            // the parser stops at its next entry once nobody takes them
+            drop(rx);
+            let _ = parser.await;
+            return Err(err);
+        }
+    }
+    Ok(Some(parser.await??))
+}
+
+/// Streams the manifest at `target` into `spool`, handing every entry to `f` as well, and
+/// checks its signature against what was spooled. The manifest is read once, whatever is
+/// read back from the spool is what was verified. Nothing `f` keeps can be trusted before
+/// this returns.
+#[tracing::instrument(skip(spool, f))]
+pub async fn spool_manifest<F>(
+    target: &Url,
+    mut spool: SpooledEntries,
+    mut f: F,
+) -> Result<Option<(Manifest, SpooledEntries)>>
+where
+    F: FnMut(&ManifestEntry) -> Result<()>,
+{
+    let header = stream_manifest(target, |e| {
+        f(&e)?;
+        spool.push(&e)
+    })
+    .await?;
+    let header = match header {
+        Some(h) => h,
+        None => return Ok(None),
+    };
+    signing::verify_digest(target, &header, || spool.unsigned_digest(&header))?;
+    Ok(Some((header, spool)))
 }
 
 #[tracing::instrument]
 pub async fn get_manifest(target: &Url) -> Result<Option<Manifest>> {
-    match target.scheme() {
-        "http" | "https" => get_manifest_http(target).await,
-        "file" => get_manifest_file(target).await,
-        _ => unimplemented!(),
+    let mut entries = Vec::new();
+    let manifest = stream_manifest(target, |e| {
+        entries.push(e);
+        Ok(())
+    })
+    .await?;
+    let manifest = manifest.map(|mut m| {
+        m.entries = entries;
+        m
+    });
+    if let Some(m) = &manifest {
+        signing::verify(target, m)?;
     }
+    Ok(manifest)
 }
 
 #[tracing::instrument]
-async fn hash_with_events(p: &Path, tx: Sender<Event>) -> Result<String> {
+async fn hash_with_events(p: &Path, algo: HashAlgo, tx: EventSender) -> Result<String> {
     let name = p
         .file_name()
         .ok_or_else(|| anyhow::anyhow!("Invalid file name passed to hash_with_events"))?
         .to_string_lossy();
-    tx.send(Event::unknown_file_started(name.to_string()))
-        .await?;
-    let sha512 = util::get_file_hash(&p)?;
-    tx.send(Event::file_done(name.to_string())).await?;
+    tx.send(Event::unknown_file_started(name.to_string()));
+    let sha512 = util::hash_file(p.to_path_buf(), name.to_string(), algo, tx.clone()).await?;
+    tx.send(Event::file_done(name.to_string()));
 
     Ok(sha512)
 }
 
+/// Writes a compressed copy of the manifest next to `comstar.json`, for publishers whose
+/// manifests are too large to serve plain. Returns its path.
+pub fn write_manifest_compressed(
+    manifest: &Manifest,
+    dir: &Path,
+    compression: Compression,
+) -> Result<PathBuf> {
+    let path = dir.join(format!("comstar.json.{}", compression.extension()));
+    let w = BufWriter::new(File::create(&path)?);
+    match compression {
+        Compression::Gzip => {
+            let mut enc = flate2::write::GzEncoder::new(w, flate2::Compression::best());
+            write_manifest_streaming(&mut enc, &signed_header(manifest)?, &manifest.entries)?;
+            enc.finish()?.flush()?;
+        }
+        Compression::Zstd => {
+            let mut enc = zstd::Encoder::new(w, 19)?;
+            write_manifest_streaming(&mut enc, &signed_header(manifest)?, &manifest.entries)?;
+            enc.finish()?.flush()?;
+        }
+    }
+    Ok(path)
+}
+
+/// The header to write for `manifest`, signed again if there is a signing key. Without one
+/// a signature the manifest already carries is kept.
+fn signed_header(manifest: &Manifest) -> Result<Manifest> {
+    let mut header = manifest.header();
+    if let Some(signature) = signing::sign(manifest)? {
+        header.signature = Some(signature);
+    }
+    Ok(header)
+}
+
 pub fn write_manifest(manifest: &Manifest, dir: &Path) -> Result<()> {
     let manifest_file = fs::OpenOptions::new()
         .truncate(true)
         .write(true)
         .create(true)
         .open(&dir.join("comstar.json"))?;
-    let writer = BufWriter::new(manifest_file);
-    serde_json::to_writer_pretty(writer, &manifest)?;
+    write_manifest_streaming(
+        BufWriter::new(manifest_file),
+        &signed_header(manifest)?,
+        &manifest.entries,
+    )
+}
+
+/// Name of the companion checksum file written next to the manifest by `--checksums`.
+pub const CHECKSUMS_FILE: &str = "SHA512SUMS";
+
+/// Writes the manifest's hashes to `SHA512SUMS` in `dir` in the format of coreutils'
+/// `sha512sum`, for verifying a download without comstar.
+pub fn write_checksums(manifest: &Manifest, dir: &Path) -> Result<()> {
+    let mut w = BufWriter::new(File::create(dir.join(CHECKSUMS_FILE))?);
+    for e in &manifest.entries {
+        let name = e.path.as_str();
+        if name.contains('\\') || name.contains('\n') {
+            // sha512sum marks escaped names with a leading backslash
+            let escaped = name.replace('\\', "\\\\").replace('\n', "\\n");
+            writeln!(w, "\\{}  {}", e.sha512, escaped)?;
+        } else {
+            writeln!(w, "{}  {}", e.sha512, name)?;
+        }
+    }
+    w.flush()?;
     Ok(())
 }
 
+/// Points every entry whose content was already seen at the first entry with the same hash.
+fn mark_duplicates(entries: &mut [ManifestEntry]) {
+    let mut seen: HashMap<String, RelativePathBuf> = HashMap::new();
+    for e in entries.iter_mut() {
+        if let Some(first) = seen.get(&e.sha512) {
+            e.duplicate_of = Some(first.clone());
+        } else {
+            seen.insert(e.sha512.clone(), e.path.clone());
+        }
+    }
+}
+
+/// URL of `relative` next to `base`, like `Url::join` but with each component
+/// percent-encoded, so names containing `#`, `?` or `%` don't turn into queries, fragments
+/// or escapes.
+fn entry_url(base: &Url, relative: &RelativePath) -> Result<Url> {
+    let mut url = base.clone();
+    url.set_query(None);
+    url.set_fragment(None);
+    url.path_segments_mut()
+        .map_err(|_| anyhow!("Cannot use {} as a base URL", base))?
+        .pop()
+        .extend(relative.components().map(|c| c.as_str()));
+    Ok(url)
+}
+
+/// Points every file that shares an inode with an earlier entry at that entry, so sync can
+/// link it instead of writing the data again. Linked files are duplicates of that entry
+/// as well, which is what sends them through the duplicate pass on sync.
+fn mark_hard_links(entries: &mut [ManifestEntry], inodes: &[Option<(u64, u64)>]) {
+    let mut seen: HashMap<(u64, u64), RelativePathBuf> = HashMap::new();
+    for (e, inode) in entries.iter_mut().zip(inodes) {
+        let inode = match inode {
+            Some(i) => *i,
+            None => continue,
+        };
+        if let Some(first) = seen.get(&inode) {
+            e.hard_link_of = Some(first.clone());
+            e.duplicate_of = Some(first.clone());
+        } else {
+            seen.insert(inode, e.path.clone());
+        }
+    }
+}
+
+/// Globs given earlier in the list map to a higher priority.
+struct PriorityGlobs {
+    set: GlobSet,
+    count: usize,
+}
+
+impl PriorityGlobs {
+    fn new(patterns: &[String]) -> Result<Self> {
+        let mut builder = GlobSetBuilder::new();
+        for p in patterns {
+            builder.add(Glob::new(p)?);
+        }
+        Ok(Self {
+            set: builder.build()?,
+            count: patterns.len(),
+        })
+    }
+
+    fn priority(&self, path: &RelativePath) -> Option<i32> {
+        self.set
+            .matches(path.as_str())
+            .into_iter()
+            .min()
+            .map(|idx| (self.count - idx) as i32)
+    }
+}
+
 #[tracing::instrument]
-pub async fn generate_manifest(base_url: Url, dir: &Path) -> Result<Manifest> {
-    let (tx, rx) = tokio::sync::mpsc::channel(50);
+pub async fn generate_manifest(
+    base_url: Url,
+    dir: &Path,
+    opts: &GenerateOptions,
+) -> Result<Manifest> {
+    let (tx, rx) = events::channel();
+    let generated_at = opts.generated_at()?;
+    let priorities = Arc::new(PriorityGlobs::new(&opts.priority)?);
+    let xattr_filter = if opts.xattrs {
+        Some(Arc::new(xattrs::name_filter(&opts.xattr_pattern)?))
+    } else {
+        None
+    };
+    let notes = opts.notes()?;
+    if opts.checksums && !opts.algo.is_default() {
+        return Err(anyhow!(t!("generate.checksums_algo", opts.algo.name())));
+    }
+    let algo = opts.algo;
+    if let Some(key) = &opts.sign_key {
+        signing::set_sign_key(key)?;
+    }
+    // older clients would take the digests for SHA-512 and find every file modified
+    let requires_comstar = match &opts.requires_comstar {
+        None if !opts.algo.is_default() => Some(env!("CARGO_PKG_VERSION").to_string()),
+        r => r.as_ref().map(|v| v.to_string()),
+    };
 
     let walker = util::get_walker(dir)?;
-    let dirents: Vec<ignore::DirEntry> = walker
-        .filter_map(|d| d.ok())
-        .filter(|d| d.path().is_file())
-        .collect();
+    let mut links = Vec::new();
+    let mut dirs = Vec::new();
+    let mut parents = HashSet::new();
+    let mut dirents: Vec<ignore::DirEntry> = Vec::new();
+    for d in walker.filter_map(|d| d.ok()) {
+        if d.depth() == 0 {
+            continue;
+        }
+        if let Some(parent) = d.path().parent() {
+            parents.insert(parent.to_path_buf());
+        }
+        if d.file_type().is_some_and(|t| t.is_dir()) {
+            dirs.push(d.into_path());
+            continue;
+        }
+        if d.path_is_symlink() {
+            if let Some(link) = symlinks::read(dir, d.path())? {
+                links.push(link);
+                continue;
+            }
+        }
+        if d.path().is_file() {
+            dirents.push(d);
+        }
+    }
+    links.sort_by(|a, b| a.path.as_str().cmp(b.path.as_str()));
+    let mut empty_dirs = dirs
+        .into_iter()
+        .filter(|d| !parents.contains(d))
+        .map(|d| {
+            let stripped = d.strip_prefix(dir)?.to_slash_lossy().to_string();
+            Ok(RelativePathBuf::from_path(stripped)?)
+        })
+        .collect::<Result<Vec<_>>>()?;
+    empty_dirs.sort_by(|a, b| a.as_str().cmp(b.as_str()));
     let count = dirents.len();
     let h = tokio::spawn(events::event_output(
         rx,
-        "Generating manifest".into(),
+        t!("progress.generating"),
         count as u64,
     ));
-    let mut entries = Vec::new();
-    let mut handles = Vec::new();
-    let sem = Arc::new(Semaphore::new(10));
-    for dirent in dirents {
-        let c = dirent.path().to_path_buf();
-        // skip dirs, we only care about files and dirs are implied by paths
-        if !c.is_file() {
-            continue;
-        }
-
+    let files = dirents
+        .into_iter()
+        .map(|d| d.into_path())
+        // skip dirs, we only care about files and empty dirs are listed on their own
+        .filter(|c| c.is_file());
+    let mut entries = util::bounded_tasks(files, util::jobs().hash, |c| {
         let t = tx.clone();
         let dir = dir.to_path_buf();
         let base = base_url.clone();
-        let permit = sem.clone().acquire_owned().await?;
-        let fut = async move {
+        let priorities = priorities.clone();
+        let xattr_filter = xattr_filter.clone();
+        async move {
             let stripped_path = c.strip_prefix(dir)?.to_slash_lossy().to_string();
             let relative = RelativePath::from_path(&stripped_path)?;
-            let src_url = base.join(relative.as_str())?;
-            let sha512 = hash_with_events(&c, t).await?;
-            drop(permit);
-            Ok::<ManifestEntry, anyhow::Error>(ManifestEntry {
+            let src_url = entry_url(&base, relative)?;
+            let sha512 = hash_with_events(&c, algo, t).await?;
+            let meta = fs::metadata(&c)?;
+            let entry = ManifestEntry {
                 path: relative.to_owned(),
                 sha512,
                 source: src_url,
-            })
-        };
-
-        handles.push(tokio::spawn(fut));
-    }
-
-    for handle in handles {
-        let e = handle.await??;
-        entries.push(e);
-    }
-    tx.send(Event::close()).await?;
+                size: Some(meta.len()),
+                mtime: mtime::of(&meta),
+                mode: perms::mode_of(&meta),
+                priority: priorities.priority(relative),
+                duplicate_of: None,
+                hard_link_of: None,
+                sparse: sparse::is_sparse(&meta),
+                xattrs: match &xattr_filter {
+                    Some(filter) => xattrs::read(&c, filter)?,
+                    None => BTreeMap::new(),
+                },
+                encoded_sha512: None,
+                encoded_size: None,
+            };
+            Ok((entry, util::linked_inode(&meta)))
+        }
+    })
+    .await?;
+    // tasks finish in any order, keep manifests stable between runs
+    // plain byte order of the slash form, the same on every platform and locale
+    entries.sort_by(|a, b| a.0.path.as_str().cmp(b.0.path.as_str()));
+    let inodes: Vec<Option<(u64, u64)>> = entries.iter().map(|(_, inode)| *inode).collect();
+    let mut entries: Vec<ManifestEntry> = entries.into_iter().map(|(e, _)| e).collect();
+    mark_duplicates(&mut entries);
+    mark_hard_links(&mut entries, &inodes);
+    tx.send(Event::close());
     h.await??;
     let manifest_file = base_url.join("comstar.json")?;
     Ok(Manifest {
         source: manifest_file,
-        generated_at: Utc::now(),
+        generated_at,
+        notes,
+        requires_comstar,
+        algo: opts.algo,
+        symlinks: links,
+        empty_dirs,
+        signature: None,
         entries,
     })
 }
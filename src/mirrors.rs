@@ -0,0 +1,143 @@
+use std::{
+    sync::RwLock,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use futures::StreamExt;
+use reqwest::header::RANGE;
+use url::Url;
+
+use crate::http;
+
+/// How much of the manifest a probe reads to estimate throughput.
+const PROBE_BYTES: u64 = 256 * 1024;
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Downloads smaller than this say more about latency than throughput.
+const MIN_MEASURED_BYTES: u64 = 1024 * 1024;
+
+/// Weight of a new measurement in a mirror's running throughput.
+const MEASUREMENT_WEIGHT: f64 = 0.3;
+
+#[derive(Debug)]
+struct Mirror {
+    base: Url,
+    /// Bytes per second, probed at sync start and updated from real downloads.
+    rate: f64,
+}
+
+/// The manifest's own base URL and the mirrors serving the same tree, fastest first.
+static MIRRORS: RwLock<Vec<Mirror>> = RwLock::new(Vec::new());
+
+/// Directory a manifest URL and the sources it lists are relative to.
+pub fn base_of(manifest: &Url) -> Result<Url> {
+    Ok(manifest.join(".")?)
+}
+
+/// `url` with a trailing slash, so paths join below it rather than next to it.
+pub fn as_directory(url: &Url) -> Url {
+    let mut url = url.clone();
+    if !url.path().ends_with('/') {
+        url.set_path(&format!("{}/", url.path()));
+    }
+    url
+}
+
+/// Where `src` lives on `mirror`, if `src` is under `primary`.
+pub fn rebase(src: &Url, primary: &Url, mirror: &Url) -> Option<Url> {
+    let relative = src.as_str().strip_prefix(primary.as_str())?;
+    mirror.join(relative).ok()
+}
+
+/// Reads the start of the manifest from `base`, returning bytes per second. Unreachable
+/// mirrors rate zero and go last.
+async fn probe(base: &Url) -> f64 {
+    if base.scheme() == "file" {
+        return f64::MAX;
+    }
+    let started = Instant::now();
+    let read = async {
+        let resp = http::client()
+            .get(base.join("comstar.json")?)
+            .header(RANGE, format!("bytes=0-{}", PROBE_BYTES - 1))
+            .send()
+            .await?
+            .error_for_status()?;
+        let mut body = resp.bytes_stream();
+        let mut n = 0;
+        while let Some(chunk) = body.next().await {
+            n += chunk?.len() as u64;
+        }
+        anyhow::Ok(n)
+    };
+    match tokio::time::timeout(PROBE_TIMEOUT, read).await {
+        Ok(Ok(n)) => n as f64 / started.elapsed().as_secs_f64().max(0.001),
+        Ok(Err(e)) => {
+            tracing::warn!("Probing mirror {} failed: {}", base, e);
+            0.0
+        }
+        Err(_) => 0.0,
+    }
+}
+
+/// Probes `primary`, the base of the manifest being synced, and every mirror, and ranks them
+/// by throughput. Returns the fastest. Without mirrors nothing is probed.
+pub async fn configure(primary: &Url, mirrors: &[Url]) -> Option<Url> {
+    if mirrors.is_empty() {
+        MIRRORS.write().unwrap().clear();
+        return None;
+    }
+    let bases: Vec<Url> = std::iter::once(primary.clone())
+        .chain(mirrors.iter().map(as_directory))
+        .collect();
+    let rates = futures::future::join_all(bases.iter().map(probe)).await;
+    let mut ranked: Vec<Mirror> = bases
+        .into_iter()
+        .zip(rates)
+        .map(|(base, rate)| Mirror { base, rate })
+        .collect();
+    ranked.sort_by(|a, b| b.rate.total_cmp(&a.rate));
+    let fastest = ranked[0].base.clone();
+    *MIRRORS.write().unwrap() = ranked;
+    Some(fastest)
+}
+
+/// Every place `src` can be downloaded from, fastest first. Just `src` itself when there are
+/// no mirrors or it doesn't live under the manifest's base.
+pub fn candidates(src: &Url) -> Vec<Url> {
+    let mirrors = MIRRORS.read().unwrap();
+    let primary = match mirrors
+        .iter()
+        .find(|m| src.as_str().starts_with(m.base.as_str()))
+    {
+        Some(m) => m.base.clone(),
+        None => return vec![src.clone()],
+    };
+    mirrors
+        .iter()
+        .filter_map(|m| rebase(src, &primary, &m.base))
+        .collect()
+}
+
+/// Feeds the throughput of a finished download from `src` back into the ranking, so a
+/// mirror that slows down during a long sync loses its place.
+pub fn record(src: &Url, bytes: u64, elapsed: Duration) {
+    if bytes < MIN_MEASURED_BYTES {
+        return;
+    }
+    let measured = bytes as f64 / elapsed.as_secs_f64().max(0.001);
+    let mut mirrors = MIRRORS.write().unwrap();
+    let mirror = match mirrors
+        .iter_mut()
+        .find(|m| src.as_str().starts_with(m.base.as_str()))
+    {
+        Some(m) => m,
+        None => return,
+    };
+    // local mirrors always win, no need to measure them
+    if mirror.rate < f64::MAX {
+        mirror.rate = mirror.rate * (1.0 - MEASUREMENT_WEIGHT) + measured * MEASUREMENT_WEIGHT;
+    }
+    mirrors.sort_by(|a, b| b.rate.total_cmp(&a.rate));
+}
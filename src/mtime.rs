@@ -0,0 +1,45 @@
+use std::{
+    fs::Metadata,
+    path::Path,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use filetime::FileTime;
+
+use crate::manifest::ManifestEntry;
+
+static QUICK_CHECK: AtomicBool = AtomicBool::new(false);
+
+/// Makes validation trust files whose size and modification time match their entry
+/// instead of hashing them.
+pub fn configure(quick_check: bool) {
+    QUICK_CHECK.store(quick_check, Ordering::Relaxed);
+}
+
+/// Modification time to record for a file.
+pub fn of(meta: &Metadata) -> Option<DateTime<Utc>> {
+    meta.modified().ok().map(DateTime::from)
+}
+
+/// Sets the modification time of `path` to the recorded one, if there is one.
+pub fn restore(path: &Path, mtime: Option<&DateTime<Utc>>) -> Result<()> {
+    if let Some(t) = mtime {
+        filetime::set_file_mtime(path, FileTime::from_system_time((*t).into()))?;
+    }
+    Ok(())
+}
+
+/// Whether the quick check lets the file with `meta` pass as `entry` without hashing it.
+/// Both size and modification time have to be recorded and match exactly, anything else
+/// gets hashed.
+pub fn trusted(meta: &Metadata, entry: &ManifestEntry) -> bool {
+    if !QUICK_CHECK.load(Ordering::Relaxed) {
+        return false;
+    }
+    match (entry.size, entry.mtime) {
+        (Some(size), Some(mtime)) => meta.len() == size && of(meta) == Some(mtime),
+        _ => false,
+    }
+}
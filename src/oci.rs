@@ -0,0 +1,383 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    str::FromStr,
+    sync::RwLock,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use reqwest::{
+    header::{HeaderMap, HeaderValue, ACCEPT, AUTHORIZATION, WWW_AUTHENTICATE},
+    StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::{http, i18n::t};
+
+pub const MANIFEST_MEDIA_TYPE: &str = "application/vnd.oci.image.manifest.v1+json";
+
+/// Annotation naming the file a layer holds, the same one `oras` uses.
+pub const TITLE_ANNOTATION: &str = "org.opencontainers.image.title";
+
+/// Registries that don't say how long a token lasts mean this long.
+const DEFAULT_TOKEN_LIFETIME: Duration = Duration::from_secs(60);
+
+/// Tokens are fetched again this long before they expire.
+const TOKEN_MARGIN: Duration = Duration::from_secs(10);
+
+/// Authorization already worked out this run, by registry, repository and whether it
+/// allows pushing.
+type AuthKey = (String, String, bool);
+static AUTH: RwLock<Option<HashMap<AuthKey, (Option<HeaderValue>, Instant)>>> = RwLock::new(None);
+
+/// An artifact in a registry, written `registry/repository[:tag|@digest]`, or as an
+/// `oci://` URL.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reference {
+    pub registry: String,
+    pub repository: String,
+    pub tag: Option<String>,
+    pub digest: Option<String>,
+}
+
+impl FromStr for Reference {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Reference::from_url(&Url::parse(&format!("oci://{}", s))?)
+    }
+}
+
+impl fmt::Display for Reference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.registry, self.repository)?;
+        if let Some(tag) = &self.tag {
+            write!(f, ":{}", tag)?;
+        }
+        if let Some(digest) = &self.digest {
+            write!(f, "@{}", digest)?;
+        }
+        Ok(())
+    }
+}
+
+impl Reference {
+    pub fn from_url(url: &Url) -> Result<Self> {
+        let bad = || {
+            anyhow!(
+                "Invalid OCI reference {}, expected registry/repository[:tag]",
+                url
+            )
+        };
+        let host = url.host_str().ok_or_else(bad)?;
+        let registry = match url.port() {
+            Some(p) => format!("{}:{}", host, p),
+            None => host.to_string(),
+        };
+        let path = url.path().trim_start_matches('/');
+        let (rest, digest) = match path.split_once('@') {
+            Some((r, d)) => (r, Some(d.to_string())),
+            None => (path, None),
+        };
+        let (repository, tag) = match rest.rsplit_once(':') {
+            Some((r, tag)) if !tag.contains('/') => (r, Some(tag.to_string())),
+            _ => (rest, None),
+        };
+        if repository.is_empty() {
+            return Err(bad());
+        }
+        Ok(Reference {
+            registry,
+            repository: repository.to_string(),
+            tag,
+            digest,
+        })
+    }
+
+    /// The same repository, pointing at `digest`.
+    pub fn with_digest(&self, digest: &str) -> Reference {
+        Reference {
+            tag: None,
+            digest: Some(digest.to_string()),
+            ..self.clone()
+        }
+    }
+
+    pub fn to_url(&self) -> Result<Url> {
+        Ok(Url::parse(&format!("oci://{}", self))?)
+    }
+
+    /// The tag, or failing that the digest, a manifest is addressed by.
+    fn manifest_ref(&self) -> &str {
+        self.digest
+            .as_deref()
+            .or(self.tag.as_deref())
+            .unwrap_or("latest")
+    }
+
+    fn api_base(&self) -> String {
+        let local = ["localhost", "127.0.0.1", "[::1]"]
+            .iter()
+            .any(|h| self.registry == *h || self.registry.starts_with(&format!("{}:", h)));
+        let scheme = if local { "http" } else { "https" };
+        format!("{}://{}/v2/", scheme, self.registry)
+    }
+
+    /// API URL of the repository's `kind`, `blobs` or `manifests`, entry `name`.
+    pub fn api_url(&self, kind: &str, name: &str) -> Result<Url> {
+        Ok(Url::parse(&format!(
+            "{}{}/{}/{}",
+            self.api_base(),
+            self.repository,
+            kind,
+            name
+        ))?)
+    }
+
+    pub fn manifest_url(&self) -> Result<Url> {
+        self.api_url("manifests", self.manifest_ref())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Descriptor {
+    pub media_type: String,
+    pub digest: String,
+    pub size: u64,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub annotations: HashMap<String, String>,
+}
+
+impl Descriptor {
+    pub fn title(&self) -> Option<&str> {
+        self.annotations.get(TITLE_ANNOTATION).map(String::as_str)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImageManifest {
+    pub schema_version: u32,
+    pub media_type: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub artifact_type: Option<String>,
+    pub config: Descriptor,
+    pub layers: Vec<Descriptor>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    #[serde(alias = "access_token")]
+    token: String,
+    expires_in: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DockerConfig {
+    #[serde(default)]
+    auths: HashMap<String, DockerAuth>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DockerAuth {
+    auth: Option<String>,
+}
+
+/// User and password for `registry`, from `COMSTAR_OCI_USER` and `COMSTAR_OCI_PASSWORD`
+/// or else from what `docker login` stored in `~/.docker/config.json`.
+fn credentials(registry: &str) -> Option<(String, String)> {
+    if let (Ok(user), Ok(password)) = (
+        std::env::var("COMSTAR_OCI_USER"),
+        std::env::var("COMSTAR_OCI_PASSWORD"),
+    ) {
+        return Some((user, password));
+    }
+    let path = dirs::home_dir()?.join(".docker").join("config.json");
+    let config: DockerConfig = serde_json::from_slice(&std::fs::read(path).ok()?).ok()?;
+    let auth = config
+        .auths
+        .iter()
+        .find(|(k, _)| k.trim_start_matches("https://").split('/').next() == Some(registry))
+        .and_then(|(_, a)| a.auth.as_deref())?;
+    let decoded = String::from_utf8(STANDARD.decode(auth).ok()?).ok()?;
+    let (user, password) = decoded.split_once(':')?;
+    Some((user.to_string(), password.to_string()))
+}
+
+/// The `key="value"` parameters of a `WWW-Authenticate` challenge.
+fn challenge_params(challenge: &str) -> HashMap<String, String> {
+    let mut params = HashMap::new();
+    let mut rest = challenge.split_once(' ').map(|(_, r)| r).unwrap_or("");
+    while let Some((key, after)) = rest.split_once('=') {
+        let key = key
+            .trim()
+            .trim_start_matches(',')
+            .trim()
+            .to_ascii_lowercase();
+        let (value, next) = match after.strip_prefix('"') {
+            Some(quoted) => match quoted.split_once('"') {
+                Some((v, n)) => (v, n),
+                None => (quoted, ""),
+            },
+            None => after.split_once(',').unwrap_or((after, "")),
+        };
+        params.insert(key, value.to_string());
+        rest = next;
+    }
+    params
+}
+
+/// Answers the registry's challenge, returning the Authorization header to send, if any,
+/// and until when it is good.
+async fn authorize(reference: &Reference, push: bool) -> Result<(Option<HeaderValue>, Instant)> {
+    let forever = Instant::now() + Duration::from_secs(24 * 60 * 60);
+    let resp = http::client().get(reference.api_base()).send().await?;
+    let challenge = match resp.headers().get(WWW_AUTHENTICATE) {
+        Some(c) if resp.status() == StatusCode::UNAUTHORIZED => c.to_str()?.to_string(),
+        _ => return Ok((None, forever)),
+    };
+    let creds = credentials(&reference.registry);
+    if challenge.to_ascii_lowercase().starts_with("basic") {
+        let (user, password) =
+            creds.ok_or_else(|| anyhow!(t!("oci.no_credentials", reference.registry)))?;
+        let basic = STANDARD.encode(format!("{}:{}", user, password));
+        return Ok((
+            Some(HeaderValue::from_str(&format!("Basic {}", basic))?),
+            forever,
+        ));
+    }
+    let params = challenge_params(&challenge);
+    let realm = params.get("realm").ok_or_else(|| {
+        anyhow!(
+            "Registry {} sent a challenge without realm",
+            reference.registry
+        )
+    })?;
+    let actions = if push { "pull,push" } else { "pull" };
+    let mut query = vec![(
+        "scope".to_string(),
+        format!("repository:{}:{}", reference.repository, actions),
+    )];
+    if let Some(service) = params.get("service") {
+        query.push(("service".to_string(), service.clone()));
+    }
+    let mut req = http::client().get(realm).query(&query);
+    if let Some((user, password)) = &creds {
+        req = req.basic_auth(user, Some(password));
+    }
+    let token: TokenResponse = req.send().await?.error_for_status()?.json().await?;
+    let lifetime = token
+        .expires_in
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_TOKEN_LIFETIME);
+    Ok((
+        Some(HeaderValue::from_str(&format!("Bearer {}", token.token))?),
+        Instant::now() + lifetime,
+    ))
+}
+
+/// Headers authorizing requests to `reference`'s repository, for pulling or, with `push`,
+/// for pushing too.
+pub async fn auth_headers(reference: &Reference, push: bool) -> Result<HeaderMap> {
+    let key = (
+        reference.registry.clone(),
+        reference.repository.clone(),
+        push,
+    );
+    let cached = AUTH
+        .read()
+        .unwrap()
+        .as_ref()
+        .and_then(|m| m.get(&key).cloned())
+        .filter(|(_, until)| Instant::now() + TOKEN_MARGIN < *until);
+    let (value, _) = match cached {
+        Some(c) => c,
+        None => {
+            let fresh = authorize(reference, push).await?;
+            AUTH.write()
+                .unwrap()
+                .get_or_insert_with(HashMap::new)
+                .insert(key, fresh.clone());
+            fresh
+        }
+    };
+    let mut headers = HeaderMap::new();
+    if let Some(v) = value {
+        headers.insert(AUTHORIZATION, v);
+    }
+    Ok(headers)
+}
+
+/// The image manifest `reference` points at, `None` if there is none.
+pub async fn get_manifest(reference: &Reference) -> Result<Option<ImageManifest>> {
+    let resp = http::client()
+        .get(reference.manifest_url()?)
+        .headers(auth_headers(reference, false).await?)
+        .header(ACCEPT, MANIFEST_MEDIA_TYPE)
+        .send()
+        .await?;
+    if resp.status() == StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    Ok(Some(resp.error_for_status()?.json().await?))
+}
+
+/// The API URL and headers to download the blob an `oci://registry/repository@digest`
+/// URL points at with.
+pub async fn request_parts(src: &Url) -> Result<(Url, HeaderMap)> {
+    let reference = Reference::from_url(src)?;
+    let digest = reference
+        .digest
+        .as_deref()
+        .ok_or_else(|| anyhow!(t!("oci.no_digest", src)))?;
+    let url = reference.api_url("blobs", digest)?;
+    Ok((url, auth_headers(&reference, false).await?))
+}
+
+/// A blob as a whole, or for a tagged reference, the comstar manifest layer of the
+/// artifact. `None` if there is no such thing.
+pub async fn fetch(src: &Url) -> Result<Option<Vec<u8>>> {
+    let reference = Reference::from_url(src)?;
+    let digest = match &reference.digest {
+        Some(d) => d.clone(),
+        None => {
+            let manifest = match get_manifest(&reference).await? {
+                Some(m) => m,
+                None => return Ok(None),
+            };
+            match manifest
+                .layers
+                .iter()
+                .find(|l| l.title() == Some("comstar.json"))
+            {
+                Some(l) => l.digest.clone(),
+                None => return Ok(None),
+            }
+        }
+    };
+    let resp = http::client()
+        .get(reference.api_url("blobs", &digest)?)
+        .headers(auth_headers(&reference, false).await?)
+        .send()
+        .await?;
+    if resp.status() == StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    Ok(Some(resp.error_for_status()?.bytes().await?.to_vec()))
+}
+
+/// Size of the blob at `src`.
+pub async fn size(src: &Url) -> Result<Option<u64>> {
+    let (url, headers) = request_parts(src).await?;
+    let resp = http::client()
+        .head(url)
+        .headers(headers)
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(resp.content_length())
+}
@@ -0,0 +1,256 @@
+use std::{
+    fmt, fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::RwLock,
+};
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::i18n::t;
+
+static SETTINGS: RwLock<Settings> = RwLock::new(Settings {
+    dir: None,
+    file: None,
+    read_only: ReadOnlyPolicy::Error,
+});
+
+/// Unix permission bits written in octal, e.g. `0755`. The process umask still applies, the
+/// same way it does for `mkdir -m` and `install -m`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Mode(pub u32);
+
+impl fmt::Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:04o}", self.0)
+    }
+}
+
+impl From<Mode> for String {
+    fn from(m: Mode) -> String {
+        m.to_string()
+    }
+}
+
+impl FromStr for Mode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let digits = s.trim().trim_start_matches("0o");
+        match u32::from_str_radix(digits, 8) {
+            Ok(m) if m <= 0o7777 => Ok(Mode(m)),
+            _ => Err(anyhow!("Invalid mode {}, expected octal like 0755", s)),
+        }
+    }
+}
+
+impl TryFrom<String> for Mode {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+/// What to do when a file that needs changing, or the directory it lives in, is read-only.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReadOnlyPolicy {
+    #[default]
+    Error,
+    Override,
+}
+
+impl FromStr for ReadOnlyPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "error" => Ok(ReadOnlyPolicy::Error),
+            "override" => Ok(ReadOnlyPolicy::Override),
+            _ => Err(anyhow!(
+                "Unknown read-only policy {}, expected error or override",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Settings {
+    dir: Option<Mode>,
+    file: Option<Mode>,
+    read_only: ReadOnlyPolicy,
+}
+
+/// Sets the modes sync gives to directories and files it creates, `None` leaves it to the
+/// umask like any other program, and how read-only paths are treated.
+pub fn configure(dir: Option<Mode>, file: Option<Mode>, read_only: ReadOnlyPolicy) {
+    *SETTINGS.write().unwrap() = Settings {
+        dir,
+        file,
+        read_only,
+    };
+}
+
+fn settings() -> Settings {
+    *SETTINGS.read().unwrap()
+}
+
+/// Paths made writable by `unlock`, read-only again once `relock` is called.
+#[derive(Debug, Default)]
+#[must_use]
+pub struct Unlocked {
+    paths: Vec<(PathBuf, fs::Permissions)>,
+}
+
+impl Unlocked {
+    pub fn relock(self) -> Result<()> {
+        for (path, perms) in self.paths {
+            if path.exists() {
+                fs::set_permissions(&path, perms)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn read_only_permissions(path: &Path) -> Option<fs::Permissions> {
+    fs::metadata(path)
+        .ok()
+        .map(|m| m.permissions())
+        .filter(|p| p.readonly())
+}
+
+/// Makes sure `path` can be written, or deleted when `removing`. A read-only file, or a
+/// read-only parent when the file has to be created or deleted, is either an error saying
+/// what to do about it or made writable until the returned guard is relocked, depending
+/// on the policy.
+pub fn unlock(path: &Path, removing: bool) -> Result<Unlocked> {
+    let exists = path.exists();
+    let mut candidates = Vec::new();
+    if exists {
+        candidates.push(path);
+    }
+    // rewriting an existing file in place doesn't touch the directory
+    if removing || !exists {
+        candidates.extend(path.parent());
+    }
+    let blocking: Vec<(PathBuf, fs::Permissions)> = candidates
+        .into_iter()
+        .filter_map(|p| read_only_permissions(p).map(|perms| (p.to_path_buf(), perms)))
+        .collect();
+    if blocking.is_empty() {
+        return Ok(Unlocked::default());
+    }
+    if settings().read_only == ReadOnlyPolicy::Error {
+        return Err(anyhow!(t!("sync.read_only", blocking[0].0.display())));
+    }
+    let mut unlocked = Unlocked::default();
+    for (p, perms) in blocking {
+        let mut writable = perms.clone();
+        // only the owner needs to write, don't open it up to everyone
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            writable.set_mode(perms.mode() | 0o200);
+        }
+        #[cfg(not(unix))]
+        #[allow(clippy::permissions_set_readonly_false)]
+        writable.set_readonly(false);
+        fs::set_permissions(&p, writable)?;
+        unlocked.paths.push((p, perms));
+    }
+    Ok(unlocked)
+}
+
+/// Permission bits to record for a file. Unix only, and without setuid, setgid and sticky,
+/// which nobody wants a download to come with.
+#[cfg(unix)]
+pub fn mode_of(meta: &fs::Metadata) -> Option<Mode> {
+    use std::os::unix::fs::PermissionsExt;
+    Some(Mode(meta.permissions().mode() & 0o777))
+}
+
+#[cfg(not(unix))]
+pub fn mode_of(_meta: &fs::Metadata) -> Option<Mode> {
+    None
+}
+
+/// Gives `path` the mode recorded for it, which takes precedence over `--file-mode`. Unix
+/// only, elsewhere files keep what they were created with.
+#[cfg(unix)]
+pub fn restore_mode(path: &Path, mode: Option<Mode>) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    if let Some(Mode(m)) = mode {
+        fs::set_permissions(path, fs::Permissions::from_mode(m & 0o777))?;
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn restore_mode(_path: &Path, _mode: Option<Mode>) -> Result<()> {
+    Ok(())
+}
+
+/// Whether a file with `meta` doesn't have the recorded `mode`. Never without one, or
+/// anywhere but Unix.
+#[cfg(unix)]
+pub fn mode_differs(meta: &fs::Metadata, mode: Option<Mode>) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    mode.is_some_and(|Mode(m)| meta.permissions().mode() & 0o777 != m & 0o777)
+}
+
+#[cfg(not(unix))]
+pub fn mode_differs(_meta: &fs::Metadata, _mode: Option<Mode>) -> bool {
+    false
+}
+
+/// Creates the missing parents of `path`. Directories that already exist are left alone.
+pub fn create_parents(path: &Path) -> Result<()> {
+    match path.parent() {
+        Some(parent) => create_dir(parent),
+        None => Ok(()),
+    }
+}
+
+/// Creates `dir` and its missing parents with the configured mode.
+pub fn create_dir(dir: &Path) -> Result<()> {
+    let mut builder = fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    if let Some(Mode(m)) = settings().dir {
+        use std::os::unix::fs::DirBuilderExt;
+        builder.mode(m);
+    }
+    builder.create(dir)?;
+    Ok(())
+}
+
+/// Opens `path` for writing from scratch, creating it and its parents if needed. Only newly
+/// created files get the configured mode, existing files keep theirs.
+pub async fn create_file(path: &Path) -> Result<tokio::fs::File> {
+    create_parents(path)?;
+    let mut opts = tokio::fs::OpenOptions::new();
+    opts.create(true).write(true).truncate(true);
+    #[cfg(unix)]
+    if let Some(Mode(m)) = settings().file {
+        opts.mode(m);
+    }
+    Ok(opts.open(path).await?)
+}
+
+/// Blocking version of `create_file`.
+pub fn create_file_sync(path: &Path) -> Result<fs::File> {
+    create_parents(path)?;
+    let mut opts = fs::OpenOptions::new();
+    opts.create(true).write(true).truncate(true);
+    #[cfg(unix)]
+    if let Some(Mode(m)) = settings().file {
+        use std::os::unix::fs::OpenOptionsExt;
+        opts.mode(m);
+    }
+    Ok(opts.open(path)?)
+}
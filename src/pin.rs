@@ -0,0 +1,88 @@
+use std::{
+    fs::{self, File},
+    io::{BufReader, BufWriter},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::{i18n::t, manifest::Manifest};
+
+const LOCK_FILE: &str = "comstar.lock";
+
+/// The manifest version a directory is pinned to. Syncs refuse any other version until
+/// the pin is updated.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Lock {
+    pub manifest: Url,
+    pub digest: String,
+    pub generated_at: DateTime<Utc>,
+}
+
+fn lock_path(dir: &Path) -> PathBuf {
+    dir.join(LOCK_FILE)
+}
+
+impl Lock {
+    pub fn for_manifest(target: &Url, manifest: &Manifest) -> Result<Lock> {
+        Ok(Lock {
+            manifest: target.clone(),
+            digest: manifest.digest()?,
+            generated_at: manifest.generated_at,
+        })
+    }
+
+    pub fn load(dir: &Path) -> Result<Option<Lock>> {
+        let path = lock_path(dir);
+        if !path.is_file() {
+            return Ok(None);
+        }
+        let br = BufReader::new(File::open(path)?);
+        Ok(Some(serde_json::from_reader(br)?))
+    }
+
+    pub fn save(&self, dir: &Path) -> Result<()> {
+        let writer = BufWriter::new(File::create(lock_path(dir))?);
+        serde_json::to_writer_pretty(writer, self)?;
+        Ok(())
+    }
+}
+
+/// Fails if `dir` is pinned and `header`, whose manifest hashes to what `digest` returns,
+/// is not the pinned version. The digest is only worked out for a pinned directory.
+pub fn check<F>(dir: &Path, header: &Manifest, digest: F) -> Result<()>
+where
+    F: FnOnce() -> Result<String>,
+{
+    if let Some(lock) = Lock::load(dir)? {
+        if lock.digest != digest()? {
+            return Err(anyhow!(t!(
+                "pin.mismatch",
+                lock.generated_at,
+                header.generated_at
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Pins `dir` to the current version of the manifest at `target`. An existing pin to a
+/// different version is only replaced when `update` is set.
+pub fn pin(dir: &Path, target: &Url, manifest: &Manifest, update: bool) -> Result<()> {
+    let lock = Lock::for_manifest(target, manifest)?;
+    match Lock::load(dir)? {
+        Some(existing) if existing == lock => {
+            println!("{}", t!("pin.unchanged", lock.generated_at));
+            return Ok(());
+        }
+        Some(_) if !update => return Err(anyhow!(t!("pin.exists"))),
+        _ => {}
+    }
+    fs::create_dir_all(dir)?;
+    lock.save(dir)?;
+    println!("{}", t!("pin.pinned", lock.generated_at));
+    Ok(())
+}
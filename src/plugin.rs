@@ -0,0 +1,156 @@
+use std::{
+    path::{Path, PathBuf},
+    process::Stdio,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    process::Command,
+};
+use url::Url;
+
+use crate::i18n::t;
+
+/// Plugins are found on `PATH` as this prefix followed by the host of a `plugin://` URL, so
+/// `plugin://vault/releases/comstar.json` runs `comstar-plugin-vault`. The host of an
+/// `exec://` URL is run as it is, `exec://my-helper/releases/comstar.json` runs `my-helper`.
+const PROGRAM_PREFIX: &str = "comstar-plugin-";
+
+static TEMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// One operation, written as a single line of JSON to the plugin's stdin. Contents never go
+/// through the pipe, the plugin reads and writes the local files named in the request.
+#[derive(Debug, Serialize)]
+#[serde(tag = "op", rename_all = "kebab-case")]
+enum Request<'a> {
+    /// Write the manifest at `url` to `dest`, or answer `not-found` if there is none.
+    GetManifest { url: &'a Url, dest: &'a Path },
+    /// Write the object at `url` to `dest`.
+    GetObject { url: &'a Url, dest: &'a Path },
+    /// Store the contents of `src` at `url`, replacing what is there.
+    PutObject {
+        url: &'a Url,
+        src: &'a Path,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        sha512: Option<&'a str>,
+    },
+    /// Remove the object at `url`. Removing something that isn't there is not an error.
+    Delete { url: &'a Url },
+    /// Write the URLs of all objects below `url` to `dest`, one per line.
+    List { url: &'a Url, dest: &'a Path },
+}
+
+/// The plugin's answer, a single line of JSON on its stdout. Anything it writes to stderr
+/// goes to comstar's stderr.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "status", rename_all = "kebab-case")]
+enum Response {
+    Ok,
+    NotFound,
+    Error { message: String },
+}
+
+fn program(url: &Url) -> Result<String> {
+    match url.host_str() {
+        Some(name) if !name.is_empty() && url.scheme() == "exec" => Ok(name.to_string()),
+        Some(name) if !name.is_empty() => Ok(format!("{}{}", PROGRAM_PREFIX, name)),
+        _ => Err(anyhow!("Plugin URL has no plugin name: {}", url)),
+    }
+}
+
+/// A path in the temp directory for a plugin to write to, unique within this process.
+fn temp_path(ext: &str) -> PathBuf {
+    let n = TEMP_COUNTER.fetch_add(1, Ordering::Relaxed);
+    std::env::temp_dir().join(format!("comstar-{}-{}.{}", std::process::id(), n, ext))
+}
+
+/// Runs the plugin for `url` with `req`. Returns `false` if it answered `not-found`.
+async fn call(url: &Url, req: &Request<'_>) -> Result<bool> {
+    let program = program(url)?;
+    let mut child = Command::new(&program)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| anyhow!(t!("plugin.spawn", program, e)))?;
+
+    let mut line = serde_json::to_vec(req)?;
+    line.push(b'\n');
+    let mut stdin = child.stdin.take().unwrap();
+    stdin.write_all(&line).await?;
+    drop(stdin);
+
+    let mut answer = String::new();
+    BufReader::new(child.stdout.take().unwrap())
+        .read_line(&mut answer)
+        .await?;
+    let status = child.wait().await?;
+    if answer.trim().is_empty() {
+        return Err(anyhow!(t!("plugin.no_answer", program, status)));
+    }
+    let response: Response =
+        serde_json::from_str(&answer).map_err(|e| anyhow!(t!("plugin.bad_answer", program, e)))?;
+    match response {
+        Response::Ok if status.success() => Ok(true),
+        Response::NotFound if status.success() => Ok(false),
+        Response::Error { message } => Err(anyhow!(t!("plugin.failed", program, message))),
+        _ => Err(anyhow!(t!("plugin.failed", program, status))),
+    }
+}
+
+/// The raw bytes of the manifest at `url`, `None` if there is none.
+pub async fn get_manifest(url: &Url) -> Result<Option<Vec<u8>>> {
+    let dest = temp_path("json");
+    let found = call(url, &Request::GetManifest { url, dest: &dest }).await;
+    let bytes = match found {
+        Ok(true) => std::fs::read(&dest).map(Some).map_err(anyhow::Error::from),
+        Ok(false) => Ok(None),
+        Err(e) => Err(e),
+    };
+    let _ = std::fs::remove_file(&dest);
+    bytes
+}
+
+/// Fetches the object at `url` into a temporary file, which the caller removes.
+pub async fn get_object(url: &Url) -> Result<PathBuf> {
+    let dest = temp_path("part");
+    match call(url, &Request::GetObject { url, dest: &dest }).await {
+        Ok(true) => Ok(dest),
+        Ok(false) => Err(anyhow!(t!("plugin.missing", url))),
+        Err(e) => {
+            let _ = std::fs::remove_file(&dest);
+            Err(e)
+        }
+    }
+}
+
+/// Stores `src` at `url`. `sha512` is passed along for plugins whose storage can check it.
+pub async fn put_object(url: &Url, src: &Path, sha512: Option<&str>) -> Result<()> {
+    call(url, &Request::PutObject { url, src, sha512 }).await?;
+    Ok(())
+}
+
+pub async fn delete(url: &Url) -> Result<()> {
+    call(url, &Request::Delete { url }).await?;
+    Ok(())
+}
+
+/// URLs of all objects stored below `url`. Nothing below it is `not-found`, which is fine.
+pub async fn list(url: &Url) -> Result<Vec<Url>> {
+    let dest = temp_path("list");
+    let listed = match call(url, &Request::List { url, dest: &dest }).await {
+        Ok(true) => std::fs::read_to_string(&dest).map_err(anyhow::Error::from),
+        Ok(false) => Ok(String::new()),
+        Err(e) => Err(e),
+    };
+    let _ = std::fs::remove_file(&dest);
+    listed?
+        .lines()
+        .filter(|l| !l.trim().is_empty())
+        .map(|l| Ok(Url::parse(l.trim())?))
+        .collect()
+}
@@ -1,148 +1,1128 @@
-use std::{path::Path, collections::HashMap, sync::Arc};
-use async_compression::tokio::bufread::GzipEncoder;
-use google_cloud_storage::{client::{Client, ClientConfig}, http::{objects::{upload::{UploadType, UploadObjectRequest}, Object, delete::DeleteObjectRequest}, storage_client::StorageClient}};
-use relative_path::{RelativePathBuf, RelativePath};
-use tokio::{fs::File, io::BufReader, sync::Semaphore};
+use anyhow::{anyhow, Result};
+use async_compression::tokio::bufread::{GzipDecoder, GzipEncoder};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use futures::StreamExt;
+use google_cloud_storage::{
+    client::{Client, ClientConfig},
+    http::{
+        objects::{
+            delete::DeleteObjectRequest,
+            download::Range,
+            get::GetObjectRequest,
+            list::ListObjectsRequest,
+            rewrite::RewriteObjectRequest,
+            upload::{UploadObjectRequest, UploadType},
+            Encryption, Object,
+        },
+        resumable_upload_client::{ChunkSize, UploadStatus},
+        storage_client::StorageClient,
+        Error as GcsError,
+    },
+    sign::{SignedURLMethod, SignedURLOptions},
+};
+use percent_encoding::percent_decode_str;
+use relative_path::{RelativePath, RelativePathBuf};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    future::Future,
+    path::Path,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::{Duration, Instant},
+};
+use tokio::{
+    fs::File,
+    io::{AsyncRead, AsyncReadExt, BufReader},
+};
 use tokio_util::io::ReaderStream;
-use anyhow::Result;
+use url::Url;
 
-use crate::{manifest::Manifest, events::{Event, self}};
+use crate::{
+    events::{self, Event, EventSender},
+    http,
+    i18n::t,
+    manifest::{self, Manifest, ManifestEntry},
+    mirrors,
+    push::{diff_manifests, manifest_changed, prefixed, ManifestDiff},
+    util,
+    validate::ValidationDifference,
+};
 use google_cloud_default::WithAuthExt;
 
-fn make_meta<S: Into<String>>(bucket: S, name: S, content_type: String) -> Object {
+/// Custom object metadata holding the sha512 of the uncompressed content.
+pub const SHA512_METADATA: &str = "comstar-sha512";
+
+/// Files above this size go up through a resumable session one chunk at a time, so a
+/// failure only costs the chunk it happened in.
+const CHUNKED_UPLOAD_THRESHOLD: u64 = 64 * 1024 * 1024;
+
+/// GCS wants every chunk but the last to be a multiple of 256 KiB.
+const UPLOAD_CHUNK: usize = 128 * 256 * 1024;
+
+/// Attempts per request, or per chunk of a chunked upload, before a push gives up.
+const MAX_ATTEMPTS: u32 = 8;
+
+fn retry_delay(attempt: u32) -> Duration {
+    Duration::from_millis(500 * 2u64.pow(attempt.min(6)))
+}
+
+/// Header naming the project billed for a request, which requester pays buckets insist on.
+const USER_PROJECT_HEADER: &str = "x-goog-user-project";
+
+/// Project billed for requests to requester pays buckets, from `--billing-project`.
+static BILLING_PROJECT: RwLock<Option<String>> = RwLock::new(None);
+
+/// Customer-supplied AES-256 key every object is encrypted with on upload and decrypted
+/// with on download.
+static CSEK: RwLock<Option<Encryption>> = RwLock::new(None);
+
+/// Cloud KMS key new objects are encrypted with, instead of the bucket's default.
+static KMS_KEY: RwLock<Option<String>> = RwLock::new(None);
+
+/// Requests per second allowed against any one bucket, 0 for no limit.
+static MAX_QPS: AtomicU32 = AtomicU32::new(0);
+
+/// Earliest time the next request may go out, per bucket. Pushed back while GCS throttles.
+static NEXT_SLOT: Mutex<BTreeMap<String, Instant>> = Mutex::new(BTreeMap::new());
+
+/// Caps the requests per second a push sends to a bucket, and encrypts what it uploads
+/// with the Cloud KMS key `kms_key` if given.
+pub fn configure(max_qps: Option<u32>, kms_key: Option<String>) {
+    MAX_QPS.store(max_qps.unwrap_or(0), Ordering::Relaxed);
+    *KMS_KEY.write().unwrap() = kms_key;
+}
+
+/// Uses the base64 encoded AES-256 `key`, or the one in `COMSTAR_GCS_ENCRYPTION_KEY`, for
+/// every object pushed or synced. Without either, any key set before is dropped.
+pub fn set_encryption_key(key: Option<String>) -> Result<()> {
+    let key = key.or_else(|| {
+        std::env::var("COMSTAR_GCS_ENCRYPTION_KEY")
+            .ok()
+            .filter(|k| !k.is_empty())
+    });
+    let key = match key {
+        Some(k) => k.trim().to_string(),
+        None => {
+            *CSEK.write().unwrap() = None;
+            return Ok(());
+        }
+    };
+    let raw = STANDARD
+        .decode(&key)
+        .ok()
+        .filter(|raw| raw.len() == 32)
+        .ok_or_else(|| anyhow!(t!("gcs.bad_key")))?;
+    *CSEK.write().unwrap() = Some(Encryption {
+        encryption_algorithm: "AES256".to_string(),
+        encryption_key: key,
+        encryption_key_sha256: STANDARD.encode(Sha256::digest(&raw)),
+    });
+    Ok(())
+}
+
+/// The customer-supplied key, for requests that read or write object content.
+pub fn encryption() -> Option<Encryption> {
+    CSEK.read().unwrap().clone()
+}
+
+fn kms_key() -> Option<String> {
+    KMS_KEY.read().unwrap().clone()
+}
+
+/// Headers that let a plain HTTP request, e.g. to a signed URL, read objects encrypted
+/// with the customer-supplied key.
+pub fn encryption_headers() -> Result<HeaderMap> {
+    let mut headers = HeaderMap::new();
+    if let Some(e) = encryption() {
+        for (name, value) in [
+            ("x-goog-encryption-algorithm", &e.encryption_algorithm),
+            ("x-goog-encryption-key", &e.encryption_key),
+            ("x-goog-encryption-key-sha256", &e.encryption_key_sha256),
+        ] {
+            headers.insert(HeaderName::from_static(name), HeaderValue::from_str(value)?);
+        }
+    }
+    Ok(headers)
+}
+
+/// Bills every GCS request of this run to `project`, for pushing to and syncing from
+/// requester pays buckets. The client is made again on next use.
+pub fn set_billing_project(project: Option<String>) {
+    *BILLING_PROJECT.write().unwrap() = project;
+    *CLIENT.write().unwrap() = None;
+}
+
+pub fn billing_project() -> Option<String> {
+    BILLING_PROJECT.read().unwrap().clone()
+}
+
+/// Waits for the bucket's next request slot under `--max-qps` and any throttling backoff.
+async fn wait_turn(bucket: &str) {
+    let qps = MAX_QPS.load(Ordering::Relaxed);
+    let interval = if qps == 0 {
+        Duration::ZERO
+    } else {
+        Duration::from_secs(1) / qps
+    };
+    let wait = {
+        let mut slots = NEXT_SLOT.lock().unwrap();
+        let now = Instant::now();
+        let slot = slots.entry(bucket.to_string()).or_insert(now);
+        let start = (*slot).max(now);
+        *slot = start + interval;
+        start - now
+    };
+    if !wait.is_zero() {
+        tokio::time::sleep(wait).await;
+    }
+}
+
+/// Holds every request to `bucket` back for `delay`. GCS throttles a bucket as a whole, so
+/// backing off only the request that was refused would just get the others refused too.
+fn back_off(bucket: &str, delay: Duration) {
+    let until = Instant::now() + delay;
+    let mut slots = NEXT_SLOT.lock().unwrap();
+    let slot = slots.entry(bucket.to_string()).or_insert(until);
+    *slot = (*slot).max(until);
+}
+
+/// HTTP status of a failed GCS call, if the service answered at all.
+fn error_status(e: &anyhow::Error) -> Option<u16> {
+    match e.downcast_ref::<GcsError>()? {
+        GcsError::Response(r) => Some(r.code),
+        GcsError::HttpClient(e) => e.status().map(|s| s.as_u16()),
+        _ => None,
+    }
+}
+
+fn is_throttled(e: &anyhow::Error) -> bool {
+    matches!(error_status(e), Some(429 | 503))
+}
+
+/// Whether trying again later can help: throttling, server errors, timeouts and requests
+/// that never got an answer.
+fn is_transient(e: &anyhow::Error) -> bool {
+    match error_status(e) {
+        Some(code) => code == 408 || code == 429 || code >= 500,
+        None => matches!(e.downcast_ref::<GcsError>(), Some(GcsError::HttpClient(_))),
+    }
+}
+
+/// Runs a GCS request for `object`, retrying transient failures with exponential backoff.
+/// Throttling backs off the whole bucket and shows up in the progress output through `tx`.
+///
+/// The client doesn't hand back response headers, so a Retry-After from GCS can't be read;
+/// the backoff starts at a second and doubles up to half a minute, which covers what GCS
+/// asks for in practice.
+async fn with_backoff<T, F, Fut>(
+    bucket: &str,
+    object: &RelativePath,
+    tx: Option<&EventSender>,
+    call: F,
+) -> Result<T>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let mut attempt = 0;
+    loop {
+        wait_turn(bucket).await;
+        match call().await {
+            Ok(v) => return Ok(v),
+            Err(e) if attempt + 1 < MAX_ATTEMPTS && is_transient(&e) => {
+                attempt += 1;
+                let delay = retry_delay(attempt);
+                if is_throttled(&e) {
+                    back_off(bucket, delay);
+                    if let Some(tx) = tx {
+                        tx.send(Event::file_throttled(object.as_str(), delay));
+                    }
+                } else if let Some(tx) = tx {
+                    tx.send(Event::file_retried(object.as_str(), attempt));
+                }
+                tracing::warn!("Request for {} failed, retrying: {}", object, e);
+                tokio::time::sleep(delay).await;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn make_meta<S: Into<String>>(
+    bucket: S,
+    name: S,
+    content_type: String,
+    sha512: Option<&str>,
+) -> Object {
     let name = name.into();
     Object {
         bucket: bucket.into(),
         name,
         content_encoding: Some("gzip".to_string()),
         content_type: Some(content_type),
+        metadata: sha512.map(|sha| HashMap::from([(SHA512_METADATA.to_string(), sha.to_string())])),
 
         ..Default::default()
     }
 }
 
-pub enum ManifestDiff {
-    Update(RelativePathBuf),
-    Delete(RelativePathBuf)
+static CLIENT: RwLock<Option<Client>> = RwLock::new(None);
+
+/// A client authenticated with the application default credentials, set up on first use
+/// and again after the billing project changes.
+pub async fn client() -> Result<Client> {
+    let cached = CLIENT.read().unwrap().clone();
+    if let Some(c) = cached {
+        return Ok(c);
+    }
+    let http = match billing_project() {
+        Some(project) => http::client_with_headers(HeaderMap::from_iter([(
+            HeaderName::from_static(USER_PROJECT_HEADER),
+            HeaderValue::from_str(&project)?,
+        )]))?,
+        None => http::client(),
+    };
+    let config = ClientConfig {
+        http: Some(http),
+        ..ClientConfig::default().with_auth().await?
+    };
+    Ok(CLIENT
+        .write()
+        .unwrap()
+        .get_or_insert_with(|| Client::new(config))
+        .clone())
 }
 
-pub async fn delete_object(client: &StorageClient, bucket: &str, object: &RelativePath) -> Result<()> {
-    client.delete_object(&DeleteObjectRequest {
-        bucket: bucket.to_string(),
-        object: object.to_string(),
-        .. Default::default()
-    }, None).await?;
-    Ok(())
+/// Bucket and object name of a `gs://bucket/object` URL or of a
+/// `https://storage.googleapis.com/bucket/object` one.
+pub fn object_of(src: &Url) -> Option<(String, String)> {
+    let path = src.path().trim_start_matches('/');
+    let (bucket, object) = match (src.scheme(), src.host_str()?) {
+        ("gs", bucket) => (bucket, path),
+        ("http" | "https", "storage.googleapis.com") => path.split_once('/')?,
+        _ => return None,
+    };
+    let object = percent_decode_str(object).decode_utf8().ok()?;
+    Some((bucket.to_string(), object.into_owned()))
 }
 
-pub async fn upload_object(client: &StorageClient, bucket: &str, path: &RelativePath, local_file: &Path) -> Result<Object> {
-    let content_type = mime_guess::from_path(&local_file).first().map(|m| m.to_string()).unwrap_or_else(|| "application/octet-stream".to_string());
-    let meta = make_meta(bucket, path.as_ref(), content_type);
-    let f = File::open(local_file).await?;
-    let reader = BufReader::new(f);
-    let upload_type = UploadType::Multipart(Box::new(meta));
-    let gz_encoder = GzipEncoder::new(reader);
-    let stream =ReaderStream::new(gz_encoder);
-    let upload = client.upload_streamed_object(&UploadObjectRequest {
+/// A GET URL for `object` in `bucket` signed with the default credentials, valid for
+/// `expires`. Signing needs a service account key, or permission to sign as the service
+/// account through IAM.
+pub async fn signed_url(bucket: &str, object: &str, expires: Duration) -> Result<Url> {
+    let mut opts = SignedURLOptions {
+        method: SignedURLMethod::GET,
+        expires,
+        ..Default::default()
+    };
+    if let Some(project) = billing_project() {
+        opts.query_parameters
+            .insert("userProject".to_string(), vec![project]);
+    }
+    let url = client()
+        .await?
+        .signed_url(bucket, object, None, None, opts)
+        .await?;
+    Ok(Url::parse(&url)?)
+}
+
+/// The contents of the object at a `gs://` URL, `None` if there is no such object. Objects
+/// push stored gzipped come back decoded.
+pub async fn fetch(src: &Url) -> Result<Option<Vec<u8>>> {
+    let (bucket, object) = object_or_err(src)?;
+    let path = RelativePathBuf::from(object.as_str());
+    let req = &GetObjectRequest {
+        bucket: bucket.clone(),
+        object,
+        encryption: encryption(),
+        ..Default::default()
+    };
+    let client = &client().await?;
+    let body = with_backoff(&bucket, &path, None, move || async move {
+        Ok(client.download_object(req, &Range::default(), None).await?)
+    })
+    .await;
+    let body = match body {
+        Ok(b) => b,
+        Err(e) if error_status(&e) == Some(404) => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    if !body.starts_with(&GZIP_MAGIC) {
+        return Ok(Some(body));
+    }
+    let mut decoded = Vec::new();
+    GzipDecoder::new(&body[..])
+        .read_to_end(&mut decoded)
+        .await?;
+    Ok(Some(decoded))
+}
+
+/// The first bytes of every gzip stream.
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Where a push uploads changed objects before promoting them, below the bucket prefix.
+const STAGING_PREFIX: &str = ".comstar-staging";
+
+pub async fn delete_object(
+    client: &StorageClient,
+    bucket: &str,
+    object: &RelativePath,
+    tx: &EventSender,
+) -> Result<()> {
+    let req = &DeleteObjectRequest {
         bucket: bucket.to_string(),
+        object: object.to_string(),
         ..Default::default()
-    }, stream, &upload_type, None).await?;
+    };
+    with_backoff(bucket, object, Some(tx), move || async move {
+        Ok(client.delete_object(req, None).await?)
+    })
+    .await
+}
 
-    Ok(upload)
+/// Deletes are small metadata requests, so they get a wider pipeline than uploads.
+fn delete_jobs() -> usize {
+    util::jobs().net * 4
 }
 
-fn diff_manifests(local: &Manifest, remote: Option<&Manifest>) -> Vec<ManifestDiff> {
-    let local_map: HashMap<&RelativePath, &str> = local.entries.iter().map(|e| (e.path.as_relative_path(), e.sha512.as_ref())).collect();
-    let remote_map: Option<HashMap<&RelativePath, &str>> = remote.map(|m| m.entries.iter().map(|e| (e.path.as_relative_path(), e.sha512.as_ref())).collect());
-    let mut update_list = Vec::new();
-    if let Some(remote_map) = remote_map {
-        for (k, v) in local_map.iter() {
-            if let Some(remote_sha) = remote_map.get(k) {
-                if remote_sha != v {
-                    update_list.push( ManifestDiff::Update(k.to_relative_path_buf()));
+/// Deletes every object, carrying on past failures. Returns the objects that could not be
+/// deleted along with their errors.
+async fn delete_objects<I>(
+    client: &StorageClient,
+    bucket: &str,
+    objects: I,
+    tx: &EventSender,
+) -> Result<Vec<(RelativePathBuf, anyhow::Error)>>
+where
+    I: IntoIterator<Item = RelativePathBuf>,
+{
+    let results = util::bounded_tasks(objects, delete_jobs(), |path| {
+        let bucket = bucket.to_string();
+        let t = tx.clone();
+        let client = client.clone();
+        async move {
+            t.send(Event::unknown_file_started(&path.to_string()));
+            match delete_object(&client, &bucket, &path, &t).await {
+                Ok(()) => {
+                    t.send(Event::file_done(&path.to_string()));
+                    Ok(None)
+                }
+                Err(e) => {
+                    t.send(Event::file_failed(&path.to_string(), &e));
+                    Ok(Some((path, e)))
                 }
-            } else {
-                update_list.push(ManifestDiff::Update(k.to_relative_path_buf()));
             }
         }
+    })
+    .await?;
+    Ok(results.into_iter().flatten().collect())
+}
+
+/// Digest and size of an object's bytes as stored, i.e. after gzip.
+#[derive(Debug, Clone)]
+pub struct Encoded {
+    pub sha512: String,
+    pub size: u64,
+}
+
+/// Hashes the gzipped bytes on their way up.
+#[derive(Default)]
+struct EncodedHasher {
+    hasher: util::Hasher,
+    size: u64,
+}
+
+impl EncodedHasher {
+    fn update(&mut self, bytes: &[u8]) {
+        self.hasher.update(bytes);
+        self.size += bytes.len() as u64;
+    }
+
+    fn finish(self) -> Encoded {
+        Encoded {
+            sha512: self.hasher.finish(),
+            size: self.size,
+        }
+    }
+}
 
-        for k in remote_map.keys() {
-            if !local_map.contains_key(k) {
-                update_list.push(ManifestDiff::Delete(k.to_relative_path_buf()));
+pub async fn upload_object(
+    client: &StorageClient,
+    bucket: &str,
+    path: &RelativePath,
+    local_file: &Path,
+    sha512: Option<&str>,
+    tx: &EventSender,
+) -> Result<(Object, Encoded)> {
+    let content_type = mime_guess::from_path(&local_file)
+        .first()
+        .map(|m| m.to_string())
+        .unwrap_or_else(|| "application/octet-stream".to_string());
+    let meta = make_meta(bucket, path.as_ref(), content_type, sha512);
+    let chunked = tokio::fs::metadata(local_file).await?.len() > CHUNKED_UPLOAD_THRESHOLD;
+    let upload_type = &UploadType::Multipart(Box::new(meta));
+    if chunked {
+        let gz_encoder = GzipEncoder::new(BufReader::new(File::open(local_file).await?));
+        return upload_chunked(client, bucket, path, upload_type, gz_encoder, tx).await;
+    }
+    let req = &UploadObjectRequest {
+        bucket: bucket.to_string(),
+        kms_key_name: kms_key(),
+        encryption: encryption(),
+        ..Default::default()
+    };
+    with_backoff(bucket, path, Some(tx), move || async move {
+        // a retry starts over from the first byte
+        let gz_encoder = GzipEncoder::new(BufReader::new(File::open(local_file).await?));
+        let hasher = Arc::new(Mutex::new(EncodedHasher::default()));
+        let stream = ReaderStream::new(gz_encoder).inspect({
+            let hasher = hasher.clone();
+            move |chunk| {
+                if let Ok(bytes) = chunk {
+                    hasher.lock().unwrap().update(bytes);
+                }
             }
+        });
+        let object = client
+            .upload_streamed_object(req, stream, upload_type, None)
+            .await?;
+        let hasher = std::mem::take(&mut *hasher.lock().unwrap());
+        Ok((object, hasher.finish()))
+    })
+    .await
+}
+
+/// Server-side copy of `from` in `from_bucket` to `to` in `bucket`, metadata included.
+/// Large objects, or copies between regions, take several rewrite calls.
+async fn copy_object(
+    client: &StorageClient,
+    from_bucket: &str,
+    from: &RelativePath,
+    bucket: &str,
+    to: &RelativePath,
+    tx: &EventSender,
+) -> Result<()> {
+    let mut rewrite_token = None;
+    loop {
+        let req = &RewriteObjectRequest {
+            destination_bucket: bucket.to_string(),
+            destination_object: to.to_string(),
+            source_bucket: from_bucket.to_string(),
+            source_object: from.to_string(),
+            rewrite_token: rewrite_token.take(),
+            destination_kms_key_name: kms_key(),
+            source_encryption: encryption(),
+            destination_encryption: encryption(),
+            ..Default::default()
+        };
+        let resp = with_backoff(bucket, to, Some(tx), move || async move {
+            Ok(client.rewrite_object(req, None).await?)
+        })
+        .await?;
+        if resp.done {
+            return Ok(());
         }
-    } else {
-        for k in local_map.keys() {
-            update_list.push(ManifestDiff::Update(k.to_relative_path_buf()));
+        rewrite_token = resp.rewrite_token;
+    }
+}
+
+/// Reads until `buf` is full or the reader is done, returning how much was read.
+async fn fill<R: AsyncRead + Unpin>(reader: &mut R, buf: &mut [u8]) -> Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        let n = reader.read(&mut buf[filled..]).await?;
+        if n == 0 {
+            break;
         }
+        filled += n;
     }
+    Ok(filled)
+}
+
+/// Uploads through a resumable session in `UPLOAD_CHUNK` pieces. Each chunk is retried on
+/// its own, and the range GCS reports as stored is checked after every one.
+async fn upload_chunked<R: AsyncRead + Unpin>(
+    client: &StorageClient,
+    bucket: &str,
+    path: &RelativePath,
+    upload_type: &UploadType,
+    mut reader: R,
+    tx: &EventSender,
+) -> Result<(Object, Encoded)> {
+    let req = &UploadObjectRequest {
+        bucket: bucket.to_string(),
+        kms_key_name: kms_key(),
+        encryption: encryption(),
+        ..Default::default()
+    };
+    let uploader = with_backoff(bucket, path, Some(tx), move || async move {
+        Ok(client
+            .prepare_resumable_upload(req, upload_type, None)
+            .await?)
+    })
+    .await?;
+    // the compressed size is only known at the end, so read a chunk ahead to spot the last one
+    let mut current = vec![0u8; UPLOAD_CHUNK];
+    let mut len = fill(&mut reader, &mut current).await?;
+    let mut offset: u64 = 0;
+    let mut hasher = EncodedHasher::default();
+    loop {
+        let mut next = vec![0u8; UPLOAD_CHUNK];
+        let next_len = if len == UPLOAD_CHUNK {
+            fill(&mut reader, &mut next).await?
+        } else {
+            0
+        };
+        let last = next_len == 0;
+        current.truncate(len);
+        hasher.update(&current);
+        let end = offset + len as u64;
+        let size = ChunkSize::new(offset, end.saturating_sub(1), last.then_some(end));
 
-    update_list
+        let mut attempt = 0;
+        let status = loop {
+            wait_turn(bucket).await;
+            match uploader.upload_multiple_chunk(current.clone(), &size).await {
+                Ok(s) => break s,
+                Err(e) if attempt + 1 < MAX_ATTEMPTS => {
+                    attempt += 1;
+                    let e = anyhow::Error::from(e);
+                    let delay = retry_delay(attempt);
+                    if is_throttled(&e) {
+                        back_off(bucket, delay);
+                        tx.send(Event::file_throttled(path.as_str(), delay));
+                    } else {
+                        tx.send(Event::file_retried(path.as_str(), attempt));
+                    }
+                    tracing::warn!(
+                        "Chunk at byte {} of {} failed, retrying: {}",
+                        offset,
+                        path,
+                        e
+                    );
+                    tokio::time::sleep(delay).await;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        };
+        match status {
+            UploadStatus::Ok(object) if last && object.size as u64 == end => {
+                return Ok((object, hasher.finish()))
+            }
+            UploadStatus::ResumeIncomplete(range) if !last && range.last_byte + 1 == end => {}
+            _ => return Err(anyhow!(t!("push.chunk_mismatch", path, offset))),
+        }
+        offset = end;
+        current = next;
+        len = next_len;
+    }
 }
 
-pub async fn push_dir(base: &Path, local_manifest: &Manifest, remote_manifest: Option<&Manifest>, bucket: &str, bucket_prefix: Option<RelativePathBuf>) -> Result<()> {
-    let config = ClientConfig::default().with_auth().await?;
-    let client = Client::new(config);
+/// `local` with the gzipped hash and size of every entry filled in, from this push's uploads
+/// or, for content that didn't change, from the manifest already published.
+fn with_encoded(
+    local: &Manifest,
+    remote: Option<&Manifest>,
+    uploaded: &HashMap<RelativePathBuf, Encoded>,
+) -> Manifest {
+    let published: HashMap<&RelativePath, &ManifestEntry> = remote
+        .map(|m| {
+            m.entries
+                .iter()
+                .map(|e| (e.path.as_relative_path(), e))
+                .collect()
+        })
+        .unwrap_or_default();
+    let mut manifest = local.clone();
+    for e in manifest.entries.iter_mut() {
+        if let Some(enc) = uploaded.get(&e.path) {
+            e.encoded_sha512 = Some(enc.sha512.clone());
+            e.encoded_size = Some(enc.size);
+        } else if let Some(p) = published
+            .get(e.path.as_relative_path())
+            .filter(|p| p.sha512 == e.sha512)
+        {
+            e.encoded_sha512 = p.encoded_sha512.clone();
+            e.encoded_size = p.encoded_size;
+        }
+    }
+    manifest
+}
+
+/// The bucket and prefix a push just went to, which a replica push copies objects from.
+#[derive(Debug, Clone)]
+pub struct Origin {
+    pub bucket: String,
+    pub prefix: Option<RelativePathBuf>,
+}
+
+/// The gzipped hash and size `manifest` records for each entry, where it has them.
+fn recorded_encoded(manifest: &Manifest) -> HashMap<&RelativePath, Encoded> {
+    manifest
+        .entries
+        .iter()
+        .filter_map(|e| {
+            Some((
+                e.path.as_relative_path(),
+                Encoded {
+                    sha512: e.encoded_sha512.clone()?,
+                    size: e.encoded_size?,
+                },
+            ))
+        })
+        .collect()
+}
 
-    let mut diffs = diff_manifests(local_manifest, remote_manifest);
-    if !diffs.is_empty() {
-        diffs.push(ManifestDiff::Update(RelativePathBuf::from("comstar.json")));
+/// Pushes in two phases so the live prefix never holds half a release. Changed objects
+/// are uploaded below a staging prefix and checked there, then copied into place, and the
+/// manifest is only published once every object it lists is live. Objects the new manifest
+/// drops are deleted last. A push that dies part way leaves the live prefix untouched or
+/// with objects the old manifest doesn't know about yet, and running it again reuses the
+/// same staging prefix.
+///
+/// With `origin`, changed objects are copied from there inside GCS instead of uploaded.
+/// Returns the manifest as published, with the gzipped hash and size of every object.
+pub async fn push_dir(
+    base: &Path,
+    local_manifest: &Manifest,
+    remote_manifest: Option<&Manifest>,
+    bucket: &str,
+    bucket_prefix: Option<RelativePathBuf>,
+    checksums: bool,
+    origin: Option<&Origin>,
+) -> Result<Manifest> {
+    let client = client().await?;
+    let recorded = recorded_encoded(local_manifest);
+
+    let diffs = diff_manifests(local_manifest, remote_manifest);
+    // unchanged content keeps what it looks like at rest, changed content changes it anyway
+    let manifest_changed = manifest_changed(
+        &with_encoded(local_manifest, remote_manifest, &HashMap::new()),
+        remote_manifest,
+    )?;
+    let (mut updates, deletes): (Vec<_>, Vec<_>) = diffs
+        .into_iter()
+        .partition(|d| matches!(d, ManifestDiff::Update(_)));
+
+    let hashes: HashMap<&RelativePath, &str> = local_manifest
+        .entries
+        .iter()
+        .map(|e| (e.path.as_relative_path(), e.sha512.as_str()))
+        .collect();
+    if !updates.is_empty() {
+        let stored = stored_hashes(&client, bucket, bucket_prefix.as_deref()).await?;
+        updates.retain(|d| {
+            let path = d.path();
+            match (hashes.get(path), stored.get(path)) {
+                (Some(local), Some(remote)) => *local != remote.as_str(),
+                _ => true,
+            }
+        });
     }
-    let sem = Arc::new(Semaphore::new(10));
+    // named after the release, so pushing it again after a crash picks up where it was
+    let staging = prefixed(
+        bucket_prefix.as_ref(),
+        RelativePathBuf::from(STAGING_PREFIX).join(&local_manifest.digest()?[..16]),
+    );
+    let updates: Vec<RelativePathBuf> = updates.into_iter().map(|d| d.into_path()).collect();
 
-    let (tx, rx) = tokio::sync::mpsc::channel(50);
+    let (tx, rx) = events::channel();
     let h = tokio::spawn(events::event_output(
         rx,
-        "Pushing differences".into(),
-        diffs.len() as u64,
+        t!("progress.pushing"),
+        (updates.len() * 3 + usize::from(manifest_changed) + usize::from(checksums) + deletes.len())
+            as u64,
     ));
-    let mut handles = Vec::new();
 
-    for d in diffs {
-        let base = base.to_path_buf();
+    let uploaded = util::bounded_tasks(updates.iter().cloned(), util::jobs().net, |rel_path| {
         let bucket = bucket.to_string();
-        let permit = sem.clone().acquire_owned().await?;
-        let bucket_prefix = bucket_prefix.clone();
+        let sha512 = hashes
+            .get(rel_path.as_relative_path())
+            .map(|s| s.to_string());
+        let local_file = rel_path.to_path(base);
+        let path = staging.join(&rel_path);
+        let known = recorded.get(rel_path.as_relative_path()).cloned();
         let t = tx.clone();
         let client = client.clone();
-        let fut = async move {
-            
-            match d {
-                ManifestDiff::Update(rel_path) => {
-                    let path = if let Some(ref p) = bucket_prefix {
-                        p.join(rel_path)
-                    } else {
-                        rel_path
-                    };
-                    let local_file = path.to_path(base);
-                    t.send(Event::unknown_file_started(&path.to_string())).await?;
-                    let _obj = upload_object(&client, &bucket, &path, &local_file).await?;
-                    t.send(Event::file_done(&path.to_string())).await?;
+        async move {
+            t.send(Event::unknown_file_started(&path.to_string()));
+            let upload = match origin {
+                Some(o) => {
+                    let from = prefixed(o.prefix.as_ref(), rel_path.clone());
+                    copy_object(&client, &o.bucket, &from, &bucket, &path, &t)
+                        .await
+                        .map(|()| known)
+                }
+                None => upload_object(&client, &bucket, &path, &local_file, sha512.as_deref(), &t)
+                    .await
+                    .map(|(_, encoded)| Some(encoded)),
+            };
+            match upload {
+                Ok(encoded) => {
+                    t.send(Event::file_done(&path.to_string()));
+                    Ok((rel_path, encoded))
+                }
+                Err(e) => {
+                    t.send(Event::file_failed(&path.to_string(), &e));
+                    Err(e)
+                }
+            }
+        }
+    })
+    .await?;
+    let encoded: HashMap<RelativePathBuf, Encoded> = uploaded
+        .into_iter()
+        .filter_map(|(path, enc)| Some((path, enc?)))
+        .collect();
 
-                },
-                ManifestDiff::Delete(rel_path) => {
-                    let path = if let Some(ref p) = bucket_prefix {
-                        p.join(rel_path)
-                    } else {
-                        rel_path
-                    };
-                    t.send(Event::unknown_file_started(&path.to_string())).await?;
-                    delete_object(&client, &bucket, &path).await?;
-                    t.send(Event::file_done(&path.to_string())).await?;
-                },
+    if !updates.is_empty() {
+        let staged = stored_hashes(&client, bucket, Some(&staging)).await?;
+        for path in &updates {
+            let expected = hashes.get(path.as_relative_path()).copied();
+            if expected.is_none() || staged.get(path).map(String::as_str) != expected {
+                return Err(anyhow!(t!("push.staging_mismatch", path)));
             }
-            drop(permit);
-            Ok::<(), anyhow::Error>(())
-        };
-        let handle = tokio::spawn(fut);
-        handles.push(handle);
+        }
     }
 
-    for h in handles {
-        h.await??;
+    util::bounded_tasks(updates.iter().cloned(), util::jobs().net, |rel_path| {
+        let bucket = bucket.to_string();
+        let from = staging.join(&rel_path);
+        let to = prefixed(bucket_prefix.as_ref(), rel_path);
+        let t = tx.clone();
+        let client = client.clone();
+        async move {
+            t.send(Event::unknown_file_started(&to.to_string()));
+            if let Err(e) = copy_object(&client, &bucket, &from, &bucket, &to, &t).await {
+                t.send(Event::file_failed(&to.to_string(), &e));
+                return Err(e);
+            }
+            t.send(Event::file_done(&to.to_string()));
+            Ok(())
+        }
+    })
+    .await?;
+
+    // what the objects look like at rest is only known now that they are uploaded
+    let published = with_encoded(local_manifest, remote_manifest, &encoded);
+    if manifest_changed {
+        manifest::write_manifest(&published, base)?;
+        let path = prefixed(
+            bucket_prefix.as_ref(),
+            RelativePathBuf::from("comstar.json"),
+        );
+        tx.send(Event::unknown_file_started(&path.to_string()));
+        if let Err(e) = upload_object(
+            &client,
+            bucket,
+            &path,
+            &base.join("comstar.json"),
+            None,
+            &tx,
+        )
+        .await
+        {
+            tx.send(Event::file_failed(&path.to_string(), &e));
+            return Err(e);
+        }
+        tx.send(Event::file_done(&path.to_string()));
     }
-    tx.send(Event::close()).await?;
-    h.await??;
-    
+    if checksums {
+        let path = prefixed(
+            bucket_prefix.as_ref(),
+            RelativePathBuf::from(manifest::CHECKSUMS_FILE),
+        );
+        tx.send(Event::unknown_file_started(&path.to_string()));
+        if let Err(e) = upload_object(
+            &client,
+            bucket,
+            &path,
+            &base.join(manifest::CHECKSUMS_FILE),
+            None,
+            &tx,
+        )
+        .await
+        {
+            tx.send(Event::file_failed(&path.to_string(), &e));
+            return Err(e);
+        }
+        tx.send(Event::file_done(&path.to_string()));
+    }
+
+    // the staged copies are no longer needed, failing to remove them only costs storage
+    let leftover = delete_objects(
+        &client,
+        bucket,
+        updates.into_iter().map(|p| staging.join(p)),
+        &tx,
+    )
+    .await?;
+    for (path, e) in leftover {
+        tracing::warn!("Could not delete staged object {}: {}", path, e);
+    }
+
+    let failed = delete_objects(
+        &client,
+        bucket,
+        deletes
+            .into_iter()
+            .map(|d| prefixed(bucket_prefix.as_ref(), d.into_path())),
+        &tx,
+    )
+    .await?;
+    tx.send(Event::close());
+    let stats = h.await??;
+    if let Err(e) = stats.save(base, "push") {
+        tracing::warn!("Could not write push statistics: {}", e);
+    }
+
+    if !failed.is_empty() {
+        let mut msg = t!("push.delete_failed", failed.len());
+        for (path, e) in failed {
+            msg.push_str(&format!("\n  {}: {}", path, e));
+        }
+        return Err(anyhow!(msg));
+    }
+    Ok(published)
+}
+
+/// The prefix the manifest `object` and the objects it lists are stored below.
+fn prefix_of(object: &str) -> Option<RelativePathBuf> {
+    RelativePath::new(object)
+        .parent()
+        .filter(|p| !p.as_str().is_empty())
+        .map(|p| p.to_relative_path_buf())
+}
+
+fn object_or_err(url: &Url) -> Result<(String, String)> {
+    object_of(url).ok_or_else(|| anyhow!("Not a GCS object URL: {}", url))
+}
+
+/// The staged `push_dir` to the bucket and prefix of the manifest URL `target`.
+pub async fn push_to(
+    base: &Path,
+    target: &Url,
+    local_manifest: &Manifest,
+    remote_manifest: Option<&Manifest>,
+    checksums: bool,
+) -> Result<Manifest> {
+    let (bucket, object) = object_or_err(target)?;
+    push_dir(
+        base,
+        local_manifest,
+        remote_manifest,
+        &bucket,
+        prefix_of(&object),
+        checksums,
+        None,
+    )
+    .await
+}
+
+/// Uploads `local_file` to the object `dest` points at on its own, outside a staged push.
+pub async fn put(dest: &Url, local_file: &Path, sha512: Option<&str>) -> Result<()> {
+    let (bucket, object) = object_or_err(dest)?;
+    // whoever stores a single object reports progress for it
+    let (tx, _) = events::channel();
+    let client = client().await?;
+    upload_object(
+        &client,
+        &bucket,
+        RelativePath::new(&object),
+        local_file,
+        sha512,
+        &tx,
+    )
+    .await?;
+    Ok(())
+}
+
+pub async fn delete(url: &Url) -> Result<()> {
+    let (bucket, object) = object_or_err(url)?;
+    let (tx, _) = events::channel();
+    let client = client().await?;
+    match delete_object(&client, &bucket, RelativePath::new(&object), &tx).await {
+        Err(e) if error_status(&e) != Some(404) => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// `gs://` URLs of every object below `prefix`.
+pub async fn list(prefix: &Url) -> Result<Vec<Url>> {
+    let (bucket, object) = object_or_err(prefix)?;
+    let below = RelativePath::new(object.trim_end_matches('/'));
+    let below = Some(below).filter(|p| !p.as_str().is_empty());
+    list_objects(&client().await?, &bucket, below)
+        .await?
+        .into_iter()
+        .map(|o| {
+            let mut url = Url::parse(&format!("gs://{}/", bucket))?;
+            url.path_segments_mut()
+                .map_err(|_| anyhow!("Cannot build a GCS URL for {}", o.name))?
+                .clear()
+                .extend(o.name.split('/'));
+            Ok(url)
+        })
+        .collect()
+}
+
+/// Brings the replica whose manifest is at `replica` up to date with `published`, the
+/// manifest a push to `primary` just published. Hashes are reused and changed objects are
+/// copied from `origin` inside GCS, so nothing is read from disk again but the manifest.
+/// Sources are rebased onto the replica so its manifest is served from there.
+pub async fn push_replica(
+    base: &Path,
+    published: &Manifest,
+    primary: &Url,
+    origin: &Origin,
+    replica: &Url,
+    checksums: bool,
+) -> Result<()> {
+    let (bucket, object) =
+        object_of(replica).ok_or_else(|| anyhow!(t!("push.bad_replica", replica)))?;
+    let prefix = prefix_of(&object);
+    let (primary_base, replica_base) = (mirrors::base_of(primary)?, mirrors::base_of(replica)?);
+    let mut local_manifest = published.clone();
+    for e in local_manifest.entries.iter_mut() {
+        if let Some(src) = mirrors::rebase(&e.source, &primary_base, &replica_base) {
+            e.source = src;
+        }
+    }
+    manifest::write_manifest(&local_manifest, base)?;
+    let remote_manifest = manifest::get_manifest(replica).await?;
+    push_dir(
+        base,
+        &local_manifest,
+        remote_manifest.as_ref(),
+        &bucket,
+        prefix,
+        checksums,
+        Some(origin),
+    )
+    .await?;
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Lists every object under `prefix`, following pagination.
+async fn list_objects(
+    client: &StorageClient,
+    bucket: &str,
+    prefix: Option<&RelativePath>,
+) -> Result<Vec<Object>> {
+    let mut objects = Vec::new();
+    let mut page_token = None;
+    let listed = prefix.unwrap_or(RelativePath::new(""));
+    loop {
+        let req = &ListObjectsRequest {
+            bucket: bucket.to_string(),
+            prefix: prefix.map(|p| format!("{}/", p)),
+            page_token: page_token.take(),
+            ..Default::default()
+        };
+        let resp = with_backoff(bucket, listed, None, move || async move {
+            Ok(client.list_objects(req, None).await?)
+        })
+        .await?;
+        objects.extend(resp.items.unwrap_or_default());
+        match resp.next_page_token {
+            Some(token) => page_token = Some(token),
+            None => break,
+        }
+    }
+    Ok(objects)
+}
+
+/// The sha512 recorded on upload for every object under `prefix`, keyed by path below it.
+async fn stored_hashes(
+    client: &StorageClient,
+    bucket: &str,
+    prefix: Option<&RelativePath>,
+) -> Result<HashMap<RelativePathBuf, String>> {
+    Ok(list_objects(client, bucket, prefix)
+        .await?
+        .into_iter()
+        .filter_map(|o| {
+            let sha = o.metadata?.remove(SHA512_METADATA)?;
+            let name = RelativePath::new(&o.name);
+            let path = match prefix {
+                Some(p) => name.strip_prefix(p).ok()?.to_relative_path_buf(),
+                None => name.to_relative_path_buf(),
+            };
+            Some((path, sha))
+        })
+        .collect())
+}
+
+/// Compares the bucket listing against `manifest` without downloading anything.
+///
+/// Objects are stored gzipped, so the size and CRC32C/MD5 GCS keeps describe the compressed
+/// bytes. Content is checked against the sha512 recorded in the object metadata on upload.
+/// Objects pushed before that was recorded are checked against the gzipped size in the
+/// manifest where it has one, otherwise only for existence.
+pub async fn verify_bucket(
+    manifest: &Manifest,
+    bucket: &str,
+    bucket_prefix: Option<RelativePathBuf>,
+    force: bool,
+) -> Result<Vec<ValidationDifference>> {
+    let client = client().await?;
+    let remote: HashMap<RelativePathBuf, Object> =
+        list_objects(&client, bucket, bucket_prefix.as_deref())
+            .await?
+            .into_iter()
+            .filter_map(|o| {
+                let name = RelativePath::new(&o.name);
+                let path = match &bucket_prefix {
+                    Some(p) => name.strip_prefix(p).ok()?.to_relative_path_buf(),
+                    None => name.to_relative_path_buf(),
+                };
+                Some((path, o))
+            })
+            .collect();
+
+    let mut differences = Vec::new();
+    for e in &manifest.entries {
+        match remote.get(e.path.as_relative_path()) {
+            None => differences.push(ValidationDifference::missing(e.path.clone(), e.clone())),
+            Some(o) => {
+                let stored = o.metadata.as_ref().and_then(|m| m.get(SHA512_METADATA));
+                if let Some(sha) = stored.filter(|sha| **sha != e.sha512) {
+                    differences.push(ValidationDifference::hash_mismatch(
+                        e.path.clone(),
+                        e.clone(),
+                        sha.clone(),
+                    ));
+                } else if stored.is_none()
+                    && e.encoded_size.is_some_and(|size| size != o.size as u64)
+                {
+                    differences.push(ValidationDifference::hash_mismatch(
+                        e.path.clone(),
+                        e.clone(),
+                        format!("{} bytes", o.size),
+                    ));
+                }
+            }
+        }
+    }
+    if force {
+        let known: HashSet<&RelativePath> = manifest
+            .entries
+            .iter()
+            .map(|e| e.path.as_relative_path())
+            .collect();
+        for path in remote.keys() {
+            if path.as_str() != "comstar.json"
+                && path.as_str() != manifest::CHECKSUMS_FILE
+                && !path.starts_with(STAGING_PREFIX)
+                && !known.contains(path.as_relative_path())
+            {
+                differences.push(ValidationDifference::unknown_file(path.clone()));
+            }
+        }
+    }
+    Ok(differences)
+}
@@ -0,0 +1,166 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+};
+
+use anyhow::{anyhow, Result};
+use relative_path::{RelativePath, RelativePathBuf};
+use reqwest::{
+    header::{CONTENT_LENGTH, CONTENT_TYPE},
+    Body, Method, StatusCode,
+};
+use tokio::{fs::File, sync::Mutex};
+use tokio_util::io::ReaderStream;
+use url::Url;
+
+use crate::{
+    github::{self, Release, Repo},
+    i18n::t,
+    manifest::Manifest,
+};
+
+/// Releases a push looked up or created, by `owner/repo@tag`, so uploads running side by
+/// side to a new release don't each create it.
+static RELEASES: Mutex<BTreeMap<String, Release>> = Mutex::const_new(BTreeMap::new());
+
+/// Release assets live in one flat namespace and GitHub renames anything but these, so
+/// directories are spelled `--` and other characters `_`.
+fn asset_name(path: &RelativePath) -> String {
+    path.components()
+        .map(|c| {
+            c.as_str()
+                .chars()
+                .map(|ch| match ch {
+                    'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '-' | '_' => ch,
+                    _ => '_',
+                })
+                .collect::<String>()
+        })
+        .collect::<Vec<_>>()
+        .join("--")
+}
+
+/// Points every entry of `manifest` at the release asset it will be uploaded as. Fails if
+/// two files would end up as the same asset.
+pub fn set_sources(manifest: &mut Manifest, repo: &Repo, tag: &str) -> Result<()> {
+    let mut taken: HashMap<String, RelativePathBuf> = HashMap::new();
+    for entry in manifest.entries.iter_mut() {
+        let name = asset_name(&entry.path);
+        if let Some(other) = taken.insert(name.clone(), entry.path.clone()) {
+            return Err(anyhow!(t!("github.asset_clash", other, entry.path, name)));
+        }
+        entry.source = github::asset_url(repo, tag, &name)?;
+    }
+    Ok(())
+}
+
+async fn create_release(repo: &Repo, tag: &str) -> Result<Release> {
+    let url = format!("https://api.github.com/repos/{}/releases", repo);
+    let resp = github::api(Method::POST, &url)?
+        .json(&serde_json::json!({ "tag_name": tag, "name": tag }))
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(resp.json().await?)
+}
+
+/// The release `tag` of `repo` as it was when the push started, created if it doesn't exist.
+async fn release_for(repo: &Repo, tag: &str) -> Result<Release> {
+    if github::token().is_none() {
+        return Err(anyhow!(t!("github.no_token")));
+    }
+    let mut releases = RELEASES.lock().await;
+    let key = format!("{}@{}", repo, tag);
+    if let Some(r) = releases.get(&key) {
+        return Ok(r.clone());
+    }
+    let release = match github::fetch_release(repo, tag).await? {
+        Some(r) => r,
+        None => create_release(repo, tag).await?,
+    };
+    releases.insert(key, release.clone());
+    Ok(release)
+}
+
+async fn delete_asset(repo: &Repo, id: u64) -> Result<()> {
+    let url = format!(
+        "https://api.github.com/repos/{}/releases/assets/{}",
+        repo, id
+    );
+    let resp = github::api(Method::DELETE, &url)?.send().await?;
+    match resp.status() {
+        s if s.is_success() || s == StatusCode::NOT_FOUND => Ok(()),
+        s => Err(anyhow!(t!("github.failed", "DELETE", url, s))),
+    }
+}
+
+/// Uploads `local_file` as asset `name`, streaming it from disk.
+async fn upload_asset(release: &Release, name: &str, local_file: &Path) -> Result<()> {
+    // the upload URL is a template, `.../assets{?name,label}`
+    let base = release
+        .upload_url
+        .split('{')
+        .next()
+        .unwrap_or(&release.upload_url);
+    let mut url = Url::parse(base)?;
+    url.query_pairs_mut().append_pair("name", name);
+    let f = File::open(local_file).await?;
+    let len = f.metadata().await?.len();
+    let content_type = mime_guess::from_path(local_file)
+        .first()
+        .map(|m| m.to_string())
+        .unwrap_or_else(|| "application/octet-stream".to_string());
+    let resp = github::api(Method::POST, url.as_str())?
+        .header(CONTENT_TYPE, content_type)
+        .header(CONTENT_LENGTH, len)
+        .body(Body::wrap_stream(ReaderStream::new(f)))
+        .send()
+        .await?;
+    if !resp.status().is_success() {
+        return Err(anyhow!(t!("github.failed", "POST", url, resp.status())));
+    }
+    Ok(())
+}
+
+/// Assets can't be overwritten, so an existing one is deleted before its replacement goes
+/// up.
+async fn replace_asset(
+    repo: &Repo,
+    release: &Release,
+    name: &str,
+    local_file: &Path,
+) -> Result<()> {
+    if let Some(old) = release.asset(name) {
+        delete_asset(repo, old.id).await?;
+    }
+    upload_asset(release, name, local_file).await
+}
+
+/// Uploads `local_file` as the asset a `github://owner/repo/tag/asset` URL names, creating
+/// the release if needed. Sources must already point at the release, see `set_sources`.
+pub async fn put(dest: &Url, local_file: &Path) -> Result<()> {
+    let asset = github::asset_of(dest)?;
+    let release = release_for(&asset.repo, &asset.tag).await?;
+    replace_asset(&asset.repo, &release, &asset.name, local_file).await
+}
+
+pub async fn delete(url: &Url) -> Result<()> {
+    let asset = github::asset_of(url)?;
+    let release = release_for(&asset.repo, &asset.tag).await?;
+    match release.asset(&asset.name) {
+        Some(a) => delete_asset(&asset.repo, a.id).await,
+        None => Ok(()),
+    }
+}
+
+/// `github://` URLs of every asset of the release `prefix`, `github://owner/repo/tag/`,
+/// points at.
+pub async fn list(prefix: &Url) -> Result<Vec<Url>> {
+    let release = github::asset_of(&prefix.join("_")?)?;
+    release_for(&release.repo, &release.tag)
+        .await?
+        .assets
+        .iter()
+        .map(|a| github::asset_url(&release.repo, &release.tag, &a.name))
+        .collect()
+}
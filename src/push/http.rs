@@ -0,0 +1,62 @@
+use std::{path::Path, sync::RwLock};
+
+use anyhow::{anyhow, Result};
+use reqwest::{
+    header::{HeaderName, HeaderValue, CONTENT_LENGTH},
+    Body, Method, RequestBuilder, StatusCode,
+};
+use tokio::fs::File;
+use tokio_util::io::ReaderStream;
+use url::Url;
+
+use crate::{http, i18n::t};
+
+/// Header and value sent with every PUT and DELETE, from `--auth-header` and
+/// `COMSTAR_HTTP_AUTH`.
+static AUTH: RwLock<Option<(HeaderName, HeaderValue)>> = RwLock::new(None);
+
+/// Sends the value of `COMSTAR_HTTP_AUTH`, if set, in the header `auth_header` with every
+/// upload. The secret stays out of argv and shell history.
+pub fn set_auth(auth_header: &str) -> Result<()> {
+    *AUTH.write().unwrap() = match std::env::var("COMSTAR_HTTP_AUTH") {
+        Ok(value) => Some((
+            HeaderName::from_bytes(auth_header.as_bytes())?,
+            HeaderValue::from_str(&value)?,
+        )),
+        Err(_) => None,
+    };
+    Ok(())
+}
+
+fn request(method: Method, url: &Url) -> RequestBuilder {
+    let req = http::client().request(method, url.clone());
+    match AUTH.read().unwrap().clone() {
+        Some((name, value)) => req.header(name, value),
+        None => req,
+    }
+}
+
+/// Stores `local_file` at `url` with a plain PUT, e.g. to nginx with the dav module or an
+/// S3 compatible gateway, streaming it from disk.
+pub async fn put(url: &Url, local_file: &Path) -> Result<()> {
+    let f = File::open(local_file).await?;
+    let len = f.metadata().await?.len();
+    let resp = request(Method::PUT, url)
+        .header(CONTENT_LENGTH, len)
+        .body(Body::wrap_stream(ReaderStream::new(f)))
+        .send()
+        .await?;
+    if !resp.status().is_success() {
+        return Err(anyhow!(t!("push.http_failed", "PUT", url, resp.status())));
+    }
+    Ok(())
+}
+
+/// Deletes `url`. Deleting something that isn't there is fine.
+pub async fn delete(url: &Url) -> Result<()> {
+    let resp = request(Method::DELETE, url).send().await?;
+    match resp.status() {
+        s if s.is_success() || s == StatusCode::NOT_FOUND => Ok(()),
+        s => Err(anyhow!(t!("push.http_failed", "DELETE", url, s))),
+    }
+}
@@ -0,0 +1,91 @@
+use std::{collections::HashMap, path::Path};
+
+use anyhow::Result;
+use relative_path::RelativePathBuf;
+use url::Url;
+
+use crate::{
+    events::{self, Event},
+    i18n::t,
+    ipfs,
+    manifest::{self, Manifest},
+    util,
+};
+
+fn ipfs_url(cid: &str) -> Result<Url> {
+    Ok(Url::parse(&format!("ipfs://{}", cid))?)
+}
+
+/// Adds every file of `local_manifest` to the local IPFS node and points its `source` at
+/// the CID it got, then writes the manifest and adds it too. Unchanged files come back
+/// with the CID they had before, so there is nothing to diff or delete. Returns the
+/// `ipfs://` URLs of the manifest and, with `checksums`, of the checksums file.
+pub async fn push_dir(
+    base: &Path,
+    local_manifest: &mut Manifest,
+    checksums: bool,
+) -> Result<(Url, Option<Url>)> {
+    let (tx, rx) = events::channel();
+    let h = tokio::spawn(events::event_output(
+        rx,
+        t!("progress.pushing"),
+        (local_manifest.entries.len() + 1 + usize::from(checksums)) as u64,
+    ));
+
+    let paths: Vec<RelativePathBuf> = local_manifest
+        .entries
+        .iter()
+        .map(|e| e.path.clone())
+        .collect();
+    let added = util::bounded_tasks(paths, util::jobs().net, |rel_path| {
+        let local_file = rel_path.to_path(base);
+        let t = tx.clone();
+        async move {
+            t.send(Event::unknown_file_started(rel_path.as_str()));
+            match ipfs::add(&local_file).await {
+                Ok(cid) => {
+                    t.send(Event::file_done(rel_path.as_str()));
+                    Ok((rel_path, cid))
+                }
+                Err(e) => {
+                    t.send(Event::file_failed(rel_path.as_str(), &e));
+                    Err(e)
+                }
+            }
+        }
+    })
+    .await?;
+    let cids: HashMap<RelativePathBuf, String> = added.into_iter().collect();
+    for entry in local_manifest.entries.iter_mut() {
+        entry.source = ipfs_url(&cids[&entry.path])?;
+    }
+    manifest::write_manifest(local_manifest, base)?;
+    if checksums {
+        manifest::write_checksums(local_manifest, base)?;
+    }
+
+    let mut companions = vec!["comstar.json"];
+    if checksums {
+        companions.push(manifest::CHECKSUMS_FILE);
+    }
+    let mut urls = Vec::new();
+    for name in companions {
+        tx.send(Event::unknown_file_started(name));
+        let cid = match ipfs::add(&base.join(name)).await {
+            Ok(cid) => cid,
+            Err(e) => {
+                tx.send(Event::file_failed(name, &e));
+                return Err(e);
+            }
+        };
+        tx.send(Event::file_done(name));
+        urls.push(ipfs_url(&cid)?);
+    }
+    tx.send(Event::close());
+    let stats = h.await??;
+    if let Err(e) = stats.save(base, "push") {
+        tracing::warn!("Could not write push statistics: {}", e);
+    }
+    let mut urls = urls.into_iter();
+    Ok((urls.next().unwrap(), urls.next()))
+}
@@ -0,0 +1,25 @@
+use std::{
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+
+/// Copies `from` over `to` through a temporary file next to it, so readers of the mirror
+/// never see half a file.
+pub async fn copy_into_place(from: &Path, to: &Path) -> Result<()> {
+    if let Some(parent) = to.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    let partial = PathBuf::from(format!("{}.comstar-part", to.display()));
+    tokio::fs::copy(from, &partial).await?;
+    tokio::fs::rename(&partial, to).await?;
+    Ok(())
+}
+
+pub async fn remove(path: &Path) -> Result<()> {
+    match tokio::fs::remove_file(path).await {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
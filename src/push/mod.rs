@@ -1 +1,367 @@
-pub mod gcs;
+pub mod gcs;
+pub mod github;
+pub mod http;
+pub mod ipfs;
+pub mod local;
+pub mod oci;
+pub mod s3;
+pub mod sftp;
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    path::Path,
+    time::SystemTime,
+};
+
+use anyhow::{anyhow, Result};
+use path_slash::PathExt;
+use relative_path::{RelativePath, RelativePathBuf};
+use url::Url;
+
+use crate::{
+    backend::{self, Backend},
+    events::{self, Event, EventSender},
+    i18n::t,
+    manifest::{self, Manifest},
+    mirrors, util,
+};
+
+/// Size and modification time of every file a manifest of the tree would list.
+pub type TreeScan = BTreeMap<RelativePathBuf, (u64, Option<SystemTime>)>;
+
+/// Looks at the tree without hashing anything, enough to tell later whether it changed.
+pub fn quick_scan(dir: &Path) -> Result<TreeScan> {
+    let mut scan = TreeScan::new();
+    for d in util::get_walker(dir)?.filter_map(|d| d.ok()) {
+        if !d.path().is_file() {
+            continue;
+        }
+        let relative = d.path().strip_prefix(dir)?.to_slash_lossy().to_string();
+        scan.insert(relative.into(), util::fingerprint(&d.metadata()?));
+    }
+    Ok(scan)
+}
+
+/// Fails, listing what changed, if the tree no longer looks like it did when `before` was
+/// taken, so a manifest generated in between would not describe what gets uploaded.
+pub fn check_unchanged(dir: &Path, before: &TreeScan) -> Result<()> {
+    let after = quick_scan(dir)?;
+    let mut changes = Vec::new();
+    for (path, fingerprint) in before {
+        match after.get(path) {
+            None => changes.push(('-', path)),
+            Some(now) if now != fingerprint => changes.push(('~', path)),
+            Some(_) => {}
+        }
+    }
+    for path in after.keys().filter(|p| !before.contains_key(*p)) {
+        changes.push(('+', path));
+    }
+    if changes.is_empty() {
+        return Ok(());
+    }
+    changes.sort_by_key(|(_, p)| p.as_str());
+    for (marker, path) in &changes {
+        eprintln!("{} {}", marker, path);
+    }
+    Err(anyhow!(t!("push.dirty", changes.len())))
+}
+
+/// `path` below the bucket prefix, if there is one.
+pub fn prefixed(prefix: Option<&RelativePathBuf>, path: RelativePathBuf) -> RelativePathBuf {
+    match prefix {
+        Some(p) => p.join(path),
+        None => path,
+    }
+}
+
+pub enum ManifestDiff {
+    Update(RelativePathBuf),
+    Delete(RelativePathBuf),
+}
+
+impl ManifestDiff {
+    pub fn path(&self) -> &RelativePath {
+        match self {
+            ManifestDiff::Update(p) | ManifestDiff::Delete(p) => p,
+        }
+    }
+
+    pub fn into_path(self) -> RelativePathBuf {
+        match self {
+            ManifestDiff::Update(p) | ManifestDiff::Delete(p) => p,
+        }
+    }
+}
+
+/// What has to be uploaded and deleted to turn the published `remote` into `local`.
+pub fn diff_manifests(local: &Manifest, remote: Option<&Manifest>) -> Vec<ManifestDiff> {
+    let local_map: HashMap<&RelativePath, &str> = local
+        .entries
+        .iter()
+        .map(|e| (e.path.as_relative_path(), e.sha512.as_ref()))
+        .collect();
+    let remote_map: Option<HashMap<&RelativePath, &str>> = remote.map(|m| {
+        m.entries
+            .iter()
+            .map(|e| (e.path.as_relative_path(), e.sha512.as_ref()))
+            .collect()
+    });
+    let mut update_list = Vec::new();
+    if let Some(remote_map) = remote_map {
+        for (k, v) in local_map.iter() {
+            if let Some(remote_sha) = remote_map.get(k) {
+                if remote_sha != v {
+                    update_list.push(ManifestDiff::Update(k.to_relative_path_buf()));
+                }
+            } else {
+                update_list.push(ManifestDiff::Update(k.to_relative_path_buf()));
+            }
+        }
+
+        for k in remote_map.keys() {
+            if !local_map.contains_key(k) {
+                update_list.push(ManifestDiff::Delete(k.to_relative_path_buf()));
+            }
+        }
+    } else {
+        for k in local_map.keys() {
+            update_list.push(ManifestDiff::Update(k.to_relative_path_buf()));
+        }
+    }
+
+    update_list
+}
+
+/// Whether publishing `local` changes anything about the published `remote`, be it files,
+/// modes, links, empty directories or header fields like the notes. When it was generated
+/// doesn't count.
+pub fn manifest_changed(local: &Manifest, remote: Option<&Manifest>) -> Result<bool> {
+    match remote {
+        Some(r) => Ok(local.content_digest(r.generated_at)? != r.unsigned_digest()?),
+        None => Ok(true),
+    }
+}
+
+/// Where `src`, a source of the manifest published below `from`, is stored when pushing
+/// below `to`. `None` for a source outside the published tree, which isn't the push's to
+/// write or delete, unless the two are the same place.
+fn destination(src: &Url, from: &Url, to: &Url) -> Option<Url> {
+    mirrors::rebase(src, from, to).or_else(|| (from == to).then(|| src.clone()))
+}
+
+/// Fails with every delete that went wrong, if any did.
+fn check_deleted(failed: Vec<(String, anyhow::Error)>) -> Result<()> {
+    if failed.is_empty() {
+        return Ok(());
+    }
+    let mut msg = t!("push.delete_failed", failed.len());
+    for (path, e) in failed {
+        msg.push_str(&format!("\n  {}: {}", path, e));
+    }
+    Err(anyhow!(msg))
+}
+
+async fn delete_all<I>(
+    backend: &dyn Backend,
+    urls: I,
+    tx: &EventSender,
+) -> Vec<(String, anyhow::Error)>
+where
+    I: IntoIterator<Item = (String, Url)>,
+{
+    let mut failed = Vec::new();
+    for (name, url) in urls {
+        tx.send(Event::unknown_file_started(&name));
+        match backend.delete_file(&url).await {
+            Ok(()) => tx.send(Event::file_done(&name)),
+            Err(e) => {
+                tx.send(Event::file_failed(&name, &e));
+                failed.push((name, e));
+            }
+        }
+    }
+    failed
+}
+
+/// Pushes `base` to the manifest URL `target` through `backend`'s `put_file` and
+/// `delete_file`. Changed files are stored first, then the manifest, then files the new
+/// manifest drops are deleted, so the published manifest never lists a file that isn't
+/// there yet. Files go below `target` at the same place their sources have below the
+/// manifest's own URL, which lets consumers read from somewhere else than is pushed to.
+pub async fn push_dir(
+    backend: &'static dyn Backend,
+    base: &Path,
+    target: &Url,
+    local_manifest: &Manifest,
+    remote_manifest: Option<&Manifest>,
+    checksums: bool,
+) -> Result<()> {
+    let published_at = mirrors::base_of(&local_manifest.source)?;
+    let root = mirrors::base_of(target)?;
+    let diffs = diff_manifests(local_manifest, remote_manifest);
+    let manifest_changed = manifest_changed(local_manifest, remote_manifest)?;
+    let (updates, deletes): (Vec<_>, Vec<_>) = diffs
+        .into_iter()
+        .partition(|d| matches!(d, ManifestDiff::Update(_)));
+    let updates: Vec<RelativePathBuf> = updates.into_iter().map(|d| d.into_path()).collect();
+
+    let sources: HashMap<&RelativePath, (&Url, &str)> = local_manifest
+        .entries
+        .iter()
+        .map(|e| (e.path.as_relative_path(), (&e.source, e.sha512.as_str())))
+        .collect();
+    // another file may have taken a dropped file's place, e.g. a flattened asset name
+    let kept: HashSet<Url> = local_manifest
+        .entries
+        .iter()
+        .filter_map(|e| destination(&e.source, &published_at, &root))
+        .collect();
+    let published: HashMap<&RelativePath, &Url> = remote_manifest
+        .map(|m| {
+            m.entries
+                .iter()
+                .map(|e| (e.path.as_relative_path(), &e.source))
+                .collect()
+        })
+        .unwrap_or_default();
+    let deletes: Vec<(String, Url)> = deletes
+        .into_iter()
+        .filter_map(|d| {
+            let path = d.into_path();
+            let src = published.get(path.as_relative_path())?;
+            let url = destination(src, &published_at, &root).filter(|u| !kept.contains(u))?;
+            Some((path.to_string(), url))
+        })
+        .collect();
+
+    let (tx, rx) = events::channel();
+    let h = tokio::spawn(events::event_output(
+        rx,
+        t!("progress.pushing"),
+        (updates.len() + usize::from(manifest_changed) + usize::from(checksums) + deletes.len())
+            as u64,
+    ));
+
+    util::bounded_tasks(updates, util::jobs().net, |rel_path| {
+        let (src, sha512) = sources[rel_path.as_relative_path()];
+        let url = destination(src, &published_at, &root)
+            .ok_or_else(|| anyhow!("{} is not below {}", src, published_at));
+        let sha512 = sha512.to_string();
+        let local_file = rel_path.to_path(base);
+        let t = tx.clone();
+        async move {
+            t.send(Event::unknown_file_started(rel_path.as_str()));
+            let res = match url {
+                Ok(url) => backend.put_file(&url, &local_file, Some(&sha512)).await,
+                Err(e) => Err(e),
+            };
+            match res {
+                Ok(()) => {
+                    t.send(Event::file_done(rel_path.as_str()));
+                    Ok(())
+                }
+                Err(e) => {
+                    t.send(Event::file_failed(rel_path.as_str(), &e));
+                    Err(e)
+                }
+            }
+        }
+    })
+    .await?;
+
+    let mut companions = Vec::new();
+    if manifest_changed {
+        companions.push(("comstar.json", target.clone()));
+    }
+    if checksums {
+        companions.push((
+            manifest::CHECKSUMS_FILE,
+            target.join(manifest::CHECKSUMS_FILE)?,
+        ));
+    }
+    for (name, url) in companions {
+        tx.send(Event::unknown_file_started(name));
+        if let Err(e) = backend.put_file(&url, &base.join(name), None).await {
+            tx.send(Event::file_failed(name, &e));
+            return Err(e);
+        }
+        tx.send(Event::file_done(name));
+    }
+
+    let failed = delete_all(backend, deletes, &tx).await;
+    tx.send(Event::close());
+    let stats = h.await??;
+    if let Err(e) = stats.save(base, "push") {
+        tracing::warn!("Could not write push statistics: {}", e);
+    }
+    check_deleted(failed)
+}
+
+/// Deletes everything `backend` lists below `target` that `published` doesn't list, other
+/// than the manifest and its checksums.
+async fn prune(backend: &dyn Backend, target: &Url, published: &Manifest) -> Result<()> {
+    let published_at = mirrors::base_of(&published.source)?;
+    let root = mirrors::base_of(target)?;
+    let mut known: HashSet<Url> = published
+        .entries
+        .iter()
+        .filter_map(|e| destination(&e.source, &published_at, &root))
+        .collect();
+    known.insert(target.clone());
+    known.insert(target.join(manifest::CHECKSUMS_FILE)?);
+    let strays: Vec<(String, Url)> = backend
+        .list(&root)
+        .await?
+        .into_iter()
+        .filter(|u| !known.contains(u))
+        .map(|u| {
+            let name = u.as_str().strip_prefix(root.as_str()).unwrap_or(u.as_str());
+            (name.to_string(), u)
+        })
+        .collect();
+    if strays.is_empty() {
+        return Ok(());
+    }
+
+    let (tx, rx) = events::channel();
+    let h = tokio::spawn(events::event_output(
+        rx,
+        t!("progress.pruning"),
+        strays.len() as u64,
+    ));
+    let failed = delete_all(backend, strays, &tx).await;
+    tx.send(Event::close());
+    h.await??;
+    check_deleted(failed)
+}
+
+/// Pushes `base`, whose manifest is `local_manifest`, to the manifest URL `target` through
+/// the backend for its scheme, diffing against the manifest published at `published`.
+/// With `prune`, whatever else the backend lists next to the manifest is deleted after.
+/// Returns the manifest as the backend published it.
+pub async fn push_to(
+    base: &Path,
+    target: &Url,
+    published: &Url,
+    local_manifest: Manifest,
+    checksums: bool,
+    prune: bool,
+) -> Result<Manifest> {
+    let backend = backend::for_url(target)?;
+    let remote_manifest = manifest::get_manifest(published).await?;
+    let published = backend
+        .push(
+            base,
+            target,
+            &local_manifest,
+            remote_manifest.as_ref(),
+            checksums,
+        )
+        .await?
+        .unwrap_or(local_manifest);
+    if prune {
+        self::prune(backend, target, &published).await?;
+    }
+    Ok(published)
+}
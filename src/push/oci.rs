@@ -0,0 +1,231 @@
+use std::{collections::HashMap, io::Read, path::Path};
+
+use anyhow::{anyhow, Result};
+use relative_path::RelativePathBuf;
+use reqwest::{
+    header::{CONTENT_LENGTH, CONTENT_TYPE, LOCATION},
+    Body, StatusCode,
+};
+use sha2::{Digest, Sha256};
+use tokio::fs::File;
+use tokio_util::io::ReaderStream;
+
+use crate::{
+    events::{self, Event},
+    http,
+    i18n::t,
+    manifest::{self, Manifest},
+    oci::{self, Descriptor, ImageManifest, Reference},
+    util,
+};
+
+/// Artifact type of a pushed directory, which tells registry UIs what they are looking at.
+const ARTIFACT_TYPE: &str = "application/vnd.comstar.manifest.v1+json";
+
+const LAYER_MEDIA_TYPE: &str = "application/octet-stream";
+
+/// Artifacts have no config, the spec's empty descriptor stands in for it.
+const EMPTY_MEDIA_TYPE: &str = "application/vnd.oci.empty.v1+json";
+const EMPTY_CONFIG: &[u8] = b"{}";
+
+fn digest_of(bytes: &[u8]) -> String {
+    format!("sha256:{:x}", Sha256::digest(bytes))
+}
+
+/// The `sha256:` digest and size of `path`.
+async fn digest_file(path: &Path) -> Result<(String, u64)> {
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        let mut f = std::fs::File::open(&path)?;
+        let mut hasher = Sha256::new();
+        let mut buf = vec![0u8; 1024 * 1024];
+        let mut size = 0;
+        loop {
+            let n = f.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+            size += n as u64;
+        }
+        Ok((format!("sha256:{:x}", hasher.finalize()), size))
+    })
+    .await?
+}
+
+async fn blob_exists(reference: &Reference, digest: &str) -> Result<bool> {
+    let resp = http::client()
+        .head(reference.api_url("blobs", digest)?)
+        .headers(oci::auth_headers(reference, true).await?)
+        .send()
+        .await?;
+    Ok(resp.status().is_success())
+}
+
+/// Uploads a blob in one request, the monolithic upload every registry supports.
+async fn upload_blob(reference: &Reference, digest: &str, len: u64, body: Body) -> Result<()> {
+    let start = reference.api_url("blobs", "uploads/")?;
+    let resp = http::client()
+        .post(start.clone())
+        .headers(oci::auth_headers(reference, true).await?)
+        .header(CONTENT_LENGTH, 0)
+        .send()
+        .await?;
+    if resp.status() != StatusCode::ACCEPTED {
+        return Err(anyhow!(t!("oci.failed", "POST", start, resp.status())));
+    }
+    let location = resp
+        .headers()
+        .get(LOCATION)
+        .ok_or_else(|| anyhow!("Registry {} sent no upload location", reference.registry))?
+        .to_str()?;
+    // the location may be relative to the registry
+    let mut url = start.join(location)?;
+    url.query_pairs_mut().append_pair("digest", digest);
+    let resp = http::client()
+        .put(url.clone())
+        .headers(oci::auth_headers(reference, true).await?)
+        .header(CONTENT_TYPE, LAYER_MEDIA_TYPE)
+        .header(CONTENT_LENGTH, len)
+        .body(body)
+        .send()
+        .await?;
+    if resp.status() != StatusCode::CREATED {
+        return Err(anyhow!(t!("oci.failed", "PUT", url, resp.status())));
+    }
+    Ok(())
+}
+
+/// Uploads `local_file` unless the registry already has it, returning its layer.
+async fn push_file(reference: &Reference, local_file: &Path, title: &str) -> Result<Descriptor> {
+    let (digest, size) = digest_file(local_file).await?;
+    if !blob_exists(reference, &digest).await? {
+        let f = File::open(local_file).await?;
+        upload_blob(
+            reference,
+            &digest,
+            size,
+            Body::wrap_stream(ReaderStream::new(f)),
+        )
+        .await?;
+    }
+    Ok(Descriptor {
+        media_type: LAYER_MEDIA_TYPE.to_string(),
+        digest,
+        size,
+        annotations: HashMap::from([(oci::TITLE_ANNOTATION.to_string(), title.to_string())]),
+    })
+}
+
+/// Pushes every file of `local_manifest` as a layer of the artifact `reference` tags,
+/// pointing each entry's `source` at its blob, then writes the manifest and pushes it as
+/// a layer too. Blobs the registry already has aren't uploaded again, and blobs nothing
+/// refers to any more are left to the registry's garbage collection. Returns the digest
+/// of the artifact.
+pub async fn push_dir(
+    base: &Path,
+    local_manifest: &mut Manifest,
+    reference: &Reference,
+    checksums: bool,
+) -> Result<String> {
+    let (tx, rx) = events::channel();
+    let h = tokio::spawn(events::event_output(
+        rx,
+        t!("progress.pushing"),
+        (local_manifest.entries.len() + 1 + usize::from(checksums)) as u64,
+    ));
+
+    let paths: Vec<RelativePathBuf> = local_manifest
+        .entries
+        .iter()
+        .map(|e| e.path.clone())
+        .collect();
+    let mut layers = util::bounded_tasks(paths, util::jobs().net, |rel_path| {
+        let local_file = rel_path.to_path(base);
+        let t = tx.clone();
+        async move {
+            t.send(Event::unknown_file_started(rel_path.as_str()));
+            match push_file(reference, &local_file, rel_path.as_str()).await {
+                Ok(layer) => {
+                    t.send(Event::file_done(rel_path.as_str()));
+                    Ok(layer)
+                }
+                Err(e) => {
+                    t.send(Event::file_failed(rel_path.as_str(), &e));
+                    Err(e)
+                }
+            }
+        }
+    })
+    .await?;
+    let digests: HashMap<&str, &str> = layers
+        .iter()
+        .filter_map(|l| Some((l.title()?, l.digest.as_str())))
+        .collect();
+    for entry in local_manifest.entries.iter_mut() {
+        entry.source = reference
+            .with_digest(digests[entry.path.as_str()])
+            .to_url()?;
+    }
+    manifest::write_manifest(local_manifest, base)?;
+    if checksums {
+        manifest::write_checksums(local_manifest, base)?;
+    }
+
+    let mut companions = vec!["comstar.json"];
+    if checksums {
+        companions.push(manifest::CHECKSUMS_FILE);
+    }
+    for name in companions {
+        tx.send(Event::unknown_file_started(name));
+        match push_file(reference, &base.join(name), name).await {
+            Ok(layer) => layers.push(layer),
+            Err(e) => {
+                tx.send(Event::file_failed(name, &e));
+                return Err(e);
+            }
+        }
+        tx.send(Event::file_done(name));
+    }
+
+    let config_digest = digest_of(EMPTY_CONFIG);
+    if !blob_exists(reference, &config_digest).await? {
+        upload_blob(
+            reference,
+            &config_digest,
+            EMPTY_CONFIG.len() as u64,
+            Body::from(EMPTY_CONFIG),
+        )
+        .await?;
+    }
+    let artifact = ImageManifest {
+        schema_version: 2,
+        media_type: oci::MANIFEST_MEDIA_TYPE.to_string(),
+        artifact_type: Some(ARTIFACT_TYPE.to_string()),
+        config: Descriptor {
+            media_type: EMPTY_MEDIA_TYPE.to_string(),
+            digest: config_digest,
+            size: EMPTY_CONFIG.len() as u64,
+            annotations: HashMap::new(),
+        },
+        layers,
+    };
+    let body = serde_json::to_vec(&artifact)?;
+    let url = reference.manifest_url()?;
+    let resp = http::client()
+        .put(url.clone())
+        .headers(oci::auth_headers(reference, true).await?)
+        .header(CONTENT_TYPE, oci::MANIFEST_MEDIA_TYPE)
+        .body(body.clone())
+        .send()
+        .await?;
+    tx.send(Event::close());
+    let stats = h.await??;
+    if let Err(e) = stats.save(base, "push") {
+        tracing::warn!("Could not write push statistics: {}", e);
+    }
+    if !resp.status().is_success() {
+        return Err(anyhow!(t!("oci.failed", "PUT", url, resp.status())));
+    }
+    Ok(digest_of(&body))
+}
@@ -0,0 +1,344 @@
+use std::{path::Path, sync::RwLock, time::Duration};
+
+use anyhow::{anyhow, Result};
+use aws_sdk_s3::{
+    config,
+    model::{CompletedMultipartUpload, CompletedPart},
+    output::GetObjectOutput,
+    presigning::config::PresigningConfig,
+    types::{ByteStream, SdkError},
+    Client, Region,
+};
+use percent_encoding::percent_decode_str;
+use relative_path::RelativePath;
+use structopt::StructOpt;
+use tokio::{fs::File, io::AsyncReadExt};
+use url::Url;
+
+use crate::push::gcs::SHA512_METADATA;
+
+/// Files above this size go up as a multipart upload, single PUTs are capped at 5 GB and
+/// a failed part is cheaper to lose than a whole file.
+const MULTIPART_THRESHOLD: u64 = 64 * 1024 * 1024;
+
+/// Size of each part of a multipart upload. S3 wants at least 5 MiB for all but the last.
+const PART_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Signing region for S3 compatible services when neither `--region` nor the environment
+/// names one. MinIO and Ceph accept it, R2 ignores it.
+const FALLBACK_REGION: &str = "us-east-1";
+
+static SHARED: RwLock<Option<Client>> = RwLock::new(None);
+
+static ENDPOINT: RwLock<Option<EndpointOptions>> = RwLock::new(None);
+
+/// Region of the bucket `push s3` goes to, from `--region`.
+static REGION: RwLock<Option<String>> = RwLock::new(None);
+
+/// Where to find an S3 compatible service such as MinIO, Cloudflare R2 or Ceph RGW, for
+/// both `push s3` and `s3://` sources.
+#[derive(Debug, Clone, Default, StructOpt)]
+pub struct EndpointOptions {
+    #[structopt(
+        long = "endpoint-url",
+        help = "S3 compatible endpoint to use instead of AWS, e.g. http://minio.local:9000."
+    )]
+    pub endpoint_url: Option<String>,
+    #[structopt(
+        long = "path-style",
+        help = "Address S3 buckets as <endpoint>/<bucket> instead of <bucket>.<endpoint>, which MinIO and Ceph usually need."
+    )]
+    pub path_style: bool,
+}
+
+/// Replaces the endpoint, the shared client is made again on next use.
+pub fn configure(endpoint: EndpointOptions) {
+    *ENDPOINT.write().unwrap() = Some(endpoint);
+    *SHARED.write().unwrap() = None;
+}
+
+/// Replaces the region, the shared client is made again on next use.
+pub fn set_region(region: Option<String>) {
+    *REGION.write().unwrap() = region;
+    *SHARED.write().unwrap() = None;
+}
+
+/// A client with credentials from the standard AWS chain: environment, profile files,
+/// web identity and instance metadata.
+pub async fn client(region: Option<&str>) -> Client {
+    let mut loader = aws_config::from_env();
+    if let Some(r) = region {
+        loader = loader.region(Region::new(r.to_string()));
+    }
+    let shared_config = loader.load().await;
+    let endpoint = ENDPOINT.read().unwrap().clone().unwrap_or_default();
+    let mut conf = config::Builder::from(&shared_config).force_path_style(endpoint.path_style);
+    if let Some(url) = endpoint.endpoint_url {
+        conf = conf.endpoint_url(url);
+        if shared_config.region().is_none() {
+            conf = conf.region(Region::new(FALLBACK_REGION));
+        }
+    }
+    Client::from_conf(conf.build())
+}
+
+/// Client for `s3://` URLs, in the region `set_region` was given or else the one from the
+/// environment or AWS profile.
+pub async fn shared() -> Client {
+    // the lock isn't held across the await below
+    let shared = SHARED.read().unwrap().clone();
+    if let Some(c) = shared {
+        return c;
+    }
+    let region = REGION.read().unwrap().clone();
+    let c = client(region.as_deref()).await;
+    SHARED.write().unwrap().get_or_insert(c).clone()
+}
+
+/// Bucket and key of an `s3://bucket/key` URL.
+pub fn object_of(src: &Url) -> Option<(String, String)> {
+    if src.scheme() != "s3" {
+        return None;
+    }
+    let key = percent_decode_str(src.path().trim_start_matches('/'))
+        .decode_utf8()
+        .ok()?;
+    Some((src.host_str()?.to_string(), key.into_owned()))
+}
+
+fn object_or_err(src: &Url) -> Result<(String, String)> {
+    object_of(src).ok_or_else(|| anyhow!("Not an S3 object URL: {}", src))
+}
+
+/// The object at `src` as a stream, `None` if there is no such object.
+pub async fn get(src: &Url) -> Result<Option<GetObjectOutput>> {
+    let (bucket, key) = object_or_err(src)?;
+    match shared()
+        .await
+        .get_object()
+        .bucket(bucket)
+        .key(key)
+        .send()
+        .await
+    {
+        Ok(o) => Ok(Some(o)),
+        Err(SdkError::ServiceError(e)) if e.err().is_no_such_key() => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// A GET URL for `key` in `bucket` presigned with the credentials from the standard AWS
+/// chain, valid for `expires`.
+pub async fn presigned_url(bucket: &str, key: &str, expires: Duration) -> Result<Url> {
+    let req = shared()
+        .await
+        .get_object()
+        .bucket(bucket)
+        .key(key)
+        .presigned(PresigningConfig::expires_in(expires)?)
+        .await?;
+    Ok(Url::parse(&req.uri().to_string())?)
+}
+
+/// Size of the object at `src`.
+pub async fn size(src: &Url) -> Result<Option<u64>> {
+    let (bucket, key) = object_or_err(src)?;
+    let head = shared()
+        .await
+        .head_object()
+        .bucket(bucket)
+        .key(key)
+        .send()
+        .await?;
+    Ok(u64::try_from(head.content_length()).ok())
+}
+
+fn content_type(local_file: &Path) -> String {
+    mime_guess::from_path(local_file)
+        .first()
+        .map(|m| m.to_string())
+        .unwrap_or_else(|| "application/octet-stream".to_string())
+}
+
+/// Uploads `local_file` to `key`, streaming it from disk. The sha512 of the content goes
+/// into the object's metadata like it does on GCS.
+pub async fn upload_object(
+    client: &Client,
+    bucket: &str,
+    key: &RelativePath,
+    local_file: &Path,
+    sha512: Option<&str>,
+) -> Result<()> {
+    if tokio::fs::metadata(local_file).await?.len() > MULTIPART_THRESHOLD {
+        return upload_multipart(client, bucket, key, local_file, sha512).await;
+    }
+    let mut req = client
+        .put_object()
+        .bucket(bucket)
+        .key(key.as_str())
+        .content_type(content_type(local_file))
+        .body(ByteStream::from_path(local_file).await?);
+    if let Some(sha) = sha512 {
+        req = req.metadata(SHA512_METADATA, sha);
+    }
+    req.send().await?;
+    Ok(())
+}
+
+async fn upload_multipart(
+    client: &Client,
+    bucket: &str,
+    key: &RelativePath,
+    local_file: &Path,
+    sha512: Option<&str>,
+) -> Result<()> {
+    let mut req = client
+        .create_multipart_upload()
+        .bucket(bucket)
+        .key(key.as_str())
+        .content_type(content_type(local_file));
+    if let Some(sha) = sha512 {
+        req = req.metadata(SHA512_METADATA, sha);
+    }
+    let upload_id = req
+        .send()
+        .await?
+        .upload_id()
+        .map(str::to_string)
+        .ok_or_else(|| anyhow!("S3 returned no upload id for {}", key))?;
+
+    let uploaded = upload_parts(client, bucket, key, local_file, &upload_id).await;
+    let parts = match uploaded {
+        Ok(p) => p,
+        Err(e) => {
+            // otherwise the parts are kept, and billed, until a lifecycle rule removes them
+            let aborted = client
+                .abort_multipart_upload()
+                .bucket(bucket)
+                .key(key.as_str())
+                .upload_id(&upload_id)
+                .send()
+                .await;
+            if let Err(abort) = aborted {
+                tracing::warn!("Could not abort upload of {}: {}", key, abort);
+            }
+            return Err(e);
+        }
+    };
+    client
+        .complete_multipart_upload()
+        .bucket(bucket)
+        .key(key.as_str())
+        .upload_id(&upload_id)
+        .multipart_upload(
+            CompletedMultipartUpload::builder()
+                .set_parts(Some(parts))
+                .build(),
+        )
+        .send()
+        .await?;
+    Ok(())
+}
+
+async fn upload_parts(
+    client: &Client,
+    bucket: &str,
+    key: &RelativePath,
+    local_file: &Path,
+    upload_id: &str,
+) -> Result<Vec<CompletedPart>> {
+    let mut f = File::open(local_file).await?;
+    let mut parts = Vec::new();
+    let mut number = 1;
+    loop {
+        let mut buf = Vec::with_capacity(PART_SIZE as usize);
+        (&mut f).take(PART_SIZE).read_to_end(&mut buf).await?;
+        if buf.is_empty() {
+            break;
+        }
+        let full = buf.len() as u64 == PART_SIZE;
+        let part = client
+            .upload_part()
+            .bucket(bucket)
+            .key(key.as_str())
+            .upload_id(upload_id)
+            .part_number(number)
+            .body(ByteStream::from(buf))
+            .send()
+            .await?;
+        parts.push(
+            CompletedPart::builder()
+                .part_number(number)
+                .set_e_tag(part.e_tag().map(str::to_string))
+                .build(),
+        );
+        if !full {
+            break;
+        }
+        number += 1;
+    }
+    Ok(parts)
+}
+
+pub async fn delete_object(client: &Client, bucket: &str, key: &RelativePath) -> Result<()> {
+    client
+        .delete_object()
+        .bucket(bucket)
+        .key(key.as_str())
+        .send()
+        .await?;
+    Ok(())
+}
+
+/// Uploads `local_file` to the object `dest` points at.
+pub async fn put(dest: &Url, local_file: &Path, sha512: Option<&str>) -> Result<()> {
+    let (bucket, key) = object_or_err(dest)?;
+    upload_object(
+        &shared().await,
+        &bucket,
+        RelativePath::new(&key),
+        local_file,
+        sha512,
+    )
+    .await
+}
+
+pub async fn delete(url: &Url) -> Result<()> {
+    let (bucket, key) = object_or_err(url)?;
+    delete_object(&shared().await, &bucket, RelativePath::new(&key)).await
+}
+
+/// `s3://` URLs of every object below `prefix`, following pagination.
+pub async fn list(prefix: &Url) -> Result<Vec<Url>> {
+    let (bucket, key) = object_or_err(prefix)?;
+    let client = shared().await;
+    let mut urls = Vec::new();
+    let mut token = None;
+    loop {
+        let resp = client
+            .list_objects_v2()
+            .bucket(&bucket)
+            .prefix(&key)
+            .set_continuation_token(token.take())
+            .send()
+            .await?;
+        for key in resp
+            .contents()
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|o| o.key())
+        {
+            let mut url = Url::parse(&format!("s3://{}/", bucket))?;
+            url.path_segments_mut()
+                .map_err(|_| anyhow!("Cannot build an S3 URL for {}", key))?
+                .clear()
+                .extend(key.split('/'));
+            urls.push(url);
+        }
+        match resp.next_continuation_token() {
+            Some(t) => token = Some(t.to_string()),
+            None => break,
+        }
+    }
+    Ok(urls)
+}
@@ -0,0 +1,319 @@
+use std::{
+    fs::File,
+    io::{self, Read},
+    net::TcpStream,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Mutex, RwLock},
+};
+
+use anyhow::{anyhow, Result};
+use percent_encoding::percent_decode_str;
+use ssh2::{CheckResult, KnownHostFileKind, Session, Sftp};
+use tokio::sync::mpsc;
+use url::Url;
+
+use crate::{i18n::t, util::Chunk};
+
+const DEFAULT_PORT: u16 = 22;
+
+/// Bytes read from the server at a time when downloading.
+const DOWNLOAD_CHUNK: usize = 64 * 1024;
+
+/// Chunks the blocking reader may get ahead of the writer.
+const CHUNKS_IN_FLIGHT: usize = 16;
+
+/// Suffix of a file while it is being uploaded, renamed away once it is complete so the
+/// server never serves half a file under its real name.
+const PARTIAL_SUFFIX: &str = ".comstar-part";
+
+/// libssh2's error for a path that doesn't exist.
+const SFTP_NO_SUCH_FILE: i32 = 2;
+
+/// Private key to log in with, from `--identity`. Without one the SSH agent is asked.
+static IDENTITY: RwLock<Option<PathBuf>> = RwLock::new(None);
+
+/// Logged in sessions not in use right now, by server. A push takes one out for every
+/// file rather than logging in again.
+static IDLE: Mutex<Vec<(String, Sftp)>> = Mutex::new(Vec::new());
+
+pub fn set_identity(identity: Option<PathBuf>) {
+    *IDENTITY.write().unwrap() = identity;
+}
+
+/// Where to push, written `[user@]host[:port]`.
+#[derive(Debug, Clone)]
+pub struct Server {
+    pub user: String,
+    pub host: String,
+    pub port: u16,
+}
+
+impl Server {
+    /// The server of an `ssh://[user@]host[:port]/path` URL.
+    pub fn from_url(url: &Url) -> Result<Self> {
+        let host = url
+            .host_str()
+            .ok_or_else(|| anyhow!("SSH URL has no host: {}", url))?;
+        let mut spec = host.to_string();
+        if !url.username().is_empty() {
+            spec = format!("{}@{}", url.username(), spec);
+        }
+        if let Some(port) = url.port() {
+            spec = format!("{}:{}", spec, port);
+        }
+        spec.parse()
+    }
+
+    /// The `ssh://` URL of `path` on this server.
+    pub fn url(&self, path: &Path) -> Result<Url> {
+        let mut url = Url::parse(&format!("ssh://{}:{}/", self.host, self.port))?;
+        url.set_username(&self.user)
+            .map_err(|_| anyhow!("Invalid SSH user {}", self.user))?;
+        url.path_segments_mut()
+            .map_err(|_| anyhow!("Cannot build an SSH URL for {}", self.host))?
+            .clear()
+            .extend(
+                path.iter()
+                    .map(|c| c.to_string_lossy())
+                    .filter(|c| c != "/" && c != "\\"),
+            );
+        Ok(url)
+    }
+}
+
+impl FromStr for Server {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (user, rest) = match s.split_once('@') {
+            Some((u, r)) => (u.to_string(), r),
+            None => (
+                std::env::var("USER")
+                    .or_else(|_| std::env::var("USERNAME"))
+                    .map_err(|_| anyhow!("No user in {} and none in the environment", s))?,
+                s,
+            ),
+        };
+        let (host, port) = match rest.rsplit_once(':') {
+            Some((h, p)) => (h.to_string(), p.parse()?),
+            None => (rest.to_string(), DEFAULT_PORT),
+        };
+        if host.is_empty() {
+            return Err(anyhow!("Invalid server {}, expected [user@]host[:port]", s));
+        }
+        Ok(Server { user, host, port })
+    }
+}
+
+/// Refuses servers whose key doesn't match `~/.ssh/known_hosts`, or that aren't in it.
+fn check_host_key(session: &Session, server: &Server) -> Result<()> {
+    let (key, _) = session
+        .host_key()
+        .ok_or_else(|| anyhow!("{} sent no host key", server.host))?;
+    let mut known = session.known_hosts()?;
+    if let Some(home) = dirs::home_dir() {
+        let file = home.join(".ssh").join("known_hosts");
+        if file.is_file() {
+            known.read_file(&file, KnownHostFileKind::OpenSSH)?;
+        }
+    }
+    match known.check_port(&server.host, server.port, key) {
+        CheckResult::Match => Ok(()),
+        CheckResult::Mismatch => Err(anyhow!(t!("sftp.host_key_mismatch", server.host))),
+        _ => Err(anyhow!(t!("sftp.host_unknown", server.host))),
+    }
+}
+
+fn identity() -> Option<PathBuf> {
+    IDENTITY.read().unwrap().clone()
+}
+
+/// Logs in with the SSH agent, or with `identity` if given.
+fn connect(server: &Server, identity: Option<&Path>) -> Result<Sftp> {
+    let tcp = TcpStream::connect((server.host.as_str(), server.port))?;
+    let mut session = Session::new()?;
+    session.set_tcp_stream(tcp);
+    session.handshake()?;
+    check_host_key(&session, server)?;
+    match identity {
+        Some(key) => session.userauth_pubkey_file(&server.user, None, key, None)?,
+        None => session.userauth_agent(&server.user)?,
+    }
+    if !session.authenticated() {
+        return Err(anyhow!(t!("sftp.auth_failed", server.user, server.host)));
+    }
+    Ok(session.sftp()?)
+}
+
+/// The absolute path on the server an `ssh://` URL points at.
+fn remote_path(url: &Url) -> Result<PathBuf> {
+    Ok(PathBuf::from(
+        percent_decode_str(url.path()).decode_utf8()?.into_owned(),
+    ))
+}
+
+fn download_blocking(url: &Url, tx: &mpsc::Sender<Result<Chunk>>) -> Result<()> {
+    let sftp = connect(&Server::from_url(url)?, identity().as_deref())?;
+    let path = remote_path(url)?;
+    let mut f = sftp.open(&path)?;
+    if let Some(size) = f.stat()?.size {
+        if tx.blocking_send(Ok(Chunk::Length(size))).is_err() {
+            return Ok(());
+        }
+    }
+    let mut buf = vec![0u8; DOWNLOAD_CHUNK];
+    loop {
+        let n = f.read(&mut buf)?;
+        if n == 0 {
+            return Ok(());
+        }
+        // the receiving end gave up, no need to read the rest
+        if tx
+            .blocking_send(Ok(Chunk::Data(buf[..n].to_vec())))
+            .is_err()
+        {
+            return Ok(());
+        }
+    }
+}
+
+/// Downloads the file an `ssh://` URL points at over SFTP on a blocking thread, logging
+/// in with the SSH agent. The receiver gets the file's length, then its data, and closes
+/// once the file is complete.
+pub fn download(url: &Url) -> mpsc::Receiver<Result<Chunk>> {
+    let (tx, rx) = mpsc::channel(CHUNKS_IN_FLIGHT);
+    let url = url.clone();
+    tokio::task::spawn_blocking(move || {
+        if let Err(e) = download_blocking(&url, &tx) {
+            let _ = tx.blocking_send(Err(e));
+        }
+    });
+    rx
+}
+
+/// Size of the file an `ssh://` URL points at.
+pub async fn size(url: &Url) -> Result<Option<u64>> {
+    let url = url.clone();
+    tokio::task::spawn_blocking(move || {
+        let sftp = connect(&Server::from_url(&url)?, identity().as_deref())?;
+        Ok(sftp.stat(&remote_path(&url)?)?.size)
+    })
+    .await?
+}
+
+fn is_missing(e: &ssh2::Error) -> bool {
+    matches!(e.code(), ssh2::ErrorCode::SFTP(SFTP_NO_SUCH_FILE))
+}
+
+/// Creates `dir` and any missing parents on the server.
+fn create_dirs(sftp: &Sftp, dir: &Path) -> Result<()> {
+    if dir.as_os_str().is_empty() || sftp.stat(dir).is_ok() {
+        return Ok(());
+    }
+    if let Some(parent) = dir.parent() {
+        create_dirs(sftp, parent)?;
+    }
+    match sftp.mkdir(dir, 0o755) {
+        Ok(()) => Ok(()),
+        // created by someone else in the meantime
+        Err(_) if sftp.stat(dir).is_ok() => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn upload(sftp: &Sftp, local_file: &Path, remote: &Path) -> Result<()> {
+    if let Some(parent) = remote.parent() {
+        create_dirs(sftp, parent)?;
+    }
+    let partial = PathBuf::from(format!("{}{}", remote.display(), PARTIAL_SUFFIX));
+    let mut input = File::open(local_file)?;
+    let mut output = sftp.create(&partial)?;
+    io::copy(&mut input, &mut output)?;
+    drop(output);
+    // SFTP rename refuses to replace an existing file on many servers
+    match sftp.unlink(remote) {
+        Err(e) if !is_missing(&e) => return Err(e.into()),
+        _ => {}
+    }
+    sftp.rename(&partial, remote, None)?;
+    Ok(())
+}
+
+fn delete(sftp: &Sftp, remote: &Path) -> Result<()> {
+    match sftp.unlink(remote) {
+        Err(e) if !is_missing(&e) => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Runs `f` with a session to `server`, logging in if none is idle. The session is kept
+/// for the next call unless `f` failed, which may have left it broken.
+fn with_session<T>(server: &Server, f: impl FnOnce(&Sftp) -> Result<T>) -> Result<T> {
+    let key = format!("{}@{}:{}", server.user, server.host, server.port);
+    let idle = {
+        let mut idle = IDLE.lock().unwrap();
+        idle.iter()
+            .position(|(k, _)| *k == key)
+            .map(|i| idle.swap_remove(i).1)
+    };
+    let sftp = match idle {
+        Some(s) => s,
+        None => connect(server, identity().as_deref())?,
+    };
+    let res = f(&sftp);
+    if res.is_ok() {
+        IDLE.lock().unwrap().push((key, sftp));
+    }
+    res
+}
+
+/// Uploads `local_file` to the file an `ssh://` URL points at, creating directories as
+/// needed. libssh2 blocks, so this runs on the blocking pool.
+pub async fn put_file(dest: &Url, local_file: &Path) -> Result<()> {
+    let (dest, local_file) = (dest.clone(), local_file.to_path_buf());
+    tokio::task::spawn_blocking(move || {
+        let remote = remote_path(&dest)?;
+        with_session(&Server::from_url(&dest)?, |sftp| {
+            upload(sftp, &local_file, &remote)
+        })
+    })
+    .await?
+}
+
+pub async fn delete_file(url: &Url) -> Result<()> {
+    let url = url.clone();
+    tokio::task::spawn_blocking(move || {
+        let remote = remote_path(&url)?;
+        with_session(&Server::from_url(&url)?, |sftp| delete(sftp, &remote))
+    })
+    .await?
+}
+
+fn list_blocking(sftp: &Sftp, dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    let entries = match sftp.readdir(dir) {
+        Err(e) if is_missing(&e) => return Ok(()),
+        res => res?,
+    };
+    for (path, stat) in entries {
+        if stat.is_dir() {
+            list_blocking(sftp, &path, files)?;
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// `ssh://` URLs of every file below the directory `prefix` points at.
+pub async fn list(prefix: &Url) -> Result<Vec<Url>> {
+    let prefix = prefix.clone();
+    tokio::task::spawn_blocking(move || {
+        let server = Server::from_url(&prefix)?;
+        let dir = remote_path(&prefix)?;
+        let mut files = Vec::new();
+        with_session(&server, |sftp| list_blocking(sftp, &dir, &mut files))?;
+        files.iter().map(|f| server.url(f)).collect()
+    })
+    .await?
+}
@@ -0,0 +1,81 @@
+use std::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use anyhow::Result;
+use indicatif::BinaryBytes;
+
+use crate::{i18n::t, util::ByteSize};
+
+/// Largest file sync may download, zero is unlimited.
+static MAX_FILE: AtomicU64 = AtomicU64::new(0);
+/// Most bytes sync may download in one run, zero is unlimited.
+static MAX_TOTAL: AtomicU64 = AtomicU64::new(0);
+/// Bytes received so far this run, including ones thrown away by a retry.
+static TOTAL: AtomicU64 = AtomicU64::new(0);
+
+/// A download went over `--max-file-size` or `--max-total-download`. Retrying it or asking
+/// another mirror won't help.
+#[derive(Debug)]
+pub struct QuotaExceeded(String);
+
+impl fmt::Display for QuotaExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for QuotaExceeded {}
+
+pub fn configure(max_file: Option<ByteSize>, max_total: Option<ByteSize>) {
+    MAX_FILE.store(max_file.map(|b| b.0).unwrap_or(0), Ordering::Relaxed);
+    MAX_TOTAL.store(max_total.map(|b| b.0).unwrap_or(0), Ordering::Relaxed);
+    TOTAL.store(0, Ordering::Relaxed);
+}
+
+/// Whether `e` is a quota refusal rather than a failure worth retrying.
+pub fn is_exceeded(e: &anyhow::Error) -> bool {
+    e.is::<QuotaExceeded>()
+}
+
+/// Refuses `name` up front if its announced `size` is over the per-file limit or would take
+/// the run over the total.
+pub fn check_size(name: &str, size: u64) -> Result<()> {
+    let max_file = MAX_FILE.load(Ordering::Relaxed);
+    if max_file > 0 && size > max_file {
+        return Err(QuotaExceeded(t!(
+            "quota.file",
+            name,
+            BinaryBytes(size),
+            BinaryBytes(max_file)
+        ))
+        .into());
+    }
+    let max_total = MAX_TOTAL.load(Ordering::Relaxed);
+    if max_total > 0 && TOTAL.load(Ordering::Relaxed) + size > max_total {
+        return Err(QuotaExceeded(t!("quota.total", name, BinaryBytes(max_total))).into());
+    }
+    Ok(())
+}
+
+/// Counts `len` more bytes received for `name`, which now has `written` bytes, failing once
+/// either limit is passed. Catches servers that send more than they announced.
+pub fn charge(name: &str, written: u64, len: u64) -> Result<()> {
+    let max_file = MAX_FILE.load(Ordering::Relaxed);
+    if max_file > 0 && written + len > max_file {
+        return Err(QuotaExceeded(t!(
+            "quota.file",
+            name,
+            BinaryBytes(written + len),
+            BinaryBytes(max_file)
+        ))
+        .into());
+    }
+    let total = TOTAL.fetch_add(len, Ordering::Relaxed) + len;
+    let max_total = MAX_TOTAL.load(Ordering::Relaxed);
+    if max_total > 0 && total > max_total {
+        return Err(QuotaExceeded(t!("quota.total", name, BinaryBytes(max_total))).into());
+    }
+    Ok(())
+}
@@ -0,0 +1,134 @@
+use std::{
+    str::FromStr,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use chrono::{Local, NaiveTime};
+use serde::Deserialize;
+
+use crate::util::ByteSize;
+
+static LIMITER: RwLock<Option<Arc<RateLimiter>>> = RwLock::new(None);
+
+/// A bandwidth cap for a time-of-day window, written `09:00-18:00=1MiB`.
+/// Windows may wrap past midnight, e.g. `22:00-06:00=unlimited`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub struct BandwidthWindow {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+    /// Bytes per second, `None` is unlimited.
+    pub rate: Option<u64>,
+}
+
+impl BandwidthWindow {
+    fn contains(&self, t: NaiveTime) -> bool {
+        if self.start <= self.end {
+            t >= self.start && t < self.end
+        } else {
+            t >= self.start || t < self.end
+        }
+    }
+}
+
+impl FromStr for BandwidthWindow {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let err = || anyhow!("Invalid bandwidth window {}, expected HH:MM-HH:MM=RATE", s);
+        let (range, rate) = s.split_once('=').ok_or_else(err)?;
+        let (start, end) = range.split_once('-').ok_or_else(err)?;
+        let rate = match rate.trim() {
+            "unlimited" => None,
+            r => Some(r.parse::<ByteSize>()?.0),
+        };
+        Ok(Self {
+            start: NaiveTime::parse_from_str(start.trim(), "%H:%M").map_err(|_| err())?,
+            end: NaiveTime::parse_from_str(end.trim(), "%H:%M").map_err(|_| err())?,
+            rate,
+        })
+    }
+}
+
+impl TryFrom<String> for BandwidthWindow {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+struct Bucket {
+    available: f64,
+    last: Instant,
+}
+
+/// Token bucket shared by all transfers. The rate is looked up on every call so
+/// schedule windows take effect as the clock moves through them.
+pub struct RateLimiter {
+    default_rate: Option<u64>,
+    schedule: Vec<BandwidthWindow>,
+    bucket: Mutex<Bucket>,
+}
+
+impl RateLimiter {
+    pub fn new(default_rate: Option<u64>, schedule: Vec<BandwidthWindow>) -> Self {
+        Self {
+            default_rate,
+            schedule,
+            bucket: Mutex::new(Bucket {
+                available: 0.0,
+                last: Instant::now(),
+            }),
+        }
+    }
+
+    fn current_rate(&self) -> Option<u64> {
+        let now = Local::now().time();
+        match self.schedule.iter().find(|w| w.contains(now)) {
+            Some(w) => w.rate,
+            None => self.default_rate,
+        }
+    }
+
+    /// Waits until `bytes` may be transferred under the current cap.
+    pub async fn acquire(&self, bytes: u64) {
+        let rate = match self.current_rate() {
+            Some(r) if r > 0 => r as f64,
+            _ => return,
+        };
+        let wait = {
+            let mut b = self.bucket.lock().unwrap();
+            let now = Instant::now();
+            let refill = now.duration_since(b.last).as_secs_f64() * rate;
+            // allow at most one second of burst
+            b.available = (b.available + refill).min(rate);
+            b.last = now;
+            b.available -= bytes as f64;
+            if b.available < 0.0 {
+                Some(Duration::from_secs_f64(-b.available / rate))
+            } else {
+                None
+            }
+        };
+        if let Some(w) = wait {
+            tokio::time::sleep(w).await;
+        }
+    }
+}
+
+/// Installs the limiter used by transfers, replacing any previous one.
+pub fn configure(default_rate: Option<u64>, schedule: Vec<BandwidthWindow>) {
+    let limiter = if default_rate.is_none() && schedule.is_empty() {
+        None
+    } else {
+        Some(Arc::new(RateLimiter::new(default_rate, schedule)))
+    };
+    *LIMITER.write().unwrap() = limiter;
+}
+
+pub fn current() -> Option<Arc<RateLimiter>> {
+    LIMITER.read().unwrap().clone()
+}
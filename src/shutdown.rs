@@ -0,0 +1,79 @@
+//! Graceful shutdown on SIGTERM and Ctrl-C for long runs.
+//!
+//! The first signal stops new transfers from starting and gives the ones in flight
+//! `GRACE_PERIOD` to finish, after which the run writes its resume journal and exits with
+//! `EXIT_INTERRUPTED`. A second signal exits right away.
+
+use std::{fmt, sync::OnceLock, time::Duration};
+
+use tokio_util::sync::CancellationToken;
+
+use crate::i18n::t;
+
+/// How long transfers already running may take to finish once shutdown is requested.
+pub const GRACE_PERIOD: Duration = Duration::from_secs(30);
+
+/// Exit code of a run stopped by a signal, `EX_TEMPFAIL`: try again later and it resumes.
+pub const EXIT_INTERRUPTED: i32 = 75;
+
+static REQUESTED: OnceLock<CancellationToken> = OnceLock::new();
+
+fn token() -> &'static CancellationToken {
+    REQUESTED.get_or_init(CancellationToken::new)
+}
+
+/// The run was stopped by a signal before it finished.
+#[derive(Debug)]
+pub struct Interrupted;
+
+impl fmt::Display for Interrupted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", t!("shutdown.interrupted"))
+    }
+}
+
+impl std::error::Error for Interrupted {}
+
+/// Starts listening for SIGTERM and Ctrl-C. Until this is called signals kill the process
+/// as usual.
+pub fn install() {
+    tokio::spawn(async {
+        wait_for_signal().await;
+        println!("{}", t!("shutdown.requested", GRACE_PERIOD.as_secs()));
+        token().cancel();
+        wait_for_signal().await;
+        std::process::exit(EXIT_INTERRUPTED);
+    });
+}
+
+#[cfg(unix)]
+async fn wait_for_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+    match signal(SignalKind::terminate()) {
+        Ok(mut term) => {
+            tokio::select! {
+                _ = term.recv() => {}
+                _ = tokio::signal::ctrl_c() => {}
+            }
+        }
+        Err(_) => {
+            let _ = tokio::signal::ctrl_c().await;
+        }
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_signal() {
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// Whether a signal asked the run to stop.
+pub fn requested() -> bool {
+    REQUESTED.get().is_some_and(|t| t.is_cancelled())
+}
+
+/// Completes once shutdown was requested and the grace period has run out.
+pub async fn grace_expired() {
+    token().cancelled().await;
+    tokio::time::sleep(GRACE_PERIOD).await;
+}
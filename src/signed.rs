@@ -0,0 +1,44 @@
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use url::Url;
+
+use crate::{
+    i18n::t,
+    push::{gcs, s3},
+};
+
+/// How long a signed URL stays valid. Storage checks the signature when a request starts,
+/// so this only has to cover resuming a download, not finishing it.
+pub const EXPIRY: Duration = Duration::from_secs(60 * 60);
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Makes downloads of private GCS and S3 objects go through signed URLs.
+pub fn configure(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// A short-lived plain HTTPS URL for the GCS or S3 object at `src`, signed with the local
+/// credentials, `None` for anything else. Downloads through it resume like any other
+/// HTTP download and never need the bucket to be public.
+pub async fn url_for(src: &Url) -> Result<Option<Url>> {
+    let signed = if let Some((bucket, object)) = gcs::object_of(src) {
+        gcs::signed_url(&bucket, &object, EXPIRY).await
+    } else if let Some((bucket, key)) = s3::object_of(src) {
+        s3::presigned_url(&bucket, &key, EXPIRY).await
+    } else {
+        return Ok(None);
+    };
+    match signed {
+        Ok(url) => Ok(Some(url)),
+        Err(e) => Err(anyhow!(t!("signed.failed", src, e))),
+    }
+}
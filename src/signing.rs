@@ -0,0 +1,105 @@
+use std::{path::Path, sync::RwLock};
+
+use anyhow::{anyhow, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use ed25519_dalek::{Keypair, PublicKey, SecretKey, Signature, Signer};
+use url::Url;
+
+use crate::{i18n::t, manifest::Manifest};
+
+static SIGN_KEY: RwLock<Option<[u8; 32]>> = RwLock::new(None);
+static VERIFY_KEY: RwLock<Option<PublicKey>> = RwLock::new(None);
+
+fn decode_key(key: &str) -> Option<[u8; 32]> {
+    STANDARD.decode(key.trim()).ok()?.try_into().ok()
+}
+
+fn keypair(seed: &[u8; 32]) -> Result<Keypair> {
+    let secret = SecretKey::from_bytes(seed).map_err(|e| anyhow!(e.to_string()))?;
+    let public = PublicKey::from(&secret);
+    Ok(Keypair { secret, public })
+}
+
+/// Signs every manifest written from now on with the Ed25519 key in the file at `path`,
+/// a base64 encoded 32 byte seed such as `openssl rand -base64 32` makes.
+pub fn set_sign_key(path: &Path) -> Result<()> {
+    let seed = std::fs::read_to_string(path)
+        .ok()
+        .and_then(|k| decode_key(&k))
+        .ok_or_else(|| anyhow!(t!("signing.bad_key", path.display())))?;
+    *SIGN_KEY.write().unwrap() = Some(seed);
+    Ok(())
+}
+
+/// The base64 public key manifests are signed for, to hand to whoever syncs them.
+pub fn public_key() -> Result<Option<String>> {
+    match *SIGN_KEY.read().unwrap() {
+        Some(seed) => Ok(Some(STANDARD.encode(keypair(&seed)?.public.to_bytes()))),
+        None => Ok(None),
+    }
+}
+
+/// Makes sync and validate refuse manifests that aren't signed with the base64 encoded
+/// Ed25519 public `key`, or the one in `COMSTAR_VERIFY_KEY`. Without either, unsigned
+/// manifests are accepted again.
+pub fn set_verify_key(key: Option<String>) -> Result<()> {
+    let key = key.or_else(|| {
+        std::env::var("COMSTAR_VERIFY_KEY")
+            .ok()
+            .filter(|k| !k.is_empty())
+    });
+    let public = match key {
+        Some(k) => Some(
+            decode_key(&k)
+                .and_then(|raw| PublicKey::from_bytes(&raw).ok())
+                .ok_or_else(|| anyhow!(t!("signing.bad_public_key")))?,
+        ),
+        None => None,
+    };
+    *VERIFY_KEY.write().unwrap() = public;
+    Ok(())
+}
+
+/// The signature to embed in `manifest`, `None` without a signing key.
+pub fn sign(manifest: &Manifest) -> Result<Option<String>> {
+    let seed = match *SIGN_KEY.read().unwrap() {
+        Some(seed) => seed,
+        None => return Ok(None),
+    };
+    let signature = keypair(&seed)?.sign(manifest.unsigned_digest()?.as_bytes());
+    Ok(Some(STANDARD.encode(signature.to_bytes())))
+}
+
+/// Fails unless the manifest read from `target` carries a valid signature from the
+/// configured public key. Manifests pass unchecked when no key is configured.
+pub fn verify(target: &Url, manifest: &Manifest) -> Result<()> {
+    verify_digest(target, manifest, || manifest.unsigned_digest())
+}
+
+/// `verify` for a manifest whose entries aren't at hand, with `header` and the
+/// `unsigned_digest` of the whole manifest. The digest is only worked out with a key.
+pub fn verify_digest<F>(target: &Url, header: &Manifest, unsigned_digest: F) -> Result<()>
+where
+    F: FnOnce() -> Result<String>,
+{
+    let public = match *VERIFY_KEY.read().unwrap() {
+        Some(k) => k,
+        None => return Ok(()),
+    };
+    let signature = header
+        .signature
+        .as_ref()
+        .ok_or_else(|| anyhow!(t!("signing.unsigned", target)))?;
+    let valid = STANDARD
+        .decode(signature)
+        .ok()
+        .and_then(|raw| Signature::try_from(&raw[..]).ok())
+        .is_some_and(|sig| {
+            unsigned_digest()
+                .is_ok_and(|digest| public.verify_strict(digest.as_bytes(), &sig).is_ok())
+        });
+    if !valid {
+        return Err(anyhow!(t!("signing.bad_signature", target)));
+    }
+    Ok(())
+}
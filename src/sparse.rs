@@ -0,0 +1,97 @@
+use std::{
+    io::SeekFrom,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use anyhow::Result;
+use tokio::{
+    fs::File,
+    io::{AsyncSeekExt, AsyncWriteExt},
+};
+
+/// Zero runs are skipped in blocks of this size, the allocation unit of most filesystems.
+/// Anything smaller isn't worth a seek.
+const SPARSE_BLOCK: u64 = 4096;
+
+static DETECT: AtomicBool = AtomicBool::new(false);
+
+/// Makes every download look for zero runs, not just entries flagged sparse.
+pub fn configure(detect: bool) {
+    DETECT.store(detect, Ordering::Relaxed);
+}
+
+/// Whether a download should be written sparse.
+pub fn wanted(entry_sparse: bool) -> bool {
+    entry_sparse || DETECT.load(Ordering::Relaxed)
+}
+
+/// Whether the file on disk takes less space than its length, meaning it has holes.
+#[cfg(unix)]
+pub fn is_sparse(meta: &std::fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    meta.blocks() * 512 < meta.len()
+}
+
+#[cfg(not(unix))]
+pub fn is_sparse(_meta: &std::fs::Metadata) -> bool {
+    false
+}
+
+/// Writes a freshly truncated file, seeking over whole zero blocks instead of writing them
+/// so filesystems that support it leave holes there. Reads of a hole return zeros, so the
+/// content is the same either way.
+pub struct SparseWriter {
+    file: File,
+    pos: u64,
+    sparse: bool,
+}
+
+impl SparseWriter {
+    pub fn new(file: File, sparse: bool) -> Self {
+        SparseWriter {
+            file,
+            pos: 0,
+            sparse,
+        }
+    }
+
+    pub async fn write_all(&mut self, buf: &[u8]) -> Result<()> {
+        if !self.sparse {
+            self.file.write_all(buf).await?;
+            self.pos += buf.len() as u64;
+            return Ok(());
+        }
+        let mut rest = buf;
+        while !rest.is_empty() {
+            // stay aligned to the file so every skipped block is a whole filesystem block
+            let to_boundary = SPARSE_BLOCK - self.pos % SPARSE_BLOCK;
+            let n = rest.len().min(to_boundary as usize);
+            let (block, tail) = rest.split_at(n);
+            if n as u64 == SPARSE_BLOCK && block.iter().all(|b| *b == 0) {
+                self.file.seek(SeekFrom::Current(n as i64)).await?;
+            } else {
+                self.file.write_all(block).await?;
+            }
+            self.pos += n as u64;
+            rest = tail;
+        }
+        Ok(())
+    }
+
+    /// Throws away everything written so far.
+    pub async fn reset(&mut self) -> Result<()> {
+        self.file.set_len(0).await?;
+        self.file.seek(SeekFrom::Start(0)).await?;
+        self.pos = 0;
+        Ok(())
+    }
+
+    /// Flushes the file, giving it its full length if it ends in a hole.
+    pub async fn finish(mut self) -> Result<()> {
+        self.file.flush().await?;
+        if self.sparse {
+            self.file.set_len(self.pos).await?;
+        }
+        Ok(())
+    }
+}
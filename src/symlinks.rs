@@ -0,0 +1,102 @@
+use std::{fs, io, path::Path};
+
+use anyhow::{anyhow, Result};
+use path_slash::PathExt;
+use relative_path::{RelativePath, RelativePathBuf};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::{backup, i18n::t, perms};
+
+/// A symbolic link in the tree, recorded as the link itself rather than what it points to.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, JsonSchema)]
+pub struct Symlink {
+    /// Slash separated path of the link relative to the synced directory.
+    #[schemars(with = "String")]
+    pub path: RelativePathBuf,
+    /// Slash separated target of the link, relative to the directory the link is in.
+    pub target: String,
+}
+
+/// Whether `target`, seen from the link at `path`, stays inside the tree. Absolute targets
+/// and targets climbing out of the tree don't.
+pub fn stays_inside(path: &RelativePath, target: &str) -> bool {
+    if target.is_empty() || target.starts_with('/') || target.contains(['\\', ':']) {
+        return false;
+    }
+    let mut depth = path.parent().map_or(0, |p| p.iter().count());
+    for part in target.split('/') {
+        match part {
+            "" | "." => {}
+            ".." if depth == 0 => return false,
+            ".." => depth -= 1,
+            _ => depth += 1,
+        }
+    }
+    true
+}
+
+/// The link at `path` below `dir`, `None` if it points outside the tree, in which case
+/// generate follows it like any other file.
+pub fn read(dir: &Path, path: &Path) -> Result<Option<Symlink>> {
+    let relative =
+        RelativePathBuf::from_path(path.strip_prefix(dir)?.to_slash_lossy().to_string())?;
+    let target = fs::read_link(path)?.to_slash_lossy().to_string();
+    Ok(stays_inside(&relative, &target).then_some(Symlink {
+        path: relative,
+        target,
+    }))
+}
+
+/// Links of `links` that are missing from `dir` or point somewhere else.
+pub fn pending<'a>(dir: &Path, links: &'a [Symlink]) -> Vec<&'a Symlink> {
+    links
+        .iter()
+        .filter(|l| {
+            fs::read_link(l.path.to_logical_path(dir))
+                .map_or(true, |t| t.to_slash_lossy() != l.target)
+        })
+        .collect()
+}
+
+/// Creates `link` below `dir`, replacing a file or link already at its path. A directory
+/// there is left alone and fails the link instead.
+pub fn create(dir: &Path, link: &Symlink) -> Result<()> {
+    if !stays_inside(&link.path, &link.target) {
+        return Err(anyhow!(t!("sync.bad_link", link.path, link.target)));
+    }
+    let path = link.path.to_logical_path(dir);
+    match fs::symlink_metadata(&path) {
+        Ok(m) if m.is_dir() => return Err(anyhow!(t!("sync.link_blocked", link.path))),
+        Ok(_) => {
+            backup::preserve(&path)?;
+            if fs::symlink_metadata(&path).is_ok() {
+                fs::remove_file(&path)?;
+            }
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => perms::create_parents(&path)?,
+        Err(e) => return Err(e.into()),
+    }
+    make_link(&path, &link.target)
+}
+
+#[cfg(unix)]
+fn make_link(path: &Path, target: &str) -> Result<()> {
+    std::os::unix::fs::symlink(target, path)?;
+    Ok(())
+}
+
+#[cfg(windows)]
+fn make_link(path: &Path, target: &str) -> Result<()> {
+    let target = Path::new(target);
+    // Windows needs to know up front what kind of link it is, the target exists by now
+    let points_at_dir = path
+        .parent()
+        .is_some_and(|parent| parent.join(target).is_dir());
+    if points_at_dir {
+        std::os::windows::fs::symlink_dir(target, path)?;
+    } else {
+        std::os::windows::fs::symlink_file(target, path)?;
+    }
+    Ok(())
+}
@@ -1,79 +1,1212 @@
-use std::{fs, path::Path, sync::Arc};
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+    process::ExitStatus,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use anyhow::{anyhow, Result};
+use async_compression::tokio::bufread::GzipDecoder;
 use futures::StreamExt;
+use google_cloud_storage::http::objects::{download::Range, get::GetObjectRequest};
+use indicatif::BinaryBytes;
+use relative_path::{RelativePath, RelativePathBuf};
+use reqwest::{
+    header::{HeaderMap, HeaderValue, CONTENT_RANGE, ETAG, RANGE},
+    StatusCode,
+};
+use serde::Deserialize;
+use structopt::StructOpt;
 use tokio::{
-    io::AsyncWriteExt,
-    sync::{mpsc::Sender, Semaphore},
+    io::{AsyncReadExt, AsyncWriteExt},
+    sync::mpsc,
 };
 use url::Url;
 
 use crate::{
-    events::{self, Event},
-    manifest, validate,
+    backend, backup,
+    events::{self, Event, EventSender},
+    http,
+    i18n::t,
+    ipc,
+    journal::Journal,
+    lazy::{self, Placeholders},
+    manifest::{self, ManifestEntry},
+    mirrors, mtime,
+    perms::{self, Mode, ReadOnlyPolicy},
+    pin, plugin,
+    push::{gcs, s3},
+    quota,
+    ratelimit::{self, BandwidthWindow},
+    shutdown, signed, signing,
+    sparse::{self, SparseWriter},
+    symlinks,
+    util::{self, ByteSize, Chunk},
+    validate, xattrs,
 };
 
-#[tracing::instrument]
-async fn get_file_http(src: &Url, dest: &Path, tx: Sender<Event>) -> Result<()> {
-    let resp = reqwest::get(src.as_ref()).await?;
+/// What to do when a file that needs changing is held open by another process.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BusyPolicy {
+    Wait,
+    Skip,
+    Abort,
+}
+
+/// In-use detection for one sync, set up only when a busy policy is given.
+pub struct BusyCheck {
+    policy: BusyPolicy,
+    open: util::OpenFiles,
+}
+
+impl FromStr for BusyPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "wait" => Ok(BusyPolicy::Wait),
+            "skip" => Ok(BusyPolicy::Skip),
+            "abort" => Ok(BusyPolicy::Abort),
+            _ => Err(anyhow!(
+                "Unknown busy policy {}, expected wait, skip or abort",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, StructOpt, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SyncOptions {
+    #[structopt(
+        short,
+        long,
+        help = "Ensure that ONLY files in the manifest are at the destination. Deletes any file not in the manifest."
+    )]
+    pub force: bool,
+    #[structopt(
+        long = "validate",
+        help = "Force validation of local files instead of trusting the local manifest"
+    )]
+    #[serde(rename = "validate")]
+    pub force_validate: bool,
+    #[structopt(
+        long,
+        parse(from_str),
+        help = "Path prefix to sync before anything else. May be repeated, earlier prefixes go first."
+    )]
+    pub prefer: Vec<RelativePathBuf>,
+    #[structopt(
+        long,
+        help = "Command to run in the synced directory once sync completes and validates cleanly."
+    )]
+    pub then: Option<String>,
+    #[structopt(
+        long = "busy-policy",
+        possible_values = &["wait", "skip", "abort"],
+        help = "What to do with files that are in use by another process. Without it files are changed whether in use or not."
+    )]
+    pub busy_policy: Option<BusyPolicy>,
+    #[structopt(
+        long = "limit-rate",
+        help = "Download bandwidth cap per second, e.g. 1MiB."
+    )]
+    pub limit_rate: Option<ByteSize>,
+    #[structopt(
+        long = "bandwidth-schedule",
+        help = "Time-of-day bandwidth cap like 09:00-18:00=1MiB or 22:00-06:00=unlimited. May be repeated, overrides --limit-rate inside the window."
+    )]
+    pub bandwidth_schedule: Vec<BandwidthWindow>,
+    #[structopt(
+        long,
+        help = "Create empty placeholders instead of downloading, fetch them later with `comstar get`."
+    )]
+    pub lazy: bool,
+    #[structopt(
+        long = "stall-timeout",
+        help = "Seconds a download may go without receiving data before it is restarted. Default is 60."
+    )]
+    pub stall_timeout: Option<u64>,
+    #[structopt(
+        long = "dir-mode",
+        help = "Octal mode for directories sync creates, e.g. 0775. The umask still applies. Unix only."
+    )]
+    pub dir_mode: Option<Mode>,
+    #[structopt(
+        long = "file-mode",
+        help = "Octal mode for files sync creates, e.g. 0664. The umask still applies. Files whose manifest entry records a mode get that instead. Unix only."
+    )]
+    pub file_mode: Option<Mode>,
+    #[structopt(
+        long = "read-only",
+        possible_values = &["error", "override"],
+        help = "What to do with read-only files or directories that need changing. override makes them writable for the update and read-only again afterwards. Default is error."
+    )]
+    pub read_only: Option<ReadOnlyPolicy>,
+    #[structopt(
+        long,
+        help = "Leave blocks of zeros in every download as holes on filesystems that support sparse files. Entries flagged sparse always are."
+    )]
+    pub sparse: bool,
+    #[structopt(
+        long,
+        help = "Restore extended attributes and ACLs recorded in the manifest on files sync writes. Unix only."
+    )]
+    pub xattrs: bool,
+    #[structopt(
+        long,
+        conflicts_with = "lazy",
+        help = "Download and verify every change in a staging area first, then move it all into place in one short final step."
+    )]
+    pub staged: bool,
+    #[structopt(
+        long,
+        help = "Keep the files sync replaces or deletes so `comstar rollback` can undo the sync."
+    )]
+    pub backup: bool,
+    #[structopt(
+        long,
+        help = "Base URL of a mirror serving the same files as the manifest. May be repeated, the fastest one is used and the others are tried when a download fails."
+    )]
+    pub mirror: Vec<Url>,
+    #[structopt(
+        long = "max-file-size",
+        help = "Refuse to download any file larger than this, e.g. 2GiB."
+    )]
+    pub max_file_size: Option<ByteSize>,
+    #[structopt(
+        long = "max-total-download",
+        help = "Stop downloading once this much has been received in one sync, e.g. 10GiB."
+    )]
+    pub max_total_download: Option<ByteSize>,
+    #[structopt(
+        long = "signed-urls",
+        help = "Download private gs:// and s3:// sources through short-lived URLs signed with local credentials, so downloads resume like public ones."
+    )]
+    pub signed_urls: bool,
+    #[structopt(
+        long = "quick-check",
+        help = "Trust local files whose size and modification time match the manifest instead of hashing them."
+    )]
+    pub quick_check: bool,
+    /// `--verify-key`, which a profile may set for its own manifest.
+    #[structopt(skip)]
+    pub verify_key: Option<String>,
+    /// `--billing-project`, which a profile may set for its own bucket.
+    #[structopt(skip)]
+    pub billing_project: Option<String>,
+    /// `--gcs-encryption-key`, which a profile may set for its own bucket.
+    #[structopt(skip)]
+    pub gcs_encryption_key: Option<String>,
+}
+
+impl SyncOptions {
+    /// Fills in anything not given on the command line from a profile.
+    pub fn merge(mut self, profile: &SyncOptions) -> SyncOptions {
+        self.force |= profile.force;
+        self.force_validate |= profile.force_validate;
+        self.lazy |= profile.lazy;
+        self.sparse |= profile.sparse;
+        self.xattrs |= profile.xattrs;
+        self.staged |= profile.staged;
+        self.backup |= profile.backup;
+        self.signed_urls |= profile.signed_urls;
+        self.quick_check |= profile.quick_check;
+        if self.prefer.is_empty() {
+            self.prefer = profile.prefer.clone();
+        }
+        if self.then.is_none() {
+            self.then = profile.then.clone();
+        }
+        if self.mirror.is_empty() {
+            self.mirror = profile.mirror.clone();
+        }
+        self.busy_policy = self.busy_policy.or(profile.busy_policy);
+        self.limit_rate = self.limit_rate.or(profile.limit_rate);
+        if self.bandwidth_schedule.is_empty() {
+            self.bandwidth_schedule = profile.bandwidth_schedule.clone();
+        }
+        self.stall_timeout = self.stall_timeout.or(profile.stall_timeout);
+        self.dir_mode = self.dir_mode.or(profile.dir_mode);
+        self.file_mode = self.file_mode.or(profile.file_mode);
+        self.read_only = self.read_only.or(profile.read_only);
+        self.max_file_size = self.max_file_size.or(profile.max_file_size);
+        self.max_total_download = self.max_total_download.or(profile.max_total_download);
+        self.verify_key = self.verify_key.or_else(|| profile.verify_key.clone());
+        self.billing_project = self
+            .billing_project
+            .or_else(|| profile.billing_project.clone());
+        self.gcs_encryption_key = self
+            .gcs_encryption_key
+            .or_else(|| profile.gcs_encryption_key.clone());
+        self
+    }
+
+    pub fn stall_timeout(&self) -> Duration {
+        self.stall_timeout
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_STALL_TIMEOUT)
+    }
+}
+
+enum Outcome {
+    Downloaded(RelativePathBuf, String, Url),
+    NotStarted,
+    Deleted,
+    ModeRestored,
+    Placeholder(RelativePathBuf),
+    Skipped(RelativePathBuf),
+    Failed(RelativePathBuf),
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct SyncSummary {
+    pub downloaded: usize,
+    pub copied: usize,
+    pub deleted: usize,
+    pub renamed: usize,
+    pub placeholders: usize,
+    /// Symbolic links created or pointed somewhere else.
+    pub linked: usize,
+    /// Empty directories created.
+    pub dirs: usize,
+    /// Files whose content was right but whose permission bits weren't.
+    pub modes: usize,
+    /// Where each download came from, when mirrors are configured.
+    pub served_by: BTreeMap<RelativePathBuf, Url>,
+    pub skipped: Vec<RelativePathBuf>,
+    pub failed: Vec<RelativePathBuf>,
+}
+
+impl SyncSummary {
+    pub fn changes(&self) -> usize {
+        self.downloaded
+            + self.copied
+            + self.deleted
+            + self.renamed
+            + self.placeholders
+            + self.linked
+            + self.dirs
+            + self.modes
+    }
+}
+
+/// How many times a broken download is resumed before giving up. Every chunk gets this
+/// many attempts of its own.
+const MAX_RESUME_ATTEMPTS: u32 = 5;
+
+/// Downloads are requested in ranges of this size, so a failure late in a large file only
+/// costs the chunk it happened in.
+const DOWNLOAD_CHUNK: u64 = 64 * 1024 * 1024;
+
+/// How long a download may go without receiving any data before it is restarted.
+pub const DEFAULT_STALL_TIMEOUT: Duration = Duration::from_secs(60);
+
+fn resume_delay(attempt: u32) -> Duration {
+    Duration::from_millis(500 * 2u64.pow(attempt.min(6)))
+}
+
+/// Parses `bytes first-last/size` into its three parts.
+fn parse_content_range(v: &str) -> Option<(u64, u64, u64)> {
+    let (range, size) = v.strip_prefix("bytes ")?.split_once('/')?;
+    let (first, last) = range.split_once('-')?;
+    Some((first.parse().ok()?, last.parse().ok()?, size.parse().ok()?))
+}
+
+/// Downloads `src` in ranged chunks, sending `headers` with every request.
+#[tracing::instrument(skip(headers))]
+pub async fn get_file_http(
+    src: &Url,
+    headers: &HeaderMap,
+    dest: &Path,
+    stall_timeout: Duration,
+    sparse: bool,
+    tx: EventSender,
+) -> Result<String> {
     let fname = dest.file_name().unwrap().to_string_lossy().to_string();
-    if let Some(p) = dest.parent() {
-        fs::create_dir_all(p)?;
-    }
-    let mut stream = resp.bytes_stream();
-    let mut f = tokio::fs::OpenOptions::new()
-        .create(true)
-        .write(true)
-        .truncate(true)
-        .open(dest)
-        .await?;
+    let limiter = ratelimit::current();
+    let (mut client, mut quic) = http::download_client(src);
+    let mut f = SparseWriter::new(perms::create_file(dest).await?, sparse);
+    let mut hasher = util::Hasher::new();
+    let mut written: u64 = 0;
+    let mut attempt = 0;
+    let mut total: Option<u64> = None;
+    let mut etag: Option<HeaderValue> = None;
+    if let Some(host) = src.host_str() {
+        tx.send(Event::file_host(&fname, host));
+    }
+
+    loop {
+        let mut chunk_start = written;
+        let req = client.get(src.as_ref()).headers(headers.clone()).header(
+            RANGE,
+            format!("bytes={}-{}", written, written + DOWNLOAD_CHUNK - 1),
+        );
+        let sent = match tokio::time::timeout(stall_timeout, req.send()).await {
+            Ok(r) => r.map_err(anyhow::Error::from),
+            Err(_) => {
+                tx.send(Event::file_stalled(&fname));
+                Err(anyhow!(t!("get.stalled", src, stall_timeout.as_secs())))
+            }
+        };
+        let resp = match sent {
+            Ok(r) => r.error_for_status()?,
+            // not counted as an attempt, the server may well be fine over TCP
+            Err(_) if quic => {
+                http::http3_failed(src);
+                client = http::client();
+                quic = false;
+                continue;
+            }
+            Err(e) if attempt < MAX_RESUME_ATTEMPTS => {
+                attempt += 1;
+                tracing::warn!("Request for {} failed, retrying: {}", src, e);
+                tx.send(Event::file_retried(&fname, attempt));
+                tokio::time::sleep(resume_delay(attempt)).await;
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        let ranged = resp.status() == StatusCode::PARTIAL_CONTENT;
+        let expected = if ranged {
+            let range = resp
+                .headers()
+                .get(CONTENT_RANGE)
+                .and_then(|v| v.to_str().ok())
+                .and_then(parse_content_range);
+            let resp_etag = resp.headers().get(ETAG);
+            let changed = etag.is_some() && resp_etag != etag.as_ref();
+            match range {
+                Some((first, last, size)) if first == written && !changed => {
+                    if total.is_none() {
+                        quota::check_size(&fname, size)?;
+                        tx.send(Event::file_length(&fname, size));
+                    }
+                    total = Some(size);
+                    etag = resp_etag.cloned();
+                    Some(last + 1 - first)
+                }
+                // the file changed between chunks or the server sent the wrong range, what
+                // we have can't be trusted
+                _ if attempt < MAX_RESUME_ATTEMPTS => {
+                    attempt += 1;
+                    tracing::warn!("Range response for {} doesn't match, restarting", src);
+                    tx.send(Event::file_retried(&fname, attempt));
+                    f.reset().await?;
+                    hasher = util::Hasher::new();
+                    written = 0;
+                    total = None;
+                    etag = None;
+                    continue;
+                }
+                _ => return Err(anyhow!(t!("get.bad_range", src))),
+            }
+        } else {
+            if written > 0 {
+                // server ignored the range, start the file over
+                f.reset().await?;
+                hasher = util::Hasher::new();
+                written = 0;
+                chunk_start = 0;
+            }
+            total = resp.content_length();
+            if let Some(len) = total {
+                quota::check_size(&fname, len)?;
+                tx.send(Event::file_length(&fname, len));
+            }
+            total
+        };
+
+        let mut stream = resp.bytes_stream();
+        let mut failure = None;
+        loop {
+            let chunk = match tokio::time::timeout(stall_timeout, stream.next()).await {
+                Ok(Some(Ok(c))) => c,
+                Ok(Some(Err(e))) => {
+                    failure = Some(e.into());
+                    break;
+                }
+                Ok(None) => break,
+                Err(_) => {
+                    // a dead connection can hang forever, drop it and resume from here
+                    tx.send(Event::file_stalled(&fname));
+                    failure = Some(anyhow!(t!("get.stalled", src, stall_timeout.as_secs())));
+                    break;
+                }
+            };
+            let len = chunk.len() as u64;
+            quota::charge(&fname, written, len)?;
+            if let Some(l) = &limiter {
+                l.acquire(len).await;
+            }
+            hasher.update(&chunk);
+            f.write_all(&chunk).await?;
+            written += len;
+            tx.send(Event::file_progress(&fname, len));
+        }
+        if failure.is_none() && expected.is_some_and(|e| written - chunk_start < e) {
+            failure = Some(anyhow!(t!("get.short_chunk", src, written)));
+        }
+        match failure {
+            // chunk complete, the next one starts with a fresh retry budget
+            None if ranged && total.is_some_and(|t| written < t) => attempt = 0,
+            None => break,
+            Some(e) if attempt < MAX_RESUME_ATTEMPTS => {
+                attempt += 1;
+                tracing::warn!(
+                    "Download of {} broke at byte {}, resuming: {}",
+                    src,
+                    written,
+                    e
+                );
+                tx.send(Event::file_retried(&fname, attempt));
+                tokio::time::sleep(resume_delay(attempt)).await;
+            }
+            Some(e) => return Err(e),
+        }
+    }
+    f.finish().await?;
+    Ok(hasher.finish())
+}
+
+/// Writes what a download running on a blocking thread receives to `dest`, for clients
+/// without an async API.
+pub async fn write_chunks(
+    src: &Url,
+    mut chunks: mpsc::Receiver<Result<Chunk>>,
+    dest: &Path,
+    stall_timeout: Duration,
+    sparse: bool,
+    tx: EventSender,
+) -> Result<String> {
+    let fname = dest.file_name().unwrap().to_string_lossy().to_string();
+    let limiter = ratelimit::current();
+    let mut f = SparseWriter::new(perms::create_file(dest).await?, sparse);
+    let mut hasher = util::Hasher::new();
+    let mut written = 0;
+    loop {
+        let chunk = match tokio::time::timeout(stall_timeout, chunks.recv()).await {
+            Ok(Some(c)) => c?,
+            Ok(None) => break,
+            Err(_) => {
+                tx.send(Event::file_stalled(&fname));
+                return Err(anyhow!(t!("get.stalled", src, stall_timeout.as_secs())));
+            }
+        };
+        let data = match chunk {
+            Chunk::Length(len) => {
+                quota::check_size(&fname, len)?;
+                tx.send(Event::file_length(&fname, len));
+                continue;
+            }
+            Chunk::Data(d) => d,
+        };
+        let len = data.len() as u64;
+        quota::charge(&fname, written, len)?;
+        written += len;
+        if let Some(l) = &limiter {
+            l.acquire(len).await;
+        }
+        hasher.update(&data);
+        f.write_all(&data).await?;
+        tx.send(Event::file_progress(&fname, len));
+    }
+    f.finish().await?;
+    Ok(hasher.finish())
+}
 
-    while let Some(chunk_result) = stream.next().await {
-        let chunk = chunk_result?;
+/// Downloads an `s3://` object with the credentials from the standard AWS chain.
+pub async fn get_file_s3(
+    src: &Url,
+    dest: &Path,
+    stall_timeout: Duration,
+    sparse: bool,
+    tx: EventSender,
+) -> Result<String> {
+    let fname = dest.file_name().unwrap().to_string_lossy().to_string();
+    let object = s3::get(src)
+        .await?
+        .ok_or_else(|| anyhow!("No such S3 object: {}", src))?;
+    if let Ok(len) = u64::try_from(object.content_length()) {
+        quota::check_size(&fname, len)?;
+        tx.send(Event::file_length(&fname, len));
+    }
+    let limiter = ratelimit::current();
+    let mut body = object.body;
+    let mut f = SparseWriter::new(perms::create_file(dest).await?, sparse);
+    let mut hasher = util::Hasher::new();
+    let mut written = 0;
+    loop {
+        let chunk = match tokio::time::timeout(stall_timeout, body.next()).await {
+            Ok(Some(c)) => c?,
+            Ok(None) => break,
+            Err(_) => {
+                tx.send(Event::file_stalled(&fname));
+                return Err(anyhow!(t!("get.stalled", src, stall_timeout.as_secs())));
+            }
+        };
         let len = chunk.len() as u64;
+        quota::charge(&fname, written, len)?;
+        written += len;
+        if let Some(l) = &limiter {
+            l.acquire(len).await;
+        }
+        hasher.update(&chunk);
         f.write_all(&chunk).await?;
-        tx.send(Event::file_progress(&fname, len)).await?;
+        tx.send(Event::file_progress(&fname, len));
+    }
+    f.finish().await?;
+    Ok(hasher.finish())
+}
+
+pub async fn get_file_file(
+    src: &Url,
+    dest: &Path,
+    sparse: bool,
+    tx: EventSender,
+) -> Result<String> {
+    let path = util::url_path(src)?;
+    copy_local(&path, dest, sparse, tx).await
+}
+
+/// Fetches `src` through its plugin into a temporary file, then copies it into place.
+pub async fn get_file_plugin(
+    src: &Url,
+    dest: &Path,
+    sparse: bool,
+    tx: EventSender,
+) -> Result<String> {
+    let fetched = plugin::get_object(src).await?;
+    let res = copy_local(&fetched, dest, sparse, tx).await;
+    let _ = fs::remove_file(&fetched);
+    res
+}
+
+/// Copies the local file at `path` to `dest`, returning the SHA-512 of the bytes written.
+async fn copy_local(path: &Path, dest: &Path, sparse: bool, tx: EventSender) -> Result<String> {
+    let fname = dest.file_name().unwrap().to_string_lossy().to_string();
+    let mut input = tokio::fs::File::open(&path).await?;
+    let len = input.metadata().await?.len();
+    quota::check_size(&fname, len)?;
+    tx.send(Event::file_length(&fname, len));
+    let mut f = SparseWriter::new(perms::create_file(dest).await?, sparse);
+    let mut hasher = util::Hasher::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut written = 0;
+    loop {
+        let n = input.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        quota::charge(&fname, written, n as u64)?;
+        written += n as u64;
+        hasher.update(&buf[..n]);
+        f.write_all(&buf[..n]).await?;
+        tx.send(Event::file_progress(&fname, n as u64));
+    }
+    f.finish().await?;
+    Ok(hasher.finish())
+}
+
+/// Downloads an object from GCS with the application default credentials, for buckets
+/// that aren't public. A failed attempt starts over, the object API has no cheap resume.
+pub async fn get_file_gcs(
+    src: &Url,
+    dest: &Path,
+    stall_timeout: Duration,
+    sparse: bool,
+    tx: EventSender,
+) -> Result<String> {
+    let fname = dest.file_name().unwrap().to_string_lossy().to_string();
+    let (bucket, object) =
+        gcs::object_of(src).ok_or_else(|| anyhow!("Not a GCS object URL: {}", src))?;
+    let req = GetObjectRequest {
+        bucket,
+        object,
+        encryption: gcs::encryption(),
+        ..Default::default()
+    };
+    let client = gcs::client().await?;
+    let limiter = ratelimit::current();
+    let mut f = SparseWriter::new(perms::create_file(dest).await?, sparse);
+    tx.send(Event::file_host(&fname, "storage.googleapis.com"));
+    let mut attempt = 0;
+    loop {
+        let mut hasher = util::Hasher::new();
+        let mut written = 0;
+        let downloaded: Result<()> = async {
+            let mut stream = Box::pin(
+                client
+                    .download_streamed_object(&req, &Range::default(), None)
+                    .await?,
+            );
+            loop {
+                let chunk = match tokio::time::timeout(stall_timeout, stream.next()).await {
+                    Ok(Some(c)) => c?,
+                    Ok(None) => return Ok(()),
+                    Err(_) => {
+                        tx.send(Event::file_stalled(&fname));
+                        return Err(anyhow!(t!("get.stalled", src, stall_timeout.as_secs())));
+                    }
+                };
+                let len = chunk.len() as u64;
+                quota::charge(&fname, written, len)?;
+                written += len;
+                if let Some(l) = &limiter {
+                    l.acquire(len).await;
+                }
+                hasher.update(&chunk);
+                f.write_all(&chunk).await?;
+                tx.send(Event::file_progress(&fname, len));
+            }
+        }
+        .await;
+        match downloaded {
+            Ok(()) => {
+                f.finish().await?;
+                return Ok(hasher.finish());
+            }
+            Err(e) if attempt < MAX_RESUME_ATTEMPTS && !quota::is_exceeded(&e) => {
+                attempt += 1;
+                tracing::warn!("Download of {} failed, starting over: {}", src, e);
+                tx.send(Event::file_retried(&fname, attempt));
+                f.reset().await?;
+                tokio::time::sleep(resume_delay(attempt)).await;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Size of a manifest entry's source without downloading it, if the source reports one.
+pub async fn remote_size(src: &Url) -> Result<Option<u64>> {
+    backend::for_url(src)?.size(src).await
+}
+
+/// Downloads `src` to `dest`, returning the SHA-512 of the bytes written. With `sparse`,
+/// zero blocks are left as holes.
+#[tracing::instrument]
+pub async fn get_file(
+    src: &Url,
+    dest: &Path,
+    stall_timeout: Duration,
+    sparse: bool,
+    t: EventSender,
+) -> Result<String> {
+    backend::for_url(src)?
+        .get_file(src, dest, stall_timeout, sparse, t)
+        .await
+}
+
+/// Refuses paths the local platform can't represent before anything is written.
+fn check_name(path: &RelativePath) -> Result<()> {
+    if cfg!(windows) {
+        if let Some(problem) = util::windows_name_problem(path) {
+            return Err(anyhow!(t!("sync.bad_name", path, problem)));
+        }
     }
     Ok(())
 }
 
-async fn get_file_file(src: &Url, dest: &Path) -> Result<()> {
-    let path = src
-        .to_file_path()
-        .map_err(|_| anyhow!("Could not create path from URL {}", src))?;
-    if let Some(p) = dest.parent() {
-        fs::create_dir_all(p)?;
+async fn copy_duplicate(original: &Path, dest: &Path, path: &RelativePath) -> Result<()> {
+    check_name(path)?;
+    backup::preserve(dest)?;
+    let unlocked = perms::unlock(dest, false)?;
+    let cleared = util::clear_blocking_attributes(dest)?;
+    let res = copy_contents(original, dest).await;
+    cleared.restore(dest)?;
+    unlocked.relock()?;
+    res
+}
+
+/// Hard links `dest` to `original`, copying instead where links aren't possible, e.g.
+/// across filesystems.
+async fn link_duplicate(original: &Path, dest: &Path, path: &RelativePath) -> Result<()> {
+    check_name(path)?;
+    backup::preserve(dest)?;
+    let unlocked = perms::unlock(dest, true)?;
+    let linked = link_file(original, dest);
+    unlocked.relock()?;
+    if let Err(e) = linked {
+        tracing::warn!(
+            "Could not link {} to {}, copying: {}",
+            path,
+            original.display(),
+            e
+        );
+        return copy_duplicate(original, dest, path).await;
     }
-    tokio::fs::copy(&path, dest).await?;
     Ok(())
 }
 
-#[tracing::instrument]
-pub async fn get_file(src: &Url, dest: &Path, t: Sender<Event>) -> Result<()> {
-    match src.scheme() {
-        "http" | "https" => get_file_http(src, dest, t).await,
-        "file" => get_file_file(src, dest).await,
-        _ => unimplemented!(),
+/// Fails unless the copy or link at `dest` holds the content recorded as `expected`, so a
+/// stale original never passes as the entry.
+async fn verify_copy(dest: &Path, expected: &str, path: &RelativePath) -> Result<()> {
+    if util::hash_file_with(dest.to_path_buf(), util::algo()).await? != expected {
+        return Err(anyhow!(t!("get.hash_mismatch", path)));
+    }
+    Ok(())
+}
+
+fn link_file(original: &Path, dest: &Path) -> Result<()> {
+    perms::create_parents(dest)?;
+    if dest.exists() {
+        fs::remove_file(dest)?;
     }
+    fs::hard_link(original, dest)?;
+    Ok(())
+}
+
+async fn copy_contents(original: &Path, dest: &Path) -> Result<()> {
+    // not fs::copy, that would carry over the original's mode instead of --file-mode
+    let mut input = tokio::fs::File::open(original).await?;
+    let mut f = perms::create_file(dest).await?;
+    tokio::io::copy(&mut input, &mut f).await?;
+    f.flush().await?;
+    Ok(())
+}
+
+/// Decodes a gzipped download in place, returning the SHA-512 of the decoded content.
+async fn gunzip_in_place(path: &Path) -> Result<String> {
+    let tmp = path.with_file_name(format!(
+        ".{}.comstar-gunzip",
+        path.file_name().unwrap().to_string_lossy()
+    ));
+    let input = tokio::io::BufReader::new(tokio::fs::File::open(path).await?);
+    let mut decoder = GzipDecoder::new(input);
+    let mut out = perms::create_file(&tmp).await?;
+    tokio::io::copy(&mut decoder, &mut out).await?;
+    out.flush().await?;
+    drop(out);
+    fs::rename(&tmp, path)?;
+    util::hash_file_with(path.to_path_buf(), util::algo()).await
 }
 
 pub async fn delete_file(f: &Path) -> Result<()> {
+    if tokio::fs::symlink_metadata(f).await?.is_dir() {
+        // only ever an unexpected empty directory, whatever appeared in it since stays
+        if fs::read_dir(f)?.next().is_none() {
+            tokio::fs::remove_dir(f).await?;
+        }
+        return Ok(());
+    }
     tokio::fs::remove_file(f).await?;
     Ok(())
 }
 
-#[tracing::instrument]
-pub async fn sync_manifest(
-    target: &Url,
+/// Where `--staged` keeps downloads until all of them are verified. Inside the synced tree
+/// so the final moves are renames on the same filesystem.
+fn staging_dir(dir: &Path) -> PathBuf {
+    util::state_dir(dir).join("staging")
+}
+
+/// Where the entries of the manifest being synced are spooled, see `SpooledEntries`.
+fn spool_path(dir: &Path) -> Result<PathBuf> {
+    let state = util::state_dir(dir);
+    fs::create_dir_all(&state)?;
+    Ok(state.join("manifest-entries"))
+}
+
+/// Moves a verified file from the staging area over its live counterpart.
+fn swap_in(staged: &Path, live: &Path) -> Result<()> {
+    backup::preserve(live)?;
+    perms::create_parents(live)?;
+    let unlocked = perms::unlock(live, true)?;
+    let cleared = util::clear_blocking_attributes(live)?;
+    let res = match fs::rename(staged, live) {
+        Err(e) if e.kind() == std::io::ErrorKind::CrossesDevices => copy_in(staged, live),
+        res => res,
+    };
+    cleared.restore(live)?;
+    unlocked.relock()?;
+    Ok(res?)
+}
+
+/// Puts `staged` in place of `live` when the staging area is on another filesystem: copies
+/// it next to `live`, flushes it to disk and renames that, so `live` is never half written.
+fn copy_in(staged: &Path, live: &Path) -> std::io::Result<()> {
+    let tmp = live.with_file_name(format!(
+        ".{}.comstar-swap",
+        live.file_name().unwrap().to_string_lossy()
+    ));
+    let res = fs::copy(staged, &tmp)
+        .and_then(|_| fs::OpenOptions::new().write(true).open(&tmp)?.sync_all())
+        .and_then(|_| fs::rename(&tmp, live));
+    if res.is_err() {
+        let _ = fs::remove_file(&tmp);
+        return res;
+    }
+    fs::remove_file(staged)
+}
+
+/// The final phase of a staged sync: moves everything the journal lists as staged into the
+/// live tree, then deletes files that are no longer in the manifest.
+async fn swap_staged(
     dir: &Path,
-    force: bool,
-    force_validate: bool,
+    journal: &mut Journal,
+    deletes: Vec<validate::ValidationDifference>,
+    busy: Option<&BusyCheck>,
+    tx: &EventSender,
+    summary: &mut SyncSummary,
 ) -> Result<()> {
+    let staging = staging_dir(dir);
+    let pending: Vec<RelativePathBuf> = journal.completed.keys().cloned().collect();
+    for path in pending {
+        let live = path.to_logical_path(dir);
+        if !check_busy(&live, busy).await? {
+            summary.skipped.push(path);
+            continue;
+        }
+        if let Err(e) = swap_in(&path.to_logical_path(&staging), &live) {
+            tx.send(Event::file_failed(path.file_name().unwrap(), e));
+            summary.failed.push(path);
+            continue;
+        }
+        journal.completed.remove(&path);
+    }
+    for d in deletes {
+        let fname = d.path.file_name().unwrap().to_string();
+        let live = d.path.to_logical_path(dir);
+        tx.send(Event::unknown_file_started(&fname));
+        if !check_busy(&live, busy).await? {
+            tx.send(Event::file_skipped(&fname, t!("sync.in_use")));
+            summary.skipped.push(d.path);
+            continue;
+        }
+        backup::preserve(&live)?;
+        let unlocked = perms::unlock(&live, true)?;
+        let res = delete_file(&live).await;
+        unlocked.relock()?;
+        match res {
+            Ok(()) => {
+                summary.deleted += 1;
+                tx.send(Event::file_done(&fname));
+            }
+            Err(e) => {
+                tx.send(Event::file_failed(&fname, e));
+                summary.failed.push(d.path);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Pairs up entries that are missing with untracked files whose path differs only in case,
+/// and renames those files into place. On a case-insensitive filesystem both sides of such
+/// a pair are the same file, so downloading one and deleting the other would delete it.
+/// Entries whose renamed file already has the right content, hashed with `algo`, drop out
+/// of `diff`.
+async fn fix_case_renames(
+    diff: &mut Vec<validate::ValidationDifference>,
+    dir: &Path,
+    algo: util::HashAlgo,
+) -> Result<usize> {
+    let mut unknown: HashMap<String, usize> = HashMap::new();
+    for (i, d) in diff.iter().enumerate() {
+        if matches!(d.ty, validate::DifferenceType::UnknownFile) {
+            unknown.insert(d.path.as_str().to_lowercase(), i);
+        }
+    }
+    if unknown.is_empty() {
+        return Ok(0);
+    }
+
+    let mut resolved = HashSet::new();
+    let mut renamed = 0;
+    for (i, d) in diff.iter().enumerate() {
+        let entry = match &d.ty {
+            validate::DifferenceType::FileMissing(e)
+            | validate::DifferenceType::HashMismatch { upstream: e, .. } => e,
+            validate::DifferenceType::ModeMismatch(_) | validate::DifferenceType::UnknownFile => {
+                continue
+            }
+        };
+        let j = match unknown.remove(&d.path.as_str().to_lowercase()) {
+            Some(j) => j,
+            None => continue,
+        };
+        let from = diff[j].path.to_logical_path(dir);
+        let to = d.path.to_logical_path(dir);
+        rename_case(&from, &to)?;
+        renamed += 1;
+        resolved.insert(j);
+        if util::hash_file_with(to, algo).await? == entry.sha512 {
+            resolved.insert(i);
+        }
+    }
+    let mut i = 0;
+    diff.retain(|_| {
+        i += 1;
+        !resolved.contains(&(i - 1))
+    });
+    Ok(renamed)
+}
+
+/// Renames `from` to `to` by way of a temporary name, since a case-insensitive filesystem
+/// may treat a direct rename between the two as a no-op.
+fn rename_case(from: &Path, to: &Path) -> Result<()> {
+    let tmp = from.with_file_name(format!(
+        ".{}.comstar-rename",
+        from.file_name().unwrap().to_string_lossy()
+    ));
+    fs::rename(from, &tmp)?;
+    perms::create_parents(to)?;
+    fs::rename(&tmp, to)?;
+    Ok(())
+}
+
+/// Returns whether the file may be modified, waiting for it to be released if the policy says so.
+async fn check_busy(path: &Path, busy: Option<&BusyCheck>) -> Result<bool> {
+    let busy = match busy {
+        Some(b) => b,
+        None => return Ok(true),
+    };
+    if !path.exists() || !busy.open.contains(path) {
+        return Ok(true);
+    }
+    match busy.policy {
+        BusyPolicy::Abort => Err(anyhow!(t!("sync.busy", path.display()))),
+        BusyPolicy::Skip => Ok(false),
+        BusyPolicy::Wait => loop {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            if !path.exists() || !util::OpenFiles::scan().await?.contains(path) {
+                return Ok(true);
+            }
+        },
+    }
+}
+
+/// Sort key for a difference: files under an earlier `prefer` prefix go first,
+/// then higher manifest priority, otherwise manifest order is kept.
+fn sync_order(
+    d: &validate::ValidationDifference,
+    prefer: &[RelativePathBuf],
+) -> (usize, Reverse<i32>) {
+    let prefix_rank = prefer
+        .iter()
+        .position(|p| d.path.starts_with(p))
+        .unwrap_or(prefer.len());
+    let priority = d.ty.entry().and_then(|e| e.priority).unwrap_or(0);
+    (prefix_rank, Reverse(priority))
+}
+
+/// Downloads `entry` to `dest` and checks its hash, trying each mirror in turn until one
+/// delivers the right content. Returns the source that did.
+async fn download_entry(
+    entry: &ManifestEntry,
+    dest: &Path,
+    stall_timeout: Duration,
+    t: EventSender,
+) -> Result<Url> {
+    let sparse = sparse::wanted(entry.sparse);
+    let candidates = mirrors::candidates(&entry.source);
+    let mut last_error = None;
+    for (i, src) in candidates.iter().enumerate() {
+        if i > 0 {
+            let fname = dest.file_name().unwrap().to_string_lossy();
+            t.send(Event::file_retried(fname, i as u32));
+        }
+        let started = Instant::now();
+        let res = match get_file(src, dest, stall_timeout, sparse, t.clone()).await {
+            Ok(sha512)
+                if sha512 != entry.sha512 && entry.encoded_sha512.as_ref() == Some(&sha512) =>
+            {
+                // served as push stored it, without decoding the gzip
+                gunzip_in_place(dest).await
+            }
+            res => res,
+        };
+        let error = match res {
+            Ok(sha512) if sha512 == entry.sha512 => {
+                if let Ok(meta) = fs::metadata(dest) {
+                    mirrors::record(src, meta.len(), started.elapsed());
+                }
+                return Ok(src.clone());
+            }
+            Ok(_) => match (entry.size, fs::metadata(dest).map(|m| m.len())) {
+                (Some(expected), Ok(actual)) if actual < expected => {
+                    anyhow!(t!("get.truncated", entry.path, actual, expected))
+                }
+                _ => anyhow!(t!("get.hash_mismatch", entry.path)),
+            },
+            Err(e) => e,
+        };
+        if shutdown::requested() || ipc::cancelled() || quota::is_exceeded(&error) {
+            return Err(error);
+        }
+        if i + 1 < candidates.len() {
+            tracing::warn!(
+                "{} failed from {}, trying the next mirror: {}",
+                entry.path,
+                src,
+                error
+            );
+        }
+        last_error = Some(error);
+    }
+    Err(last_error.unwrap())
+}
+
+/// Refuses to start when the downloads in `diff` won't fit on the disk holding `dir`.
+/// Only entries with a recorded size count. Files being replaced free their space first,
+/// unless the new versions are staged next to them.
+fn check_space(dir: &Path, diff: &[validate::ValidationDifference], staged: bool) -> Result<()> {
+    let mut needed: u64 = 0;
+    let mut freed: u64 = 0;
+    for d in diff {
+        let size = match d.ty.entry() {
+            Some(e) if e.hard_link_of.is_none() => e.size.unwrap_or(0),
+            _ => continue,
+        };
+        needed += size;
+        if !staged {
+            if let Ok(meta) = fs::metadata(d.path.to_logical_path(dir)) {
+                freed += meta.len();
+            }
+        }
+    }
+    let needed = needed.saturating_sub(freed);
+    let available = match fs2::available_space(dir) {
+        Ok(a) => a,
+        Err(e) => {
+            tracing::debug!("Could not read free space of {}: {}", dir.display(), e);
+            return Ok(());
+        }
+    };
+    if needed > available {
+        return Err(anyhow!(t!(
+            "sync.no_space",
+            BinaryBytes(needed),
+            BinaryBytes(available)
+        )));
+    }
+    Ok(())
+}
+
+/// Applies a single difference to the local tree.
+async fn apply_difference(
+    d: validate::ValidationDifference,
+    sync_path: &Path,
+    lazy_sync: bool,
+    stall_timeout: Duration,
+    t: EventSender,
+) -> Result<Outcome> {
+    match d.ty {
+        validate::DifferenceType::FileMissing(_)
+        | validate::DifferenceType::HashMismatch { .. }
+            if lazy_sync =>
+        {
+            backup::preserve(sync_path)?;
+            lazy::create_placeholder(sync_path)?;
+            Ok(Outcome::Placeholder(d.path))
+        }
+        validate::DifferenceType::FileMissing(entry)
+        | validate::DifferenceType::HashMismatch {
+            upstream: entry, ..
+        } => {
+            check_name(&d.path)?;
+            backup::preserve(sync_path)?;
+            let unlocked = perms::unlock(sync_path, false)?;
+            let cleared = util::clear_blocking_attributes(sync_path)?;
+            let res = download_entry(&entry, sync_path, stall_timeout, t)
+                .await
+                .and_then(|src| {
+                    mtime::restore(sync_path, entry.mtime.as_ref())?;
+                    Ok(src)
+                });
+            // attributes first, restoring them would clear the read-only flag on Windows
+            cleared.restore(sync_path)?;
+            unlocked.relock()?;
+            let served_by = res?;
+            // after relocking, which would put back the mode of the file this replaced
+            perms::restore_mode(sync_path, entry.mode)?;
+            xattrs::restore(sync_path, &entry.xattrs)?;
+            Ok(Outcome::Downloaded(d.path, entry.sha512, served_by))
+        }
+        validate::DifferenceType::ModeMismatch(entry) => {
+            perms::restore_mode(sync_path, entry.mode)?;
+            Ok(Outcome::ModeRestored)
+        }
+        validate::DifferenceType::UnknownFile => {
+            backup::preserve(sync_path)?;
+            let unlocked = perms::unlock(sync_path, true)?;
+            let res = delete_file(sync_path).await;
+            unlocked.relock()?;
+            res?;
+            Ok(Outcome::Deleted)
+        }
+    }
+}
+
+#[tracing::instrument]
+pub async fn sync_manifest(target: &Url, dir: &Path, opts: &SyncOptions) -> Result<SyncSummary> {
+    let force = opts.force;
+    // one look at what other processes have open serves every file of the sync
+    let busy = match opts.busy_policy {
+        Some(policy) => Some(Arc::new(BusyCheck {
+            policy,
+            open: util::OpenFiles::scan().await?,
+        })),
+        None => None,
+    };
+    let lazy_sync = opts.lazy;
+    let staged = opts.staged;
+    let staging = staging_dir(dir);
+    // with --staged nothing is written to the live tree until the final swap
+    let download_root: &Path = if staged { &staging } else { dir };
+    let stall_timeout = opts.stall_timeout();
+    ratelimit::configure(
+        opts.limit_rate.map(|r| r.0),
+        opts.bandwidth_schedule.clone(),
+    );
+    perms::configure(
+        opts.dir_mode,
+        opts.file_mode,
+        opts.read_only.unwrap_or_default(),
+    );
+    sparse::configure(opts.sparse);
+    xattrs::configure(opts.xattrs);
+    signed::configure(opts.signed_urls);
+    mtime::configure(opts.quick_check);
+    quota::configure(opts.max_file_size, opts.max_total_download);
+    backup::configure(opts.backup.then_some(dir));
+    // set on every run, so one profile's key or project never carries over to the next
+    signing::set_verify_key(opts.verify_key.clone())?;
+    gcs::set_billing_project(opts.billing_project.clone());
+    gcs::set_encryption_key(opts.gcs_encryption_key.clone())?;
+    let mut placeholders = Placeholders::load(dir)?;
+    // entries go to disk as they arrive, only the ones a regular sync fills in are kept.
+    // The manifest is read this once, the diff and the downloads work off the verified spool
+    let mut unfilled = Vec::new();
+    let (remote_manifest, mut spool) = manifest::spool_manifest(
+        target,
+        manifest::SpooledEntries::create(spool_path(dir)?)?,
+        |e| {
+            if !lazy_sync && placeholders.paths.contains(&e.path) {
+                unfilled.push(e.clone());
+            }
+            Ok(())
+        },
+    )
+    .await?
+    .ok_or_else(|| anyhow!(t!("manifest.not_found", target)))?;
+    pin::check(dir, &remote_manifest, || spool.digest(&remote_manifest))?;
+    util::set_algo(remote_manifest.algo);
+    let primary = mirrors::base_of(&remote_manifest.source)?;
+    if let Some(fastest) = mirrors::configure(&primary, &opts.mirror).await {
+        println!("{}", t!("sync.mirror", fastest));
+    }
     let local_manifest = dir.join("comstar.json");
     // get differences
-    let diff = if local_manifest.exists() && local_manifest.is_file() && !force_validate {
+    let mut diff = if local_manifest.exists() && local_manifest.is_file() && !opts.force_validate {
         if let Some(d) = validate::diff_manifests(
-            target,
+            &remote_manifest,
+            &mut spool,
             &Url::from_file_path(&local_manifest).map_err(|_| {
                 anyhow!(
                     "Could not create URL from path {}",
@@ -84,66 +1217,455 @@ pub async fn sync_manifest(
         )
         .await?
         {
-            println!("Syncing against manifest, {} changes found.", d.len());
+            println!("{}", t!("sync.changes", d.len()));
             d
         } else {
-            println!("Could not sync against manifest, running full validation.");
-            validate::verify_manifest(target, dir, force).await?
+            println!("{}", t!("sync.full_validation"));
+            validate::verify_entries(&remote_manifest, &mut spool, dir, force).await?
         }
     } else {
-        validate::verify_manifest(target, dir, force).await?
+        validate::verify_entries(&remote_manifest, &mut spool, dir, force).await?
     };
 
-    // return early if there's nothing to do
-    if diff.is_empty() {
-        return Ok(());
+    if !unfilled.is_empty() {
+        // a regular sync fills in anything an earlier lazy sync left as a placeholder
+        let pending: HashSet<RelativePathBuf> = diff.iter().map(|d| d.path.clone()).collect();
+        for e in unfilled {
+            if !pending.contains(&e.path) {
+                diff.push(validate::ValidationDifference::missing(e.path.clone(), e));
+            }
+        }
+    }
+
+    // an interrupted sync of this manifest already fetched some of the files
+    let mut journal = Journal::load(dir, target, staged)?;
+    if staged && journal.completed.is_empty() && staging.exists() {
+        // left over from a staged sync of another manifest
+        fs::remove_dir_all(&staging)?;
+    }
+    diff.retain(|d| match d.ty.entry() {
+        Some(e) if e.duplicate_of.is_none() => {
+            journal.completed.get(&d.path) != Some(&e.sha512)
+                || !d.path.to_logical_path(download_root).is_file()
+        }
+        _ => true,
+    });
+
+    let mut summary = SyncSummary::default();
+    summary.renamed = fix_case_renames(&mut diff, dir, remote_manifest.algo).await?;
+    let links = symlinks::pending(dir, &remote_manifest.symlinks);
+    let empty_dirs: Vec<&RelativePathBuf> = remote_manifest
+        .empty_dirs
+        .iter()
+        .filter(|d| !d.to_logical_path(dir).is_dir())
+        .collect();
+    // return early if there's nothing to do, staged files from an earlier run still need
+    // moving into place
+    if diff.is_empty()
+        && links.is_empty()
+        && empty_dirs.is_empty()
+        && (!staged || journal.completed.is_empty())
+    {
+        return Ok(summary);
+    }
+    // a resumed sync keeps adding to the backup of the run it continues
+    if opts.backup && journal.completed.is_empty() {
+        let created = diff
+            .iter()
+            .filter(|d| d.ty.entry().is_some() && !d.path.to_logical_path(dir).exists())
+            .map(|d| d.path.clone())
+            .collect();
+        backup::start(dir, created)?;
     }
-    let (tx, rx) = tokio::sync::mpsc::channel(50);
-    let sem = Arc::new(Semaphore::new(10));
+    if let Some(notes) = &remote_manifest.notes {
+        println!("{}", notes);
+    }
+    if !lazy_sync {
+        check_space(dir, &diff, staged)?;
+    }
+    diff.sort_by_key(|d| sync_order(d, &opts.prefer));
+    let (tx, rx) = events::channel();
     let h = tokio::spawn(events::event_output(
         rx,
-        "Synchronizing files".into(),
-        diff.len() as u64,
+        t!("progress.syncing"),
+        (diff.len() + links.len() + empty_dirs.len()) as u64,
     ));
-    // set up async runtime
-    let mut handles = Vec::new();
-    for d in diff {
+    // content already present elsewhere in the tree is copied once everything is downloaded
+    let (duplicates, work): (Vec<_>, Vec<_>) = diff
+        .into_iter()
+        .partition(|d| d.ty.entry().is_some_and(|e| e.duplicate_of.is_some()));
+    let (deletes, work): (Vec<_>, Vec<_>) = work
+        .into_iter()
+        .partition(|d| staged && matches!(d.ty, validate::DifferenceType::UnknownFile));
+    let outcomes = util::bounded_tasks(work, util::jobs().net, |d| {
         let t = tx.clone();
-        let permit = sem.clone().acquire_owned().await?;
-        let sync_path = d.path.to_logical_path(dir);
-
-        let fut = async move {
+        let busy = busy.clone();
+        // a file that only needs its mode put right has nothing to stage
+        let root = match d.ty {
+            validate::DifferenceType::ModeMismatch(_) => dir,
+            _ => download_root,
+        };
+        let sync_path = d.path.to_logical_path(root);
+        async move {
+            if ipc::cancelled() {
+                return Err(anyhow!(t!("sync.cancelled")));
+            }
+            if shutdown::requested() {
+                return Ok(Outcome::NotStarted);
+            }
             let fname = &d.path.file_name().unwrap().to_string();
-            t.send(Event::unknown_file_started(fname)).await?;
-            match d.ty {
-                validate::DifferenceType::FileMissing(entry) => {
-                    get_file(&entry.source, &sync_path, t.clone()).await?;
+            let size = d.ty.entry().and_then(|e| e.size).filter(|_| !lazy_sync);
+            t.send(Event::file_started(fname, size));
+            if !check_busy(&sync_path, busy.as_deref()).await? {
+                t.send(Event::file_skipped(fname, t!("sync.in_use")));
+                return Ok(Outcome::Skipped(d.path));
+            }
+            let path = d.path.clone();
+            let applied = tokio::select! {
+                res = apply_difference(d, &sync_path, lazy_sync, stall_timeout, t.clone()) => res,
+                _ = shutdown::grace_expired() => Err(shutdown::Interrupted.into()),
+            };
+            match applied {
+                Ok(outcome) => {
+                    t.send(Event::file_done(fname));
+                    Ok(outcome)
                 }
-                validate::DifferenceType::HashMismatch { upstream, .. } => {
-                    get_file(&upstream.source, &sync_path, t.clone()).await?;
+                Err(e) => {
+                    t.send(Event::file_failed(fname, e));
+                    Ok(Outcome::Failed(path))
                 }
-                validate::DifferenceType::UnknownFile => {
-                    delete_file(&sync_path).await?;
+            }
+        }
+    })
+    .await?;
+    for outcome in outcomes {
+        match outcome {
+            Outcome::Downloaded(path, sha512, src) => {
+                summary.downloaded += 1;
+                if !opts.mirror.is_empty() {
+                    summary.served_by.insert(path.clone(), src);
                 }
+                journal.completed.insert(path, sha512);
+            }
+            Outcome::NotStarted => {}
+            Outcome::Deleted => summary.deleted += 1,
+            Outcome::ModeRestored => summary.modes += 1,
+            Outcome::Placeholder(p) => {
+                summary.placeholders += 1;
+                placeholders.paths.insert(p);
             }
-            t.send(Event::file_done(fname)).await?;
-            drop(permit);
-            Ok::<(), anyhow::Error>(())
+            Outcome::Skipped(p) => summary.skipped.push(p),
+            Outcome::Failed(p) => summary.failed.push(p),
+        }
+    }
+    if shutdown::requested() {
+        tx.send(Event::close());
+        let stats = h.await??;
+        if let Err(e) = stats.save(dir, "sync") {
+            tracing::warn!("Could not write sync statistics: {}", e);
+        }
+        journal.save(dir)?;
+        placeholders.save(dir)?;
+        println!(
+            "{}",
+            t!(
+                "sync.interrupted",
+                summary.downloaded,
+                summary.deleted,
+                summary.failed.len()
+            )
+        );
+        return Err(shutdown::Interrupted.into());
+    }
+    // links go last, whatever they point to may itself be a copy made in this pass
+    let (links, copies): (Vec<_>, Vec<_>) = duplicates
+        .into_iter()
+        .partition(|d| d.ty.entry().is_some_and(|e| e.hard_link_of.is_some()));
+    for d in copies.into_iter().chain(links) {
+        let path = d.path;
+        let (original, linked, attrs, sha512, modified, mode) = match d.ty.entry() {
+            Some(ManifestEntry {
+                duplicate_of: Some(o),
+                hard_link_of,
+                xattrs,
+                sha512,
+                mtime,
+                mode,
+                ..
+            }) => (
+                o.clone(),
+                hard_link_of.is_some(),
+                xattrs.clone(),
+                sha512.clone(),
+                *mtime,
+                *mode,
+            ),
+            _ => continue,
+        };
+        let fname = path.file_name().unwrap().to_string();
+        let dest = path.to_logical_path(download_root);
+        tx.send(Event::unknown_file_started(&fname));
+        // whatever is at the original's path now is not the content this entry wants
+        if summary.failed.contains(&original) || summary.skipped.contains(&original) {
+            tx.send(Event::file_failed(
+                &fname,
+                t!("sync.original_failed", original),
+            ));
+            summary.failed.push(path);
+            continue;
+        }
+        if !check_busy(&dest, busy.as_deref()).await? {
+            tx.send(Event::file_skipped(&fname, t!("sync.in_use")));
+            summary.skipped.push(path);
+            continue;
+        }
+        let res = if lazy_sync {
+            backup::preserve(&dest).and_then(|()| lazy::create_placeholder(&dest))
+        } else {
+            let staged_original = original.to_logical_path(download_root);
+            // an unchanged original is only in the live tree
+            let original = if staged_original.is_file() {
+                staged_original
+            } else {
+                original.to_logical_path(dir)
+            };
+            let copied = if linked {
+                link_duplicate(&original, &dest, &path).await
+            } else {
+                copy_duplicate(&original, &dest, &path).await
+            };
+            let verified = match copied {
+                Ok(()) => verify_copy(&dest, &sha512, &path).await,
+                Err(e) => Err(e),
+            };
+            verified
+                .and_then(|()| xattrs::restore(&dest, &attrs))
+                .and_then(|()| mtime::restore(&dest, modified.as_ref()))
+                .and_then(|()| perms::restore_mode(&dest, mode))
         };
-        let handle = tokio::spawn(fut);
-        handles.push(handle);
+        match res {
+            Ok(()) if lazy_sync => {
+                summary.placeholders += 1;
+                placeholders.paths.insert(path);
+            }
+            Ok(()) => {
+                summary.copied += 1;
+                if staged {
+                    journal.completed.insert(path, sha512);
+                }
+            }
+            Err(e) => {
+                tx.send(Event::file_failed(&fname, e));
+                summary.failed.push(path);
+                continue;
+            }
+        }
+        tx.send(Event::file_done(&fname));
+    }
+    if staged && summary.failed.is_empty() {
+        // everything is downloaded and verified, only now is the live tree touched
+        swap_staged(
+            dir,
+            &mut journal,
+            deletes,
+            busy.as_deref(),
+            &tx,
+            &mut summary,
+        )
+        .await?;
+    }
+    // empty directories and links go into the live tree last, links may point at either
+    if summary.failed.is_empty() {
+        for d in empty_dirs {
+            let fname = d.file_name().unwrap_or(d.as_str());
+            tx.send(Event::unknown_file_started(fname));
+            match perms::create_dir(&d.to_logical_path(dir)) {
+                Ok(()) => {
+                    summary.dirs += 1;
+                    tx.send(Event::file_done(fname));
+                }
+                Err(e) => {
+                    tx.send(Event::file_failed(fname, e));
+                    summary.failed.push(d.clone());
+                }
+            }
+        }
+        for link in links {
+            let fname = link.path.file_name().unwrap_or(link.path.as_str());
+            tx.send(Event::unknown_file_started(fname));
+            match symlinks::create(dir, link) {
+                Ok(()) => {
+                    summary.linked += 1;
+                    tx.send(Event::file_done(fname));
+                }
+                Err(e) => {
+                    tx.send(Event::file_failed(fname, e));
+                    summary.failed.push(link.path.clone());
+                }
+            }
+        }
     }
-    for h in handles {
-        h.await??;
+    tx.send(Event::close());
+    let stats = h.await??;
+    if let Err(e) = stats.save(dir, "sync") {
+        tracing::warn!("Could not write sync statistics: {}", e);
     }
-    tx.send(Event::close()).await?;
+    let mut per_mirror: BTreeMap<&str, usize> = BTreeMap::new();
+    for src in summary.served_by.values() {
+        *per_mirror
+            .entry(src.host_str().unwrap_or("localhost"))
+            .or_default() += 1;
+    }
+    for (mirror, files) in per_mirror {
+        println!("{}", t!("sync.served_by", mirror, files));
+    }
+    // leave the local manifest alone so the next sync picks failed and skipped files up again
+    if !summary.failed.is_empty() || !summary.skipped.is_empty() {
+        journal.save(dir)?;
+    }
+    if !summary.failed.is_empty() {
+        return Err(anyhow!(t!("sync.failed", summary.failed.len())));
+    }
+    if !summary.skipped.is_empty() {
+        println!("{}", t!("sync.skipped_summary"));
+        return Ok(summary);
+    }
+    if !lazy_sync {
+        placeholders.paths.clear();
+    }
+    placeholders.save(dir)?;
+    spool.write_manifest(&remote_manifest, dir)?;
+    Journal::clear(dir)?;
+    if staging.exists() {
+        fs::remove_dir_all(&staging)?;
+    }
+    Ok(summary)
+}
+
+/// Downloads and verifies just the entries at or below `paths` into `dir`, e.g. to repair
+/// single files or fill in placeholders left by a lazy sync.
+#[tracing::instrument]
+pub async fn get_paths(target: &Url, dir: &Path, paths: &[RelativePathBuf]) -> Result<()> {
+    let mut wanted: Vec<ManifestEntry> = Vec::new();
+    let mut matched = vec![false; paths.len()];
+    // a signature or pin covers every entry, not just the wanted ones
+    let spool = manifest::SpooledEntries::create(spool_path(dir)?)?;
+    let (header, mut spool) = manifest::spool_manifest(target, spool, |e| {
+        let mut hit = false;
+        for (i, p) in paths.iter().enumerate() {
+            if e.path == *p || e.path.starts_with(p) {
+                matched[i] = true;
+                hit = true;
+            }
+        }
+        if hit {
+            wanted.push(e.clone());
+        }
+        Ok(())
+    })
+    .await?
+    .ok_or_else(|| anyhow!(t!("manifest.not_found", target)))?;
+    // a pinned directory only takes files from the pinned version
+    pin::check(dir, &header, || spool.digest(&header))?;
+    util::set_algo(header.algo);
+    if let Some(i) = matched.iter().position(|m| !m) {
+        return Err(anyhow!(t!("get.unknown_path", paths[i])));
+    }
+
+    let (tx, rx) = events::channel();
+    let h = tokio::spawn(events::event_output(
+        rx,
+        t!("progress.fetching"),
+        wanted.len() as u64,
+    ));
+    let fetched = util::bounded_tasks(wanted, util::jobs().net, |entry| {
+        let t = tx.clone();
+        let dest = entry.path.to_logical_path(dir);
+        async move {
+            let fname = entry
+                .path
+                .file_name()
+                .unwrap_or(entry.path.as_str())
+                .to_string();
+            t.send(Event::file_started(&fname, entry.size));
+            let sparse = sparse::wanted(entry.sparse);
+            let res = match get_file(
+                &entry.source,
+                &dest,
+                DEFAULT_STALL_TIMEOUT,
+                sparse,
+                t.clone(),
+            )
+            .await
+            {
+                Ok(sha512) if sha512 == entry.sha512 => mtime::restore(&dest, entry.mtime.as_ref())
+                    .and_then(|()| perms::restore_mode(&dest, entry.mode)),
+                Ok(_) => Err(anyhow!(t!("get.hash_mismatch", entry.path))),
+                Err(e) => Err(e),
+            };
+            match res {
+                Ok(()) => {
+                    t.send(Event::file_done(&fname));
+                    Ok(Some(entry.path))
+                }
+                Err(e) => {
+                    t.send(Event::file_failed(&fname, e));
+                    Ok(None)
+                }
+            }
+        }
+    })
+    .await?;
+    tx.send(Event::close());
     h.await??;
-    let new_manifest = manifest::get_manifest(&target).await?.ok_or_else(|| {
-        anyhow!(
-            "Somehow we reached the end of sync and the remote manifest disappeared: {}",
-            &target
-        )
-    })?;
-    manifest::write_manifest(&new_manifest, dir)?;
+
+    let total = fetched.len();
+    let fetched: Vec<RelativePathBuf> = fetched.into_iter().flatten().collect();
+    let mut placeholders = Placeholders::load(dir)?;
+    let before = placeholders.paths.len();
+    for p in fetched.iter() {
+        placeholders.paths.remove(p);
+    }
+    if placeholders.paths.len() != before {
+        placeholders.save(dir)?;
+    }
+    if fetched.len() < total {
+        return Err(anyhow!(t!("sync.failed", total - fetched.len())));
+    }
     Ok(())
 }
+
+/// Runs a user command in the synced directory, exposing the sync summary as
+/// `COMSTAR_*` environment variables.
+pub async fn run_then(
+    command: &str,
+    target: &Url,
+    dir: &Path,
+    summary: &SyncSummary,
+) -> Result<ExitStatus> {
+    let mut cmd = if cfg!(windows) {
+        let mut c = tokio::process::Command::new("cmd");
+        c.arg("/C");
+        c
+    } else {
+        let mut c = tokio::process::Command::new("sh");
+        c.arg("-c");
+        c
+    };
+    let status = cmd
+        .arg(command)
+        .current_dir(dir)
+        .env("COMSTAR_MANIFEST", target.as_str())
+        .env("COMSTAR_DIR", dir)
+        .env("COMSTAR_CHANGES", summary.changes().to_string())
+        .env("COMSTAR_DOWNLOADED", summary.downloaded.to_string())
+        .env("COMSTAR_COPIED", summary.copied.to_string())
+        .env("COMSTAR_DELETED", summary.deleted.to_string())
+        .env("COMSTAR_RENAMED", summary.renamed.to_string())
+        .env("COMSTAR_LINKED", summary.linked.to_string())
+        .env("COMSTAR_DIRS", summary.dirs.to_string())
+        .env("COMSTAR_MODES", summary.modes.to_string())
+        .status()
+        .await?;
+    Ok(status)
+}
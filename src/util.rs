@@ -1,7 +1,77 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use futures::{stream, Future, StreamExt, TryStreamExt};
 use ignore::{overrides::OverrideBuilder, Walk, WalkBuilder};
-use sha2::{Digest, Sha512};
-use std::{fs::File, io, path::Path};
+use percent_encoding::percent_decode_str;
+use relative_path::RelativePath;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256, Sha512};
+use std::{
+    fs::File,
+    io::{self, Read},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::RwLock,
+    time::SystemTime,
+};
+use url::Url;
+
+use crate::{
+    events::{Event, EventSender},
+    i18n::t,
+    manifest,
+};
+
+/// A byte count written with an optional binary or decimal suffix, e.g. `512K`, `1MiB`, `2GB`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct ByteSize(pub u64);
+
+impl FromStr for ByteSize {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+        let (num, unit) = s.split_at(split);
+        let num: u64 = num
+            .parse()
+            .map_err(|_| anyhow!("Invalid size {}, expected e.g. 512K or 1MiB", s))?;
+        let mult: u64 = match unit.trim().to_ascii_lowercase().as_str() {
+            "" | "b" => 1,
+            "k" | "kib" => 1 << 10,
+            "kb" => 1000,
+            "m" | "mib" => 1 << 20,
+            "mb" => 1000 * 1000,
+            "g" | "gib" => 1 << 30,
+            "gb" => 1000 * 1000 * 1000,
+            "t" | "tib" => 1 << 40,
+            "tb" => 1000 * 1000 * 1000 * 1000,
+            _ => return Err(anyhow!("Unknown size unit in {}", s)),
+        };
+        Ok(ByteSize(num * mult))
+    }
+}
+
+impl TryFrom<String> for ByteSize {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+/// What a download running on a blocking thread hands back to be written.
+pub enum Chunk {
+    /// The size the server reported for the file, sent before any data if it has one.
+    Length(u64),
+    Data(Vec<u8>),
+}
+
+/// Directory inside a synced tree where comstar keeps its own bookkeeping.
+pub fn state_dir(dir: &Path) -> PathBuf {
+    dir.join(".comstar")
+}
 
 pub fn get_walker(dir: &Path) -> Result<Walk> {
     let mut builder = WalkBuilder::new(dir);
@@ -12,16 +82,543 @@ pub fn get_walker(dir: &Path) -> Result<Walk> {
     builder.ignore(false);
 
     let mut o = OverrideBuilder::new(dir);
-    let o = o.add("!comstar.json")?;
+    o.add("!comstar.json")?;
+    o.add("!comstar.json.*")?;
+    o.add("!comstar.lock")?;
+    o.add(&format!("!/{}", manifest::CHECKSUMS_FILE))?;
+    let o = o.add("!.comstar/")?;
     builder.overrides(o.build()?);
 
     Ok(builder.build())
 }
 
-pub fn get_file_hash(path: &Path) -> Result<String> {
-    let mut hasher = Sha512::new();
+static JOBS: RwLock<Option<Jobs>> = RwLock::new(None);
+
+/// How many transfers and how many hashes may run at once. Kept apart so a slow disk
+/// doesn't starve the network and the other way around.
+#[derive(Debug, Clone, Copy)]
+pub struct Jobs {
+    pub net: usize,
+    pub hash: usize,
+}
+
+impl Default for Jobs {
+    fn default() -> Self {
+        Jobs {
+            net: 10,
+            hash: std::thread::available_parallelism()
+                .map(|n| n.get())
+                .unwrap_or(4),
+        }
+    }
+}
+
+/// Sets the job limits, replacing any set before.
+pub fn set_jobs(jobs: Jobs) {
+    *JOBS.write().unwrap() = Some(jobs);
+}
+
+pub fn jobs() -> Jobs {
+    (*JOBS.read().unwrap()).unwrap_or_default()
+}
+
+/// Runs `f` for every item on its own task, keeping at most `limit` tasks alive at once.
+/// Results come back in completion order.
+pub async fn bounded_tasks<I, F, Fut, T>(items: I, limit: usize, mut f: F) -> Result<Vec<T>>
+where
+    I: IntoIterator,
+    F: FnMut(I::Item) -> Fut,
+    Fut: Future<Output = Result<T>> + Send + 'static,
+    T: Send + 'static,
+{
+    stream::iter(items)
+        .map(|item| tokio::spawn(f(item)))
+        .buffer_unordered(limit)
+        .map(|joined| match joined {
+            Ok(r) => r,
+            Err(e) => Err(e.into()),
+        })
+        .try_collect()
+        .await
+}
+
+/// Splits `items` into one chunk per hash job and runs `f` on each chunk on its own thread.
+pub fn sharded<T, R, F>(items: &[T], f: F) -> Vec<R>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&[T]) -> R + Sync,
+{
+    if items.is_empty() {
+        return Vec::new();
+    }
+    let chunk = items.len().div_ceil(jobs().hash.max(1));
+    let f = &f;
+    std::thread::scope(|s| {
+        let handles: Vec<_> = items.chunks(chunk).map(|c| s.spawn(move || f(c))).collect();
+        handles
+            .into_iter()
+            .map(|h| h.join().expect("shard panicked"))
+            .collect()
+    })
+}
+
+/// Digest used for file content.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgo {
+    #[default]
+    Sha512,
+    Sha256,
+    Blake3,
+}
+
+impl HashAlgo {
+    /// Name of the algorithm, which is also the field `hash` prints its digests in.
+    pub fn name(self) -> &'static str {
+        match self {
+            HashAlgo::Sha512 => "sha512",
+            HashAlgo::Sha256 => "sha256",
+            HashAlgo::Blake3 => "blake3",
+        }
+    }
+
+    pub fn is_default(&self) -> bool {
+        *self == HashAlgo::default()
+    }
+}
+
+impl FromStr for HashAlgo {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "sha512" => Ok(HashAlgo::Sha512),
+            "sha256" => Ok(HashAlgo::Sha256),
+            "blake3" => Ok(HashAlgo::Blake3),
+            _ => Err(anyhow!(
+                "Unknown hash algorithm {}, expected sha512, sha256 or blake3",
+                s
+            )),
+        }
+    }
+}
+
+static ALGO: RwLock<HashAlgo> = RwLock::new(HashAlgo::Sha512);
+
+/// Sets the algorithm downloads are hashed with, the one of the manifest they come from.
+/// Set once before a sync, `get` or `cat` starts downloading, hashing anything else takes
+/// the algorithm as an argument.
+pub fn set_algo(algo: HashAlgo) {
+    *ALGO.write().unwrap() = algo;
+}
+
+pub fn algo() -> HashAlgo {
+    *ALGO.read().unwrap()
+}
+
+/// Incremental hash in any of the supported algorithms.
+pub enum Hasher {
+    Sha512(Sha512),
+    Sha256(Sha256),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl Default for Hasher {
+    /// A hasher for the configured algorithm.
+    fn default() -> Self {
+        Hasher::with(algo())
+    }
+}
+
+impl Hasher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with(algo: HashAlgo) -> Self {
+        match algo {
+            HashAlgo::Sha512 => Hasher::Sha512(Sha512::new()),
+            HashAlgo::Sha256 => Hasher::Sha256(Sha256::new()),
+            HashAlgo::Blake3 => Hasher::Blake3(Box::new(blake3::Hasher::new())),
+        }
+    }
+
+    pub fn update(&mut self, bytes: &[u8]) {
+        match self {
+            Hasher::Sha512(h) => h.update(bytes),
+            Hasher::Sha256(h) => h.update(bytes),
+            Hasher::Blake3(h) => {
+                h.update(bytes);
+            }
+        }
+    }
+
+    /// Lowercase hex digest.
+    pub fn finish(self) -> String {
+        match self {
+            Hasher::Sha512(h) => format!("{:x}", h.finalize()),
+            Hasher::Sha256(h) => format!("{:x}", h.finalize()),
+            Hasher::Blake3(h) => h.finalize().to_hex().to_string(),
+        }
+    }
+}
+
+/// Hashes `path` with `algo` on the blocking pool, without reporting progress.
+pub async fn hash_file_with(path: PathBuf, algo: HashAlgo) -> Result<String> {
+    tokio::task::spawn_blocking(move || hash_path(&path, algo, |_| {})).await?
+}
+
+/// How often a file that changes while it is read gets hashed before giving up on it.
+const STABLE_HASH_ATTEMPTS: u32 = 3;
+
+/// Size and modification time, enough to tell that a file was written to while hashing.
+pub fn fingerprint(meta: &std::fs::Metadata) -> (u64, Option<SystemTime>) {
+    (meta.len(), meta.modified().ok())
+}
+
+/// Hashes on the blocking pool so large files don't stall the async workers, reporting
+/// progress for `name` as it reads.
+///
+/// A file whose size or modification time changed during the read is hashed again, the
+/// digest could otherwise belong to no version the file ever had. Files that keep
+/// changing are reported as volatile.
+pub async fn hash_file(
+    path: PathBuf,
+    name: String,
+    algo: HashAlgo,
+    tx: EventSender,
+) -> Result<String> {
+    for attempt in 1..=STABLE_HASH_ATTEMPTS {
+        if attempt > 1 {
+            tx.send(Event::file_retried(&name, attempt));
+        }
+        let before = fingerprint(&tokio::fs::metadata(&path).await?);
+        tx.send(Event::file_length(&name, before.0));
+        let sha512 = {
+            let path = path.clone();
+            let name = name.clone();
+            let tx = tx.clone();
+            tokio::task::spawn_blocking(move || {
+                get_file_hash(&path, algo, |n| {
+                    tx.send(Event::file_progress(&name, n));
+                })
+            })
+            .await??
+        };
+        if fingerprint(&tokio::fs::metadata(&path).await?) == before {
+            return Ok(sha512);
+        }
+    }
+    Err(anyhow!(t!(
+        "hash.volatile",
+        path.display(),
+        STABLE_HASH_ATTEMPTS
+    )))
+}
+
+const HASH_BLOCK: usize = 1024 * 1024;
+
+/// Files at least this large are read on a separate thread so disk reads overlap with hashing.
+const LARGE_FILE_THRESHOLD: u64 = 64 * 1024 * 1024;
+const LARGE_FILE_BLOCK: usize = 4 * 1024 * 1024;
+
+/// Keeps the reader and the hasher busy at the same time. BLAKE3 is a tree hash and also
+/// spreads every block across cores; SHA-512 and SHA-256 can't be split without changing the
+/// digest, so they stay on one core.
+fn get_large_file_hash<F: FnMut(u64)>(
+    path: &Path,
+    algo: HashAlgo,
+    mut progress: F,
+) -> Result<String> {
+    let mut input = File::open(path)?;
+    let (tx, rx) = std::sync::mpsc::sync_channel::<Vec<u8>>(4);
+    let reader = std::thread::spawn(move || -> io::Result<()> {
+        loop {
+            let mut block = vec![0u8; LARGE_FILE_BLOCK];
+            let n = input.read(&mut block)?;
+            if n == 0 {
+                return Ok(());
+            }
+            block.truncate(n);
+            if tx.send(block).is_err() {
+                return Ok(());
+            }
+        }
+    });
+    let mut hasher = Hasher::with(algo);
+    for block in rx {
+        match &mut hasher {
+            Hasher::Blake3(h) => {
+                h.update_rayon(&block);
+            }
+            h => h.update(&block),
+        }
+        progress(block.len() as u64);
+    }
+    reader
+        .join()
+        .map_err(|_| anyhow!("Reader thread panicked while hashing {}", path.display()))??;
+    Ok(hasher.finish())
+}
+
+/// Hashes `path` with `algo`, calling `progress` with the number of bytes consumed after
+/// every block.
+pub fn get_file_hash<F: FnMut(u64)>(path: &Path, algo: HashAlgo, progress: F) -> Result<String> {
+    hash_path(path, algo(), progress)
+}
+
+fn hash_path<F: FnMut(u64)>(path: &Path, algo: HashAlgo, mut progress: F) -> Result<String> {
+    if path.metadata()?.len() >= LARGE_FILE_THRESHOLD {
+        return get_large_file_hash(path, algo, progress);
+    }
+    let mut hasher = Hasher::with(algo);
     let mut input = File::open(&path)?;
-    let _ = io::copy(&mut input, &mut hasher)?;
-    let hash_bytes = hasher.finalize();
-    Ok(format!("{:x}", &hash_bytes))
+    let mut buf = vec![0u8; HASH_BLOCK];
+    loop {
+        let n = input.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+        progress(n as u64);
+    }
+    Ok(hasher.finish())
+}
+
+/// The `file:` URL of `path`. A UNC path `\\server\share\dir` becomes
+/// `file://server/share/dir`.
+pub fn file_url(path: &Path) -> Result<Url> {
+    Url::from_file_path(path).map_err(|_| anyhow!("Cannot make URL from path {}", path.display()))
+}
+
+/// The local path of a `file:` URL. A host, `file://server/share/dir`, or an empty host
+/// followed by one, `file:////server/share/dir`, names a network share, which is read
+/// through its UNC path `\\server\share\dir` on Windows.
+pub fn url_path(url: &Url) -> Result<PathBuf> {
+    let unc = match url
+        .host_str()
+        .filter(|h| !h.is_empty() && *h != "localhost")
+    {
+        Some(host) => Some(format!("{}{}", host, url.path())),
+        // elsewhere a leading `//` is just a redundant slash
+        None if cfg!(windows) => url.path().strip_prefix("//").map(str::to_string),
+        None => None,
+    };
+    let unc = match unc {
+        Some(u) => u,
+        None => {
+            return url
+                .to_file_path()
+                .map_err(|_| anyhow!("Could not create path from URL {}", url))
+        }
+    };
+    if !cfg!(windows) {
+        return Err(anyhow!(t!("file.unc_unsupported", url)));
+    }
+    let mut path = String::from(r"\\");
+    for (i, segment) in unc.split('/').enumerate() {
+        if i > 0 {
+            path.push('\\');
+        }
+        path.push_str(&percent_decode_str(segment).decode_utf8()?);
+    }
+    Ok(PathBuf::from(path))
+}
+
+const WINDOWS_RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Why `path` can't be written on Windows, if it can't. Manifests generated elsewhere can
+/// contain names Windows rejects or, worse, interprets as devices or alternate data streams.
+pub fn windows_name_problem(path: &RelativePath) -> Option<String> {
+    for component in path.components() {
+        let name = component.as_str();
+        if name.contains(':') {
+            return Some(t!("sync.name_stream", name));
+        }
+        if let Some(c) = name
+            .chars()
+            .find(|&c| matches!(c, '<' | '>' | '"' | '|' | '?' | '*' | '\\') || c.is_control())
+        {
+            return Some(t!("sync.name_char", name, c.escape_default()));
+        }
+        if name.ends_with('.') || name.ends_with(' ') {
+            return Some(t!("sync.name_trailing", name));
+        }
+        // devices are reserved with any extension, `nul.txt` is still NUL
+        let stem = name.split('.').next().unwrap_or(name).trim_end();
+        if WINDOWS_RESERVED_NAMES
+            .iter()
+            .any(|r| r.eq_ignore_ascii_case(stem))
+        {
+            return Some(t!("sync.name_reserved", name));
+        }
+    }
+    None
+}
+
+/// Attributes that were cleared so an existing file could be overwritten.
+#[derive(Debug, Default)]
+pub struct ClearedAttributes {
+    #[cfg(windows)]
+    attrs: Option<u32>,
+}
+
+/// Windows refuses to replace read-only files, and refuses to truncate hidden or system
+/// files through a plain create. Clears those attributes so the file can be rewritten.
+#[cfg(windows)]
+pub fn clear_blocking_attributes(path: &Path) -> Result<ClearedAttributes> {
+    use std::os::windows::fs::MetadataExt;
+    use windows_sys::Win32::Storage::FileSystem::{
+        FILE_ATTRIBUTE_HIDDEN, FILE_ATTRIBUTE_READONLY, FILE_ATTRIBUTE_SYSTEM,
+    };
+    const BLOCKING: u32 = FILE_ATTRIBUTE_READONLY | FILE_ATTRIBUTE_HIDDEN | FILE_ATTRIBUTE_SYSTEM;
+
+    let attrs = match std::fs::metadata(path) {
+        Ok(m) => m.file_attributes(),
+        Err(_) => return Ok(ClearedAttributes::default()),
+    };
+    if attrs & BLOCKING == 0 {
+        return Ok(ClearedAttributes::default());
+    }
+    set_file_attributes(path, attrs & !BLOCKING)?;
+    Ok(ClearedAttributes { attrs: Some(attrs) })
+}
+
+#[cfg(not(windows))]
+pub fn clear_blocking_attributes(_path: &Path) -> Result<ClearedAttributes> {
+    Ok(ClearedAttributes::default())
+}
+
+impl ClearedAttributes {
+    /// Puts the cleared attributes back on the rewritten file.
+    #[cfg(windows)]
+    pub fn restore(self, path: &Path) -> Result<()> {
+        match self.attrs {
+            Some(attrs) => set_file_attributes(path, attrs),
+            None => Ok(()),
+        }
+    }
+
+    #[cfg(not(windows))]
+    pub fn restore(self, _path: &Path) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(windows)]
+fn set_file_attributes(path: &Path, attrs: u32) -> Result<()> {
+    use std::os::windows::ffi::OsStrExt;
+    use windows_sys::Win32::Storage::FileSystem::SetFileAttributesW;
+
+    let wide: Vec<u16> = path.as_os_str().encode_wide().chain(Some(0)).collect();
+    // SAFETY: `wide` is a NUL terminated UTF-16 path that outlives the call
+    if unsafe { SetFileAttributesW(wide.as_ptr(), attrs) } == 0 {
+        return Err(anyhow!(t!(
+            "sync.attributes",
+            path.display(),
+            io::Error::last_os_error()
+        )));
+    }
+    Ok(())
+}
+
+/// Device and inode of a file with more than one hard link, to find the other links.
+#[cfg(unix)]
+pub fn linked_inode(meta: &std::fs::Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    (meta.nlink() > 1).then(|| (meta.dev(), meta.ino()))
+}
+
+#[cfg(not(unix))]
+pub fn linked_inode(_meta: &std::fs::Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+/// Files other processes hold open, found in one pass so checking each file a sync touches
+/// stays cheap.
+pub struct OpenFiles {
+    /// Device and inode of every file open in, or running as, another process.
+    #[cfg(target_os = "linux")]
+    inodes: std::collections::HashSet<(u64, u64)>,
+}
+
+impl OpenFiles {
+    /// Looks at every other process once, on the blocking pool. Windows asks per file
+    /// instead, which is cheap there.
+    pub async fn scan() -> Result<OpenFiles> {
+        Ok(tokio::task::spawn_blocking(scan_open_files).await?)
+    }
+
+    /// Whether another process held `path` open when the scan ran.
+    pub fn contains(&self, path: &Path) -> bool {
+        held_open(self, path)
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn scan_open_files() -> OpenFiles {
+    use std::os::unix::fs::MetadataExt;
+    let own = std::process::id().to_string();
+    let mut inodes = std::collections::HashSet::new();
+    let procs = std::fs::read_dir("/proc").into_iter().flatten();
+    for proc_dir in procs.filter_map(|p| p.ok()) {
+        let name = proc_dir.file_name();
+        let is_other_pid = name
+            .to_str()
+            .is_some_and(|n| n != own && n.bytes().all(|b| b.is_ascii_digit()));
+        if !is_other_pid {
+            continue;
+        }
+        let proc_path = proc_dir.path();
+        let fds = std::fs::read_dir(proc_path.join("fd"))
+            .into_iter()
+            .flatten()
+            .filter_map(|f| f.ok())
+            .map(|f| f.path());
+        for link in std::iter::once(proc_path.join("exe")).chain(fds) {
+            // metadata follows the link to the open file itself
+            if let Ok(meta) = std::fs::metadata(&link) {
+                if meta.is_file() {
+                    inodes.insert((meta.dev(), meta.ino()));
+                }
+            }
+        }
+    }
+    OpenFiles { inodes }
+}
+
+#[cfg(target_os = "linux")]
+fn held_open(open: &OpenFiles, path: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    std::fs::metadata(path).is_ok_and(|m| open.inodes.contains(&(m.dev(), m.ino())))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn scan_open_files() -> OpenFiles {
+    OpenFiles {}
+}
+
+#[cfg(windows)]
+fn held_open(_open: &OpenFiles, path: &Path) -> bool {
+    use std::os::windows::fs::OpenOptionsExt;
+    const ERROR_SHARING_VIOLATION: i32 = 32;
+    match std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .share_mode(0)
+        .open(path)
+    {
+        Ok(_) => false,
+        Err(e) => e.raw_os_error() == Some(ERROR_SHARING_VIOLATION),
+    }
+}
+
+/// Not detectable on this platform.
+#[cfg(not(any(target_os = "linux", windows)))]
+fn held_open(_open: &OpenFiles, _path: &Path) -> bool {
+    false
 }
@@ -1,18 +1,22 @@
 use std::{
     collections::{HashMap, HashSet},
+    fs,
     path::{Path, PathBuf},
-    sync::Arc,
 };
 
 use anyhow::{anyhow, Result};
 use relative_path::{RelativePath, RelativePathBuf};
-use tokio::sync::Semaphore;
+use reqwest::header::{CONTENT_ENCODING, CONTENT_LENGTH};
 use url::Url;
 
 use crate::{
     events::{self, Event},
-    manifest::{self, ManifestEntry},
-    util,
+    http,
+    i18n::t,
+    manifest::{self, Manifest, ManifestEntry, SpooledEntries},
+    mtime,
+    perms::{self, Mode},
+    push, sync, util,
 };
 
 #[derive(Debug, Clone)]
@@ -20,11 +24,25 @@ pub enum DifferenceType {
     FileMissing(ManifestEntry),
     HashMismatch {
         upstream: ManifestEntry,
+        /// Hash of the local file, empty when its size alone showed it differs.
         local: String,
     },
+    /// The content matches, the permission bits don't.
+    ModeMismatch(ManifestEntry),
     UnknownFile,
 }
 
+impl DifferenceType {
+    /// The entry to download, if any.
+    pub fn entry(&self) -> Option<&ManifestEntry> {
+        match self {
+            DifferenceType::FileMissing(e) => Some(e),
+            DifferenceType::HashMismatch { upstream, .. } => Some(upstream),
+            DifferenceType::ModeMismatch(_) | DifferenceType::UnknownFile => None,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct ValidationDifference {
     pub ty: DifferenceType,
@@ -32,14 +50,14 @@ pub struct ValidationDifference {
 }
 
 impl ValidationDifference {
-    fn missing<P: Into<RelativePathBuf>>(path: P, entry: ManifestEntry) -> Self {
+    pub fn missing<P: Into<RelativePathBuf>>(path: P, entry: ManifestEntry) -> Self {
         Self {
             ty: DifferenceType::FileMissing(entry),
             path: path.into(),
         }
     }
 
-    fn hash_mismatch<P: Into<RelativePathBuf>>(
+    pub fn hash_mismatch<P: Into<RelativePathBuf>>(
         path: P,
         upstream: ManifestEntry,
         local: String,
@@ -50,7 +68,14 @@ impl ValidationDifference {
         }
     }
 
-    fn unknown_file<P: Into<RelativePathBuf>>(path: P) -> Self {
+    pub fn mode_mismatch<P: Into<RelativePathBuf>>(path: P, entry: ManifestEntry) -> Self {
+        Self {
+            ty: DifferenceType::ModeMismatch(entry),
+            path: path.into(),
+        }
+    }
+
+    pub fn unknown_file<P: Into<RelativePathBuf>>(path: P) -> Self {
         Self {
             ty: DifferenceType::UnknownFile,
             path: path.into(),
@@ -58,144 +83,414 @@ impl ValidationDifference {
     }
 }
 
-#[tracing::instrument]
+/// Differences between the manifest `authority`, whose entries are in `spool`, and the
+/// local one at `other`, `None` if their hashes can't be compared. The authority's entries
+/// are read back from the spool, only the local manifest's paths and hashes and the
+/// authority's entries that differ are held.
+#[tracing::instrument(skip(authority, spool))]
 pub async fn diff_manifests(
-    authority: &Url,
+    authority: &Manifest,
+    spool: &mut SpooledEntries,
     other: &Url,
     force: bool,
 ) -> Result<Option<Vec<ValidationDifference>>> {
-    let authority_manifest = manifest::get_manifest(authority)
-        .await?
-        .ok_or_else(|| anyhow!("Remote manifest not found: {}", &authority))?;
-    let local_manifest = manifest::get_manifest(other).await?;
+    let mut local_hashes: HashMap<RelativePathBuf, (String, Option<Mode>)> = HashMap::new();
+    let local_manifest = manifest::stream_manifest(other, |e| {
+        local_hashes.insert(e.path, (e.sha512, e.mode));
+        Ok(())
+    })
+    .await?;
+    let local_manifest = match local_manifest {
+        // digests in different algorithms can't be compared, the files have to be hashed
+        Some(m) if m.algo == authority.algo => m,
+        _ => return Ok(None),
+    };
+    let local_links: HashSet<&RelativePath> = local_manifest
+        .symlinks
+        .iter()
+        .map(|l| l.path.as_relative_path())
+        .collect();
 
-    if let Some(local) = local_manifest {
-        let mut differences = Vec::new();
-        let authority_entries: HashMap<&RelativePath, &ManifestEntry> = authority_manifest
-            .entries
+    // authority entries are compared in batches
+    let mut differences = Vec::new();
+    let mut listed = HashSet::new();
+    let mut links_replaced = HashSet::new();
+    let mut entries = spool.entries()?;
+    loop {
+        let batch = entries
+            .by_ref()
+            .take(DIFF_BATCH)
+            .collect::<Result<Vec<ManifestEntry>>>()?;
+        if batch.is_empty() {
+            break;
+        }
+        if force {
+            for e in &batch {
+                add_parents(&mut listed, &e.path);
+                if local_links.contains(e.path.as_relative_path()) {
+                    links_replaced.insert(e.path.clone());
+                }
+            }
+        }
+        let mut seen = Vec::new();
+        for (d, s) in util::sharded(&batch, |c| diff_shard(&local_hashes, c)) {
+            differences.extend(d);
+            seen.extend(s);
+        }
+        for p in seen {
+            local_hashes.remove(p);
+        }
+    }
+    if force {
+        // links are recreated by sync on every run, only the ones that are gone upstream matter here
+        let links: HashSet<&RelativePath> = authority
+            .symlinks
             .iter()
-            .map(|e| (e.path.as_ref(), e))
+            .map(|l| l.path.as_relative_path())
             .collect();
-        let local_entries: HashMap<&RelativePath, &ManifestEntry> =
-            local.entries.iter().map(|e| (e.path.as_ref(), e)).collect();
+        differences.extend(
+            local_manifest
+                .symlinks
+                .iter()
+                .filter(|l| {
+                    !links.contains(l.path.as_relative_path()) && !links_replaced.contains(&l.path)
+                })
+                .map(|l| ValidationDifference::unknown_file(l.path.clone())),
+        );
+        listed.extend(listed_dirs(authority));
+        differences.extend(
+            local_manifest
+                .empty_dirs
+                .iter()
+                .filter(|d| !listed.contains(*d))
+                .map(|d| ValidationDifference::unknown_file(d.clone())),
+        );
+        // whatever the authority didn't list
+        differences.extend(
+            local_hashes
+                .into_keys()
+                .map(ValidationDifference::unknown_file),
+        );
+    }
+    Ok(Some(differences))
+}
+
+/// Inserts every directory above `path` into `dirs`.
+fn add_parents(dirs: &mut HashSet<RelativePathBuf>, path: &RelativePath) {
+    let mut parent = path.parent();
+    while let Some(d) = parent.filter(|d| !d.as_str().is_empty()) {
+        dirs.insert(d.to_owned());
+        parent = d.parent();
+    }
+}
 
-        for (k, v) in authority_entries.iter() {
-            if let Some(local_entry) = local_entries.get(k) {
-                if local_entry.sha512 != v.sha512 {
+/// Directories the manifest lists as empty or puts anything in, which stay even if they
+/// are empty locally.
+fn listed_dirs(manifest: &Manifest) -> HashSet<RelativePathBuf> {
+    let mut dirs: HashSet<RelativePathBuf> = manifest.empty_dirs.iter().cloned().collect();
+    let paths = manifest
+        .entries
+        .iter()
+        .map(|e| &e.path)
+        .chain(manifest.symlinks.iter().map(|l| &l.path))
+        .chain(manifest.empty_dirs.iter());
+    for p in paths {
+        add_parents(&mut dirs, p);
+    }
+    dirs
+}
+
+/// Whether `dir` is a directory with nothing in it.
+fn is_empty_dir(dir: &Path) -> bool {
+    fs::read_dir(dir).is_ok_and(|mut entries| entries.next().is_none())
+}
+
+/// Authority entries are compared against the local manifest, and checked against the
+/// tree or their sources, this many at a time.
+const DIFF_BATCH: usize = 16 * 1024;
+
+/// Compares one shard of authority entries, keeping the entries that differ. Also returns
+/// the paths the local manifest has as well.
+fn diff_shard<'a>(
+    local: &HashMap<RelativePathBuf, (String, Option<Mode>)>,
+    authority: &'a [ManifestEntry],
+) -> (Vec<ValidationDifference>, Vec<&'a RelativePath>) {
+    let mut differences = Vec::new();
+    let mut seen = Vec::with_capacity(authority.len());
+    for entry in authority {
+        match local.get(&entry.path) {
+            Some((sha512, mode)) => {
+                if *sha512 != entry.sha512 {
                     differences.push(ValidationDifference::hash_mismatch(
-                        v.path.to_relative_path_buf(),
-                        (*v).clone(),
-                        local_entry.sha512.to_string(),
+                        entry.path.clone(),
+                        entry.clone(),
+                        sha512.clone(),
+                    ));
+                } else if entry.mode.is_some() && *mode != entry.mode {
+                    // e.g. synced before modes were applied, the file lost its +x
+                    differences.push(ValidationDifference::mode_mismatch(
+                        entry.path.clone(),
+                        entry.clone(),
                     ));
                 }
-            } else {
-                differences.push(ValidationDifference::missing(
-                    v.path.to_relative_path_buf(),
-                    (*v).clone(),
-                ))
-            }
-        }
-        if force {
-            for (k, v) in local_entries {
-                if !authority_entries.contains_key(&k) {
-                    differences.push(ValidationDifference::unknown_file(v.path.clone()));
-                }
+                seen.push(entry.path.as_relative_path());
             }
+            None => differences.push(ValidationDifference::missing(
+                entry.path.clone(),
+                entry.clone(),
+            )),
         }
-        Ok(Some(differences))
-    } else {
-        Ok(None)
     }
+    (differences, seen)
 }
 
+/// Hashes the files in `dir` against the manifest at `target`, see `verify_entries`.
 #[tracing::instrument]
 pub async fn verify_manifest(
     target: &Url,
     dir: &Path,
     force: bool,
 ) -> Result<Vec<ValidationDifference>> {
-    let (tx, rx) = tokio::sync::mpsc::channel(50);
-    let manifest = manifest::get_manifest(&target)
-        .await?
-        .ok_or_else(|| anyhow!("Remote manifest not found: {}", &target))?;
-    let mut differences = Vec::new();
+    let (manifest, mut spool) =
+        manifest::spool_manifest(target, SpooledEntries::temp()?, |_| Ok(()))
+            .await?
+            .ok_or_else(|| anyhow!(t!("manifest.not_found", target)))?;
+    verify_entries(&manifest, &mut spool, dir, force).await
+}
+
+/// Hashes the files in `dir` against `manifest`, whose entries are in `spool`. Entries are
+/// read back and checked a batch at a time. With `force` anything in `dir` that no entry
+/// lists is a difference too.
+pub async fn verify_entries(
+    manifest: &Manifest,
+    spool: &mut SpooledEntries,
+    dir: &Path,
+    force: bool,
+) -> Result<Vec<ValidationDifference>> {
+    let algo = manifest.algo;
+    let is_dir = |d: &ignore::DirEntry| d.file_type().is_some_and(|t| t.is_dir());
+    // the tree is looked at first, entries cross their paths off as they are read
+    let walker: Vec<ignore::DirEntry> = if force {
+        util::get_walker(dir)?
+            .filter_map(|d| d.ok())
+            .filter(|d| {
+                d.path().is_file()
+                    || d.path_is_symlink()
+                    || (d.depth() > 0 && is_dir(d) && is_empty_dir(d.path()))
+            })
+            .collect()
+    } else {
+        Vec::new()
+    };
+    let mut unlisted: HashSet<PathBuf> = walker
+        .iter()
+        .filter(|d| !is_dir(d))
+        .map(|d| d.path().to_path_buf())
+        .collect();
+    let mut listed = HashSet::new();
+
+    let (tx, rx) = events::channel();
     let h = tokio::spawn(events::event_output(
         rx,
-        "Validating files".into(),
-        manifest.entries.len() as u64,
+        t!("progress.validating"),
+        spool.count() as u64,
     ));
-    let sem = Arc::new(Semaphore::new(10));
-    let mut handles = Vec::new();
-
-    for e in manifest.entries.iter() {
-        //let local_path = dir.join(&e.path);
-        let local_path = e.path.to_logical_path(&dir);
-        let fname = local_path
-            .file_name()
-            .unwrap()
-            .to_string_lossy()
-            .to_string();
-        tx.send(Event::unknown_file_started(fname.clone())).await?;
-        let permit = sem.clone().acquire_owned().await?;
-        let t = tx.clone();
-        let e = e.clone();
-        let fut = async move {
-            if !local_path.exists() {
-                t.send(Event::file_done(fname)).await?;
-                return Ok::<Option<ValidationDifference>, anyhow::Error>(Some(
-                    ValidationDifference::missing(&e.path, e.clone()),
-                ));
-            }
-            let sha512 = util::get_file_hash(&local_path)?;
-            if sha512 != e.sha512 {
-                t.send(Event::file_done(fname)).await?;
-                return Ok::<Option<ValidationDifference>, anyhow::Error>(Some(
-                    ValidationDifference::hash_mismatch(&e.path, e.clone(), sha512),
-                ));
+    let mut differences = Vec::new();
+    let mut entries = spool.entries()?;
+    loop {
+        let batch = entries
+            .by_ref()
+            .take(DIFF_BATCH)
+            .collect::<Result<Vec<ManifestEntry>>>()?;
+        if batch.is_empty() {
+            break;
+        }
+        if force {
+            for e in &batch {
+                unlisted.remove(&e.path.to_logical_path(dir));
+                add_parents(&mut listed, &e.path);
             }
-            t.send(Event::file_done(fname)).await?;
-            drop(permit);
-            Ok(None)
-        };
-        handles.push(tokio::spawn(fut));
-    }
-    for handle in handles {
-        if let Some(d) = handle.await?? {
-            differences.push(d);
         }
+        let checked = util::bounded_tasks(batch, util::jobs().hash, |e| {
+            let t = tx.clone();
+            let local_path = e.path.to_logical_path(dir);
+            async move {
+                let fname = local_path
+                    .file_name()
+                    .unwrap()
+                    .to_string_lossy()
+                    .to_string();
+                t.send(Event::unknown_file_started(fname.clone()));
+                let difference = if !local_path.exists() {
+                    Some(ValidationDifference::missing(&e.path, e.clone()))
+                } else if local_path.metadata().is_ok_and(|m| mtime::trusted(&m, &e)) {
+                    None
+                } else if e
+                    .size
+                    .is_some_and(|s| local_path.metadata().is_ok_and(|m| m.len() != s))
+                {
+                    // no need to read a file that is already the wrong size, e.g. truncated
+                    Some(ValidationDifference::hash_mismatch(
+                        &e.path,
+                        e.clone(),
+                        String::new(),
+                    ))
+                } else {
+                    let sha512 =
+                        util::hash_file(local_path, fname.clone(), algo, t.clone()).await?;
+                    if sha512 != e.sha512 {
+                        Some(ValidationDifference::hash_mismatch(
+                            &e.path,
+                            e.clone(),
+                            sha512,
+                        ))
+                    } else {
+                        None
+                    }
+                };
+                let difference = difference.or_else(|| {
+                    local_path
+                        .metadata()
+                        .is_ok_and(|m| perms::mode_differs(&m, e.mode))
+                        .then(|| ValidationDifference::mode_mismatch(&e.path, e.clone()))
+                });
+                t.send(Event::file_done(fname));
+                Ok(difference)
+            }
+        })
+        .await?;
+        differences.extend(checked.into_iter().flatten());
     }
-    tx.send(Event::close()).await?;
+    tx.send(Event::close());
     h.await??;
     if force {
-        let (tx, rx) = tokio::sync::mpsc::channel(50);
-        let fnames: HashSet<PathBuf> = manifest
-            .entries
-            .iter()
-            .map(|e| e.path.to_logical_path(&dir))
-            .collect();
+        for l in &manifest.symlinks {
+            unlisted.remove(&l.path.to_logical_path(dir));
+        }
+        listed.extend(listed_dirs(manifest));
 
-        let walker: Vec<ignore::DirEntry> = util::get_walker(&dir)?
-            .filter_map(|d| d.ok())
-            .filter(|d| d.path().is_file())
-            .collect();
+        let (tx, rx) = events::channel();
         let h = tokio::spawn(events::event_output(
             rx,
-            "Searching for untracked files".into(),
+            t!("progress.untracked"),
             walker.len() as u64,
         ));
         for dirent in walker {
             let path = dirent.path();
             let relative = RelativePathBuf::from_path(path.strip_prefix(dir)?)?;
             let fname = path.file_name().unwrap().to_string_lossy();
-            tx.send(Event::unknown_file_started(fname.clone())).await?;
-            if !fnames.contains(path) {
+            tx.send(Event::unknown_file_started(fname.clone()));
+            let unexpected = if is_dir(&dirent) {
+                !listed.contains(&relative)
+            } else {
+                unlisted.contains(path)
+            };
+            if unexpected {
                 differences.push(ValidationDifference::unknown_file(relative));
             }
-            tx.send(Event::file_done(fname.clone())).await?;
+            tx.send(Event::file_done(fname.clone()));
         }
-        tx.send(Event::close()).await?;
+        tx.send(Event::close());
         h.await??;
     }
 
     Ok(differences)
 }
+
+/// Something wrong with an entry's source, found without downloading it.
+#[derive(Debug)]
+pub enum SourceProblem {
+    /// The source couldn't be reached or answered with an error status.
+    Unreachable(String),
+    /// The source is a different size than the manifest records.
+    SizeMismatch { expected: u64, actual: u64 },
+    /// The source reports content hashed differently than the manifest records.
+    HashMismatch,
+}
+
+/// Header GCS returns an object's comstar hash in.
+fn stored_hash_header() -> String {
+    format!("x-goog-meta-{}", push::gcs::SHA512_METADATA)
+}
+
+/// Checks one source with a HEAD request, or by asking its backend for the size for
+/// anything that isn't HTTP.
+async fn check_source(e: &ManifestEntry) -> Option<SourceProblem> {
+    if !matches!(e.source.scheme(), "http" | "https") {
+        return match sync::remote_size(&e.source).await {
+            Ok(Some(actual)) => e
+                .size
+                .filter(|expected| *expected != actual)
+                .map(|expected| SourceProblem::SizeMismatch { expected, actual }),
+            Ok(None) => None,
+            Err(err) => Some(SourceProblem::Unreachable(err.to_string())),
+        };
+    }
+    let resp = match http::client().head(e.source.as_ref()).send().await {
+        Ok(r) => r,
+        Err(err) => return Some(SourceProblem::Unreachable(err.to_string())),
+    };
+    if !resp.status().is_success() {
+        return Some(SourceProblem::Unreachable(resp.status().to_string()));
+    }
+    let header = |name: &str| {
+        resp.headers()
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string)
+    };
+    if header(&stored_hash_header()).is_some_and(|h| h != e.sha512) {
+        return Some(SourceProblem::HashMismatch);
+    }
+    // read the header itself, the client hides the length of compressed bodies
+    let length = header(CONTENT_LENGTH.as_str()).and_then(|l| l.parse::<u64>().ok());
+    let gzipped = header(CONTENT_ENCODING.as_str()).as_deref() == Some("gzip");
+    let expected = if gzipped { e.encoded_size } else { e.size };
+    match (length, expected) {
+        (Some(actual), Some(expected)) if actual != expected => {
+            Some(SourceProblem::SizeMismatch { expected, actual })
+        }
+        _ => None,
+    }
+}
+
+/// Looks at the source of every entry in the manifest at `target` without downloading
+/// anything, to find dead links and sources that no longer match the manifest.
+#[tracing::instrument]
+pub async fn check_sources(target: &Url) -> Result<Vec<(RelativePathBuf, SourceProblem)>> {
+    let (_, mut spool) = manifest::spool_manifest(target, SpooledEntries::temp()?, |_| Ok(()))
+        .await?
+        .ok_or_else(|| anyhow!(t!("manifest.not_found", target)))?;
+    let (tx, rx) = events::channel();
+    let h = tokio::spawn(events::event_output(
+        rx,
+        t!("progress.checking_sources"),
+        spool.count() as u64,
+    ));
+    let mut problems = Vec::new();
+    let mut entries = spool.entries()?;
+    loop {
+        let batch = entries
+            .by_ref()
+            .take(DIFF_BATCH)
+            .collect::<Result<Vec<ManifestEntry>>>()?;
+        if batch.is_empty() {
+            break;
+        }
+        let checked = util::bounded_tasks(batch, util::jobs().net, |e| {
+            let t = tx.clone();
+            async move {
+                t.send(Event::unknown_file_started(e.path.as_str()));
+                let problem = check_source(&e).await;
+                t.send(Event::file_done(e.path.as_str()));
+                Ok(problem.map(|p| (e.path, p)))
+            }
+        })
+        .await?;
+        problems.extend(checked.into_iter().flatten());
+    }
+    tx.send(Event::close());
+    h.await??;
+    problems.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(problems)
+}
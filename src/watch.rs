@@ -0,0 +1,224 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::OpenOptions,
+    io::Write,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use notify::{RecursiveMode, Watcher};
+use relative_path::RelativePathBuf;
+use serde::Serialize;
+use structopt::StructOpt;
+use url::Url;
+
+use crate::{
+    i18n::t,
+    manifest::{self, ManifestEntry},
+    util::{self, HashAlgo},
+};
+
+/// Files are checked once they have been quiet this long, so a file being written is
+/// hashed once at the end instead of on every write.
+const SETTLE_TIME: Duration = Duration::from_secs(1);
+
+#[derive(Debug, StructOpt)]
+pub struct WatchOptions {
+    #[structopt(
+        long,
+        conflicts_with = "bucket",
+        help = "Keep running and report files that diverge from the manifest as they change."
+    )]
+    pub watch: bool,
+    #[structopt(
+        long = "watch-log",
+        parse(from_os_str),
+        help = "Append every watch report to this file as JSON lines."
+    )]
+    pub watch_log: Option<PathBuf>,
+    #[structopt(
+        long,
+        parse(try_from_str = Url::parse),
+        help = "POST every watch report to this URL as JSON."
+    )]
+    pub webhook: Option<Url>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum ReportKind {
+    Missing,
+    Mismatch,
+    Unknown,
+    Restored,
+}
+
+#[derive(Debug, Serialize)]
+struct Report {
+    time: DateTime<Utc>,
+    path: RelativePathBuf,
+    kind: ReportKind,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expected: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    actual: Option<String>,
+}
+
+impl Report {
+    fn new(path: RelativePathBuf, kind: ReportKind) -> Self {
+        Report {
+            time: Utc::now(),
+            path,
+            kind,
+            expected: None,
+            actual: None,
+        }
+    }
+
+    fn message(&self) -> String {
+        let msg = match self.kind {
+            ReportKind::Missing => t!("validate.missing", self.path),
+            ReportKind::Mismatch => t!("validate.mismatch", self.path),
+            ReportKind::Unknown => t!("validate.unknown", self.path),
+            ReportKind::Restored => t!("validate.restored", self.path),
+        };
+        format!("[{}] {}", self.time.format("%Y-%m-%d %H:%M:%S"), msg)
+    }
+}
+
+/// Watches `dir` until interrupted, reporting every file whose content stops matching the
+/// manifest at `target`. With `force`, files the manifest doesn't know are reported too.
+pub async fn watch(target: &Url, dir: &Path, force: bool, opts: &WatchOptions) -> Result<()> {
+    let manifest = manifest::get_manifest(target)
+        .await?
+        .ok_or_else(|| anyhow!(t!("manifest.not_found", target)))?;
+    let algo = manifest.algo;
+    let entries: HashMap<RelativePathBuf, ManifestEntry> = manifest
+        .entries
+        .into_iter()
+        .map(|e| (e.path.clone(), e))
+        .collect();
+    let state_dir = util::state_dir(dir);
+
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |res| {
+        let _ = tx.send(res);
+    })?;
+    watcher.watch(dir, RecursiveMode::Recursive)?;
+    println!("{}", t!("validate.watching", dir.display()));
+
+    let client = http::client();
+    let mut pending: HashMap<PathBuf, Instant> = HashMap::new();
+    let mut diverged: HashSet<RelativePathBuf> = HashSet::new();
+    let mut tick = tokio::time::interval(SETTLE_TIME / 4);
+    loop {
+        tokio::select! {
+            res = rx.recv() => {
+                let event: notify::Event = match res {
+                    Some(r) => r?,
+                    None => return Ok(()),
+                };
+                for path in event.paths {
+                    if path.starts_with(&state_dir) {
+                        continue;
+                    }
+                    pending.insert(path, Instant::now());
+                }
+            }
+            _ = tick.tick() => {
+                let settled: Vec<PathBuf> = pending
+                    .iter()
+                    .filter(|(_, at)| at.elapsed() >= SETTLE_TIME)
+                    .map(|(p, _)| p.clone())
+                    .collect();
+                for path in settled {
+                    pending.remove(&path);
+                    if let Some(report) = check(dir, &path, &entries, algo, force, &mut diverged).await? {
+                        publish(&report, opts, &client).await;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Compares one changed path to the manifest. Only changes of state are reported, so a file
+/// that keeps being rewritten with the wrong content is reported once.
+async fn check(
+    dir: &Path,
+    path: &Path,
+    entries: &HashMap<RelativePathBuf, ManifestEntry>,
+    algo: HashAlgo,
+    force: bool,
+    diverged: &mut HashSet<RelativePathBuf>,
+) -> Result<Option<Report>> {
+    let relative = RelativePathBuf::from_path(path.strip_prefix(dir)?)?;
+    if path.is_dir()
+        || relative == "comstar.json"
+        || relative.as_str().starts_with("comstar.json.")
+        || relative == "comstar.lock"
+        || relative == manifest::CHECKSUMS_FILE
+    {
+        return Ok(None);
+    }
+    let report = match entries.get(&relative) {
+        Some(entry) if !path.is_file() => {
+            let mut r = Report::new(relative.clone(), ReportKind::Missing);
+            r.expected = Some(entry.sha512.clone());
+            Some(r)
+        }
+        Some(entry) => {
+            let actual = util::hash_file_with(path.to_path_buf(), algo).await?;
+            if actual == entry.sha512 {
+                None
+            } else {
+                let mut r = Report::new(relative.clone(), ReportKind::Mismatch);
+                r.expected = Some(entry.sha512.clone());
+                r.actual = Some(actual);
+                Some(r)
+            }
+        }
+        None if force && path.is_file() => Some(Report::new(relative.clone(), ReportKind::Unknown)),
+        None => None,
+    };
+    match report {
+        Some(r) if diverged.insert(relative) => Ok(Some(r)),
+        Some(_) => Ok(None),
+        None if diverged.remove(&relative) => Ok(Some(Report::new(relative, ReportKind::Restored))),
+        None => Ok(None),
+    }
+}
+
+/// Prints a report and hands it to the log and webhook. Failing to log or deliver a report
+/// never stops the watch.
+async fn publish(report: &Report, opts: &WatchOptions, client: &reqwest::Client) {
+    println!("{}", report.message());
+    if let Some(log) = &opts.watch_log {
+        let res = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(log)
+            .map_err(anyhow::Error::from)
+            .and_then(|mut f| {
+                serde_json::to_writer(&mut f, report)?;
+                writeln!(f)?;
+                Ok(())
+            });
+        if let Err(e) = res {
+            tracing::warn!("Could not write watch log {}: {}", log.display(), e);
+        }
+    }
+    if let Some(hook) = &opts.webhook {
+        let res = client
+            .post(hook.as_ref())
+            .json(report)
+            .send()
+            .await
+            .and_then(|r| r.error_for_status());
+        if let Err(e) = res {
+            tracing::warn!("Could not deliver watch report to {}: {}", hook, e);
+        }
+    }
+}
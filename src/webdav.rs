@@ -0,0 +1,198 @@
+use std::{path::Path, sync::RwLock};
+
+use anyhow::{anyhow, Result};
+use digest_auth::{AuthContext, HttpMethod};
+use futures::future::BoxFuture;
+use reqwest::{
+    header::{AUTHORIZATION, WWW_AUTHENTICATE},
+    Body, Method, RequestBuilder, Response, StatusCode,
+};
+use tokio::fs::File;
+use tokio_util::io::ReaderStream;
+use url::Url;
+
+use crate::{http, i18n::t};
+
+/// The `http(s)` URL a `dav://` or `davs://` one is served from. Other URLs are returned
+/// as they are.
+pub fn to_http(url: &Url) -> Result<Url> {
+    let scheme = match url.scheme() {
+        "dav" => "http",
+        "davs" => "https",
+        _ => return Ok(url.clone()),
+    };
+    // the url crate won't switch between special and non-special schemes in place
+    let rest = &url.as_str()[url.scheme().len()..];
+    Ok(Url::parse(&format!("{}{}", scheme, rest))?)
+}
+
+/// The `dav(s)` URL of an `http(s)` one, so pushing to it goes through WebDAV rather than
+/// plain PUT requests. Other URLs are returned as they are.
+pub fn to_dav(url: &Url) -> Result<Url> {
+    let scheme = match url.scheme() {
+        "http" => "dav",
+        "https" => "davs",
+        _ => return Ok(url.clone()),
+    };
+    let rest = &url.as_str()[url.scheme().len()..];
+    Ok(Url::parse(&format!("{}{}", scheme, rest))?)
+}
+
+/// `url` without a user name or password, fit to be written into a manifest.
+pub fn without_credentials(url: &Url) -> Url {
+    let mut url = url.clone();
+    let _ = url.set_username("");
+    let _ = url.set_password(None);
+    url
+}
+
+#[derive(Debug, Clone)]
+pub struct Credentials {
+    pub user: String,
+    pub password: String,
+}
+
+impl Credentials {
+    /// What to push to `url` with: `user`, else the user in `url`, and the password from
+    /// `COMSTAR_WEBDAV_PASSWORD`, else the one in `url`. `None` without a user.
+    pub fn for_push(url: &Url, user: Option<String>) -> Option<Self> {
+        let user = user.or_else(|| Some(url.username().to_string()).filter(|u| !u.is_empty()))?;
+        Some(Credentials {
+            user,
+            password: std::env::var("COMSTAR_WEBDAV_PASSWORD")
+                .ok()
+                .or_else(|| url.password().map(str::to_string))
+                .unwrap_or_default(),
+        })
+    }
+}
+
+/// What `push webdav` logs in with.
+static CREDENTIALS: RwLock<Option<Credentials>> = RwLock::new(None);
+
+pub fn set_credentials(credentials: Option<Credentials>) {
+    *CREDENTIALS.write().unwrap() = credentials;
+}
+
+/// Talks to one WebDAV server, answering basic or digest challenges with `credentials`.
+#[derive(Debug, Clone)]
+pub struct Client {
+    client: reqwest::Client,
+    credentials: Option<Credentials>,
+}
+
+impl Client {
+    pub fn new(credentials: Option<Credentials>) -> Self {
+        Client {
+            client: http::client(),
+            credentials,
+        }
+    }
+
+    /// A client with the credentials `set_credentials` was given, if any.
+    pub fn configured() -> Self {
+        Self::new(CREDENTIALS.read().unwrap().clone())
+    }
+
+    /// Sends the request `build` makes, with basic auth up front. If the server wants
+    /// digest auth instead, the request is built again and answered with a digest.
+    async fn send<F>(&self, method: Method, url: &Url, build: F) -> Result<Response>
+    where
+        F: Fn(RequestBuilder) -> futures::future::BoxFuture<'static, Result<RequestBuilder>>,
+    {
+        let url = to_http(url)?;
+        let mut req = self.client.request(method.clone(), url.clone());
+        if let Some(c) = &self.credentials {
+            req = req.basic_auth(&c.user, Some(&c.password));
+        }
+        let resp = build(req).await?.send().await?;
+        let (c, challenge) = match (&self.credentials, resp.headers().get(WWW_AUTHENTICATE)) {
+            (Some(c), Some(h)) if resp.status() == StatusCode::UNAUTHORIZED => {
+                (c, h.to_str()?.to_string())
+            }
+            _ => return Ok(resp),
+        };
+        if !challenge
+            .trim_start()
+            .to_ascii_lowercase()
+            .starts_with("digest")
+        {
+            return Ok(resp);
+        }
+        let mut prompt = digest_auth::parse(&challenge)?;
+        let context = AuthContext::new_with_method(
+            &c.user,
+            &c.password,
+            url.path(),
+            Option::<&[u8]>::None,
+            HttpMethod::from(method.as_str()),
+        );
+        let answer = prompt.respond(&context)?.to_header_string();
+        let req = self
+            .client
+            .request(method, url)
+            .header(AUTHORIZATION, answer);
+        Ok(build(req).await?.send().await?)
+    }
+
+    /// Creates the collection at `url`, a URL ending in a slash, and any missing parents.
+    /// One that already exists is fine.
+    pub fn mkcol<'a>(&'a self, url: &'a Url) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let method = Method::from_bytes(b"MKCOL")?;
+            let resp = self
+                .send(method, url, |r| Box::pin(async move { Ok(r) }))
+                .await?;
+            match resp.status() {
+                s if s.is_success() || s == StatusCode::METHOD_NOT_ALLOWED => Ok(()),
+                // the parent is missing as well
+                StatusCode::CONFLICT if url.path() != "/" => {
+                    self.mkcol(&url.join("..")?).await?;
+                    self.mkcol(url).await
+                }
+                s => Err(anyhow!(t!("webdav.failed", "MKCOL", url, s))),
+            }
+        })
+    }
+
+    async fn put_once(&self, url: &Url, local_file: &Path) -> Result<StatusCode> {
+        let local_file = local_file.to_path_buf();
+        let resp = self
+            .send(Method::PUT, url, move |r| {
+                let local_file = local_file.clone();
+                Box::pin(async move {
+                    let f = File::open(&local_file).await?;
+                    let len = f.metadata().await?.len();
+                    Ok(r.header(reqwest::header::CONTENT_LENGTH, len)
+                        .body(Body::wrap_stream(ReaderStream::new(f))))
+                })
+            })
+            .await?;
+        Ok(resp.status())
+    }
+
+    /// Uploads `local_file` to `url`, streaming it from disk. Collections it goes into are
+    /// created if the server says they are missing.
+    pub async fn put(&self, url: &Url, local_file: &Path) -> Result<()> {
+        let mut status = self.put_once(url, local_file).await?;
+        if status == StatusCode::CONFLICT {
+            self.mkcol(&url.join(".")?).await?;
+            status = self.put_once(url, local_file).await?;
+        }
+        if !status.is_success() {
+            return Err(anyhow!(t!("webdav.failed", "PUT", url, status)));
+        }
+        Ok(())
+    }
+
+    /// Deletes `url`. Deleting something that isn't there is fine.
+    pub async fn delete(&self, url: &Url) -> Result<()> {
+        let resp = self
+            .send(Method::DELETE, url, |r| Box::pin(async move { Ok(r) }))
+            .await?;
+        match resp.status() {
+            s if s.is_success() || s == StatusCode::NOT_FOUND => Ok(()),
+            s => Err(anyhow!(t!("webdav.failed", "DELETE", url, s))),
+        }
+    }
+}
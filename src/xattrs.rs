@@ -0,0 +1,113 @@
+use std::{
+    collections::BTreeMap,
+    path::Path,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use anyhow::{anyhow, Result};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+
+/// Recorded by `generate --xattrs` when no `--xattr-pattern` is given: SELinux labels,
+/// POSIX ACLs and anything in the user namespace.
+const DEFAULT_PATTERNS: &[&str] = &[
+    "user.*",
+    "security.selinux",
+    "system.posix_acl_access",
+    "system.posix_acl_default",
+];
+
+static RESTORE: AtomicBool = AtomicBool::new(false);
+
+/// Makes sync put recorded attributes back on every file it writes.
+pub fn configure(restore: bool) {
+    RESTORE.store(restore, Ordering::Relaxed);
+}
+
+/// Which attribute names to record, falling back to `DEFAULT_PATTERNS`.
+pub fn name_filter(patterns: &[String]) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    if patterns.is_empty() {
+        for p in DEFAULT_PATTERNS {
+            builder.add(Glob::new(p)?);
+        }
+    } else {
+        for p in patterns {
+            builder.add(Glob::new(p)?);
+        }
+    }
+    Ok(builder.build()?)
+}
+
+fn to_hex(value: &[u8]) -> String {
+    value.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(value: &str) -> Result<Vec<u8>> {
+    if value.len() % 2 != 0 {
+        return Err(anyhow!("Invalid attribute value {}", value));
+    }
+    (0..value.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&value[i..i + 2], 16)
+                .map_err(|_| anyhow!("Invalid attribute value {}", value))
+        })
+        .collect()
+}
+
+/// Reads the attributes of `path` whose names match `filter`, values hex encoded since
+/// they are often binary.
+#[cfg(unix)]
+pub fn read(path: &Path, filter: &GlobSet) -> Result<BTreeMap<String, String>> {
+    let mut attrs = BTreeMap::new();
+    if !xattr::SUPPORTED_PLATFORM {
+        return Ok(attrs);
+    }
+    for name in xattr::list(path)? {
+        let name = match name.to_str() {
+            Some(n) if filter.is_match(n) => n.to_string(),
+            _ => continue,
+        };
+        if let Some(value) = xattr::get(path, &name)? {
+            attrs.insert(name, to_hex(&value));
+        }
+    }
+    Ok(attrs)
+}
+
+#[cfg(not(unix))]
+pub fn read(_path: &Path, _filter: &GlobSet) -> Result<BTreeMap<String, String>> {
+    Ok(BTreeMap::new())
+}
+
+/// Sets recorded attributes on a file sync just wrote, if sync was asked to.
+pub fn restore(path: &Path, attrs: &BTreeMap<String, String>) -> Result<()> {
+    if !RESTORE.load(Ordering::Relaxed) || attrs.is_empty() {
+        return Ok(());
+    }
+    for (name, value) in attrs {
+        set(path, name, &from_hex(value)?)?;
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn set(path: &Path, name: &str, value: &[u8]) -> Result<()> {
+    xattr::set(path, name, value).map_err(|e| {
+        anyhow!(
+            "Could not set attribute {} on {}: {}",
+            name,
+            path.display(),
+            e
+        )
+    })
+}
+
+#[cfg(not(unix))]
+fn set(path: &Path, name: &str, _value: &[u8]) -> Result<()> {
+    Err(anyhow!(
+        "Could not set attribute {} on {}: extended attributes are not supported on this platform",
+        name,
+        path.display()
+    ))
+}